@@ -0,0 +1,535 @@
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use chacha20poly1305::{
+    ChaCha20Poly1305, Key, KeyInit, Nonce,
+    aead::{Aead, Payload},
+};
+use hkdf::Hkdf;
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256, Sha512};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    env, fs,
+    path::{Path, PathBuf},
+};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// Version 2 binds every wrapped key to its envelope, version 1 envelopes
+/// are still opened.
+const ENVELOPE_VERSION: u8 = 2;
+const WRAP_INFO: &[u8] = b"rustcrab e2ee v1 key wrap";
+const SAFETY_NUMBER_INFO: &[u8] = b"rustcrab safety number v1";
+
+/// Directory holding the local keys of every account used on this machine:
+/// `$RUSTCRAB_HOME`, or `~/.rustcrab`.
+fn data_dir(username: &str) -> PathBuf {
+    let base = match env::var_os("RUSTCRAB_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => match env::var_os("HOME") {
+            Some(home) => PathBuf::from(home).join(".rustcrab"),
+            None => PathBuf::from(".rustcrab"),
+        },
+    };
+    base.join(username)
+}
+
+fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    std::io::Write::write_all(&mut options.open(path)?, contents)
+}
+
+#[derive(Deserialize, Serialize, Clone, PartialEq)]
+pub struct DeviceKey {
+    pub device_id: String,
+    pub public_key: String,
+}
+
+#[derive(Deserialize, Serialize)]
+struct StoredIdentity {
+    device_id: String,
+    secret_key: String,
+}
+
+/// The X25519 identity key of this device. Created on the first login and
+/// kept in `identity.json`; the secret never leaves the machine.
+pub struct Identity {
+    pub device_id: String,
+    secret: StaticSecret,
+    public: PublicKey,
+}
+
+impl Identity {
+    pub fn load_or_create(username: &str) -> Result<Self, String> {
+        let dir = data_dir(username);
+        let path = dir.join("identity.json");
+        if let Ok(raw) = fs::read(&path) {
+            let stored: StoredIdentity = serde_json::from_slice(&raw)
+                .map_err(|err| format!("Invalid identity file {}: {err}", path.display()))?;
+            let bytes: [u8; 32] = BASE64
+                .decode(&stored.secret_key)
+                .ok()
+                .and_then(|b| b.try_into().ok())
+                .ok_or(format!("Invalid secret key in {}", path.display()))?;
+            let secret = StaticSecret::from(bytes);
+            return Ok(Self {
+                device_id: stored.device_id,
+                public: PublicKey::from(&secret),
+                secret,
+            });
+        }
+
+        let identity = Self::generate();
+        let stored = StoredIdentity {
+            device_id: identity.device_id.clone(),
+            secret_key: BASE64.encode(identity.secret.to_bytes()),
+        };
+        fs::create_dir_all(&dir)
+            .map_err(|err| format!("Error while creating {}: {err}", dir.display()))?;
+        let raw = serde_json::to_vec_pretty(&stored).map_err(|err| err.to_string())?;
+        write_private(&path, &raw)
+            .map_err(|err| format!("Error while saving {}: {err}", path.display()))?;
+        Ok(identity)
+    }
+
+    /// A new device identity, not saved anywhere.
+    fn generate() -> Self {
+        let secret = StaticSecret::random_from_rng(OsRng);
+        Self {
+            device_id: uuid::Uuid::new_v4().to_string(),
+            public: PublicKey::from(&secret),
+            secret,
+        }
+    }
+
+    pub fn public_key(&self) -> String {
+        BASE64.encode(self.public.as_bytes())
+    }
+
+    /// Key used to wrap a message key between this device and `other`.
+    fn wrapping_key(&self, other: &PublicKey, sender: &PublicKey, receiver: &PublicKey) -> Key {
+        let shared = self.secret.diffie_hellman(other);
+        let mut info = WRAP_INFO.to_vec();
+        info.extend_from_slice(sender.as_bytes());
+        info.extend_from_slice(receiver.as_bytes());
+        let mut key = Key::default();
+        let _ = Hkdf::<Sha256>::new(None, shared.as_bytes()).expand(&info, &mut key);
+        key
+    }
+}
+
+fn decode_key(value: &str) -> Option<PublicKey> {
+    let bytes: [u8; 32] = BASE64.decode(value).ok()?.try_into().ok()?;
+    Some(PublicKey::from(bytes))
+}
+
+fn random_nonce() -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    OsRng.fill_bytes(&mut nonce);
+    nonce
+}
+
+#[derive(Deserialize, Serialize)]
+struct WrappedKey {
+    recipient: String,
+    nonce: String,
+    key: String,
+}
+
+/// Binds a wrapped key to the envelope and the device it is meant for, so
+/// it cannot be moved to another envelope or entry.
+fn wrap_aad(envelope_nonce: &str, recipient: &str) -> String {
+    format!("{envelope_nonce}\n{recipient}")
+}
+
+/// Wraps `message_key` for `recipient`, one of the recipient device keys.
+fn wrap_key(
+    identity: &Identity,
+    recipient: &str,
+    envelope_nonce: &str,
+    message_key: &Key,
+) -> Result<Option<WrappedKey>, String> {
+    let Some(public) = decode_key(recipient) else {
+        return Ok(None);
+    };
+    let wrap_nonce = random_nonce();
+    let wrapping_key = identity.wrapping_key(&public, &identity.public, &public);
+    let aad = wrap_aad(envelope_nonce, recipient);
+    let wrapped = ChaCha20Poly1305::new(&wrapping_key)
+        .encrypt(
+            Nonce::from_slice(&wrap_nonce),
+            Payload {
+                msg: message_key.as_slice(),
+                aad: aad.as_bytes(),
+            },
+        )
+        .map_err(|_| "Error while encrypting the message".to_string())?;
+    Ok(Some(WrappedKey {
+        recipient: recipient.to_string(),
+        nonce: BASE64.encode(wrap_nonce),
+        key: BASE64.encode(wrapped),
+    }))
+}
+
+/// Unwraps the message key `sender` wrapped for this device. Version 1
+/// envelopes have no `envelope_nonce` binding.
+fn unwrap_key(
+    identity: &Identity,
+    sender: &PublicKey,
+    wrapped: &WrappedKey,
+    envelope_nonce: Option<&str>,
+) -> Result<Vec<u8>, String> {
+    let decode = |v: &str| BASE64.decode(v).map_err(|_| "Malformed encrypted message");
+    let wrap_nonce = decode(&wrapped.nonce)?;
+    let key = decode(&wrapped.key)?;
+    let aad = envelope_nonce
+        .map(|nonce| wrap_aad(nonce, &wrapped.recipient))
+        .unwrap_or_default();
+    let wrapping_key = identity.wrapping_key(sender, sender, &identity.public);
+    ChaCha20Poly1305::new(&wrapping_key)
+        .decrypt(
+            Nonce::from_slice(&wrap_nonce),
+            Payload {
+                msg: &key,
+                aad: aad.as_bytes(),
+            },
+        )
+        .map_err(|_| "Unable to decrypt this message".to_string())
+}
+
+/// What the server stores in `messages.content` for an encrypted message.
+/// The body is sealed with a random message key, which is wrapped once for
+/// every recipient device (including the sender's own devices).
+#[derive(Deserialize, Serialize)]
+struct Envelope {
+    v: u8,
+    sender_key: String,
+    nonce: String,
+    body: String,
+    keys: Vec<WrappedKey>,
+}
+
+/// Encrypts `plaintext` from `from` to `to` for every key in `recipients`.
+pub fn seal(
+    identity: &Identity,
+    from: &str,
+    to: &str,
+    plaintext: &str,
+    recipients: &[String],
+) -> Result<String, String> {
+    let mut message_key = Key::default();
+    OsRng.fill_bytes(&mut message_key);
+    let nonce = random_nonce();
+    let aad = format!("{from}\n{to}");
+    let body = ChaCha20Poly1305::new(&message_key)
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: plaintext.as_bytes(),
+                aad: aad.as_bytes(),
+            },
+        )
+        .map_err(|_| "Error while encrypting the message".to_string())?;
+
+    let nonce = BASE64.encode(nonce);
+    let mut keys = Vec::new();
+    let unique: BTreeSet<&String> = recipients.iter().collect();
+    for recipient in unique {
+        keys.extend(wrap_key(identity, recipient, &nonce, &message_key)?);
+    }
+    let envelope = Envelope {
+        v: ENVELOPE_VERSION,
+        sender_key: identity.public_key(),
+        nonce,
+        body: BASE64.encode(body),
+        keys,
+    };
+    serde_json::to_string(&envelope).map_err(|err| err.to_string())
+}
+
+/// Decrypts an envelope addressed to this device. Returns the plaintext and
+/// the sender's device key, which the caller should check against the keys
+/// it knows for `from`.
+pub fn open(
+    identity: &Identity,
+    from: &str,
+    to: &str,
+    envelope: &str,
+) -> Result<(String, String), String> {
+    let envelope: Envelope =
+        serde_json::from_str(envelope).map_err(|_| "Malformed encrypted message".to_string())?;
+    let binding = match envelope.v {
+        1 => None,
+        ENVELOPE_VERSION => Some(envelope.nonce.as_str()),
+        v => return Err(format!("Unsupported encryption version {v}")),
+    };
+    let own_key = identity.public_key();
+    let wrapped = envelope
+        .keys
+        .iter()
+        .find(|k| k.recipient == own_key)
+        .ok_or("This message was not encrypted for this device".to_string())?;
+    let sender = decode_key(&envelope.sender_key).ok_or("Invalid sender key".to_string())?;
+    let decode = |v: &str| BASE64.decode(v).map_err(|_| "Malformed encrypted message");
+
+    let message_key = unwrap_key(identity, &sender, wrapped, binding)?;
+    let nonce = decode(&envelope.nonce)?;
+    let aad = format!("{from}\n{to}");
+    let plaintext = ChaCha20Poly1305::new(Key::from_slice(&message_key))
+        .decrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: &decode(&envelope.body)?,
+                aad: aad.as_bytes(),
+            },
+        )
+        .map_err(|_| "Unable to decrypt this message".to_string())?;
+    let plaintext = String::from_utf8(plaintext).map_err(|err| err.to_string())?;
+    Ok((plaintext, envelope.sender_key))
+}
+
+#[derive(Deserialize, Serialize, Default)]
+struct PinnedUser {
+    keys: BTreeMap<String, String>,
+    verified: bool,
+    changed: bool,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Trust {
+    Unverified,
+    Verified,
+    KeyChanged,
+}
+
+/// Device keys seen for other users, pinned on first use and kept in
+/// `known_keys.json`. A later change to a user's keys clears the verified
+/// flag and is reported until the user acknowledges it.
+pub struct KeyStore {
+    path: PathBuf,
+    users: BTreeMap<String, PinnedUser>,
+}
+
+impl KeyStore {
+    pub fn load(username: &str) -> Self {
+        let path = data_dir(username).join("known_keys.json");
+        let users = match fs::read(&path) {
+            Ok(raw) => serde_json::from_slice(&raw).unwrap_or_else(|err| {
+                println!("Ignoring invalid {}: {err}", path.display());
+                BTreeMap::new()
+            }),
+            Err(_) => BTreeMap::new(),
+        };
+        Self { path, users }
+    }
+
+    fn save(&self) {
+        let result = serde_json::to_vec_pretty(&self.users)
+            .map_err(|err| err.to_string())
+            .and_then(|raw| write_private(&self.path, &raw).map_err(|err| err.to_string()));
+        if let Err(err) = result {
+            println!("Error while saving {}: {err}", self.path.display());
+        }
+    }
+
+    /// Records the keys fetched from the key directory for `user`.
+    pub fn update(&mut self, user: &str, keys: Vec<DeviceKey>) {
+        let keys: BTreeMap<String, String> = keys
+            .into_iter()
+            .filter(|k| decode_key(&k.public_key).is_some())
+            .map(|k| (k.device_id, k.public_key))
+            .collect();
+        let pinned = self.users.entry(user.to_string()).or_default();
+        if pinned.keys == keys {
+            return;
+        }
+        if !pinned.keys.is_empty() {
+            pinned.changed = true;
+            pinned.verified = false;
+        }
+        pinned.keys = keys;
+        self.save();
+    }
+
+    pub fn keys(&self, user: &str) -> Vec<String> {
+        self.users
+            .get(user)
+            .map(|u| u.keys.values().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn knows(&self, user: &str, key: &str) -> bool {
+        self.users
+            .get(user)
+            .is_some_and(|u| u.keys.values().any(|k| k == key))
+    }
+
+    pub fn trust(&self, user: &str) -> Trust {
+        match self.users.get(user) {
+            Some(u) if u.changed => Trust::KeyChanged,
+            Some(u) if u.verified => Trust::Verified,
+            _ => Trust::Unverified,
+        }
+    }
+
+    pub fn set_verified(&mut self, user: &str) {
+        if let Some(u) = self.users.get_mut(user) {
+            u.verified = true;
+            u.changed = false;
+            self.save();
+        }
+    }
+
+    pub fn acknowledge_change(&mut self, user: &str) {
+        if let Some(u) = self.users.get_mut(user) {
+            u.changed = false;
+            self.save();
+        }
+    }
+}
+
+/// Number both users compare out of band to check that no one swapped the
+/// keys in the directory. It covers every device key of both users and is
+/// the same on both sides.
+pub fn safety_number(user_a: &str, keys_a: &[String], user_b: &str, keys_b: &[String]) -> String {
+    let mut sides = [(user_a, keys_a), (user_b, keys_b)];
+    sides.sort_by_key(|(user, _)| *user);
+    let mut hasher = Sha512::new();
+    hasher.update(SAFETY_NUMBER_INFO);
+    for (user, keys) in sides {
+        hasher.update(user.as_bytes());
+        hasher.update([0u8]);
+        let mut keys: Vec<Vec<u8>> = keys.iter().filter_map(|k| BASE64.decode(k).ok()).collect();
+        keys.sort();
+        for key in keys {
+            hasher.update(&key);
+        }
+        hasher.update([0u8]);
+    }
+    let digest = hasher.finalize();
+    digest
+        .chunks(5)
+        .take(12)
+        .map(|chunk| {
+            let value = chunk.iter().fold(0u64, |acc, b| (acc << 8) | u64::from(*b));
+            format!("{:05}", value % 100_000)
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn envelope(sealed: &str) -> Envelope {
+        serde_json::from_str(sealed).unwrap()
+    }
+
+    #[test]
+    fn sealed_messages_open_on_every_recipient_device() {
+        let alice = Identity::generate();
+        let alice_laptop = Identity::generate();
+        let bob = Identity::generate();
+        let recipients = [
+            bob.public_key(),
+            alice.public_key(),
+            alice_laptop.public_key(),
+        ];
+        let sealed = seal(&alice, "alice", "bob", "hi bob", &recipients).unwrap();
+
+        for device in [&bob, &alice, &alice_laptop] {
+            let (plaintext, sender) = open(device, "alice", "bob", &sealed).unwrap();
+            assert_eq!(plaintext, "hi bob");
+            assert_eq!(sender, alice.public_key());
+        }
+        let carol = Identity::generate();
+        assert!(open(&carol, "alice", "bob", &sealed).is_err());
+    }
+
+    #[test]
+    fn tampered_messages_do_not_open() {
+        let alice = Identity::generate();
+        let bob = Identity::generate();
+        let sealed = seal(&alice, "alice", "bob", "hi bob", &[bob.public_key()]).unwrap();
+
+        // The sender and receiver are authenticated with the body.
+        assert!(open(&bob, "bob", "alice", &sealed).is_err());
+        assert!(open(&bob, "mallory", "bob", &sealed).is_err());
+
+        let mut tampered = envelope(&sealed);
+        let mut body = BASE64.decode(&tampered.body).unwrap();
+        body[0] ^= 1;
+        tampered.body = BASE64.encode(body);
+        let tampered = serde_json::to_string(&tampered).unwrap();
+        assert!(open(&bob, "alice", "bob", &tampered).is_err());
+    }
+
+    #[test]
+    fn wrapped_keys_are_bound_to_their_envelope() {
+        let alice = Identity::generate();
+        let bob = Identity::generate();
+        let mut message_key = Key::default();
+        OsRng.fill_bytes(&mut message_key);
+        let wrapped = wrap_key(&alice, &bob.public_key(), "nonce-a", &message_key)
+            .unwrap()
+            .unwrap();
+
+        let unwrapped = unwrap_key(&bob, &alice.public, &wrapped, Some("nonce-a")).unwrap();
+        assert_eq!(unwrapped, message_key.as_slice());
+        assert!(unwrap_key(&bob, &alice.public, &wrapped, Some("nonce-b")).is_err());
+        assert!(unwrap_key(&bob, &alice.public, &wrapped, None).is_err());
+        let moved = WrappedKey {
+            recipient: alice.public_key(),
+            ..wrapped
+        };
+        assert!(unwrap_key(&bob, &alice.public, &moved, Some("nonce-a")).is_err());
+    }
+
+    #[test]
+    fn safety_numbers_match_on_both_sides() {
+        let a = [
+            Identity::generate().public_key(),
+            Identity::generate().public_key(),
+        ];
+        let b = [Identity::generate().public_key()];
+        let number = safety_number("alice", &a, "bob", &b);
+        assert_eq!(number, safety_number("bob", &b, "alice", &a));
+        assert_eq!(number.split(' ').count(), 12);
+
+        let swapped = [Identity::generate().public_key()];
+        assert_ne!(number, safety_number("alice", &a, "bob", &swapped));
+    }
+
+    #[test]
+    fn a_changed_key_clears_the_verification() {
+        let path = env::temp_dir().join(format!("known_keys-{}.json", uuid::Uuid::new_v4()));
+        let mut store = KeyStore {
+            path: path.clone(),
+            users: BTreeMap::new(),
+        };
+        let key = |public_key: String| DeviceKey {
+            device_id: "phone".to_string(),
+            public_key,
+        };
+        let first = Identity::generate().public_key();
+
+        store.update("bob", vec![key(first.clone())]);
+        assert!(store.knows("bob", &first));
+        assert!(store.trust("bob") == Trust::Unverified);
+        store.set_verified("bob");
+        store.update("bob", vec![key(first.clone())]);
+        assert!(store.trust("bob") == Trust::Verified);
+
+        let second = Identity::generate().public_key();
+        store.update("bob", vec![key(second.clone())]);
+        assert!(store.trust("bob") == Trust::KeyChanged);
+        assert_eq!(store.keys("bob"), [second]);
+        store.acknowledge_change("bob");
+        assert!(store.trust("bob") == Trust::Unverified);
+
+        let _ = fs::remove_file(path);
+    }
+}
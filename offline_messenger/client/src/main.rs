@@ -1,12 +1,19 @@
+mod e2ee;
+mod markdown;
+
+use chrono::{DateTime, Local, NaiveDate, Utc};
 use core::f32;
+use e2ee::{DeviceKey, Identity, KeyStore, Trust};
 use eframe::egui;
 use futures_util::{SinkExt, StreamExt};
 use reqwest::Certificate;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::{
+    collections::HashSet,
     fs,
-    path::PathBuf,
     sync::mpsc::{Receiver, Sender, channel},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 use tokio_tungstenite::connect_async_tls_with_config;
 
@@ -33,6 +40,45 @@ struct ChatMessage {
     message: String,
     resp_msg: Option<String>,
     resp_user: Option<String>,
+    encrypted: bool,
+    message_id: Option<i64>,
+}
+/// A stored message as the server sends it in a chat's history.
+#[derive(Deserialize, Serialize, Clone)]
+struct HistoryMessage {
+    id: i64,
+    from: String,
+    to: String,
+    content: String,
+    /// Whether `content` and the quoted message are encrypted envelopes.
+    encrypted: bool,
+    sent_at: DateTime<Utc>,
+    reply_to: Option<Reply>,
+    edited_at: Option<DateTime<Utc>>,
+}
+#[derive(Deserialize, Serialize, Clone)]
+struct Reply {
+    user: String,
+    message: String,
+}
+/// Where `user` has read the conversation with `with` up to.
+#[derive(Deserialize, Serialize, Clone)]
+struct ReadMark {
+    user: String,
+    with: String,
+    last_read: i64,
+}
+/// What changed after the cursor sent in a `Sync` request.
+#[derive(Deserialize, Serialize, Clone)]
+struct Synced {
+    messages: Vec<HistoryMessage>,
+    deleted: Vec<i64>,
+    reads: Vec<ReadMark>,
+    cursor: i64,
+    /// Another page is waiting.
+    more: bool,
+    /// The cursor was too old, everything has to be reloaded.
+    reset: bool,
 }
 #[derive(Clone, PartialEq)]
 enum MessageStatus {
@@ -40,6 +86,15 @@ enum MessageStatus {
     Sent,
     Failed,
 }
+/// How a message travelled, shown next to it in the chat.
+#[derive(Clone, PartialEq)]
+enum Encryption {
+    Plain,
+    Encrypted,
+    /// Decrypted, but signed by a device key not known for the sender.
+    UnknownKey,
+    Unreadable,
+}
 struct OnScreenMessage {
     id: String,
     from: String,
@@ -47,6 +102,46 @@ struct OnScreenMessage {
     resp_msg: Option<String>,
     resp_usr: Option<String>,
     status: MessageStatus,
+    encryption: Encryption,
+    /// Server-side id, known once the message is stored.
+    message_id: Option<i64>,
+    sent_at: DateTime<Utc>,
+    edited: bool,
+}
+/// A message waiting on the server to be delivered later.
+#[derive(Deserialize, Serialize, Clone)]
+struct ScheduledItem {
+    scheduled_id: i64,
+    to: String,
+    message: String,
+    resp_msg: Option<String>,
+    resp_user: Option<String>,
+    encrypted: bool,
+    /// Unix seconds.
+    deliver_at: i64,
+}
+/// One entry of the inbox in the side panel.
+#[derive(Deserialize, Serialize, Clone)]
+struct Conversation {
+    with: String,
+    last_from: Option<String>,
+    last_message: Option<String>,
+    encrypted: bool,
+    /// Unix seconds.
+    last_at: Option<i64>,
+    last_message_id: Option<i64>,
+    unread: i64,
+}
+#[derive(Deserialize)]
+struct KeyDirectoryResp {
+    keys: Vec<DeviceKey>,
+}
+#[derive(Serialize)]
+struct PublishKeyReq {
+    username: String,
+    token: String,
+    device_id: String,
+    public_key: String,
 }
 #[derive(Serialize, Deserialize, Clone)]
 struct Response {
@@ -57,23 +152,46 @@ struct Response {
 struct LoginResp {
     succes: bool,
     token: String,
+    /// How the account is spelled, the name typed may differ in case.
+    #[serde(default)]
+    username: String,
     message: String,
 }
 
 enum LoginEvent {
     Signin,
-    Login(String),
+    /// The session token and the account's username.
+    Login((String, String)),
     Error(String),
-    ServerResponse((String, bool, String)),
-    ChatDump(Vec<(String, String, Option<String>, Option<String>)>),
+    ServerResponse((String, bool, String, Option<i64>)),
+    ChatDump(Vec<HistoryMessage>),
+    Synced(Synced),
     NewMessage(ChatMessage),
-    TheList(Vec<String>),
-    ConnectionLost,
+    Conversations(Vec<Conversation>),
+    ConversationUpdate(Conversation),
+    DeviceKeys((String, Vec<DeviceKey>)),
+    ExpirySetting((String, Option<i64>, Option<String>)),
+    MessagesExpired(Vec<i64>),
+    ScheduledList(Vec<ScheduledItem>),
+    ScheduledDelivered(i64),
+    ServerShutdown(u64),
+    CommandOutput((String, String)),
+    /// The server answered on the connection with this id.
+    Connected(u64),
+    /// The connection with this id ended, rejected when the server closed it
+    /// without a word because the session is gone.
+    ConnectionLost((u64, bool)),
 }
 enum Event {
     NewMessage(ChatMessage),
     ChangeChat((String, i64)),
-    GetUsersList(String),
+    GetConversations,
+    MarkRead((String, i64)),
+    Sync(Option<i64>),
+    SetExpiry((String, Option<i64>)),
+    ScheduleMessage((ChatMessage, i64)),
+    CancelScheduled(i64),
+    Logout,
 }
 enum Page {
     Signin,
@@ -92,14 +210,40 @@ enum WsMessage {
         message: String,
         resp_msg: Option<String>,
         resp_user: Option<String>,
+        encrypted: bool,
     },
     GetMessage {
         from: String,
         idx: i64,
     },
-    GetUserList {
-        user: String,
+    GetConversations,
+    MarkRead {
+        with: String,
+        message_id: i64,
+    },
+    Sync {
+        since: Option<i64>,
+    },
+    SetExpiry {
+        id: String,
+        with: String,
+        expiry_secs: Option<i64>,
     },
+    ScheduleMessage {
+        id: String,
+        to: String,
+        message: String,
+        resp_msg: Option<String>,
+        resp_user: Option<String>,
+        encrypted: bool,
+        deliver_at: i64,
+    },
+    ListScheduled,
+    CancelScheduled {
+        id: String,
+        scheduled_id: i64,
+    },
+    Logout,
 }
 #[derive(Deserialize, Serialize, Clone)]
 #[serde(tag = "type")]
@@ -110,22 +254,232 @@ enum WsMessageBack {
         message: String,
         resp_msg: Option<String>,
         resp_user: Option<String>,
+        #[serde(default)]
+        encrypted: bool,
+        message_id: i64,
     },
     Response {
         id: String,
         succes: bool,
         message: String,
+        #[serde(default)]
+        message_id: Option<i64>,
+    },
+    ExpirySetting {
+        with: String,
+        expiry_secs: Option<i64>,
+        changed_by: Option<String>,
+    },
+    MessagesExpired {
+        ids: Vec<i64>,
+    },
+    ScheduledList {
+        items: Vec<ScheduledItem>,
+    },
+    ScheduledDelivered {
+        scheduled_id: i64,
+        message_id: i64,
     },
     Chat {
-        messages: Vec<(String, String, Option<String>, Option<String>)>,
+        messages: Vec<HistoryMessage>,
+    },
+    Conversations {
+        conversations: Vec<Conversation>,
+    },
+    ConversationUpdate {
+        conversation: Conversation,
     },
-    UserList {
-        list: Vec<String>,
+    Synced(Synced),
+    ServerShutdown {
+        reconnect_after_ms: u64,
+    },
+    CommandOutput {
+        id: String,
+        text: String,
     },
 }
 
+/// Timers offered for disappearing messages.
+const EXPIRY_CHOICES: &[(Option<i64>, &str)] = &[
+    (None, "Off"),
+    (Some(30), "30 seconds"),
+    (Some(5 * 60), "5 minutes"),
+    (Some(60 * 60), "1 hour"),
+    (Some(24 * 60 * 60), "1 day"),
+    (Some(7 * 24 * 60 * 60), "1 week"),
+];
+
+/// How often the inbox is reloaded as a whole.
+const INBOX_REFRESH: Duration = Duration::from_secs(30);
+/// First and longest wait before reconnecting after the connection dropped.
+const RECONNECT_MIN: Duration = Duration::from_secs(1);
+const RECONNECT_MAX: Duration = Duration::from_secs(30);
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+/// How long until a scheduled message goes out, e.g. "in 2 h 5 min".
+fn until_label(deliver_at: i64) -> String {
+    let secs = (deliver_at - unix_now()).max(0);
+    match secs {
+        0..60 => "in less than a minute".to_string(),
+        60..3600 => format!("in {} min", secs / 60),
+        3600..86_400 => format!("in {} h {} min", secs / 3600, secs % 3600 / 60),
+        _ => format!("in {} days {} h", secs / 86_400, secs % 86_400 / 3600),
+    }
+}
+
+/// How long ago something happened, e.g. "5 min".
+fn ago_label(at: i64) -> String {
+    let secs = (unix_now() - at).max(0);
+    match secs {
+        0..60 => "now".to_string(),
+        60..3600 => format!("{} min", secs / 60),
+        3600..86_400 => format!("{} h", secs / 3600),
+        _ => format!("{} d", secs / 86_400),
+    }
+}
+
+fn expiry_label(expiry_secs: Option<i64>) -> String {
+    match EXPIRY_CHOICES.iter().find(|(secs, _)| *secs == expiry_secs) {
+        Some((_, label)) => label.to_string(),
+        None => format!("{} seconds", expiry_secs.unwrap_or_default()),
+    }
+}
+
+/// Separator above the first message of each day, in local time.
+fn show_day(ui: &mut egui::Ui, date: NaiveDate) {
+    let today = Local::now().date_naive();
+    let label = if date == today {
+        "Today".to_string()
+    } else if today.pred_opt() == Some(date) {
+        "Yesterday".to_string()
+    } else {
+        date.format("%A, %-d %B %Y").to_string()
+    };
+    ui.vertical_centered(|ui| {
+        ui.label(
+            egui::RichText::new(label)
+                .size(11.0)
+                .color(egui::Color32::GRAY),
+        );
+    });
+}
+
+/// Local time a message was sent, and whether it was edited since.
+fn show_time(ui: &mut egui::Ui, msg: &OnScreenMessage) {
+    let mut text = msg
+        .sent_at
+        .with_timezone(&Local)
+        .format("%H:%M")
+        .to_string();
+    if msg.edited {
+        text.push_str(" · edited");
+    }
+    ui.label(
+        egui::RichText::new(text)
+            .size(10.0)
+            .color(egui::Color32::LIGHT_GRAY),
+    );
+}
+
+/// Small note under a message that was encrypted or could not be read.
+fn show_encryption(ui: &mut egui::Ui, encryption: &Encryption) {
+    let (text, color) = match encryption {
+        Encryption::Plain => return,
+        Encryption::Encrypted => ("🔒", egui::Color32::LIGHT_GRAY),
+        Encryption::UnknownKey => (
+            "⚠ Sent from a device key not known for this user",
+            egui::Color32::from_rgb(255, 165, 0),
+        ),
+        Encryption::Unreadable => ("⚠ Could not be decrypted", egui::Color32::LIGHT_RED),
+    };
+    ui.label(egui::RichText::new(text).size(10.0).color(color));
+}
+
+/// PEM client certificate and key named by MESSENGER_CLIENT_CERT and
+/// MESSENGER_CLIENT_KEY, for servers that use mutual TLS. The key must be
+/// PKCS#8.
+fn client_certificate() -> Option<(Vec<u8>, Vec<u8>)> {
+    let cert = std::env::var("MESSENGER_CLIENT_CERT").ok()?;
+    let key = std::env::var("MESSENGER_CLIENT_KEY").ok()?;
+    match (fs::read(&cert), fs::read(&key)) {
+        (Ok(cert), Ok(key)) => Some((cert, key)),
+        (Err(err), _) | (_, Err(err)) => {
+            println!("Error while reading the client certificate: {err}");
+            None
+        }
+    }
+}
+
+/// HTTP client builder carrying the client certificate, if one is configured.
+fn http_builder() -> Result<reqwest::ClientBuilder, String> {
+    let builder = reqwest::Client::builder();
+    match client_certificate() {
+        Some((cert, key)) => match reqwest::Identity::from_pem(&[cert, key].concat()) {
+            Ok(identity) => Ok(builder.identity(identity)),
+            Err(err) => Err(format!("Invalid client certificate: {err}")),
+        },
+        None => Ok(builder),
+    }
+}
+
+/// DER certificate the server has to present: the PEM file named by
+/// MESSENGER_SERVER_CERT, or the certificate the server presents now if its
+/// SHA-256 matches MESSENGER_SERVER_FINGERPRINT (logged by the server at
+/// startup). None when neither is set, then any certificate is accepted.
+async fn server_certificate() -> Result<Option<Vec<u8>>, String> {
+    if let Ok(path) = std::env::var("MESSENGER_SERVER_CERT") {
+        let pem = fs::read(&path)
+            .map_err(|err| format!("Error while reading the server certificate {path}: {err}"))?;
+        let cert = native_tls::Certificate::from_pem(&pem)
+            .and_then(|cert| cert.to_der())
+            .map_err(|err| format!("Invalid server certificate {path}: {err}"))?;
+        return Ok(Some(cert));
+    }
+    let Ok(expected) = std::env::var("MESSENGER_SERVER_FINGERPRINT") else {
+        return Ok(None);
+    };
+    // Nothing secret is sent before the certificate is checked.
+    let probe = http_builder()?
+        .danger_accept_invalid_certs(true)
+        .tls_info(true)
+        .build()
+        .map_err(|err| format!("Error while building the client: {err}"))?;
+    let response = probe
+        .get("https://127.0.0.1:3000/")
+        .send()
+        .await
+        .map_err(|err| format!("Could not reach the server: {err}"))?;
+    let cert = response
+        .extensions()
+        .get::<reqwest::tls::TlsInfo>()
+        .and_then(|info| info.peer_certificate())
+        .ok_or("The server presented no certificate")?
+        .to_vec();
+    let actual: String = Sha256::digest(&cert)
+        .iter()
+        .map(|b| format!("{b:02X}"))
+        .collect();
+    if actual != expected.replace(':', "").to_ascii_uppercase() {
+        return Err(format!(
+            "The server certificate does not match MESSENGER_SERVER_FINGERPRINT, it is {actual}"
+        ));
+    }
+    Ok(Some(cert))
+}
+
+/// Opens connection `connection` for the session. With a `resume` cursor the
+/// changes made since are asked for right away.
 fn start_websocket(
     session_info: SessionInfo,
+    server_cert: Option<Vec<u8>>,
+    connection: u64,
+    resume: Option<i64>,
     ctx: egui::Context,
     gui_sender: Sender<LoginEvent>,
 ) -> Option<tokio::sync::mpsc::Sender<Event>> {
@@ -133,10 +487,33 @@ fn start_websocket(
     let session_info_clone = session_info.clone();
     tokio::spawn(async move {
         let url = "wss://127.0.0.1:3000/ws";
-        let connector = match native_tls::TlsConnector::builder()
-            .danger_accept_invalid_certs(true)
-            .build()
-        {
+        let mut builder = native_tls::TlsConnector::builder();
+        match server_cert.map(|cert| native_tls::Certificate::from_der(&cert)) {
+            Some(Ok(cert)) => {
+                builder
+                    .add_root_certificate(cert)
+                    .disable_built_in_roots(true);
+            }
+            Some(Err(err)) => {
+                println!("Invalid server certificate: {err}");
+                return;
+            }
+            None => {
+                builder.danger_accept_invalid_certs(true);
+            }
+        }
+        if let Some((cert, key)) = client_certificate() {
+            match native_tls::Identity::from_pkcs8(&cert, &key) {
+                Ok(identity) => {
+                    builder.identity(identity);
+                }
+                Err(err) => {
+                    println!("Invalid client certificate: {err}");
+                    return;
+                }
+            }
+        }
+        let connector = match builder.build() {
             Ok(c) => c,
             Err(err) => {
                 println!("Error while building connector: {err}");
@@ -157,6 +534,21 @@ fn start_websocket(
                             ))
                             .await;
                     }
+                    let mut opening = Vec::new();
+                    if resume.is_some() {
+                        opening.push(WsMessage::Sync { since: resume });
+                        opening.push(WsMessage::GetConversations);
+                    }
+                    opening.push(WsMessage::ListScheduled);
+                    for ceva in opening {
+                        if let Ok(msg_back) = serde_json::to_string(&ceva) {
+                            let _ = wr
+                                .send(tokio_tungstenite::tungstenite::Message::Text(
+                                    msg_back.into(),
+                                ))
+                                .await;
+                        }
+                    }
 
                     while let Some(msg) = gui_msg_rx.recv().await {
                         match msg {
@@ -169,6 +561,7 @@ fn start_websocket(
                                     message: c.message,
                                     resp_msg: c.resp_msg,
                                     resp_user: c.resp_user,
+                                    encrypted: c.encrypted,
                                 };
                                 if let Ok(msg_back) = serde_json::to_string(&ceva) {
                                     let _ = wr
@@ -191,8 +584,60 @@ fn start_websocket(
                                         .await;
                                 }
                             }
-                            Event::GetUsersList(user) => {
-                                let ceva = WsMessage::GetUserList { user };
+                            Event::GetConversations => {
+                                let ceva = WsMessage::GetConversations;
+                                if let Ok(msg_back) = serde_json::to_string(&ceva) {
+                                    let _ = wr
+                                        .send(tokio_tungstenite::tungstenite::Message::Text(
+                                            msg_back.into(),
+                                        ))
+                                        .await;
+                                }
+                            }
+                            Event::MarkRead((with, message_id)) => {
+                                let ceva = WsMessage::MarkRead { with, message_id };
+                                if let Ok(msg_back) = serde_json::to_string(&ceva) {
+                                    let _ = wr
+                                        .send(tokio_tungstenite::tungstenite::Message::Text(
+                                            msg_back.into(),
+                                        ))
+                                        .await;
+                                }
+                            }
+                            Event::Sync(since) => {
+                                let ceva = WsMessage::Sync { since };
+                                if let Ok(msg_back) = serde_json::to_string(&ceva) {
+                                    let _ = wr
+                                        .send(tokio_tungstenite::tungstenite::Message::Text(
+                                            msg_back.into(),
+                                        ))
+                                        .await;
+                                }
+                            }
+                            Event::SetExpiry((with, expiry_secs)) => {
+                                let ceva = WsMessage::SetExpiry {
+                                    id: format!("{}", uuid::Uuid::new_v4()),
+                                    with,
+                                    expiry_secs,
+                                };
+                                if let Ok(msg_back) = serde_json::to_string(&ceva) {
+                                    let _ = wr
+                                        .send(tokio_tungstenite::tungstenite::Message::Text(
+                                            msg_back.into(),
+                                        ))
+                                        .await;
+                                }
+                            }
+                            Event::ScheduleMessage((c, deliver_at)) => {
+                                let ceva = WsMessage::ScheduleMessage {
+                                    id: c.id,
+                                    to: c.to,
+                                    message: c.message,
+                                    resp_msg: c.resp_msg,
+                                    resp_user: c.resp_user,
+                                    encrypted: c.encrypted,
+                                    deliver_at,
+                                };
                                 if let Ok(msg_back) = serde_json::to_string(&ceva) {
                                     let _ = wr
                                         .send(tokio_tungstenite::tungstenite::Message::Text(
@@ -201,12 +646,39 @@ fn start_websocket(
                                         .await;
                                 }
                             }
+                            Event::CancelScheduled(scheduled_id) => {
+                                let ceva = WsMessage::CancelScheduled {
+                                    id: format!("{}", uuid::Uuid::new_v4()),
+                                    scheduled_id,
+                                };
+                                if let Ok(msg_back) = serde_json::to_string(&ceva) {
+                                    let _ = wr
+                                        .send(tokio_tungstenite::tungstenite::Message::Text(
+                                            msg_back.into(),
+                                        ))
+                                        .await;
+                                }
+                            }
+                            Event::Logout => {
+                                if let Ok(msg_back) = serde_json::to_string(&WsMessage::Logout) {
+                                    let _ = wr
+                                        .send(tokio_tungstenite::tungstenite::Message::Text(
+                                            msg_back.into(),
+                                        ))
+                                        .await;
+                                }
+                            }
                         }
                     }
                 });
 
+                let mut answered = false;
                 while let Some(Ok(msg)) = rd.next().await {
                     if let tokio_tungstenite::tungstenite::Message::Text(raw_json) = msg {
+                        if !answered {
+                            answered = true;
+                            let _ = gui_sender.send(LoginEvent::Connected(connection));
+                        }
                         let message: Result<WsMessageBack, _> = serde_json::from_str(&raw_json);
                         match message {
                             Ok(WsMessageBack::Message {
@@ -215,6 +687,8 @@ fn start_websocket(
                                 message: msg_content,
                                 resp_msg: r_m,
                                 resp_user: r_u,
+                                encrypted,
+                                message_id,
                             }) => {
                                 let rand_id = format!("{}", uuid::Uuid::new_v4());
                                 let _ = gui_sender.send(LoginEvent::NewMessage(ChatMessage {
@@ -224,21 +698,60 @@ fn start_websocket(
                                     message: msg_content,
                                     resp_msg: r_m,
                                     resp_user: r_u,
+                                    encrypted,
+                                    message_id: Some(message_id),
                                 }));
                             }
                             Ok(WsMessageBack::Response {
                                 id,
                                 succes,
                                 message,
+                                message_id,
                             }) => {
-                                let _ = gui_sender
-                                    .send(LoginEvent::ServerResponse((id, succes, message)));
+                                let _ = gui_sender.send(LoginEvent::ServerResponse((
+                                    id, succes, message, message_id,
+                                )));
+                            }
+                            Ok(WsMessageBack::ExpirySetting {
+                                with,
+                                expiry_secs,
+                                changed_by,
+                            }) => {
+                                let _ = gui_sender.send(LoginEvent::ExpirySetting((
+                                    with,
+                                    expiry_secs,
+                                    changed_by,
+                                )));
+                            }
+                            Ok(WsMessageBack::MessagesExpired { ids }) => {
+                                let _ = gui_sender.send(LoginEvent::MessagesExpired(ids));
+                            }
+                            Ok(WsMessageBack::ScheduledList { items }) => {
+                                let _ = gui_sender.send(LoginEvent::ScheduledList(items));
+                            }
+                            Ok(WsMessageBack::ScheduledDelivered { scheduled_id, .. }) => {
+                                let _ =
+                                    gui_sender.send(LoginEvent::ScheduledDelivered(scheduled_id));
                             }
                             Ok(WsMessageBack::Chat { messages }) => {
                                 let _ = gui_sender.send(LoginEvent::ChatDump(messages));
                             }
-                            Ok(WsMessageBack::UserList { list }) => {
-                                let _ = gui_sender.send(LoginEvent::TheList(list));
+                            Ok(WsMessageBack::Conversations { conversations }) => {
+                                let _ = gui_sender.send(LoginEvent::Conversations(conversations));
+                            }
+                            Ok(WsMessageBack::ConversationUpdate { conversation }) => {
+                                let _ =
+                                    gui_sender.send(LoginEvent::ConversationUpdate(conversation));
+                            }
+                            Ok(WsMessageBack::Synced(synced)) => {
+                                let _ = gui_sender.send(LoginEvent::Synced(synced));
+                            }
+                            Ok(WsMessageBack::ServerShutdown { reconnect_after_ms }) => {
+                                let _ = gui_sender
+                                    .send(LoginEvent::ServerShutdown(reconnect_after_ms));
+                            }
+                            Ok(WsMessageBack::CommandOutput { id, text }) => {
+                                let _ = gui_sender.send(LoginEvent::CommandOutput((id, text)));
                             }
                             Err(err) => {
                                 println!("Error while recieving message from server: {err}");
@@ -248,12 +761,14 @@ fn start_websocket(
                     }
                 }
                 println!("Connection ended");
-                let _ = gui_sender.send(LoginEvent::ConnectionLost);
+                let _ = gui_sender.send(LoginEvent::ConnectionLost((connection, !answered)));
             }
             Err(err) => {
                 println!("Connection failed: {err}");
+                let _ = gui_sender.send(LoginEvent::ConnectionLost((connection, false)));
             }
         };
+        ctx.request_repaint();
     });
     Some(gui_msg_tx)
 }
@@ -268,6 +783,8 @@ struct MyApp {
     rx: Receiver<LoginEvent>,
     tx: Sender<LoginEvent>,
     client: reqwest::Client,
+    /// Certificate the server has to present, None to accept any.
+    server_cert: Option<Vec<u8>>,
 
     chat: Vec<OnScreenMessage>,
     current_chat: String,
@@ -275,15 +792,45 @@ struct MyApp {
     selected_message: Option<String>,
     selected_from: Option<String>,
 
-    contacts: Vec<String>,
+    /// The inbox, newest activity first.
+    conversations: Vec<Conversation>,
+    /// When the inbox was last asked for. Live updates keep it current, the
+    /// refresh picks up new accounts.
+    conversations_requested: Option<Instant>,
+    /// Whose messages are on screen and the sync cursor of the last `Sync`.
+    /// Kept when the connection is lost, so reconnecting only fetches what
+    /// changed meanwhile.
+    sync: Option<(String, i64)>,
 
     ws_tx: Option<tokio::sync::mpsc::Sender<Event>>,
+    /// Id of the current connection, events of older ones are ignored.
+    connection: u64,
+    /// When to reconnect after the connection dropped, and the wait after
+    /// that one fails too.
+    reconnect_at: Option<Instant>,
+    reconnect_delay: Duration,
+
+    identity: Option<Identity>,
+    key_store: Option<KeyStore>,
+    plaintext_chats: HashSet<String>,
+    show_safety_number: bool,
+    /// Disappearing-messages timer of the open chat and who last set it.
+    chat_expiry: (Option<i64>, Option<String>),
+    /// Own messages waiting on the server, with the text already revealed.
+    scheduled: Vec<ScheduledItem>,
+    schedule_minutes: u32,
+    /// Ids of schedule requests still waiting for the server's answer.
+    schedule_requests: HashSet<String>,
+    schedule_error: String,
+    /// A client certificate is configured, so logging in works without a
+    /// password.
+    has_client_cert: bool,
 
     err_msg: String,
 }
 
 impl MyApp {
-    fn new(client: reqwest::Client) -> Self {
+    fn new(client: reqwest::Client, server_cert: Option<Vec<u8>>) -> Self {
         let (tx, rx) = channel();
         Self {
             current_page: Page::Login,
@@ -294,16 +841,308 @@ impl MyApp {
             rx,
             tx,
             client,
+            server_cert,
             chat: Vec::new(),
             current_chat: String::new(),
             message_input: String::new(),
             selected_message: None,
             selected_from: None,
-            contacts: Vec::new(),
+            conversations: Vec::new(),
+            conversations_requested: None,
+            sync: None,
             ws_tx: None,
+            connection: 0,
+            reconnect_at: None,
+            reconnect_delay: RECONNECT_MIN,
+            identity: None,
+            key_store: None,
+            plaintext_chats: HashSet::new(),
+            show_safety_number: false,
+            chat_expiry: (None, None),
+            scheduled: Vec::new(),
+            schedule_minutes: 60,
+            schedule_requests: HashSet::new(),
+            schedule_error: String::new(),
+            has_client_cert: client_certificate().is_some(),
             err_msg: String::new(),
         }
     }
+
+    /// Opens a new connection for the session, replacing the current one.
+    fn connect(&mut self, ctx: &egui::Context, resume: Option<i64>) {
+        self.connection += 1;
+        self.reconnect_at = None;
+        self.ws_tx = start_websocket(
+            SessionInfo {
+                username: self.username.clone(),
+                token: self.token.clone(),
+            },
+            self.server_cert.clone(),
+            self.connection,
+            resume,
+            ctx.clone(),
+            self.tx.clone(),
+        );
+    }
+
+    /// Goes back to the login page. The chat and inbox stay for `sync` to
+    /// bring up to date if the same user logs in again.
+    fn leave_session(&mut self) {
+        for msg in &mut self.chat {
+            if msg.status == MessageStatus::Sending {
+                msg.status = MessageStatus::Failed;
+            }
+        }
+        self.current_page = Page::Login;
+        self.token.clear();
+        self.username.clear();
+        self.password.clear();
+        self.conversations_requested = None;
+        self.message_input.clear();
+        self.selected_message = None;
+        self.selected_from = None;
+        self.ws_tx = None;
+        self.reconnect_at = None;
+        self.reconnect_delay = RECONNECT_MIN;
+        self.identity = None;
+        self.key_store = None;
+        self.plaintext_chats.clear();
+        self.show_safety_number = false;
+        self.scheduled.clear();
+        self.schedule_requests.clear();
+        self.schedule_error.clear();
+    }
+
+    /// Looks up the published device keys of `user` in the key directory.
+    fn fetch_keys(&self, ctx: &egui::Context, user: String) {
+        let tx_clone = self.tx.clone();
+        let ctx_clone = ctx.clone();
+        let client_clone = self.client.clone();
+        tokio::spawn(async move {
+            let base_url = "https://127.0.0.1:3000";
+            let resp = match client_clone
+                .get(format!("{base_url}/keys/{user}"))
+                .send()
+                .await
+            {
+                Ok(snd) => snd.json::<KeyDirectoryResp>().await,
+                Err(err) => {
+                    println!("Error while fetching the keys of {user}: {err}");
+                    return;
+                }
+            };
+            match resp {
+                Ok(r) => {
+                    let _ = tx_clone.send(LoginEvent::DeviceKeys((user, r.keys)));
+                    ctx_clone.request_repaint();
+                }
+                Err(err) => println!("Invalid key directory response for {user}: {err}"),
+            }
+        });
+    }
+
+    /// Publishes this device's identity key so that others can encrypt to it.
+    fn publish_key(&self) {
+        let Some(identity) = &self.identity else {
+            return;
+        };
+        let req = PublishKeyReq {
+            username: self.username.clone(),
+            token: self.token.clone(),
+            device_id: identity.device_id.clone(),
+            public_key: identity.public_key(),
+        };
+        let client_clone = self.client.clone();
+        tokio::spawn(async move {
+            let base_url = "https://127.0.0.1:3000";
+            match client_clone
+                .put(format!("{base_url}/keys"))
+                .json(&req)
+                .send()
+                .await
+            {
+                Ok(snd) if snd.status().is_success() => {}
+                Ok(snd) => println!("The server refused the device key: {}", snd.status()),
+                Err(err) => println!("Error while publishing the device key: {err}"),
+            }
+        });
+    }
+
+    /// Whether messages to `peer` are sent end-to-end encrypted.
+    fn encrypts_to(&self, peer: &str) -> bool {
+        self.identity.is_some()
+            && !self.plaintext_chats.contains(peer)
+            && self
+                .key_store
+                .as_ref()
+                .is_some_and(|store| !store.keys(peer).is_empty())
+    }
+
+    /// The typed message and the quoted one as they go on the wire, sealed
+    /// for every device of both users when `encrypt` is set.
+    fn seal_outgoing(&self, encrypt: bool) -> Option<(String, Option<String>)> {
+        let (true, Some(identity), Some(store)) = (encrypt, &self.identity, &self.key_store) else {
+            return Some((self.message_input.clone(), self.selected_message.clone()));
+        };
+        let mut recipients = store.keys(&self.current_chat);
+        recipients.extend(store.keys(&self.username));
+        recipients.push(identity.public_key());
+        let seal = |text: &str| {
+            e2ee::seal(
+                identity,
+                &self.username,
+                &self.current_chat,
+                text,
+                &recipients,
+            )
+            .inspect_err(|err| println!("{err}"))
+            .ok()
+        };
+        let content = seal(&self.message_input)?;
+        let resp_msg = match &self.selected_message {
+            Some(m) => Some(seal(m)?),
+            None => None,
+        };
+        Some((content, resp_msg))
+    }
+
+    /// Turns a message body from the server into text for the screen.
+    /// Decrypts the preview of an inbox entry.
+    fn reveal_preview(&self, mut conversation: Conversation) -> Conversation {
+        if let (Some(from), Some(content)) = (
+            conversation.last_from.clone(),
+            conversation.last_message.take(),
+        ) {
+            let to = if from == self.username {
+                conversation.with.clone()
+            } else {
+                self.username.clone()
+            };
+            conversation.last_message =
+                Some(self.reveal(&from, &to, content, conversation.encrypted).0);
+        }
+        conversation
+    }
+
+    fn history_entry(&self, m: HistoryMessage) -> OnScreenMessage {
+        let (message, encryption) = self.reveal(&m.from, &m.to, m.content, m.encrypted);
+        let (resp_msg, resp_usr) = match m.reply_to {
+            Some(reply) => (
+                Some(self.reveal(&m.from, &m.to, reply.message, m.encrypted).0),
+                Some(reply.user),
+            ),
+            None => (None, None),
+        };
+        OnScreenMessage {
+            id: format!("{}", uuid::Uuid::new_v4()),
+            from: m.from,
+            message,
+            status: MessageStatus::Sent,
+            resp_msg,
+            resp_usr,
+            encryption,
+            message_id: Some(m.id),
+            sent_at: m.sent_at,
+            edited: m.edited_at.is_some(),
+        }
+    }
+
+    /// Brings the open chat up to date with what changed after the last
+    /// cursor. The inbox is reloaded as a whole when anything did.
+    fn apply_sync(&mut self, synced: Synced) {
+        let Some(tx) = self.ws_tx.clone() else {
+            return;
+        };
+        self.sync = Some((self.username.clone(), synced.cursor));
+        if synced.reset {
+            self.chat.clear();
+            if !self.current_chat.is_empty() {
+                let _ = tx.try_send(Event::ChangeChat((self.current_chat.clone(), 0)));
+            }
+            self.conversations_requested = None;
+            return;
+        }
+        let changed =
+            !(synced.messages.is_empty() && synced.deleted.is_empty() && synced.reads.is_empty());
+        for m in synced.messages {
+            let peer = if m.from == self.username {
+                &m.to
+            } else {
+                &m.from
+            };
+            if *peer != self.current_chat {
+                continue;
+            }
+            let entry = self.history_entry(m);
+            match self
+                .chat
+                .iter_mut()
+                .find(|msg| msg.message_id == entry.message_id)
+            {
+                Some(msg) => {
+                    msg.message = entry.message;
+                    msg.encryption = entry.encryption;
+                    msg.edited = entry.edited;
+                }
+                None => self.chat.push(entry),
+            }
+        }
+        self.chat
+            .retain(|m| m.message_id.is_none_or(|id| !synced.deleted.contains(&id)));
+        if synced.more {
+            let _ = tx.try_send(Event::Sync(Some(synced.cursor)));
+        } else if changed {
+            self.conversations_requested = None;
+        }
+    }
+
+    /// Marks what arrived in the open chat as read, since it is on screen.
+    fn mark_current_read(&mut self) {
+        let Some(conversation) = self
+            .conversations
+            .iter_mut()
+            .find(|c| c.with == self.current_chat && c.unread > 0)
+        else {
+            return;
+        };
+        if let (Some(tx), Some(message_id)) = (&self.ws_tx, conversation.last_message_id)
+            && tx
+                .try_send(Event::MarkRead((conversation.with.clone(), message_id)))
+                .is_ok()
+        {
+            conversation.unread = 0;
+        }
+    }
+
+    fn reveal(
+        &self,
+        from: &str,
+        to: &str,
+        content: String,
+        encrypted: bool,
+    ) -> (String, Encryption) {
+        if !encrypted {
+            return (content, Encryption::Plain);
+        }
+        let Some(identity) = &self.identity else {
+            return ("[Encrypted message]".to_string(), Encryption::Unreadable);
+        };
+        match e2ee::open(identity, from, to, &content) {
+            Ok((text, sender_key)) => {
+                let known = sender_key == identity.public_key()
+                    || self
+                        .key_store
+                        .as_ref()
+                        .is_some_and(|store| store.knows(from, &sender_key));
+                if known {
+                    (text, Encryption::Encrypted)
+                } else {
+                    (text, Encryption::UnknownKey)
+                }
+            }
+            Err(err) => (format!("[{err}]"), Encryption::Unreadable),
+        }
+    }
     fn show_signin_screen(&mut self, ctx: &egui::Context) {
         if let Ok(event) = self.rx.try_recv() {
             match event {
@@ -424,19 +1263,37 @@ impl MyApp {
     fn show_login_screen(&mut self, ctx: &egui::Context) {
         if let Ok(event) = self.rx.try_recv() {
             match event {
-                LoginEvent::Login(token) => {
+                LoginEvent::Login((token, username)) => {
                     self.token = token;
+                    if !username.is_empty() {
+                        self.username = username;
+                    }
                     self.current_page = Page::MainApp;
+                    if self
+                        .sync
+                        .as_ref()
+                        .is_none_or(|(user, _)| *user != self.username)
+                    {
+                        self.sync = None;
+                        self.chat.clear();
+                        self.conversations.clear();
+                        self.current_chat.clear();
+                        self.chat_expiry = (None, None);
+                    }
+                    match Identity::load_or_create(&self.username) {
+                        Ok(identity) => {
+                            self.identity = Some(identity);
+                            self.key_store = Some(KeyStore::load(&self.username));
+                            self.publish_key();
+                            self.fetch_keys(ctx, self.username.clone());
+                        }
+                        Err(err) => {
+                            println!("End-to-end encryption is unavailable: {err}");
+                        }
+                    }
 
-                    let tx = start_websocket(
-                        SessionInfo {
-                            username: self.username.clone(),
-                            token: self.token.clone(),
-                        },
-                        ctx.clone(),
-                        self.tx.clone(),
-                    );
-                    self.ws_tx = tx;
+                    self.err_msg.clear();
+                    self.connect(ctx, None);
                     ctx.request_repaint();
                 }
                 LoginEvent::Error(err) => {
@@ -462,7 +1319,7 @@ impl MyApp {
                 if ui.button("Log In").clicked() {
                     if !self.username.trim().is_empty()
                     {
-                        if !self.password.trim().is_empty()
+                        if !self.password.trim().is_empty() || self.has_client_cert
                         {
                             let login = LoginReq {
                                 username: self.username.clone(),
@@ -485,6 +1342,7 @@ impl MyApp {
                                         Err(err) => LoginResp {
                                             succes: false,
                                             token: "".to_string(),
+                                            username: "".to_string(),
                                             message: format!(
                                                 "Invalid response from the server after login: {err}"
                                             ),
@@ -493,12 +1351,13 @@ impl MyApp {
                                     Err(err) => LoginResp {
                                         succes: false,
                                         token: "".to_string(),
+                                        username: "".to_string(),
                                         message: format!("Error while sending the login request: {err}"),
                                     },
                                 };
 
                                 let result = match resp.succes {
-                                    true => LoginEvent::Login(resp.token),
+                                    true => LoginEvent::Login((resp.token, resp.username)),
                                     false => LoginEvent::Error(resp.message),
                                 };
 
@@ -534,16 +1393,44 @@ impl MyApp {
         });
     }
     fn show_main_app(&mut self, ctx: &egui::Context) {
-        if let Some(tx) = &self.ws_tx {
-            let event = Event::GetUsersList(self.username.clone());
-            let _ = tx.try_send(event);
+        if let Some(at) = self.reconnect_at {
+            let now = Instant::now();
+            if now >= at {
+                // The session outlived the socket, so the same token picks
+                // up where the cursor left off.
+                let resume = self.sync.as_ref().map(|(_, cursor)| *cursor);
+                self.connect(ctx, resume);
+                self.conversations_requested = resume.map(|_| now);
+            } else {
+                ctx.request_repaint_after(at - now);
+            }
+        }
+        if let Some(tx) = &self.ws_tx
+            && self
+                .conversations_requested
+                .is_none_or(|at| at.elapsed() >= INBOX_REFRESH)
+            && tx.try_send(Event::GetConversations).is_ok()
+        {
+            self.conversations_requested = Some(Instant::now());
+            // Also moves the cursor past what arrived live.
+            let _ = tx.try_send(Event::Sync(self.sync.as_ref().map(|(_, cursor)| *cursor)));
         }
         while let Ok(event) = self.rx.try_recv() {
             match event {
-                LoginEvent::ServerResponse((id, success, message)) => {
+                LoginEvent::ServerResponse((id, success, message, _))
+                    if self.schedule_requests.remove(&id) =>
+                {
+                    if success {
+                        self.schedule_error.clear();
+                    } else {
+                        self.schedule_error = message;
+                    }
+                }
+                LoginEvent::ServerResponse((id, success, message, message_id)) => {
                     if let Some(msg) = self.chat.iter_mut().find(|m| m.id == id) {
                         if success {
                             msg.status = MessageStatus::Sent;
+                            msg.message_id = message_id;
                         } else {
                             msg.status = MessageStatus::Failed;
                             println!("Message {id} failed: {message}");
@@ -551,53 +1438,130 @@ impl MyApp {
                     }
                 }
                 LoginEvent::ChatDump(messages) => {
-                    self.chat.clear();
-                    for e in messages {
-                        let rand_id = format!("{}", uuid::Uuid::new_v4());
-                        self.chat.push(OnScreenMessage {
-                            id: rand_id,
-                            from: e.0,
-                            message: e.1,
-                            status: MessageStatus::Sent,
-                            resp_msg: e.2,
-                            resp_usr: e.3,
-                        });
-                    }
+                    self.chat = messages
+                        .into_iter()
+                        .map(|m| self.history_entry(m))
+                        .collect();
                 }
-                LoginEvent::NewMessage(c) => {
-                    if c.from == self.current_chat || c.to == self.current_chat {
-                        self.chat.push(OnScreenMessage {
-                            id: c.id,
-                            from: c.from,
-                            message: c.message,
-                            resp_msg: c.resp_msg,
-                            resp_usr: c.resp_user,
-                            status: MessageStatus::Sent,
-                        });
+                LoginEvent::Synced(synced) => self.apply_sync(synced),
+                LoginEvent::NewMessage(c)
+                    if c.from == self.current_chat || c.to == self.current_chat =>
+                {
+                    let (message, encryption) = self.reveal(&c.from, &c.to, c.message, c.encrypted);
+                    let resp_msg = c
+                        .resp_msg
+                        .map(|m| self.reveal(&c.from, &c.to, m, c.encrypted).0);
+                    self.chat.push(OnScreenMessage {
+                        id: c.id,
+                        from: c.from,
+                        message,
+                        resp_msg,
+                        resp_usr: c.resp_user,
+                        status: MessageStatus::Sent,
+                        encryption,
+                        message_id: c.message_id,
+                        sent_at: Utc::now(),
+                        edited: false,
+                    });
+                }
+                LoginEvent::ExpirySetting((with, expiry_secs, changed_by))
+                    if with == self.current_chat =>
+                {
+                    self.chat_expiry = (expiry_secs, changed_by);
+                }
+                LoginEvent::MessagesExpired(ids) => {
+                    self.chat
+                        .retain(|m| m.message_id.is_none_or(|id| !ids.contains(&id)));
+                }
+                LoginEvent::ScheduledList(items) => {
+                    self.scheduled = items
+                        .into_iter()
+                        .map(|mut item| {
+                            let content = std::mem::take(&mut item.message);
+                            item.message = self
+                                .reveal(&self.username, &item.to, content, item.encrypted)
+                                .0;
+                            item
+                        })
+                        .collect();
+                    self.scheduled.sort_by_key(|item| item.deliver_at);
+                }
+                LoginEvent::ScheduledDelivered(scheduled_id) => {
+                    self.scheduled
+                        .retain(|item| item.scheduled_id != scheduled_id);
+                }
+                LoginEvent::DeviceKeys((user, keys)) => {
+                    if let Some(store) = &mut self.key_store {
+                        store.update(&user, keys);
                     }
                 }
-                LoginEvent::TheList(list) => {
-                    self.contacts = list;
+                LoginEvent::Conversations(conversations) => {
+                    self.conversations = conversations
+                        .into_iter()
+                        .map(|c| self.reveal_preview(c))
+                        .collect();
+                    self.mark_current_read();
                 }
-                LoginEvent::ConnectionLost => {
-                    self.current_page = Page::Login;
-                    self.token.clear();
-                    self.username.clear();
-                    self.password.clear();
-                    self.chat.clear();
-                    self.contacts.clear();
-                    self.current_chat.clear();
-                    self.message_input.clear();
-                    self.selected_message = None;
-                    self.selected_from = None;
+                LoginEvent::ConversationUpdate(conversation) => {
+                    let conversation = self.reveal_preview(conversation);
+                    self.conversations.retain(|c| c.with != conversation.with);
+                    self.conversations.push(conversation);
+                    self.conversations.sort_by(|a, b| {
+                        (b.last_at, b.last_message_id)
+                            .cmp(&(a.last_at, a.last_message_id))
+                            .then_with(|| a.with.cmp(&b.with))
+                    });
+                    self.mark_current_read();
+                }
+                LoginEvent::ServerShutdown(reconnect_after_ms) => {
+                    self.reconnect_delay = Duration::from_millis(reconnect_after_ms);
+                    self.err_msg = format!(
+                        "The server is restarting, reconnecting in {} seconds",
+                        reconnect_after_ms.div_ceil(1000)
+                    );
+                }
+                LoginEvent::CommandOutput((id, text)) => {
+                    self.chat.push(OnScreenMessage {
+                        id: format!("{id}-output"),
+                        from: "server".to_string(),
+                        message: text,
+                        resp_msg: None,
+                        resp_usr: None,
+                        status: MessageStatus::Sent,
+                        encryption: Encryption::Plain,
+                        message_id: None,
+                        sent_at: Utc::now(),
+                        edited: false,
+                    });
+                }
+                LoginEvent::Connected(connection) if connection == self.connection => {
+                    self.reconnect_delay = RECONNECT_MIN;
+                    self.err_msg.clear();
+                }
+                LoginEvent::ConnectionLost((connection, true)) if connection == self.connection => {
+                    self.leave_session();
+                    self.err_msg = "The session has ended, log in again".to_string();
+                }
+                LoginEvent::ConnectionLost((connection, false))
+                    if connection == self.connection =>
+                {
+                    for msg in &mut self.chat {
+                        if msg.status == MessageStatus::Sending {
+                            msg.status = MessageStatus::Failed;
+                        }
+                    }
                     self.ws_tx = None;
-                    self.err_msg = "Server unreacheble".to_string();
+                    self.reconnect_at = Some(Instant::now() + self.reconnect_delay);
+                    if self.err_msg.is_empty() {
+                        self.err_msg = "Server unreacheble, reconnecting".to_string();
+                    }
+                    self.reconnect_delay = (self.reconnect_delay * 2).min(RECONNECT_MAX);
                 }
                 _ => {}
             }
         }
         egui::SidePanel::left("users_panel").show(ctx, |ui| {
-            ui.heading("Contacts");
+            ui.heading("Chats");
             ui.separator();
 
             ui.allocate_ui_with_layout(
@@ -608,44 +1572,83 @@ impl MyApp {
                         ui.visuals_mut().selection.bg_fill = egui::Color32::from_rgb(165, 42, 0);
                         ui.visuals_mut().selection.stroke =
                             egui::Stroke::new(1.0, egui::Color32::BLACK);
-                        for contact in &self.contacts {
-                            let selected = *contact == self.current_chat;
-                            if ui.selectable_label(selected, contact).clicked() && !selected {
-                                self.current_chat = contact.to_string();
-                                self.chat.clear();
-                                self.message_input.clear();
-                                self.selected_message = None;
-                                self.selected_from = None;
-
-                                if let Some(tx) = &self.ws_tx {
-                                    let event = Event::ChangeChat((contact.to_string(), 0));
-                                    let _ = tx.try_send(event);
+                        let mut opened = None;
+                        for conversation in &self.conversations {
+                            let selected = conversation.with == self.current_chat;
+                            let title = match conversation.unread {
+                                0 => egui::RichText::new(&conversation.with),
+                                n => egui::RichText::new(format!("{}  ({n})", conversation.with))
+                                    .strong(),
+                            };
+                            if ui.selectable_label(selected, title).clicked() && !selected {
+                                opened = Some(conversation.with.clone());
+                            }
+                            if let (Some(from), Some(message), Some(at)) = (
+                                &conversation.last_from,
+                                &conversation.last_message,
+                                conversation.last_at,
+                            ) {
+                                let from = if *from == self.username { "You" } else { from };
+                                let mut preview: String =
+                                    message.replace('\n', " ").chars().take(40).collect();
+                                if preview.len() < message.len() {
+                                    preview.push_str("...");
                                 }
+                                ui.label(
+                                    egui::RichText::new(format!(
+                                        "{from}: {preview} · {}",
+                                        ago_label(at)
+                                    ))
+                                    .size(10.0)
+                                    .color(egui::Color32::GRAY),
+                                );
+                            }
+                        }
+                        if let Some(with) = opened {
+                            self.current_chat = with.clone();
+                            self.chat.clear();
+                            self.message_input.clear();
+                            self.selected_message = None;
+                            self.selected_from = None;
+                            self.show_safety_number = false;
+                            self.chat_expiry = (None, None);
+                            self.schedule_error.clear();
+
+                            if let Some(tx) = &self.ws_tx {
+                                let event = Event::ChangeChat((with.clone(), 0));
+                                let _ = tx.try_send(event);
                             }
+                            self.fetch_keys(ctx, with);
+                            self.fetch_keys(ctx, self.username.clone());
+                            self.mark_current_read();
                         }
                     });
                     ui.with_layout(egui::Layout::bottom_up(egui::Align::Min), |ui| {
                         ui.add_space(10.0);
 
                         if ui.button("Log Out").clicked() {
-                            self.current_page = Page::Login;
-                            self.token.clear();
-                            self.username.clear();
-                            self.password.clear();
+                            if let Some(tx) = &self.ws_tx {
+                                let _ = tx.try_send(Event::Logout);
+                            }
+                            self.leave_session();
                             self.chat.clear();
-                            self.contacts.clear();
+                            self.conversations.clear();
                             self.current_chat.clear();
-                            self.message_input.clear();
-                            self.selected_message = None;
-                            self.selected_from = None;
-                            self.ws_tx = None;
+                            self.chat_expiry = (None, None);
                         }
                     });
                 },
             );
         });
 
+        if !self.current_chat.is_empty() {
+            self.show_chat_header(ctx);
+        }
+
         egui::TopBottomPanel::bottom("input_panel").show(ctx, |ui| {
+            if !self.err_msg.is_empty() {
+                ui.colored_label(egui::Color32::LIGHT_RED, &self.err_msg);
+            }
             if let (Some(m), Some(u)) = (self.selected_message.clone(), self.selected_from.clone())
             {
                 ui.horizontal(|ui| {
@@ -684,6 +1687,10 @@ impl MyApp {
                     && !self.current_chat.trim().is_empty()
                 {
                     let rand_id = format!("{}", uuid::Uuid::new_v4());
+                    // Commands are read by the server, so they go out in the clear.
+                    let command = self.message_input.starts_with('/')
+                        && !self.message_input.starts_with("//");
+                    let encrypt = !command && self.encrypts_to(&self.current_chat);
 
                     self.chat.push(OnScreenMessage {
                         id: rand_id.clone(),
@@ -692,24 +1699,43 @@ impl MyApp {
                         status: MessageStatus::Sending,
                         resp_msg: self.selected_message.clone(),
                         resp_usr: self.selected_from.clone(),
+                        encryption: if encrypt {
+                            Encryption::Encrypted
+                        } else {
+                            Encryption::Plain
+                        },
+                        message_id: None,
+                        sent_at: Utc::now(),
+                        edited: false,
                     });
 
-                    if let Some(tx) = &self.ws_tx {
-                        let event = Event::NewMessage(ChatMessage {
-                            id: rand_id,
-                            from: self.username.clone(),
-                            to: self.current_chat.clone(),
-                            message: self.message_input.clone(),
-                            resp_msg: self.selected_message.clone(),
-                            resp_user: self.selected_from.clone(),
-                        });
-                        let _ = tx.try_send(event);
+                    match (&self.ws_tx, self.seal_outgoing(encrypt)) {
+                        (Some(tx), Some((content, resp_msg))) => {
+                            let event = Event::NewMessage(ChatMessage {
+                                id: rand_id,
+                                from: self.username.clone(),
+                                to: self.current_chat.clone(),
+                                message: content,
+                                resp_msg,
+                                resp_user: self.selected_from.clone(),
+                                encrypted: encrypt,
+                                message_id: None,
+                            });
+                            let _ = tx.try_send(event);
+                        }
+                        (_, None) => {
+                            if let Some(msg) = self.chat.iter_mut().find(|m| m.id == rand_id) {
+                                msg.status = MessageStatus::Failed;
+                            }
+                        }
+                        _ => {}
                     }
                     self.message_input.clear();
                     self.selected_message = None;
                     self.selected_from = None;
                 }
             });
+            self.show_scheduling(ui);
         });
 
         egui::CentralPanel::default().show(ctx, |ui| {
@@ -719,7 +1745,13 @@ impl MyApp {
                 .show(ui, |ui| {
                     ui.spacing_mut().item_spacing.y = 10.0;
 
+                    let mut day = None;
                     for msg in &self.chat {
+                        let date = msg.sent_at.with_timezone(&Local).date_naive();
+                        if day != Some(date) {
+                            day = Some(date);
+                            show_day(ui, date);
+                        }
                         if msg.from == self.username {
                             let (bg_color, text_color) = match msg.status {
                                 MessageStatus::Sending => {
@@ -758,9 +1790,9 @@ impl MyApp {
                                             ui.add_space(5.0);
                                             //ui.separator();
                                         }
-                                        ui.label(
-                                            egui::RichText::new(&msg.message).color(text_color),
-                                        );
+                                        markdown::show(ui, &msg.message, text_color);
+                                        show_encryption(ui, &msg.encryption);
+                                        show_time(ui, msg);
                                     });
                                 });
 
@@ -796,10 +1828,9 @@ impl MyApp {
                                             ui.add_space(5.0);
                                             //ui.separator();
                                         }
-                                        ui.label(
-                                            egui::RichText::new(&msg.message)
-                                                .color(egui::Color32::WHITE),
-                                        );
+                                        markdown::show(ui, &msg.message, egui::Color32::WHITE);
+                                        show_encryption(ui, &msg.encryption);
+                                        show_time(ui, msg);
                                     });
                                 });
 
@@ -815,6 +1846,213 @@ impl MyApp {
     }
 }
 
+impl MyApp {
+    /// Messages waiting to be sent to the open chat and the controls to
+    /// send the typed message later.
+    fn show_scheduling(&mut self, ui: &mut egui::Ui) {
+        let mut cancelled = None;
+        for item in self.scheduled.iter().filter(|i| i.to == self.current_chat) {
+            ui.horizontal(|ui| {
+                ui.label(
+                    egui::RichText::new(format!("⏰ {}", until_label(item.deliver_at)))
+                        .color(egui::Color32::LIGHT_BLUE),
+                );
+                if item.message.chars().count() > 40 {
+                    let preview: String = item.message.chars().take(40).collect();
+                    ui.label(
+                        egui::RichText::new(format!("{preview}..."))
+                            .italics()
+                            .color(egui::Color32::GRAY),
+                    );
+                } else {
+                    ui.label(
+                        egui::RichText::new(&item.message)
+                            .italics()
+                            .color(egui::Color32::GRAY),
+                    );
+                }
+                if ui.small_button("Cancel").clicked() {
+                    cancelled = Some(item.scheduled_id);
+                }
+            });
+        }
+        if let (Some(scheduled_id), Some(tx)) = (cancelled, &self.ws_tx) {
+            self.scheduled.retain(|i| i.scheduled_id != scheduled_id);
+            let _ = tx.try_send(Event::CancelScheduled(scheduled_id));
+        }
+
+        ui.horizontal(|ui| {
+            ui.label("Send in");
+            ui.add(
+                egui::DragValue::new(&mut self.schedule_minutes)
+                    .range(1..=525_600)
+                    .suffix(" min"),
+            );
+            if ui.button("⏰ Schedule").clicked()
+                && !self.message_input.trim().is_empty()
+                && !self.current_chat.trim().is_empty()
+            {
+                let encrypt = self.encrypts_to(&self.current_chat);
+                match (&self.ws_tx, self.seal_outgoing(encrypt)) {
+                    (Some(tx), Some((content, resp_msg))) => {
+                        let rand_id = format!("{}", uuid::Uuid::new_v4());
+                        let deliver_at = unix_now() + i64::from(self.schedule_minutes) * 60;
+                        let event = Event::ScheduleMessage((
+                            ChatMessage {
+                                id: rand_id.clone(),
+                                from: self.username.clone(),
+                                to: self.current_chat.clone(),
+                                message: content,
+                                resp_msg,
+                                resp_user: self.selected_from.clone(),
+                                encrypted: encrypt,
+                                message_id: None,
+                            },
+                            deliver_at,
+                        ));
+                        self.schedule_requests.insert(rand_id);
+                        let _ = tx.try_send(event);
+                        self.message_input.clear();
+                        self.selected_message = None;
+                        self.selected_from = None;
+                    }
+                    (_, None) => {
+                        self.schedule_error = "The message could not be encrypted".to_string();
+                    }
+                    _ => {}
+                }
+            }
+            if !self.schedule_error.is_empty() {
+                ui.colored_label(egui::Color32::LIGHT_RED, &self.schedule_error);
+            }
+        });
+    }
+
+    /// Name of the open chat, its encryption state and the safety number.
+    fn show_chat_header(&mut self, ctx: &egui::Context) {
+        let peer = self.current_chat.clone();
+        let peer_has_keys = self
+            .key_store
+            .as_ref()
+            .is_some_and(|store| !store.keys(&peer).is_empty());
+        let trust = self
+            .key_store
+            .as_ref()
+            .map(|store| store.trust(&peer))
+            .unwrap_or(Trust::Unverified);
+
+        egui::TopBottomPanel::top("chat_header").show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                ui.heading(&peer);
+                ui.add_space(10.0);
+                if self.encrypts_to(&peer) {
+                    ui.colored_label(egui::Color32::LIGHT_GREEN, "🔒 End-to-end encrypted");
+                    if trust == Trust::Verified {
+                        ui.colored_label(egui::Color32::LIGHT_GREEN, "✔ Verified");
+                    }
+                } else if self.identity.is_some() && !peer_has_keys {
+                    ui.colored_label(
+                        egui::Color32::GRAY,
+                        format!("🔓 Not encrypted, {peer} has no encryption keys"),
+                    );
+                } else {
+                    ui.colored_label(egui::Color32::GRAY, "🔓 Not encrypted");
+                }
+
+                ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+                    let mut expiry = self.chat_expiry.0;
+                    let hover = match &self.chat_expiry.1 {
+                        Some(by) => format!("Disappearing messages, set by {by}"),
+                        None => "Disappearing messages".to_string(),
+                    };
+                    egui::ComboBox::from_id_salt("expiry")
+                        .selected_text(format!("⏱ {}", expiry_label(expiry)))
+                        .show_ui(ui, |ui| {
+                            for (secs, _) in EXPIRY_CHOICES {
+                                ui.selectable_value(&mut expiry, *secs, expiry_label(*secs));
+                            }
+                        })
+                        .response
+                        .on_hover_text(hover);
+                    if expiry != self.chat_expiry.0
+                        && let Some(tx) = &self.ws_tx
+                    {
+                        let _ = tx.try_send(Event::SetExpiry((peer.clone(), expiry)));
+                    }
+                    if peer_has_keys && self.identity.is_some() {
+                        if ui.button("Safety number").clicked() {
+                            self.show_safety_number = !self.show_safety_number;
+                        }
+                        let mut encrypt = !self.plaintext_chats.contains(&peer);
+                        if ui.checkbox(&mut encrypt, "Encrypt").changed() {
+                            if encrypt {
+                                self.plaintext_chats.remove(&peer);
+                            } else {
+                                self.plaintext_chats.insert(peer.clone());
+                            }
+                        }
+                    }
+                });
+            });
+            if trust == Trust::KeyChanged {
+                ui.horizontal(|ui| {
+                    ui.colored_label(
+                        egui::Color32::from_rgb(255, 165, 0),
+                        format!(
+                            "⚠ The encryption keys of {peer} changed. Compare the safety number again."
+                        ),
+                    );
+                    if ui.small_button("Dismiss").clicked()
+                        && let Some(store) = &mut self.key_store
+                    {
+                        store.acknowledge_change(&peer);
+                    }
+                });
+            }
+        });
+
+        if !self.show_safety_number {
+            return;
+        }
+        let Some(store) = &self.key_store else {
+            return;
+        };
+        let mut own_keys = store.keys(&self.username);
+        if let Some(identity) = &self.identity
+            && !own_keys.contains(&identity.public_key())
+        {
+            own_keys.push(identity.public_key());
+        }
+        let number = e2ee::safety_number(&self.username, &own_keys, &peer, &store.keys(&peer));
+        let mut open = true;
+        let mut verified = false;
+        egui::Window::new(format!("Safety number with {peer}"))
+            .open(&mut open)
+            .collapsible(false)
+            .resizable(false)
+            .show(ctx, |ui| {
+                ui.label(format!(
+                    "Compare this number with {peer} in person or over a trusted channel. \
+                     If it matches on both devices, nobody is reading your messages in between."
+                ));
+                ui.add_space(10.0);
+                for line in number.split(' ').collect::<Vec<_>>().chunks(4) {
+                    ui.monospace(line.join(" "));
+                }
+                ui.add_space(10.0);
+                if trust == Trust::Verified {
+                    ui.colored_label(egui::Color32::LIGHT_GREEN, "✔ Verified");
+                } else if ui.button("Mark as verified").clicked() {
+                    verified = true;
+                }
+            });
+        if verified && let Some(store) = &mut self.key_store {
+            store.set_verified(&peer);
+        }
+        self.show_safety_number = open;
+    }
+}
+
 impl eframe::App for MyApp {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
         match self.current_page {
@@ -827,29 +2065,41 @@ impl eframe::App for MyApp {
 
 #[tokio::main]
 async fn main() -> eframe::Result<()> {
-    let cert_bytes = match fs::read(
-        PathBuf::from(env!("CARGO_MANIFEST_DIR"))
-            .join("certs")
-            .join("server.crt"),
-    ) {
-        Ok(v) => v,
+    let server_cert = match server_certificate().await {
+        Ok(cert) => cert,
         Err(err) => {
-            println!("Error while trying to read the certificate: {err}");
+            println!("{err}");
             return Ok(());
         }
     };
-    let cert = match Certificate::from_pem(&cert_bytes) {
-        Ok(c) => c,
+    let mut builder = match http_builder() {
+        Ok(builder) => builder,
         Err(err) => {
-            println!("Invalid certificate: {err}");
+            println!("{err}");
             return Ok(());
         }
     };
-    let client = match reqwest::Client::builder()
-        .add_root_certificate(cert)
-        .danger_accept_invalid_certs(true)
-        .build()
-    {
+    match &server_cert {
+        Some(cert) => match Certificate::from_der(cert) {
+            Ok(cert) => {
+                builder = builder
+                    .tls_built_in_root_certs(false)
+                    .add_root_certificate(cert);
+            }
+            Err(err) => {
+                println!("Invalid server certificate: {err}");
+                return Ok(());
+            }
+        },
+        None => {
+            println!(
+                "Neither MESSENGER_SERVER_CERT nor MESSENGER_SERVER_FINGERPRINT is set, \
+                 the server certificate is not checked"
+            );
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+    }
+    let client = match builder.build() {
         Ok(cli) => cli,
         Err(err) => {
             println!("Error while building the client: {err}");
@@ -861,6 +2111,6 @@ async fn main() -> eframe::Result<()> {
     eframe::run_native(
         "Rustcrab",
         options,
-        Box::new(|_cc| Ok(Box::<MyApp>::new(MyApp::new(client)))),
+        Box::new(|_cc| Ok(Box::<MyApp>::new(MyApp::new(client, server_cert)))),
     )
 }
@@ -0,0 +1,233 @@
+use eframe::egui;
+use pulldown_cmark::{Event, LinkType, Options, Parser, Tag, TagEnd};
+use std::ops::Range;
+
+const LINK_SCHEMES: [&str; 3] = ["http://", "https://", "mailto:"];
+/// Width of one level of list nesting.
+const INDENT: f32 = 12.0;
+
+// The server escapes markup outside the subset before storing a message,
+// but cannot read encrypted ones, so the same rules are applied here.
+
+fn supported(tag: &Tag) -> bool {
+    match tag {
+        Tag::Paragraph
+        | Tag::Strong
+        | Tag::Emphasis
+        | Tag::CodeBlock(_)
+        | Tag::List(_)
+        | Tag::Item => true,
+        Tag::Link {
+            link_type: LinkType::Email,
+            ..
+        } => true,
+        Tag::Link { dest_url, .. } => {
+            let url = dest_url.to_ascii_lowercase();
+            LINK_SCHEMES.iter().any(|scheme| url.starts_with(scheme))
+        }
+        _ => false,
+    }
+}
+
+fn unsupported(text: &str) -> Vec<Range<usize>> {
+    let mut ranges = Vec::new();
+    let mut depth = 0;
+    for (event, range) in Parser::new_ext(text, Options::empty()).into_offset_iter() {
+        match event {
+            Event::Start(_) if depth > 0 => depth += 1,
+            Event::End(_) if depth > 0 => depth -= 1,
+            _ if depth > 0 => {}
+            Event::Start(tag) if !supported(&tag) => {
+                depth = 1;
+                ranges.push(range);
+            }
+            Event::Html(_) | Event::InlineHtml(_) | Event::Rule => ranges.push(range),
+            _ => {}
+        }
+    }
+    ranges
+}
+
+/// `text` with the markup outside the subset backslash-escaped, so it
+/// shows as typed.
+fn sanitize(text: &str) -> String {
+    let mut sanitized = text.to_string();
+    loop {
+        let ranges = unsupported(&sanitized);
+        if ranges.is_empty() {
+            return sanitized;
+        }
+        let escaped = escape(&sanitized, &ranges);
+        if escaped == sanitized {
+            return sanitized;
+        }
+        sanitized = escaped;
+    }
+}
+
+fn escape(text: &str, ranges: &[Range<usize>]) -> String {
+    let mut escaped = String::with_capacity(text.len() + text.len() / 8);
+    let mut done = 0;
+    for range in ranges {
+        let start = range.start.max(done);
+        let end = range.end.max(start);
+        escaped.push_str(&text[done..start]);
+        let mut chars = text[start..end].chars().peekable();
+        while let Some(c) = chars.next() {
+            if c == '\\' && chars.peek().is_some_and(|c| c.is_ascii_punctuation()) {
+                escaped.push(c);
+                escaped.extend(chars.next());
+                continue;
+            }
+            if c.is_ascii_punctuation() {
+                escaped.push('\\');
+            }
+            escaped.push(c);
+        }
+        done = end;
+    }
+    escaped.push_str(&text[done..]);
+    escaped
+}
+
+/// A run of text styled one way.
+struct Span {
+    text: String,
+    strong: bool,
+    italics: bool,
+    code: bool,
+    link: Option<String>,
+}
+
+/// Shows a message body: bold, italics, inline code, code blocks with a
+/// copy button, links and lists. Everything else is plain text.
+pub fn show(ui: &mut egui::Ui, text: &str, color: egui::Color32) {
+    let text = sanitize(text);
+    let mut line: Vec<Span> = Vec::new();
+    let (mut strong, mut italics) = (0, 0);
+    let mut link: Option<String> = None;
+    // Next number of each open list, `None` for bulleted ones.
+    let mut lists: Vec<Option<u64>> = Vec::new();
+    let mut code_block: Option<String> = None;
+    for event in Parser::new_ext(&text, Options::empty()) {
+        match event {
+            Event::Start(Tag::Strong) => strong += 1,
+            Event::End(TagEnd::Strong) => strong -= 1,
+            Event::Start(Tag::Emphasis) => italics += 1,
+            Event::End(TagEnd::Emphasis) => italics -= 1,
+            Event::Start(Tag::Link { dest_url, .. }) => link = Some(dest_url.to_string()),
+            Event::End(TagEnd::Link) => link = None,
+            Event::Start(Tag::CodeBlock(_)) => {
+                flush(ui, &mut line, lists.len(), color);
+                code_block = Some(String::new());
+            }
+            Event::End(TagEnd::CodeBlock) => {
+                if let Some(code) = code_block.take() {
+                    show_code_block(ui, code.trim_end_matches('\n'), lists.len());
+                }
+            }
+            Event::Text(text) => match &mut code_block {
+                Some(code) => code.push_str(&text),
+                None => line.push(Span {
+                    text: text.to_string(),
+                    strong: strong > 0,
+                    italics: italics > 0,
+                    code: false,
+                    link: link.clone(),
+                }),
+            },
+            Event::Code(text) => line.push(Span {
+                text: text.to_string(),
+                strong: false,
+                italics: false,
+                code: true,
+                link: link.clone(),
+            }),
+            Event::Start(Tag::List(first)) => {
+                flush(ui, &mut line, lists.len(), color);
+                lists.push(first);
+            }
+            Event::End(TagEnd::List(_)) => {
+                flush(ui, &mut line, lists.len(), color);
+                lists.pop();
+            }
+            Event::Start(Tag::Item) => {
+                flush(ui, &mut line, lists.len(), color);
+                let marker = match lists.last_mut() {
+                    Some(Some(number)) => {
+                        *number += 1;
+                        format!("{}. ", *number - 1)
+                    }
+                    _ => "• ".to_string(),
+                };
+                line.push(Span {
+                    text: marker,
+                    strong: false,
+                    italics: false,
+                    code: false,
+                    link: None,
+                });
+            }
+            // Chat messages keep their line breaks.
+            Event::SoftBreak
+            | Event::HardBreak
+            | Event::End(TagEnd::Paragraph)
+            | Event::End(TagEnd::Item) => flush(ui, &mut line, lists.len(), color),
+            _ => {}
+        }
+    }
+    flush(ui, &mut line, lists.len(), color);
+}
+
+/// Lays out one line of spans, wrapping at the bubble's width.
+fn flush(ui: &mut egui::Ui, line: &mut Vec<Span>, depth: usize, color: egui::Color32) {
+    if line.is_empty() {
+        return;
+    }
+    ui.horizontal_wrapped(|ui| {
+        ui.spacing_mut().item_spacing.x = 0.0;
+        ui.add_space(depth.saturating_sub(1) as f32 * INDENT);
+        for span in line.drain(..) {
+            let mut text = egui::RichText::new(span.text);
+            // egui has no bold face, bold text stands out by brightness.
+            // Links keep the link color.
+            if span.link.is_none() {
+                text = match span.strong {
+                    true => text.color(color),
+                    false => text.color(color.gamma_multiply(0.85)),
+                };
+            }
+            if span.strong {
+                text = text.strong();
+            }
+            if span.italics {
+                text = text.italics();
+            }
+            if span.code {
+                text = text.code();
+            }
+            match span.link {
+                Some(url) => ui.hyperlink_to(text, url),
+                None => ui.label(text),
+            };
+        }
+    });
+}
+
+fn show_code_block(ui: &mut egui::Ui, code: &str, depth: usize) {
+    ui.horizontal(|ui| {
+        ui.add_space(depth as f32 * INDENT);
+        egui::Frame::none()
+            .fill(ui.visuals().extreme_bg_color)
+            .rounding(egui::Rounding::same(6.0))
+            .inner_margin(6.0)
+            .show(ui, |ui| {
+                ui.vertical(|ui| {
+                    ui.label(egui::RichText::new(code).monospace());
+                    if ui.small_button("Copy").clicked() {
+                        ui.ctx().copy_text(code.to_string());
+                    }
+                });
+            });
+    });
+}
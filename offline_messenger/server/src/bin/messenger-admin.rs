@@ -0,0 +1,571 @@
+use clap::{Parser, Subcommand};
+use serde_json::{Value, json};
+use server::network_manager::{
+    archive, bots,
+    config::ServerConfig,
+    database_manager::{AuditEntry, DataBase},
+    moderation::Role,
+    tls, validation,
+};
+use std::{error::Error, fs, io, path::PathBuf, process::ExitCode};
+
+/// Administration tool for the messenger server.
+///
+/// Talks to the database directly and to the server's admin listener for
+/// anything that only lives in memory (connected sessions).
+#[derive(Parser)]
+#[command(name = "messenger-admin")]
+struct Cli {
+    /// Print JSON instead of human-readable text.
+    #[arg(long, global = true)]
+    json: bool,
+    /// Database connection string. Defaults to MESSENGER_DATABASE_URL or
+    /// the server's built-in default.
+    #[arg(long, global = true)]
+    database_url: Option<String>,
+    /// Base URL of the server's admin listener. Defaults to
+    /// MESSENGER_ADMIN_ADDR.
+    #[arg(long, global = true)]
+    admin_url: Option<String>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// List every account.
+    Users,
+    /// Show connected WebSocket sessions, optionally for one user.
+    Sessions { user: Option<String> },
+    /// Set a new password. Reads it from stdin when --password is omitted.
+    ResetPassword {
+        user: String,
+        #[arg(long)]
+        password: Option<String>,
+    },
+    /// Prevent a user from logging in and close their live sessions.
+    Disable { user: String },
+    /// Allow a disabled user to log in again.
+    Enable { user: String },
+    /// Delete every message a user sent or received.
+    PurgeMessages {
+        user: String,
+        /// Required, the deletion cannot be undone.
+        #[arg(long)]
+        yes: bool,
+    },
+    /// Message counts and activity per user.
+    Stats { user: Option<String> },
+    /// Change a user's role: user, moderator or admin.
+    SetRole { user: String, role: String },
+    /// Show the most recent moderation actions.
+    Audit {
+        #[arg(long, default_value_t = 50)]
+        limit: i64,
+    },
+    /// Lift a login lockout and forget the failed attempts of a user and/or
+    /// a source address.
+    Unlock {
+        user: Option<String>,
+        #[arg(long)]
+        ip: Option<String>,
+    },
+    /// Show the most recent login attempts.
+    AuthLog {
+        #[arg(long)]
+        user: Option<String>,
+        #[arg(long)]
+        ip: Option<String>,
+        #[arg(long, default_value_t = 50)]
+        limit: i64,
+    },
+    /// Show the most recent webhook deliveries.
+    Webhooks {
+        /// Only deliveries that are pending, delivered or failed.
+        #[arg(long)]
+        status: Option<String>,
+        #[arg(long, default_value_t = 50)]
+        limit: i64,
+    },
+    /// Queue a failed webhook delivery again.
+    WebhookRetry { id: i64 },
+    /// Create a bot account and print its API token.
+    CreateBot { name: String },
+    /// Give a bot a new API token, the old one stops working.
+    RotateBotToken { name: String },
+    /// Write a user's whole history to a tar archive.
+    Export {
+        user: String,
+        /// Defaults to USER-history.tar in the current directory.
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+    /// Load an archive made by `export`, keeping message ids where free.
+    Import { archive: PathBuf },
+    /// Bring the database schema up to date. The server does this at
+    /// startup, the other commands never do.
+    Migrate,
+    /// SHA-256 fingerprint of the server's TLS certificate, for pinning.
+    Fingerprint {
+        /// Defaults to MESSENGER_TLS_CERT or the server's certs/server.crt.
+        #[arg(long)]
+        cert: Option<PathBuf>,
+    },
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    match run(cli).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Error: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run(cli: Cli) -> Result<(), Box<dyn Error>> {
+    let config = ServerConfig::from_env();
+    let admin_url = cli
+        .admin_url
+        .clone()
+        .or_else(|| config.admin_addr.map(|addr| format!("http://{addr}")));
+
+    if let Command::Sessions { user } = &cli.command {
+        let sessions = fetch_sessions(admin_url.as_deref(), user.as_deref()).await?;
+        if cli.json {
+            println!("{}", Value::Array(sessions));
+        } else if sessions.is_empty() {
+            println!("No connected sessions");
+        } else {
+            println!("{:<24} {:<8} {:>11}", "USER", "SESSION", "QUEUE DEPTH");
+            for s in sessions {
+                println!(
+                    "{:<24} {:<8} {:>11}",
+                    s["user"].as_str().unwrap_or_default(),
+                    s["session"].as_str().unwrap_or_default(),
+                    s["queue_depth"].as_u64().unwrap_or_default()
+                );
+            }
+        }
+        return Ok(());
+    }
+
+    if let Command::Fingerprint { cert } = &cli.command {
+        let cert = cert.clone().unwrap_or(config.tls_cert);
+        let fingerprint = tls::fingerprint(&cert)
+            .map_err(|err| format!("cannot read {}: {err}", cert.display()))?;
+        report(
+            cli.json,
+            json!({ "cert": cert, "sha256": fingerprint }),
+            || format!("SHA256 {fingerprint}"),
+        );
+        return Ok(());
+    }
+
+    let database_url = cli
+        .database_url
+        .clone()
+        .unwrap_or_else(|| config.database_url.clone());
+    let database = DataBase::connect(&database_url).await?;
+
+    match cli.command {
+        Command::Sessions { .. } | Command::Fingerprint { .. } => {
+            unreachable!("handled above")
+        }
+        Command::Migrate => {
+            database.migrate().await?;
+            report(cli.json, json!({ "migrated": true }), || {
+                "Database schema is up to date".to_string()
+            });
+        }
+        Command::Users => {
+            let users: Vec<Value> = database
+                .list_users()
+                .await?
+                .into_iter()
+                .map(|row| {
+                    json!({
+                        "username": row.get::<_, String>(0),
+                        "disabled": row.get::<_, bool>(1),
+                        "sent": row.get::<_, i64>(2),
+                        "received": row.get::<_, i64>(3),
+                        "bot": row.get::<_, bool>(4),
+                    })
+                })
+                .collect();
+            if cli.json {
+                println!("{}", Value::Array(users));
+            } else {
+                println!(
+                    "{:<24} {:<9} {:>8} {:>8}",
+                    "USERNAME", "STATUS", "SENT", "RECEIVED"
+                );
+                for u in users {
+                    let status = match (u["disabled"] == true, u["bot"] == true) {
+                        (true, _) => "disabled",
+                        (false, true) => "bot",
+                        (false, false) => "active",
+                    };
+                    println!(
+                        "{:<24} {:<9} {:>8} {:>8}",
+                        u["username"].as_str().unwrap_or_default(),
+                        status,
+                        u["sent"].as_i64().unwrap_or_default(),
+                        u["received"].as_i64().unwrap_or_default()
+                    );
+                }
+            }
+        }
+        Command::ResetPassword { user, password } => {
+            let password = match password {
+                Some(p) => p,
+                None => {
+                    let mut line = String::new();
+                    io::stdin().read_line(&mut line)?;
+                    line.trim_end_matches(['\r', '\n']).to_string()
+                }
+            };
+            if password.trim().is_empty() {
+                return Err("the new password is empty".into());
+            }
+            require_user(database.set_password(&user, &password).await?, &user)?;
+            report(
+                cli.json,
+                json!({ "user": user, "password_reset": true }),
+                || format!("Password of {user} was reset"),
+            );
+        }
+        Command::Disable { user } => {
+            require_user(database.set_disabled(&user, true).await?, &user)?;
+            let kicked = match kick_sessions(admin_url.as_deref(), &user).await {
+                Ok(n) => Some(n),
+                Err(err) => {
+                    eprintln!("Warning: could not close live sessions: {err}");
+                    None
+                }
+            };
+            report(
+                cli.json,
+                json!({ "user": user, "disabled": true, "sessions_closed": kicked }),
+                || match kicked {
+                    Some(n) => format!("{user} is disabled, {n} live session(s) closed"),
+                    None => format!("{user} is disabled"),
+                },
+            );
+        }
+        Command::Enable { user } => {
+            require_user(database.set_disabled(&user, false).await?, &user)?;
+            report(cli.json, json!({ "user": user, "disabled": false }), || {
+                format!("{user} is enabled")
+            });
+        }
+        Command::PurgeMessages { user, yes } => {
+            if !yes {
+                return Err("purging messages cannot be undone, pass --yes to confirm".into());
+            }
+            let deleted = database.purge_messages(&user).await?;
+            report(
+                cli.json,
+                json!({ "user": user, "deleted_messages": deleted }),
+                || format!("Deleted {deleted} message(s) of {user}"),
+            );
+        }
+        Command::Stats { user } => {
+            let stats: Vec<Value> = database
+                .user_stats(user.as_deref())
+                .await?
+                .into_iter()
+                .map(|row| {
+                    json!({
+                        "username": row.get::<_, String>(0),
+                        "sent": row.get::<_, i64>(1),
+                        "received": row.get::<_, i64>(2),
+                        "peers": row.get::<_, i64>(3),
+                        "first_message": row.get::<_, Option<String>>(4),
+                        "last_message": row.get::<_, Option<String>>(5),
+                    })
+                })
+                .collect();
+            if let Some(user) = &user
+                && stats.is_empty()
+            {
+                return Err(format!("no user named {user}").into());
+            }
+            if cli.json {
+                println!("{}", Value::Array(stats));
+            } else {
+                for s in stats {
+                    println!("{}", s["username"].as_str().unwrap_or_default());
+                    println!("  sent:          {}", s["sent"]);
+                    println!("  received:      {}", s["received"]);
+                    println!("  conversations: {}", s["peers"]);
+                    println!(
+                        "  first message: {}",
+                        s["first_message"].as_str().unwrap_or("-")
+                    );
+                    println!(
+                        "  last message:  {}",
+                        s["last_message"].as_str().unwrap_or("-")
+                    );
+                }
+            }
+        }
+        Command::SetRole { user, role } => {
+            let role = Role::parse(&role).ok_or("the role must be user, moderator or admin")?;
+            let audit = AuditEntry {
+                actor: "cli",
+                action: "set_role",
+                detail: &format!("role={}", role.as_str()),
+            };
+            require_user(database.set_role(&user, role, audit).await?, &user)?;
+            report(cli.json, json!({ "user": user, "role": role }), || {
+                format!("{user} is now {}", role.as_str())
+            });
+        }
+        Command::Audit { limit } => {
+            let entries: Vec<Value> = database
+                .audit_entries(limit)
+                .await?
+                .into_iter()
+                .map(|row| {
+                    json!({
+                        "at": row.get::<_, String>(0),
+                        "actor": row.get::<_, String>(1),
+                        "action": row.get::<_, String>(2),
+                        "target": row.get::<_, String>(3),
+                        "detail": row.get::<_, String>(4),
+                    })
+                })
+                .collect();
+            if cli.json {
+                println!("{}", Value::Array(entries));
+            } else {
+                println!(
+                    "{:<19} {:<16} {:<10} {:<16} DETAIL",
+                    "AT (UTC)", "ACTOR", "ACTION", "TARGET"
+                );
+                for e in entries {
+                    println!(
+                        "{:<19} {:<16} {:<10} {:<16} {}",
+                        e["at"].as_str().unwrap_or_default(),
+                        e["actor"].as_str().unwrap_or_default(),
+                        e["action"].as_str().unwrap_or_default(),
+                        e["target"].as_str().unwrap_or_default(),
+                        e["detail"].as_str().unwrap_or_default()
+                    );
+                }
+            }
+        }
+        Command::Unlock { user, ip } => {
+            if user.is_none() && ip.is_none() {
+                return Err("pass a user, --ip or both".into());
+            }
+            let cleared = database
+                .unlock_login(user.as_deref(), ip.as_deref())
+                .await?;
+            let target = [user.as_deref(), ip.as_deref()]
+                .into_iter()
+                .flatten()
+                .collect::<Vec<_>>()
+                .join(" ");
+            database.audit("cli", "unlock", &target, "").await?;
+            report(
+                cli.json,
+                json!({ "user": user, "ip": ip, "cleared": cleared }),
+                || match cleared {
+                    0 => format!("{target} had no failed logins"),
+                    _ => format!("{target} unlocked"),
+                },
+            );
+        }
+        Command::AuthLog { user, ip, limit } => {
+            let entries: Vec<Value> = database
+                .auth_log_entries(user.as_deref(), ip.as_deref(), limit)
+                .await?
+                .into_iter()
+                .map(|row| {
+                    json!({
+                        "at": row.get::<_, String>(0),
+                        "user": row.get::<_, String>(1),
+                        "ip": row.get::<_, String>(2),
+                        "outcome": row.get::<_, String>(3),
+                    })
+                })
+                .collect();
+            if cli.json {
+                println!("{}", Value::Array(entries));
+            } else {
+                println!(
+                    "{:<19} {:<24} {:<39} OUTCOME",
+                    "AT (UTC)", "USER", "ADDRESS"
+                );
+                for e in entries {
+                    println!(
+                        "{:<19} {:<24} {:<39} {}",
+                        e["at"].as_str().unwrap_or_default(),
+                        e["user"].as_str().unwrap_or_default(),
+                        e["ip"].as_str().unwrap_or_default(),
+                        e["outcome"].as_str().unwrap_or_default()
+                    );
+                }
+            }
+        }
+        Command::Webhooks { status, limit } => {
+            let deliveries: Vec<Value> = database
+                .webhook_deliveries(status.as_deref(), limit)
+                .await?
+                .into_iter()
+                .map(|row| {
+                    json!({
+                        "id": row.get::<_, i64>(0),
+                        "created_at": row.get::<_, String>(1),
+                        "url": row.get::<_, String>(2),
+                        "event": row.get::<_, String>(3),
+                        "status": row.get::<_, String>(4),
+                        "attempts": row.get::<_, i32>(5),
+                        "last_status": row.get::<_, Option<i32>>(6),
+                        "last_error": row.get::<_, Option<String>>(7),
+                    })
+                })
+                .collect();
+            if cli.json {
+                println!("{}", Value::Array(deliveries));
+            } else {
+                println!(
+                    "{:>8} {:<19} {:<16} {:<9} {:>8} URL / LAST ERROR",
+                    "ID", "CREATED (UTC)", "EVENT", "STATUS", "ATTEMPTS"
+                );
+                for d in deliveries {
+                    println!(
+                        "{:>8} {:<19} {:<16} {:<9} {:>8} {}",
+                        d["id"],
+                        d["created_at"].as_str().unwrap_or_default(),
+                        d["event"].as_str().unwrap_or_default(),
+                        d["status"].as_str().unwrap_or_default(),
+                        d["attempts"],
+                        d["url"].as_str().unwrap_or_default()
+                    );
+                    if let Some(error) = d["last_error"].as_str() {
+                        println!("{:>65} {error}", "");
+                    }
+                }
+            }
+        }
+        Command::WebhookRetry { id } => {
+            if !database.retry_webhook(id).await? {
+                return Err(format!("no failed webhook delivery {id}").into());
+            }
+            database
+                .audit("cli", "webhook_retry", &id.to_string(), "")
+                .await?;
+            report(cli.json, json!({ "id": id, "status": "pending" }), || {
+                format!("Webhook delivery {id} queued again")
+            });
+        }
+        Command::CreateBot { name } => {
+            let name = validation::username(&name, &config).map_err(|err| err.to_string())?;
+            let token = bots::new_token();
+            let resp = database
+                .create_bot(&name, &bots::token_hash(&token))
+                .await?;
+            if !resp.succes {
+                return Err(resp.message.into());
+            }
+            database.audit("cli", "create_bot", &name, "").await?;
+            report(cli.json, json!({ "user": name, "token": token }), || {
+                format!("Bot {name} created, its API token is shown only once:\n{token}")
+            });
+        }
+        Command::RotateBotToken { name } => {
+            let token = bots::new_token();
+            if !database
+                .set_bot_token(&name, &bots::token_hash(&token))
+                .await?
+            {
+                return Err(format!("no bot named {name}").into());
+            }
+            database.audit("cli", "rotate_bot_token", &name, "").await?;
+            report(cli.json, json!({ "user": name, "token": token }), || {
+                format!("New API token of {name}, shown only once:\n{token}")
+            });
+        }
+        Command::Export { user, output } => {
+            if database.get_role(&user).await?.is_none() {
+                return Err(format!("no user named {user}").into());
+            }
+            let data = archive::export(&database, &user).await?;
+            let output = output.unwrap_or_else(|| PathBuf::from(format!("{user}-history.tar")));
+            fs::write(&output, &data)?;
+            report(
+                cli.json,
+                json!({ "user": user, "file": output, "bytes": data.len() }),
+                || format!("History of {user} written to {}", output.display()),
+            );
+        }
+        Command::Import { archive: path } => {
+            let data = fs::read(&path)?;
+            let result = archive::import(&database, &data).await?;
+            database
+                .audit("cli", "import", &result.user, &result.detail())
+                .await?;
+            let text = format!(
+                "Imported {} message(s) of {}: {} renumbered, {} already present, {} skipped",
+                result.imported, result.user, result.renumbered, result.duplicates, result.skipped
+            );
+            report(cli.json, json!(result), || text);
+        }
+    }
+    database.close();
+    Ok(())
+}
+
+fn require_user(found: bool, user: &str) -> Result<(), Box<dyn Error>> {
+    if found {
+        Ok(())
+    } else {
+        Err(format!("no user named {user}").into())
+    }
+}
+
+fn report(json: bool, value: Value, text: impl FnOnce() -> String) {
+    if json {
+        println!("{value}");
+    } else {
+        println!("{}", text());
+    }
+}
+
+async fn fetch_sessions(
+    admin_url: Option<&str>,
+    user: Option<&str>,
+) -> Result<Vec<Value>, Box<dyn Error>> {
+    let admin_url = admin_url.ok_or("the admin listener is disabled, pass --admin-url")?;
+    let sessions: Vec<Value> = reqwest::get(format!("{admin_url}/sessions"))
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    Ok(sessions
+        .into_iter()
+        .filter(|s| user.is_none_or(|u| s["user"] == u))
+        .collect())
+}
+
+async fn kick_sessions(admin_url: Option<&str>, user: &str) -> Result<u64, Box<dyn Error>> {
+    let admin_url = admin_url.ok_or("the admin listener is disabled")?;
+    let mut url = reqwest::Url::parse(admin_url)?;
+    url.path_segments_mut()
+        .map_err(|()| format!("{admin_url} cannot be a base URL"))?
+        .pop_if_empty()
+        .extend(["sessions", user]);
+    let resp: Value = reqwest::Client::new()
+        .delete(url)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    Ok(resp["kicked"].as_u64().unwrap_or(0))
+}
@@ -0,0 +1,544 @@
+use clap::Parser;
+use futures_util::{SinkExt, StreamExt, stream};
+use rustls::{
+    DigitallySignedStruct, SignatureScheme,
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::WebPkiSupportedAlgorithms,
+    pki_types::{CertificateDer, ServerName, UnixTime, pem::PemObject},
+};
+use serde_json::{Value, json};
+use server::network_manager::handlers::{
+    LoginReq, SessionInfo, SigninReq, WsMessage, WsMessageBack,
+};
+use std::{
+    collections::BTreeMap, error::Error, fs, path::PathBuf, process::ExitCode, sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    net::TcpStream,
+    time::{Instant, MissedTickBehavior},
+};
+use tokio_tungstenite::{
+    Connector, MaybeTlsStream, WebSocketStream, connect_async_tls_with_config, tungstenite::Message,
+};
+use uuid::Uuid;
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Load generator for the messenger server.
+///
+/// Signs up a batch of synthetic users (`load-RUN-N`), connects each of them
+/// over `/ws` and has every user send messages to the others in turn. Every
+/// message carries its send time, so the receiving side measures end-to-end
+/// latency. The accounts are left on the server afterwards.
+#[derive(Parser)]
+#[command(name = "messenger-loadtest")]
+struct Cli {
+    /// Base URL of the server.
+    #[arg(long, default_value = "https://localhost:3000")]
+    url: String,
+    /// PEM certificate to trust for the server. Defaults to the server's
+    /// certs/server.crt.
+    #[arg(long)]
+    ca_cert: Option<PathBuf>,
+    /// Accept any server certificate, like the desktop client does.
+    #[arg(long, short = 'k')]
+    insecure: bool,
+    /// Number of simulated users.
+    #[arg(long, short, default_value_t = 50)]
+    users: usize,
+    /// Messages per second sent by each user.
+    #[arg(long, default_value_t = 1.0)]
+    rate: f64,
+    /// Size of each message body in bytes.
+    #[arg(long, default_value_t = 64)]
+    size: usize,
+    /// Seconds to send messages for.
+    #[arg(long, default_value_t = 30)]
+    duration: u64,
+    /// Seconds to keep waiting for deliveries after sending stops.
+    #[arg(long, default_value_t = 5)]
+    drain: u64,
+    /// Users signing up and connecting at the same time.
+    #[arg(long, default_value_t = 32)]
+    connect_concurrency: usize,
+    /// Print JSON instead of human-readable text.
+    #[arg(long)]
+    json: bool,
+}
+
+/// Width of the send timestamp at the start of every message body.
+const STAMP_LEN: usize = 16;
+
+/// What one simulated user saw.
+#[derive(Default)]
+struct Stats {
+    sent: u64,
+    acked: u64,
+    /// Failed responses, by error code.
+    rejected: BTreeMap<String, u64>,
+    delivered: u64,
+    /// Microseconds from sending to the server's response.
+    ack_latency: Vec<u64>,
+    /// Microseconds from sending to the receiver getting the message.
+    delivery_latency: Vec<u64>,
+    disconnected: u64,
+}
+
+impl Stats {
+    fn merge(&mut self, other: Stats) {
+        self.sent += other.sent;
+        self.acked += other.acked;
+        for (code, n) in other.rejected {
+            *self.rejected.entry(code).or_default() += n;
+        }
+        self.delivered += other.delivered;
+        self.ack_latency.extend(other.ack_latency);
+        self.delivery_latency.extend(other.delivery_latency);
+        self.disconnected += other.disconnected;
+    }
+}
+
+/// Shared by every user task.
+struct Plan {
+    users: Vec<String>,
+    /// Time zero for the timestamps in request ids and message bodies.
+    epoch: Instant,
+    period: Duration,
+    size: usize,
+    send_until: Instant,
+    stop_at: Instant,
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    match run(cli).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Error: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run(cli: Cli) -> Result<(), Box<dyn Error>> {
+    if cli.users < 2 {
+        return Err("at least 2 users are needed".into());
+    }
+    if !(cli.rate > 0.0 && cli.rate.is_finite()) {
+        return Err("--rate must be a positive number".into());
+    }
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+
+    let (http, tls) = if cli.insecure {
+        let http = reqwest::Client::builder()
+            .danger_accept_invalid_certs(true)
+            .build()?;
+        let tls = rustls::ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(AcceptAnyCert))
+            .with_no_client_auth();
+        (http, tls)
+    } else {
+        let ca_cert = cli.ca_cert.clone().unwrap_or_else(|| {
+            PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+                .join("certs")
+                .join("server.crt")
+        });
+        let pem = fs::read(&ca_cert)
+            .map_err(|err| format!("cannot read {}: {err}", ca_cert.display()))?;
+        let http = reqwest::Client::builder()
+            .add_root_certificate(reqwest::Certificate::from_pem(&pem)?)
+            .build()?;
+        let mut roots = rustls::RootCertStore::empty();
+        for cert in CertificateDer::pem_slice_iter(&pem) {
+            roots.add(cert?)?;
+        }
+        let tls = rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        (http, tls)
+    };
+    let tls = Arc::new(tls);
+    let base_url = cli.url.trim_end_matches('/').to_string();
+    let ws_url = match base_url.strip_prefix("https://") {
+        Some(host) => format!("wss://{host}/ws"),
+        None => return Err("--url must start with https://".into()),
+    };
+
+    let run_id = Uuid::new_v4().simple().to_string()[..8].to_string();
+    let names: Vec<String> = (0..cli.users)
+        .map(|i| format!("load-{run_id}-{i}"))
+        .collect();
+    if !cli.json {
+        println!("Connecting {} users (run {run_id})", cli.users);
+    }
+    let setup_started = Instant::now();
+    let connected: Vec<Result<(usize, Socket), String>> =
+        stream::iter(names.iter().cloned().enumerate())
+            .map(|(index, name)| {
+                let http = http.clone();
+                let base_url = base_url.clone();
+                let ws_url = ws_url.clone();
+                let tls = tls.clone();
+                let password = run_id.clone();
+                async move {
+                    connect_user(&http, &base_url, &ws_url, tls, &name, &password)
+                        .await
+                        .map(|socket| (index, socket))
+                        .map_err(|err| format!("{name}: {}", error_chain(err.as_ref())))
+                }
+            })
+            .buffer_unordered(cli.connect_concurrency.max(1))
+            .collect()
+            .await;
+    let setup_secs = setup_started.elapsed().as_secs_f64();
+    let mut sockets = Vec::new();
+    let mut setup_errors = Vec::new();
+    for result in connected {
+        match result {
+            Ok(s) => sockets.push(s),
+            Err(err) => setup_errors.push(err),
+        }
+    }
+    if sockets.len() < 2 {
+        for err in &setup_errors {
+            eprintln!("{err}");
+        }
+        return Err("fewer than 2 users could connect".into());
+    }
+    if !cli.json {
+        println!(
+            "{} connected in {setup_secs:.1} s, sending for {} s",
+            sockets.len(),
+            cli.duration
+        );
+    }
+
+    let epoch = Instant::now();
+    let send_until = epoch + Duration::from_secs(cli.duration);
+    // Only users that made it through setup take part, so nobody sends to
+    // a user that is not listening.
+    let plan = Arc::new(Plan {
+        users: sockets.iter().map(|(i, _)| names[*i].clone()).collect(),
+        epoch,
+        period: Duration::from_secs_f64(1.0 / cli.rate),
+        size: cli.size.max(STAMP_LEN),
+        send_until,
+        stop_at: send_until + Duration::from_secs(cli.drain),
+    });
+    let total = sockets.len();
+    let tasks: Vec<_> = sockets
+        .into_iter()
+        .enumerate()
+        .map(|(slot, (_, socket))| {
+            // Spread the first sends over one period instead of sending in
+            // lockstep.
+            let offset = plan.period.mul_f64(slot as f64 / total as f64);
+            tokio::spawn(simulate_user(slot, socket, plan.clone(), offset))
+        })
+        .collect();
+    let mut stats = Stats::default();
+    for task in tasks {
+        stats.merge(task.await?);
+    }
+
+    print_report(&cli, &stats, setup_secs, &setup_errors);
+    Ok(())
+}
+
+/// `err` followed by its sources, which is where reqwest and rustls put the
+/// useful part.
+fn error_chain(err: &dyn Error) -> String {
+    let mut text = err.to_string();
+    let mut source = err.source();
+    while let Some(cause) = source {
+        text.push_str(": ");
+        text.push_str(&cause.to_string());
+        source = cause.source();
+    }
+    text
+}
+
+/// Certificate verifier for `--insecure`. Signatures are still checked, so
+/// the handshake itself stays sound.
+#[derive(Debug)]
+struct AcceptAnyCert;
+
+impl ServerCertVerifier for AcceptAnyCert {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &signature_algorithms())
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &signature_algorithms())
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        signature_algorithms().supported_schemes()
+    }
+}
+
+fn signature_algorithms() -> WebPkiSupportedAlgorithms {
+    rustls::crypto::aws_lc_rs::default_provider().signature_verification_algorithms
+}
+
+async fn connect_user(
+    http: &reqwest::Client,
+    base_url: &str,
+    ws_url: &str,
+    tls: Arc<rustls::ClientConfig>,
+    name: &str,
+    password: &str,
+) -> Result<Socket, Box<dyn Error>> {
+    let response = http
+        .get(format!("{base_url}/signin"))
+        .json(&SigninReq {
+            username: name.to_string(),
+            password: password.to_string(),
+        })
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        return Err(format!("signin failed with {}", response.status()).into());
+    }
+    let response = http
+        .get(format!("{base_url}/login"))
+        .json(&LoginReq {
+            username: name.to_string(),
+            password: password.to_string(),
+        })
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        return Err(format!("login failed with {}", response.status()).into());
+    }
+    let body: Value = response.json().await?;
+    let token = body["token"].as_str().unwrap_or_default().to_string();
+
+    let (mut socket, _) =
+        connect_async_tls_with_config(ws_url, None, false, Some(Connector::Rustls(tls))).await?;
+    let info = SessionInfo {
+        username: name.to_string(),
+        token,
+    };
+    socket
+        .send(Message::text(serde_json::to_string(&info)?))
+        .await?;
+    Ok(socket)
+}
+
+/// Sends to every other user in turn until `send_until`, then keeps
+/// reading until `stop_at`.
+async fn simulate_user(
+    index: usize,
+    mut socket: Socket,
+    plan: Arc<Plan>,
+    offset: Duration,
+) -> Stats {
+    let mut stats = Stats::default();
+    let me = &plan.users[index];
+    let others = plan.users.len() - 1;
+    let padding = "x".repeat(plan.size - STAMP_LEN);
+    let mut ticker = tokio::time::interval_at(Instant::now() + offset, plan.period);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let stop = tokio::time::sleep_until(plan.stop_at);
+    tokio::pin!(stop);
+
+    loop {
+        tokio::select! {
+            _ = ticker.tick(), if Instant::now() < plan.send_until => {
+                let to = &plan.users[(index + 1 + stats.sent as usize % others) % plan.users.len()];
+                let stamp = plan.epoch.elapsed().as_micros() as u64;
+                let request = WsMessage::SendMessage {
+                    id: stamp.to_string(),
+                    token: String::new(),
+                    from: me.clone(),
+                    to: to.clone(),
+                    message: format!("{stamp:0width$}{padding}", width = STAMP_LEN),
+                    resp_msg: None,
+                    resp_user: None,
+                    encrypted: false,
+                };
+                let Ok(text) = serde_json::to_string(&request) else {
+                    continue;
+                };
+                if socket.send(Message::text(text)).await.is_err() {
+                    stats.disconnected += 1;
+                    break;
+                }
+                stats.sent += 1;
+            }
+            frame = socket.next() => {
+                let text = match frame {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => {
+                        stats.disconnected += 1;
+                        break;
+                    }
+                    Some(Ok(_)) => continue,
+                };
+                let now = plan.epoch.elapsed().as_micros() as u64;
+                match serde_json::from_str::<WsMessageBack>(&text) {
+                    Ok(WsMessageBack::Response { id, succes, code, .. }) => {
+                        let Ok(stamp) = id.parse::<u64>() else {
+                            continue;
+                        };
+                        if succes {
+                            stats.acked += 1;
+                            stats.ack_latency.push(now.saturating_sub(stamp));
+                        } else {
+                            let code = code
+                                .and_then(|c| serde_json::to_value(c).ok())
+                                .and_then(|v| v.as_str().map(str::to_string))
+                                .unwrap_or_else(|| "unknown".to_string());
+                            *stats.rejected.entry(code).or_default() += 1;
+                        }
+                    }
+                    Ok(WsMessageBack::Message { to, message, .. }) if to == *me => {
+                        stats.delivered += 1;
+                        if let Some(Ok(stamp)) = message.get(..STAMP_LEN).map(str::parse::<u64>) {
+                            stats.delivery_latency.push(now.saturating_sub(stamp));
+                        }
+                    }
+                    Ok(WsMessageBack::ServerShutdown { .. }) => {
+                        stats.disconnected += 1;
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+            _ = &mut stop => break,
+        }
+    }
+    let _ = socket.close(None).await;
+    stats
+}
+
+/// p50, p90, p99 and max of `samples`, in milliseconds.
+fn percentiles(samples: &mut [u64]) -> Option<[f64; 4]> {
+    if samples.is_empty() {
+        return None;
+    }
+    samples.sort_unstable();
+    let at = |p: f64| {
+        let i = ((samples.len() - 1) as f64 * p).round() as usize;
+        samples[i] as f64 / 1000.0
+    };
+    Some([at(0.5), at(0.9), at(0.99), at(1.0)])
+}
+
+fn print_report(cli: &Cli, stats: &Stats, setup_secs: f64, setup_errors: &[String]) {
+    let mut ack = stats.ack_latency.clone();
+    let mut delivery = stats.delivery_latency.clone();
+    let ack = percentiles(&mut ack);
+    let delivery = percentiles(&mut delivery);
+    let secs = cli.duration.max(1) as f64;
+    let lost = stats.acked.saturating_sub(stats.delivered);
+    let rejected: u64 = stats.rejected.values().sum();
+
+    if cli.json {
+        let latency = |p: Option<[f64; 4]>| {
+            p.map(|[p50, p90, p99, max]| {
+                json!({ "p50_ms": p50, "p90_ms": p90, "p99_ms": p99, "max_ms": max })
+            })
+        };
+        let report = json!({
+            "users": cli.users,
+            "connect_errors": setup_errors.len(),
+            "setup_secs": setup_secs,
+            "duration_secs": cli.duration,
+            "rate_per_user": cli.rate,
+            "message_bytes": cli.size.max(STAMP_LEN),
+            "sent": stats.sent,
+            "acked": stats.acked,
+            "rejected": stats.rejected,
+            "delivered": stats.delivered,
+            "lost": lost,
+            "disconnects": stats.disconnected,
+            "send_throughput": stats.acked as f64 / secs,
+            "delivery_throughput": stats.delivered as f64 / secs,
+            "ack_latency": latency(ack),
+            "delivery_latency": latency(delivery),
+        });
+        println!("{report}");
+        return;
+    }
+
+    for err in setup_errors {
+        eprintln!("Connect error: {err}");
+    }
+    println!();
+    println!(
+        "{:<18} {} ({} failed to connect, setup {setup_secs:.1} s)",
+        "Users",
+        cli.users,
+        setup_errors.len()
+    );
+    println!(
+        "{:<18} {} s at {} msg/s per user, {} B per message",
+        "Load",
+        cli.duration,
+        cli.rate,
+        cli.size.max(STAMP_LEN)
+    );
+    println!(
+        "{:<18} {} ({:.1} msg/s)",
+        "Sent",
+        stats.sent,
+        stats.sent as f64 / secs
+    );
+    println!(
+        "{:<18} {} ({:.1} msg/s)",
+        "Acknowledged",
+        stats.acked,
+        stats.acked as f64 / secs
+    );
+    println!("{:<18} {rejected}", "Rejected");
+    for (code, n) in &stats.rejected {
+        println!("{:<18}   {code}: {n}", "");
+    }
+    println!(
+        "{:<18} {} ({:.1} msg/s)",
+        "Delivered",
+        stats.delivered,
+        stats.delivered as f64 / secs
+    );
+    println!("{:<18} {lost}", "Not delivered");
+    println!("{:<18} {}", "Disconnects", stats.disconnected);
+    println!();
+    println!(
+        "{:<18} {:>9} {:>9} {:>9} {:>9}",
+        "LATENCY (ms)", "P50", "P90", "P99", "MAX"
+    );
+    for (label, p) in [("Acknowledgement", ack), ("Delivery", delivery)] {
+        match p {
+            Some([p50, p90, p99, max]) => {
+                println!("{label:<18} {p50:>9.2} {p90:>9.2} {p99:>9.2} {max:>9.2}")
+            }
+            None => println!("{label:<18} {:>9}", "-"),
+        }
+    }
+}
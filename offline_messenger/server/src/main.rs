@@ -1,14 +1,13 @@
-mod network_manager;
-
-use network_manager::server::Server;
+use server::network_manager::{logging, server::Server};
 
 #[tokio::main]
 async fn main() {
+    logging::init();
     let server = Server::new();
     match server.start().await {
         Ok(_) => {},
         Err(err) => {
-            println!("Error while starting the server: {err}");
+            tracing::error!("Error while starting the server: {err}");
         },
     }
     
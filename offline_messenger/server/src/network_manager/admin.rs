@@ -0,0 +1,116 @@
+use axum::{
+    Json, Router,
+    extract::{Path, State},
+    http::{StatusCode, header},
+    response::IntoResponse,
+    routing::{delete, get},
+};
+use serde_json::json;
+use std::{
+    sync::{Arc, atomic::Ordering},
+    time::{Duration, Instant},
+};
+use tracing::warn;
+
+use crate::network_manager::{
+    fanout::FanoutEvent, logging::session_tag, metrics::METRICS, server::AppState,
+};
+
+const DB_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Routes served on the admin listener. It speaks plain HTTP and is meant
+/// to be bound to a private interface only.
+pub struct AdminHandlers {}
+impl AdminHandlers {
+    pub fn router(app_state: Arc<AppState>) -> Router {
+        Router::new()
+            .route("/metrics", get(AdminHandlers::metrics))
+            .route("/healthz", get(AdminHandlers::healthz))
+            .route("/readyz", get(AdminHandlers::readyz))
+            .route("/sessions", get(AdminHandlers::sessions))
+            .route("/sessions/{user}", delete(AdminHandlers::kick))
+            .with_state(app_state)
+    }
+
+    async fn metrics(State(app_state): State<Arc<AppState>>) -> impl IntoResponse {
+        (
+            [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+            METRICS.render(&app_state),
+        )
+    }
+
+    /// Liveness: the process is up and the runtime is answering requests.
+    async fn healthz() -> impl IntoResponse {
+        (StatusCode::OK, Json(json!({ "status": "ok" })))
+    }
+
+    /// Readiness: every dependency needed to serve clients is usable.
+    async fn readyz(State(app_state): State<Arc<AppState>>) -> impl IntoResponse {
+        let start = Instant::now();
+        let database = match tokio::time::timeout(DB_CHECK_TIMEOUT, app_state.database.ping()).await
+        {
+            Ok(Ok(())) => json!({
+                "ok": true,
+                "latency_ms": start.elapsed().as_secs_f64() * 1000.0,
+            }),
+            Ok(Err(err)) => {
+                warn!("Readiness check: database error: {err}");
+                json!({ "ok": false, "error": err.to_string() })
+            }
+            Err(_) => {
+                warn!("Readiness check: database timed out");
+                json!({ "ok": false, "error": "timed out" })
+            }
+        };
+        let tls_ok = app_state.tls_loaded.load(Ordering::Relaxed);
+        let sessions = app_state.map.lock().ok().map(|map| map.len());
+        let store_ok = sessions.is_some();
+        let shutting_down = *app_state.shutdown.borrow();
+
+        let ready = database["ok"] == true && tls_ok && store_ok && !shutting_down;
+        let status = if ready {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+        (
+            status,
+            Json(json!({
+                "status": if ready { "ready" } else { "not_ready" },
+                "checks": {
+                    "database": database,
+                    "tls": { "ok": tls_ok },
+                    "session_store": { "ok": store_ok, "connected_users": sessions },
+                    "shutting_down": shutting_down,
+                },
+            })),
+        )
+    }
+
+    /// Every connected WebSocket session, named by the same tag as in the
+    /// logs rather than by its token.
+    async fn sessions(State(app_state): State<Arc<AppState>>) -> impl IntoResponse {
+        let sessions: Vec<_> = app_state
+            .queue_depths()
+            .into_iter()
+            .map(|(user, token, depth)| {
+                json!({
+                    "user": user,
+                    "session": session_tag(&token),
+                    "queue_depth": depth,
+                })
+            })
+            .collect();
+        Json(sessions)
+    }
+
+    async fn kick(
+        State(app_state): State<Arc<AppState>>,
+        Path(user): Path<String>,
+    ) -> impl IntoResponse {
+        // The count is for this instance; the bus closes the rest.
+        let kicked = app_state.kick_user(&user);
+        app_state.publish(FanoutEvent::Kick { user: user.clone() });
+        Json(json!({ "user": user, "kicked": kicked }))
+    }
+}
@@ -0,0 +1,358 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    io::{self, Cursor, Read},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::info;
+
+use crate::network_manager::database_manager::DataBase;
+
+/// Written to `manifest.json`, checked on import.
+pub const FORMAT: &str = "rustcrab-history";
+pub const VERSION: u32 = 1;
+/// Largest archive accepted by the import endpoint.
+pub const MAX_ARCHIVE_BYTES: usize = 64 * 1024 * 1024;
+
+/// Table of contents of an archive.
+///
+/// The archive is a plain tar file holding `manifest.json` and one JSON
+/// lines file per conversation under `conversations/`.
+#[derive(Serialize, Deserialize)]
+pub struct Manifest {
+    pub format: String,
+    pub version: u32,
+    pub user: String,
+    /// Unix seconds.
+    pub exported_at: u64,
+    pub conversations: Vec<Conversation>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Conversation {
+    pub peer: String,
+    /// Path of the JSON lines file inside the archive.
+    pub file: String,
+    pub messages: usize,
+}
+
+/// One line of a conversation file.
+#[derive(Serialize, Deserialize)]
+pub struct ArchivedMessage {
+    pub id: i32,
+    pub from: String,
+    pub to: String,
+    pub content: String,
+    /// UTC, RFC 3339.
+    pub sent_at: String,
+    pub reply: Option<Reply>,
+    /// Content and quote are end-to-end encrypted envelopes.
+    pub encrypted: bool,
+    /// UTC, RFC 3339. Set for disappearing messages.
+    pub expires_at: Option<String>,
+}
+
+/// The quoted message, by author and content like everywhere else. Replies
+/// do not refer to message ids, so they stay intact when an import
+/// renumbers messages.
+#[derive(Serialize, Deserialize)]
+pub struct Reply {
+    pub user: String,
+    pub content: String,
+}
+
+/// Row shape expected by `DataBase::import_messages`.
+#[derive(Serialize)]
+struct ImportRow<'a> {
+    id: i32,
+    sender: &'a str,
+    receiver: &'a str,
+    content: &'a str,
+    sent_at: &'a str,
+    resp_msg: Option<&'a str>,
+    resp_user: Option<&'a str>,
+    encrypted: bool,
+    expires_at: Option<&'a str>,
+}
+
+/// What an import did, per message.
+#[derive(Serialize, Default, Debug)]
+pub struct ImportReport {
+    /// Owner of the archive.
+    pub user: String,
+    pub imported: i64,
+    /// Imported under a new id because theirs was taken.
+    pub renumbered: i64,
+    /// Already present on this server.
+    pub duplicates: i64,
+    /// Sender or receiver has no account here, or the message expired.
+    pub skipped: i64,
+}
+
+impl ImportReport {
+    /// Counts in `key=value` form, for the audit log.
+    pub fn detail(&self) -> String {
+        format!(
+            "imported={} renumbered={} duplicates={} skipped={}",
+            self.imported, self.renumbered, self.duplicates, self.skipped
+        )
+    }
+}
+
+#[derive(Debug)]
+pub enum ArchiveError {
+    Invalid(String),
+    Io(io::Error),
+    Database(tokio_postgres::Error),
+}
+
+impl fmt::Display for ArchiveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArchiveError::Invalid(reason) => write!(f, "invalid archive: {reason}"),
+            ArchiveError::Io(err) => write!(f, "{err}"),
+            ArchiveError::Database(err) => write!(f, "database error: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for ArchiveError {}
+
+impl From<io::Error> for ArchiveError {
+    fn from(err: io::Error) -> Self {
+        ArchiveError::Io(err)
+    }
+}
+
+impl From<tokio_postgres::Error> for ArchiveError {
+    fn from(err: tokio_postgres::Error) -> Self {
+        ArchiveError::Database(err)
+    }
+}
+
+impl From<serde_json::Error> for ArchiveError {
+    fn from(err: serde_json::Error) -> Self {
+        ArchiveError::Invalid(err.to_string())
+    }
+}
+
+/// File name for a conversation, unique within the archive even when two
+/// peers only differ in characters that are not safe in paths.
+fn conversation_file(index: usize, peer: &str) -> String {
+    let safe: String = peer
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!("conversations/{index:04}-{safe}.jsonl")
+}
+
+/// Builds the archive of everything `user` sent or received.
+pub async fn export(database: &DataBase, user: &str) -> Result<Vec<u8>, ArchiveError> {
+    let rows = database.export_messages(user).await?;
+    let mut conversations: Vec<(String, Vec<u8>, usize)> = Vec::new();
+    for row in &rows {
+        let from: String = row.get(1);
+        let to: String = row.get(2);
+        let peer = if from == user {
+            to.clone()
+        } else {
+            from.clone()
+        };
+        let reply = match (
+            row.get::<_, Option<String>>(5),
+            row.get::<_, Option<String>>(6),
+        ) {
+            (Some(content), Some(user)) => Some(Reply { user, content }),
+            _ => None,
+        };
+        let message = ArchivedMessage {
+            id: row.get(0),
+            from,
+            to,
+            content: row.get(3),
+            sent_at: row.get(4),
+            reply,
+            encrypted: row.get(7),
+            expires_at: row.get(8),
+        };
+        if conversations.last().is_none_or(|(p, _, _)| *p != peer) {
+            conversations.push((peer, Vec::new(), 0));
+        }
+        if let Some((_, lines, count)) = conversations.last_mut() {
+            serde_json::to_writer(&mut *lines, &message).map_err(io::Error::from)?;
+            lines.push(b'\n');
+            *count += 1;
+        }
+    }
+
+    let manifest = Manifest {
+        format: FORMAT.to_string(),
+        version: VERSION,
+        user: user.to_string(),
+        exported_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default(),
+        conversations: conversations
+            .iter()
+            .enumerate()
+            .map(|(index, (peer, _, count))| Conversation {
+                peer: peer.clone(),
+                file: conversation_file(index, peer),
+                messages: *count,
+            })
+            .collect(),
+    };
+
+    let mut builder = tar::Builder::new(Vec::new());
+    append(
+        &mut builder,
+        "manifest.json",
+        &serde_json::to_vec_pretty(&manifest).map_err(io::Error::from)?,
+        manifest.exported_at,
+    )?;
+    for (entry, (_, lines, _)) in manifest.conversations.iter().zip(&conversations) {
+        append(&mut builder, &entry.file, lines, manifest.exported_at)?;
+    }
+    let archive = builder.into_inner()?;
+    info!(%user, messages = rows.len(), conversations = conversations.len(), "History exported");
+    Ok(archive)
+}
+
+fn append(
+    builder: &mut tar::Builder<Vec<u8>>,
+    path: &str,
+    data: &[u8],
+    mtime: u64,
+) -> io::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(mtime);
+    header.set_cksum();
+    builder.append_data(&mut header, path, data)
+}
+
+/// Reads and checks an archive without touching the database.
+pub fn read(archive: &[u8]) -> Result<(Manifest, Vec<ArchivedMessage>), ArchiveError> {
+    let invalid = |err: io::Error| ArchiveError::Invalid(err.to_string());
+    let mut files: HashMap<String, Vec<u8>> = HashMap::new();
+    let mut reader = tar::Archive::new(Cursor::new(archive));
+    for entry in reader.entries().map_err(invalid)? {
+        let mut entry = entry.map_err(invalid)?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let path = entry
+            .path()
+            .map_err(invalid)?
+            .to_string_lossy()
+            .into_owned();
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data).map_err(invalid)?;
+        files.insert(path, data);
+    }
+
+    let manifest: Manifest = match files.get("manifest.json") {
+        Some(data) => serde_json::from_slice(data)?,
+        None => {
+            return Err(ArchiveError::Invalid(
+                "manifest.json is missing".to_string(),
+            ));
+        }
+    };
+    if manifest.format != FORMAT || manifest.version != VERSION {
+        return Err(ArchiveError::Invalid(format!(
+            "unsupported format {} version {}",
+            manifest.format, manifest.version
+        )));
+    }
+
+    let mut messages = Vec::new();
+    let mut ids = HashSet::new();
+    for conversation in &manifest.conversations {
+        let Some(data) = files.get(&conversation.file) else {
+            return Err(ArchiveError::Invalid(format!(
+                "{} is missing",
+                conversation.file
+            )));
+        };
+        for line in data.split(|b| *b == b'\n').filter(|l| !l.is_empty()) {
+            let message: ArchivedMessage = serde_json::from_slice(line)?;
+            let peer = if message.from == manifest.user {
+                &message.to
+            } else {
+                &message.from
+            };
+            if (message.from != manifest.user && message.to != manifest.user)
+                || *peer != conversation.peer
+            {
+                return Err(ArchiveError::Invalid(format!(
+                    "message {} does not belong to {}",
+                    message.id, conversation.file
+                )));
+            }
+            if !ids.insert(message.id) {
+                return Err(ArchiveError::Invalid(format!(
+                    "message id {} appears twice",
+                    message.id
+                )));
+            }
+            messages.push(message);
+        }
+    }
+    Ok((manifest, messages))
+}
+
+/// Loads an archive into the database, keeping message ids where possible.
+pub async fn import(database: &DataBase, archive: &[u8]) -> Result<ImportReport, ArchiveError> {
+    let (manifest, messages) = read(archive)?;
+    let mut report = ImportReport {
+        user: manifest.user.clone(),
+        ..Default::default()
+    };
+    let Some(max_id) = messages.iter().map(|m| m.id).max() else {
+        return Ok(report);
+    };
+    database.reserve_message_ids(max_id).await?;
+
+    let rows: Vec<ImportRow> = messages
+        .iter()
+        .map(|m| ImportRow {
+            id: m.id,
+            sender: &m.from,
+            receiver: &m.to,
+            content: &m.content,
+            sent_at: &m.sent_at,
+            resp_msg: m.reply.as_ref().map(|r| r.content.as_str()),
+            resp_user: m.reply.as_ref().map(|r| r.user.as_str()),
+            encrypted: m.encrypted,
+            expires_at: m.expires_at.as_deref(),
+        })
+        .collect();
+    let row = database
+        .import_messages(&serde_json::to_string(&rows)?)
+        .await?;
+    let (total, known, kept, renumbered): (i64, i64, i64, i64) =
+        (row.get(0), row.get(1), row.get(2), row.get(3));
+    report.imported = kept + renumbered;
+    report.renumbered = renumbered;
+    report.duplicates = known - kept - renumbered;
+    report.skipped = total - known;
+    info!(
+        user = %report.user,
+        imported = report.imported,
+        renumbered = report.renumbered,
+        duplicates = report.duplicates,
+        skipped = report.skipped,
+        "History imported"
+    );
+    Ok(report)
+}
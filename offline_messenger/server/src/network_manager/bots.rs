@@ -0,0 +1,171 @@
+use axum::{
+    Json,
+    extract::{ConnectInfo, State},
+    http::{HeaderMap, StatusCode, header},
+    response::IntoResponse,
+};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::{net::SocketAddr, sync::Arc};
+use tracing::{Span, error, info, warn};
+use uuid::Uuid;
+
+use crate::network_manager::{
+    handlers::ErrorCode,
+    lockout::{self, AuthOutcome},
+    logging::session_tag,
+    metrics::METRICS,
+    moderation::SanctionKind,
+    server::AppState,
+};
+
+/// A new API token for a bot. Shown once, only its hash is stored.
+pub fn new_token() -> String {
+    format!("bot_{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple())
+}
+
+/// What `users.bot_token_hash` holds for `token`.
+pub fn token_hash(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Bot accounts are ordinary users that log in with an API token instead
+/// of a password. The session token they get works on `/ws` like any other.
+pub struct BotHandlers {}
+impl BotHandlers {
+    /// `POST /bot/login` with `Authorization: Bearer <API token>`.
+    pub async fn login(
+        State(app_state): State<Arc<AppState>>,
+        ConnectInfo(peer): ConnectInfo<SocketAddr>,
+        headers: HeaderMap,
+    ) -> axum::response::Response {
+        let ip = peer.ip().to_string();
+        let refuse = |status: StatusCode, message: &str, code: ErrorCode| {
+            (
+                status,
+                Json(json!({
+                    "succes": false,
+                    "token": "".to_string(),
+                    "message": message,
+                    "code": code,
+                })),
+            )
+                .into_response()
+        };
+        let Some(token) = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+        else {
+            return refuse(
+                StatusCode::UNAUTHORIZED,
+                "Send the API token as a bearer token.",
+                ErrorCode::InvalidCredentials,
+            );
+        };
+        // Unknown tokens count against the address only.
+        let database = &app_state.database;
+        match lockout::check(database, &app_state.config, "", &ip).await {
+            Ok(None) => {}
+            Ok(Some(retry_after)) => {
+                METRICS.login(false);
+                warn!(%ip, retry_after, "Bot login refused, locked out");
+                lockout::record(database, "", &ip, AuthOutcome::LockedOut).await;
+                return (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    [(header::RETRY_AFTER, retry_after.to_string())],
+                    Json(json!({
+                        "succes": false,
+                        "token": "".to_string(),
+                        "message": format!(
+                            "Too many failed logins, try again in {retry_after} seconds."
+                        ),
+                        "code": ErrorCode::TooManyAttempts,
+                        "retry_after": retry_after,
+                    })),
+                )
+                    .into_response();
+            }
+            Err(err) => {
+                error!("Error while checking failed logins: {err}");
+                return refuse(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Internal server error",
+                    ErrorCode::Internal,
+                );
+            }
+        }
+        let bot = match app_state.database.bot_by_token(&token_hash(token)).await {
+            Ok(bot) => bot,
+            Err(err) => {
+                error!("Error during bot login: {err}");
+                lockout::refund(database, &app_state.config, "", &ip).await;
+                return refuse(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Internal server error",
+                    ErrorCode::Internal,
+                );
+            }
+        };
+        let Some((username, disabled)) = bot else {
+            METRICS.login(false);
+            warn!(%ip, "Bot login with an unknown token");
+            lockout::record(database, "", &ip, AuthOutcome::InvalidCredentials).await;
+            return refuse(
+                StatusCode::UNAUTHORIZED,
+                "Invalid API token.",
+                ErrorCode::InvalidCredentials,
+            );
+        };
+        Span::current().record("user", username.as_str());
+        let ban = match app_state
+            .database
+            .active_sanction(&username, SanctionKind::Ban)
+            .await
+        {
+            Ok(ban) => ban,
+            Err(err) => {
+                error!("Error during bot login: {err}");
+                lockout::refund(database, &app_state.config, "", &ip).await;
+                return refuse(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Internal server error",
+                    ErrorCode::Internal,
+                );
+            }
+        };
+        let outcome = match (disabled, &ban) {
+            (true, _) => AuthOutcome::Disabled,
+            (_, Some(_)) => AuthOutcome::Banned,
+            _ => AuthOutcome::Success,
+        };
+        lockout::record(database, &username, &ip, outcome).await;
+        lockout::refund(database, &app_state.config, "", &ip).await;
+        METRICS.login(outcome == AuthOutcome::Success);
+        if disabled {
+            return refuse(
+                StatusCode::UNAUTHORIZED,
+                "This account is disabled.",
+                ErrorCode::Disabled,
+            );
+        }
+        if let Some(ban) = ban {
+            return refuse(StatusCode::FORBIDDEN, &ban.describe(), ban.code());
+        }
+        let token = app_state.session_manager.new_session(&username);
+        info!(session = session_tag(&token), "Bot logged in");
+        (
+            StatusCode::OK,
+            Json(json!({
+                "succes": true,
+                "token": token,
+                "username": username,
+                "message": "Logged in with succes!",
+            })),
+        )
+            .into_response()
+    }
+}
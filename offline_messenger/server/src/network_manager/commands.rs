@@ -0,0 +1,308 @@
+use futures_util::future::BoxFuture;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio_postgres::Row;
+use tracing::{error, info};
+
+use crate::network_manager::{
+    handlers::{ErrorCode, Handlers, ScheduledMessage},
+    server::AppState,
+};
+
+/// Output shown to the user, or why the command failed.
+pub type CommandResult = Result<String, (ErrorCode, String)>;
+
+/// A command as typed: `/name args`.
+pub struct Invocation<'a> {
+    pub user: &'a str,
+    /// Whom the message was addressed to.
+    pub to: &'a str,
+    pub args: &'a str,
+}
+
+type Handler = for<'a> fn(&'a AppState, Invocation<'a>) -> BoxFuture<'a, CommandResult>;
+
+pub struct Command {
+    pub name: &'static str,
+    pub usage: &'static str,
+    pub summary: &'static str,
+    handler: Handler,
+}
+
+impl Command {
+    pub fn new(
+        name: &'static str,
+        usage: &'static str,
+        summary: &'static str,
+        handler: Handler,
+    ) -> Self {
+        Self {
+            name,
+            usage,
+            summary,
+            handler,
+        }
+    }
+}
+
+/// What to do with a `SendMessage`.
+pub enum Dispatch {
+    /// Store and deliver this text.
+    Send(String),
+    /// It was a command, this is its result.
+    Ran(CommandResult),
+}
+
+/// Commands the server answers itself when a message starts with `/`.
+/// Messages to bot accounts are passed on instead, bots answer their own
+/// commands. A leading `//` sends a literal `/`.
+pub struct Commands {
+    commands: Vec<Command>,
+}
+
+impl Commands {
+    pub fn empty() -> Self {
+        Self {
+            commands: Vec::new(),
+        }
+    }
+
+    pub fn builtin() -> Self {
+        let mut commands = Commands::empty();
+        commands.register(Command::new("help", "/help", "List the commands", help));
+        commands.register(Command::new("who", "/who", "Show who is online", who));
+        commands.register(Command::new(
+            "remind",
+            "/remind <10m|2h|1d...> <text>",
+            "Send yourself a message later",
+            remind,
+        ));
+        commands
+    }
+
+    /// Adds a command, replacing one of the same name.
+    pub fn register(&mut self, command: Command) {
+        self.commands.retain(|c| c.name != command.name);
+        self.commands.push(command);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Command> {
+        self.commands.iter().find(|c| c.name == name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Command> {
+        self.commands.iter()
+    }
+
+    /// Runs the command in `message`, if it is one. A command sent again
+    /// under the same `client_id` is not run twice, the retry gets the first
+    /// result.
+    pub async fn dispatch(
+        &self,
+        app_state: &AppState,
+        user: &str,
+        to: &str,
+        message: String,
+        encrypted: bool,
+        client_id: Option<&str>,
+    ) -> Dispatch {
+        if encrypted {
+            return Dispatch::Send(message);
+        }
+        if let Some(literal) = message.strip_prefix("//") {
+            return Dispatch::Send(format!("/{literal}"));
+        }
+        let Some(command) = message.strip_prefix('/') else {
+            return Dispatch::Send(message);
+        };
+        match app_state.database.is_bot(to).await {
+            Ok(true) => return Dispatch::Send(message),
+            Ok(false) => {}
+            Err(err) => {
+                error!("Error while looking up the receiver: {err}");
+                return Dispatch::Ran(Err((
+                    ErrorCode::Internal,
+                    "Internal server error".to_string(),
+                )));
+            }
+        }
+        let (name, args) = command
+            .split_once(char::is_whitespace)
+            .unwrap_or((command, ""));
+        let Some(command) = self.get(name) else {
+            return Dispatch::Ran(Err((
+                ErrorCode::UnknownCommand,
+                format!(
+                    "Unknown command /{name}, try /help. Start with // to send a message beginning with /."
+                ),
+            )));
+        };
+        if let Some(client_id) = client_id {
+            match app_state.database.claim_command(user, client_id).await {
+                Ok(None) => {}
+                Ok(Some(earlier)) => {
+                    info!(
+                        command = command.name,
+                        "Repeated command id, not running it again"
+                    );
+                    return Dispatch::Ran(replay(&earlier));
+                }
+                Err(err) => {
+                    error!("Error while claiming the command: {err}");
+                    return Dispatch::Ran(Err((
+                        ErrorCode::Internal,
+                        "Internal server error".to_string(),
+                    )));
+                }
+            }
+        }
+        info!(command = command.name, "Running command");
+        let invocation = Invocation {
+            user,
+            to,
+            args: args.trim(),
+        };
+        let result = (command.handler)(app_state, invocation).await;
+        if let Some(client_id) = client_id {
+            let recorded = match &result {
+                // Nothing was done, so a retry may try again.
+                Err((ErrorCode::Internal, _)) => {
+                    app_state.database.forget_command(user, client_id).await
+                }
+                Ok(text) => {
+                    app_state
+                        .database
+                        .finish_command(user, client_id, true, None, text)
+                        .await
+                }
+                Err((code, message)) => {
+                    let code = serde_json::to_value(code).ok();
+                    app_state
+                        .database
+                        .finish_command(
+                            user,
+                            client_id,
+                            false,
+                            code.as_ref().and_then(|code| code.as_str()),
+                            message,
+                        )
+                        .await
+                }
+            };
+            if let Err(err) = recorded {
+                error!("Error while storing the command result: {err}");
+            }
+        }
+        Dispatch::Ran(result)
+    }
+}
+
+/// The stored `(succes, code, output)` of an earlier run of a command.
+fn replay(earlier: &Row) -> CommandResult {
+    let succes: Option<bool> = earlier.get(0);
+    let code: Option<String> = earlier.get(1);
+    let output: Option<String> = earlier.get(2);
+    match (succes, output) {
+        (Some(true), Some(text)) => Ok(text),
+        (Some(false), Some(message)) => {
+            let code = code
+                .and_then(|code| serde_json::from_value(serde_json::Value::String(code)).ok())
+                .unwrap_or(ErrorCode::InvalidRequest);
+            Err((code, message))
+        }
+        _ => Err((
+            ErrorCode::InvalidRequest,
+            "The command is still running".to_string(),
+        )),
+    }
+}
+
+fn help<'a>(app_state: &'a AppState, _: Invocation<'a>) -> BoxFuture<'a, CommandResult> {
+    Box::pin(async move {
+        let width = app_state
+            .commands
+            .iter()
+            .map(|c| c.usage.len())
+            .max()
+            .unwrap_or_default();
+        Ok(app_state
+            .commands
+            .iter()
+            .map(|c| format!("{:<width$}  {}", c.usage, c.summary))
+            .collect::<Vec<_>>()
+            .join("\n"))
+    })
+}
+
+/// Users with a live session on any instance.
+fn who<'a>(app_state: &'a AppState, _: Invocation<'a>) -> BoxFuture<'a, CommandResult> {
+    Box::pin(async move {
+        let users = app_state.online_users().await.map_err(|err| {
+            error!("Error while loading who is online: {err}");
+            (ErrorCode::Internal, "Internal server error".to_string())
+        })?;
+        Ok(format!("Online: {}", users.join(", ")))
+    })
+}
+
+fn remind<'a>(app_state: &'a AppState, invocation: Invocation<'a>) -> BoxFuture<'a, CommandResult> {
+    Box::pin(async move {
+        let usage = || {
+            (
+                ErrorCode::InvalidRequest,
+                "Usage: /remind <10m|2h|1d...> <text>".to_string(),
+            )
+        };
+        let (delay, text) = invocation.args.split_once(' ').ok_or_else(usage)?;
+        let delay = parse_duration(delay).ok_or_else(usage)?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or_default();
+        let message = ScheduledMessage {
+            to: invocation.user.to_string(),
+            message: format!("Reminder: {}", text.trim()),
+            resp_msg: None,
+            resp_user: None,
+            encrypted: false,
+            deliver_at: now.saturating_add(delay),
+        };
+        Handlers::schedule(app_state, invocation.user, message).await?;
+        Ok(format!("I will remind you in {}", format_duration(delay)))
+    })
+}
+
+/// Seconds in a duration such as `90s`, `10m`, `1h30m` or `2d`.
+pub fn parse_duration(text: &str) -> Option<i64> {
+    let mut total: i64 = 0;
+    let mut number = String::new();
+    for c in text.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86_400,
+            _ => return None,
+        };
+        let n: i64 = number.parse().ok()?;
+        total = total.checked_add(n.checked_mul(unit)?)?;
+        number.clear();
+    }
+    (number.is_empty() && total > 0).then_some(total)
+}
+
+fn format_duration(secs: i64) -> String {
+    let parts = [(86_400, "d"), (3600, "h"), (60, "m"), (1, "s")];
+    let mut rest = secs;
+    let mut text = String::new();
+    for (unit, suffix) in parts {
+        if rest >= unit {
+            text.push_str(&format!("{}{suffix}", rest / unit));
+            rest %= unit;
+        }
+    }
+    text
+}
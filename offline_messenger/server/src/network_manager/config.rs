@@ -0,0 +1,440 @@
+use std::{env, net::SocketAddr, path::PathBuf, time::Duration};
+use tracing::warn;
+
+/// What to do with a live notification when the receiving session's outbound
+/// queue is already full.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SlowConsumerPolicy {
+    /// Remove the session from `AppState.map` and close its socket.
+    Disconnect,
+    /// Park the notification in the session's overflow store, which is
+    /// written to the socket once the queue drains. The session is
+    /// disconnected only when the store holds `spill_capacity` notifications.
+    Spill,
+}
+
+impl SlowConsumerPolicy {
+    fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "disconnect" => Some(SlowConsumerPolicy::Disconnect),
+            "spill" => Some(SlowConsumerPolicy::Spill),
+            _ => None,
+        }
+    }
+}
+
+/// How strictly client certificates are enforced when `client_ca` is set.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ClientAuth {
+    /// Certificates signed by the CA are checked and used when presented.
+    /// Clients without one log in with their password.
+    Optional,
+    /// The TLS handshake fails without a certificate signed by the CA.
+    Required,
+}
+
+impl ClientAuth {
+    fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "optional" => Some(ClientAuth::Optional),
+            "required" => Some(ClientAuth::Required),
+            _ => None,
+        }
+    }
+}
+
+/// How live events reach sessions connected to other server instances.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FanoutBackend {
+    /// In-process only, for a single instance.
+    Local,
+    /// Postgres LISTEN/NOTIFY, shared by every instance on the database.
+    Postgres,
+}
+
+impl FanoutBackend {
+    fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "local" => Some(FanoutBackend::Local),
+            "postgres" => Some(FanoutBackend::Postgres),
+            _ => None,
+        }
+    }
+}
+
+pub struct ServerConfig {
+    pub database_url: String,
+    /// Address of the HTTPS and WebSocket listener.
+    pub listen_addr: SocketAddr,
+    /// PEM certificate chain and private key of the HTTPS listener.
+    pub tls_cert: PathBuf,
+    pub tls_key: PathBuf,
+    /// Names and addresses put in the certificate generated on first start,
+    /// when neither `tls_cert` nor `tls_key` exists.
+    pub tls_sans: Vec<String>,
+    /// How often the certificate files are checked for changes. `None`
+    /// leaves reloading to SIGHUP.
+    pub tls_watch_interval: Option<Duration>,
+    /// PEM bundle of the CA that signs client certificates. `None` turns
+    /// mutual TLS off.
+    pub client_ca: Option<PathBuf>,
+    pub client_auth: ClientAuth,
+    pub fanout: FanoutBackend,
+    /// Events each fan-out bus queue holds before new ones are dropped.
+    pub fanout_capacity: usize,
+    pub queue_capacity: usize,
+    pub slow_consumer_policy: SlowConsumerPolicy,
+    /// Notifications a session may keep in its overflow store under
+    /// `SlowConsumerPolicy::Spill`.
+    pub spill_capacity: usize,
+    /// Log message bodies at `trace` level. Off unless explicitly enabled.
+    pub log_message_content: bool,
+    /// Plain HTTP listener for `/metrics` and other operator endpoints.
+    /// `None` disables it.
+    pub admin_addr: Option<SocketAddr>,
+    /// How long a shutdown may take to flush sessions before the remaining
+    /// connections are dropped.
+    pub shutdown_deadline: Duration,
+    /// Delay suggested to clients in the `ServerShutdown` event.
+    pub reconnect_after_ms: u64,
+    /// How long a session token stays valid after its last WebSocket
+    /// closed, so the client can reconnect with it and `Sync`.
+    pub session_grace: Duration,
+    /// How often expired messages are deleted.
+    pub expiry_sweep_interval: Duration,
+    /// How often the scheduler looks for scheduled messages that are due.
+    pub scheduler_interval: Duration,
+    /// Length bounds for new usernames, in characters after normalization.
+    pub username_min_chars: usize,
+    pub username_max_chars: usize,
+    /// Names nobody can sign up with, compared case-insensitively.
+    pub reserved_usernames: Vec<String>,
+    /// Largest message body accepted, in bytes. For end-to-end encrypted
+    /// messages this is the size of the envelope.
+    pub max_message_bytes: usize,
+    /// Failed logins after which a username, or a source address, is locked
+    /// out for `login_lockout`. 0 turns the lockout off.
+    pub login_lockout_threshold: u32,
+    pub login_ip_lockout_threshold: u32,
+    /// How long a lockout lasts. Failures older than this are forgotten.
+    pub login_lockout: Duration,
+    /// How long a login must wait after one failure, doubled for each
+    /// further failure up to `login_backoff_max`. Earlier attempts are
+    /// refused with a retry-after.
+    pub login_backoff_base: Duration,
+    pub login_backoff_max: Duration,
+    /// Endpoints every webhook event is POSTed to. Empty turns webhooks off.
+    pub webhook_urls: Vec<String>,
+    /// Key of the HMAC-SHA256 signature sent with every webhook. Webhooks
+    /// stay off without one.
+    pub webhook_secret: Option<String>,
+    /// Events sent, e.g. `message.sent`. Empty means all of them.
+    pub webhook_events: Vec<String>,
+    /// Attempts before a delivery is given up on.
+    pub webhook_max_attempts: u32,
+    /// Delay before the first retry, doubled for each further one.
+    pub webhook_backoff_base: Duration,
+    pub webhook_timeout: Duration,
+    /// How often the delivery queue is checked.
+    pub webhook_interval: Duration,
+}
+
+impl ServerConfig {
+    pub fn from_env() -> Self {
+        let mut config = ServerConfig::default();
+        if let Ok(v) = env::var("MESSENGER_DATABASE_URL") {
+            config.database_url = v;
+        }
+        if let Ok(v) = env::var("MESSENGER_LISTEN_ADDR") {
+            match v.parse::<SocketAddr>() {
+                Ok(addr) => config.listen_addr = addr,
+                Err(_) => warn!("Ignoring invalid MESSENGER_LISTEN_ADDR: {v}"),
+            }
+        }
+        if let Ok(v) = env::var("MESSENGER_TLS_CERT") {
+            config.tls_cert = PathBuf::from(v);
+        }
+        if let Ok(v) = env::var("MESSENGER_TLS_KEY") {
+            config.tls_key = PathBuf::from(v);
+        }
+        if let Ok(v) = env::var("MESSENGER_TLS_SANS") {
+            let sans = list(&v);
+            if sans.is_empty() {
+                warn!("Ignoring empty MESSENGER_TLS_SANS");
+            } else {
+                config.tls_sans = sans;
+            }
+        }
+        if let Ok(v) = env::var("MESSENGER_TLS_WATCH_SECS") {
+            match v.parse::<u64>() {
+                Ok(0) => config.tls_watch_interval = None,
+                Ok(n) => config.tls_watch_interval = Some(Duration::from_secs(n)),
+                Err(_) if v.eq_ignore_ascii_case("off") => config.tls_watch_interval = None,
+                Err(_) => warn!("Ignoring invalid MESSENGER_TLS_WATCH_SECS: {v}"),
+            }
+        }
+        if let Ok(v) = env::var("MESSENGER_CLIENT_CA") {
+            config.client_ca = Some(PathBuf::from(v));
+        }
+        if let Ok(v) = env::var("MESSENGER_CLIENT_AUTH") {
+            match ClientAuth::parse(&v) {
+                Some(a) => config.client_auth = a,
+                None => warn!("Ignoring invalid MESSENGER_CLIENT_AUTH: {v}"),
+            }
+        }
+        if let Ok(v) = env::var("MESSENGER_FANOUT") {
+            match FanoutBackend::parse(&v) {
+                Some(b) => config.fanout = b,
+                None => warn!("Ignoring invalid MESSENGER_FANOUT: {v}"),
+            }
+        }
+        if let Ok(v) = env::var("MESSENGER_FANOUT_CAPACITY") {
+            match v.parse::<usize>() {
+                Ok(n) if n > 0 => config.fanout_capacity = n,
+                _ => warn!("Ignoring invalid MESSENGER_FANOUT_CAPACITY: {v}"),
+            }
+        }
+        if let Ok(v) = env::var("MESSENGER_QUEUE_CAPACITY") {
+            match v.parse::<usize>() {
+                Ok(n) if n > 0 => config.queue_capacity = n,
+                _ => warn!("Ignoring invalid MESSENGER_QUEUE_CAPACITY: {v}"),
+            }
+        }
+        if let Ok(v) = env::var("MESSENGER_SLOW_CONSUMER_POLICY") {
+            match SlowConsumerPolicy::parse(&v) {
+                Some(p) => config.slow_consumer_policy = p,
+                None => warn!("Ignoring invalid MESSENGER_SLOW_CONSUMER_POLICY: {v}"),
+            }
+        }
+        if let Ok(v) = env::var("MESSENGER_SPILL_CAPACITY") {
+            match v.parse::<usize>() {
+                Ok(n) if n > 0 => config.spill_capacity = n,
+                _ => warn!("Ignoring invalid MESSENGER_SPILL_CAPACITY: {v}"),
+            }
+        }
+        if let Ok(v) = env::var("MESSENGER_LOG_MESSAGE_CONTENT") {
+            config.log_message_content = v == "1" || v.eq_ignore_ascii_case("true");
+        }
+        if let Ok(v) = env::var("MESSENGER_ADMIN_ADDR") {
+            if v.eq_ignore_ascii_case("off") {
+                config.admin_addr = None;
+            } else {
+                match v.parse::<SocketAddr>() {
+                    Ok(addr) => config.admin_addr = Some(addr),
+                    Err(_) => warn!("Ignoring invalid MESSENGER_ADMIN_ADDR: {v}"),
+                }
+            }
+        }
+        if let Ok(v) = env::var("MESSENGER_SHUTDOWN_DEADLINE_SECS") {
+            match v.parse::<u64>() {
+                Ok(n) => config.shutdown_deadline = Duration::from_secs(n),
+                Err(_) => warn!("Ignoring invalid MESSENGER_SHUTDOWN_DEADLINE_SECS: {v}"),
+            }
+        }
+        if let Ok(v) = env::var("MESSENGER_RECONNECT_AFTER_MS") {
+            match v.parse::<u64>() {
+                Ok(n) => config.reconnect_after_ms = n,
+                Err(_) => warn!("Ignoring invalid MESSENGER_RECONNECT_AFTER_MS: {v}"),
+            }
+        }
+        if let Ok(v) = env::var("MESSENGER_SESSION_GRACE_SECS") {
+            match v.parse::<u64>() {
+                Ok(n) => config.session_grace = Duration::from_secs(n),
+                Err(_) => warn!("Ignoring invalid MESSENGER_SESSION_GRACE_SECS: {v}"),
+            }
+        }
+        if let Ok(v) = env::var("MESSENGER_EXPIRY_SWEEP_SECS") {
+            match v.parse::<u64>() {
+                Ok(n) if n > 0 => config.expiry_sweep_interval = Duration::from_secs(n),
+                _ => warn!("Ignoring invalid MESSENGER_EXPIRY_SWEEP_SECS: {v}"),
+            }
+        }
+        if let Ok(v) = env::var("MESSENGER_SCHEDULER_INTERVAL_MS") {
+            match v.parse::<u64>() {
+                Ok(n) if n > 0 => config.scheduler_interval = Duration::from_millis(n),
+                _ => warn!("Ignoring invalid MESSENGER_SCHEDULER_INTERVAL_MS: {v}"),
+            }
+        }
+        if let Ok(v) = env::var("MESSENGER_USERNAME_MIN_CHARS") {
+            match v.parse::<usize>() {
+                Ok(n) if n > 0 => config.username_min_chars = n,
+                _ => warn!("Ignoring invalid MESSENGER_USERNAME_MIN_CHARS: {v}"),
+            }
+        }
+        if let Ok(v) = env::var("MESSENGER_USERNAME_MAX_CHARS") {
+            match v.parse::<usize>() {
+                Ok(n) if n > 0 => config.username_max_chars = n,
+                _ => warn!("Ignoring invalid MESSENGER_USERNAME_MAX_CHARS: {v}"),
+            }
+        }
+        if config.username_min_chars > config.username_max_chars {
+            warn!(
+                min = config.username_min_chars,
+                max = config.username_max_chars,
+                "Username length bounds are inverted, using the defaults"
+            );
+            let defaults = ServerConfig::default();
+            config.username_min_chars = defaults.username_min_chars;
+            config.username_max_chars = defaults.username_max_chars;
+        }
+        if let Ok(v) = env::var("MESSENGER_RESERVED_USERNAMES") {
+            config.reserved_usernames = list(&v);
+        }
+        if let Ok(v) = env::var("MESSENGER_MAX_MESSAGE_BYTES") {
+            match v.parse::<usize>() {
+                Ok(n) if n > 0 => config.max_message_bytes = n,
+                _ => warn!("Ignoring invalid MESSENGER_MAX_MESSAGE_BYTES: {v}"),
+            }
+        }
+        if let Ok(v) = env::var("MESSENGER_LOGIN_LOCKOUT_THRESHOLD") {
+            match v.parse::<u32>() {
+                Ok(n) => config.login_lockout_threshold = n,
+                Err(_) => warn!("Ignoring invalid MESSENGER_LOGIN_LOCKOUT_THRESHOLD: {v}"),
+            }
+        }
+        if let Ok(v) = env::var("MESSENGER_LOGIN_IP_LOCKOUT_THRESHOLD") {
+            match v.parse::<u32>() {
+                Ok(n) => config.login_ip_lockout_threshold = n,
+                Err(_) => warn!("Ignoring invalid MESSENGER_LOGIN_IP_LOCKOUT_THRESHOLD: {v}"),
+            }
+        }
+        if let Ok(v) = env::var("MESSENGER_LOGIN_LOCKOUT_SECS") {
+            match v.parse::<u64>() {
+                Ok(n) if n > 0 => config.login_lockout = Duration::from_secs(n),
+                _ => warn!("Ignoring invalid MESSENGER_LOGIN_LOCKOUT_SECS: {v}"),
+            }
+        }
+        if let Ok(v) = env::var("MESSENGER_LOGIN_BACKOFF_MS") {
+            match v.parse::<u64>() {
+                Ok(n) => config.login_backoff_base = Duration::from_millis(n),
+                Err(_) => warn!("Ignoring invalid MESSENGER_LOGIN_BACKOFF_MS: {v}"),
+            }
+        }
+        if let Ok(v) = env::var("MESSENGER_LOGIN_BACKOFF_MAX_MS") {
+            match v.parse::<u64>() {
+                Ok(n) => config.login_backoff_max = Duration::from_millis(n),
+                Err(_) => warn!("Ignoring invalid MESSENGER_LOGIN_BACKOFF_MAX_MS: {v}"),
+            }
+        }
+        if let Ok(v) = env::var("MESSENGER_WEBHOOK_URLS") {
+            config.webhook_urls = list(&v);
+        }
+        if let Ok(v) = env::var("MESSENGER_WEBHOOK_SECRET")
+            && !v.is_empty()
+        {
+            config.webhook_secret = Some(v);
+        }
+        if let Ok(v) = env::var("MESSENGER_WEBHOOK_EVENTS") {
+            config.webhook_events = list(&v);
+        }
+        if let Ok(v) = env::var("MESSENGER_WEBHOOK_MAX_ATTEMPTS") {
+            match v.parse::<u32>() {
+                Ok(n) if n > 0 => config.webhook_max_attempts = n,
+                _ => warn!("Ignoring invalid MESSENGER_WEBHOOK_MAX_ATTEMPTS: {v}"),
+            }
+        }
+        if let Ok(v) = env::var("MESSENGER_WEBHOOK_BACKOFF_MS") {
+            match v.parse::<u64>() {
+                Ok(n) => config.webhook_backoff_base = Duration::from_millis(n),
+                Err(_) => warn!("Ignoring invalid MESSENGER_WEBHOOK_BACKOFF_MS: {v}"),
+            }
+        }
+        if let Ok(v) = env::var("MESSENGER_WEBHOOK_TIMEOUT_SECS") {
+            match v.parse::<u64>() {
+                Ok(n) if n > 0 => config.webhook_timeout = Duration::from_secs(n),
+                _ => warn!("Ignoring invalid MESSENGER_WEBHOOK_TIMEOUT_SECS: {v}"),
+            }
+        }
+        if let Ok(v) = env::var("MESSENGER_WEBHOOK_INTERVAL_MS") {
+            match v.parse::<u64>() {
+                Ok(n) if n > 0 => config.webhook_interval = Duration::from_millis(n),
+                _ => warn!("Ignoring invalid MESSENGER_WEBHOOK_INTERVAL_MS: {v}"),
+            }
+        }
+        if !config.webhook_urls.is_empty() && config.webhook_secret.is_none() {
+            warn!(
+                "MESSENGER_WEBHOOK_URLS is set without MESSENGER_WEBHOOK_SECRET, webhooks are off"
+            );
+            config.webhook_urls.clear();
+        }
+        config
+    }
+
+    /// Webhooks are sent only to configured URLs and only when they can be
+    /// signed.
+    pub fn webhooks_enabled(&self) -> bool {
+        !self.webhook_urls.is_empty() && self.webhook_secret.is_some()
+    }
+
+    /// Size of the overflow store given to each new session.
+    pub fn session_spill_capacity(&self) -> usize {
+        match self.slow_consumer_policy {
+            SlowConsumerPolicy::Disconnect => 0,
+            SlowConsumerPolicy::Spill => self.spill_capacity,
+        }
+    }
+}
+
+/// Non-empty entries of a comma separated list.
+fn list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            database_url: "host=localhost user=postgres password=mysecretpassword dbname=postgres"
+                .to_string(),
+            listen_addr: SocketAddr::from(([127, 0, 0, 1], 3000)),
+            tls_cert: PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("certs/server.crt"),
+            tls_key: PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("certs/server.key"),
+            tls_sans: vec!["localhost".to_string(), "127.0.0.1".to_string()],
+            tls_watch_interval: Some(Duration::from_secs(30)),
+            client_ca: None,
+            client_auth: ClientAuth::Optional,
+            fanout: FanoutBackend::Local,
+            fanout_capacity: 8192,
+            queue_capacity: 256,
+            slow_consumer_policy: SlowConsumerPolicy::Disconnect,
+            spill_capacity: 4096,
+            log_message_content: false,
+            admin_addr: Some(SocketAddr::from(([127, 0, 0, 1], 9090))),
+            shutdown_deadline: Duration::from_secs(10),
+            reconnect_after_ms: 5000,
+            session_grace: Duration::from_secs(120),
+            expiry_sweep_interval: Duration::from_secs(5),
+            scheduler_interval: Duration::from_secs(1),
+            username_min_chars: 3,
+            username_max_chars: 32,
+            reserved_usernames: [
+                "admin",
+                "administrator",
+                "moderator",
+                "root",
+                "server",
+                "support",
+                "system",
+            ]
+            .map(str::to_string)
+            .to_vec(),
+            max_message_bytes: 16 * 1024,
+            login_lockout_threshold: 5,
+            login_ip_lockout_threshold: 20,
+            login_lockout: Duration::from_secs(15 * 60),
+            login_backoff_base: Duration::from_millis(250),
+            login_backoff_max: Duration::from_secs(8),
+            webhook_urls: Vec::new(),
+            webhook_secret: None,
+            webhook_events: Vec::new(),
+            webhook_max_attempts: 8,
+            webhook_backoff_base: Duration::from_secs(10),
+            webhook_timeout: Duration::from_secs(10),
+            webhook_interval: Duration::from_secs(1),
+        }
+    }
+}
@@ -1,37 +1,96 @@
-use std::sync::Arc;
-use tokio_postgres::{Client, Error, NoTls, Row};
+use std::{
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::task::AbortHandle;
+use tokio_postgres::{Client, Error, NoTls, Row, error::SqlState};
+use tracing::{debug, error, info, warn};
+use uuid::Uuid;
 
-use crate::network_manager::handlers::{LoginReq, Response, SigninReq};
+use crate::network_manager::{
+    handlers::{ErrorCode, LoginReq, Response, ScheduledMessage, SigninReq},
+    metrics::METRICS,
+    moderation::{Role, Sanction, SanctionKind},
+    validation,
+};
+
+/// Awaits a single query and records how long it took under the given name.
+async fn timed<T>(
+    query: &'static str,
+    fut: impl Future<Output = Result<T, Error>>,
+) -> Result<T, Error> {
+    let start = Instant::now();
+    let result = fut.await;
+    let elapsed = start.elapsed().as_secs_f64();
+    METRICS
+        .db_query_seconds
+        .with_label_values(&[query])
+        .observe(elapsed);
+    debug!(
+        query,
+        elapsed_ms = elapsed * 1000.0,
+        ok = result.is_ok(),
+        "db query"
+    );
+    result
+}
 
 pub struct DataBase {
     client: Arc<Client>,
+    connection: AbortHandle,
+}
+
+/// Audit row written together with a change, by the same statement.
+pub struct AuditEntry<'a> {
+    pub actor: &'a str,
+    pub action: &'a str,
+    pub detail: &'a str,
 }
 
+/// `expires_at` of a new message from `$2` to `$3`, following the
+/// conversation's timer (NULL when it has none).
+const EXPIRES_AT: &str = r"now() + (SELECT make_interval(secs => expiry_secs) FROM conversation_settings
+    WHERE user_a = LEAST($2, $3) AND user_b = GREATEST($2, $3))";
+
 impl DataBase {
-    pub async fn new() -> Result<Arc<Self>, Error> {
-        let (client, connection) = tokio_postgres::connect(
-            "host=localhost user=postgres password=mysecretpassword dbname=postgres",
-            NoTls,
-        )
-        .await?;
+    /// Connects to the database at `url`. The schema is left as it is, see
+    /// [`DataBase::migrate`].
+    pub async fn connect(url: &str) -> Result<Arc<Self>, Error> {
+        let (client, connection) = tokio_postgres::connect(url, NoTls).await?;
 
-        tokio::spawn(async move {
+        let connection = tokio::spawn(async move {
             if let Err(err) = connection.await {
-                println!("Error durring connection to the databse: {err}");
+                error!("Error durring connection to the databse: {err}");
             }
-        });
+        })
+        .abort_handle();
+
+        Ok(Arc::new(Self {
+            client: Arc::new(client),
+            connection,
+        }))
+    }
 
-        client
-            .execute(
+    /// Creates the tables, columns and indexes that are missing and fills in
+    /// the columns added since. Run by the server at startup and by
+    /// `messenger-admin migrate`.
+    pub async fn migrate(&self) -> Result<(), Error> {
+        let client = &self.client;
+        timed(
+            "create_users",
+            client.execute(
                 r"CREATE TABLE IF NOT EXISTS users (
                         username TEXT PRIMARY KEY,
                         password TEXT NOT NULL
                         );",
                 &[],
-            )
-            .await?;
-        client
-            .execute(
+            ),
+        )
+        .await?;
+        timed(
+            "create_messages",
+            client.execute(
                 r"CREATE TABLE IF NOT EXISTS messages (
                         id_message INT PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
                         sender TEXT NOT NULL REFERENCES users(username) ON DELETE CASCADE,
@@ -42,206 +101,2027 @@ impl DataBase {
                         responding_to_user TEXT
                         );",
                 &[],
+            ),
+        )
+        .await?;
+        timed(
+            "alter_users_disabled",
+            client.execute(
+                "ALTER TABLE users ADD COLUMN IF NOT EXISTS disabled BOOLEAN NOT NULL DEFAULT false;",
+                &[],
+            ),
+        )
+        .await?;
+        timed(
+            "alter_users_role",
+            client.execute(
+                r"ALTER TABLE users ADD COLUMN IF NOT EXISTS role TEXT NOT NULL DEFAULT 'user'
+                        CHECK (role IN ('user', 'moderator', 'admin'));",
+                &[],
+            ),
+        )
+        .await?;
+        timed(
+            "create_sanctions",
+            client.execute(
+                r"CREATE TABLE IF NOT EXISTS sanctions (
+                        id BIGINT PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
+                        username TEXT NOT NULL REFERENCES users(username) ON DELETE CASCADE,
+                        kind TEXT NOT NULL CHECK (kind IN ('ban', 'mute')),
+                        until TIMESTAMPTZ,
+                        reason TEXT,
+                        issued_by TEXT NOT NULL,
+                        issued_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+                        lifted_at TIMESTAMPTZ
+                        );",
+                &[],
+            ),
+        )
+        .await?;
+        timed(
+            "create_audit_log",
+            client.execute(
+                r"CREATE TABLE IF NOT EXISTS audit_log (
+                        id BIGINT PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
+                        actor TEXT NOT NULL,
+                        action TEXT NOT NULL,
+                        target TEXT NOT NULL,
+                        detail TEXT NOT NULL DEFAULT '',
+                        at TIMESTAMPTZ NOT NULL DEFAULT now()
+                        );",
+                &[],
+            ),
+        )
+        .await?;
+        timed(
+            "alter_messages_encrypted",
+            client.execute(
+                "ALTER TABLE messages ADD COLUMN IF NOT EXISTS encrypted BOOLEAN NOT NULL DEFAULT false;",
+                &[],
+            ),
+        )
+        .await?;
+        timed(
+            "create_device_keys",
+            client.execute(
+                r"CREATE TABLE IF NOT EXISTS device_keys (
+                        username TEXT NOT NULL REFERENCES users(username) ON DELETE CASCADE,
+                        device_id TEXT NOT NULL,
+                        public_key TEXT NOT NULL,
+                        updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+                        PRIMARY KEY (username, device_id)
+                        );",
+                &[],
+            ),
+        )
+        .await?;
+        timed(
+            "alter_messages_expires_at",
+            client.execute(
+                "ALTER TABLE messages ADD COLUMN IF NOT EXISTS expires_at TIMESTAMPTZ;",
+                &[],
+            ),
+        )
+        .await?;
+        timed(
+            "create_messages_expires_at_index",
+            client.execute(
+                r"CREATE INDEX IF NOT EXISTS messages_expires_at
+                        ON messages (expires_at) WHERE expires_at IS NOT NULL;",
+                &[],
+            ),
+        )
+        .await?;
+        timed(
+            "create_conversation_settings",
+            client.execute(
+                r"CREATE TABLE IF NOT EXISTS conversation_settings (
+                        user_a TEXT NOT NULL REFERENCES users(username) ON DELETE CASCADE,
+                        user_b TEXT NOT NULL REFERENCES users(username) ON DELETE CASCADE,
+                        expiry_secs BIGINT,
+                        updated_by TEXT NOT NULL,
+                        updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+                        PRIMARY KEY (user_a, user_b),
+                        CHECK (user_a <= user_b)
+                        );",
+                &[],
+            ),
+        )
+        .await?;
+        timed(
+            "create_scheduled_messages",
+            client.execute(
+                r"CREATE TABLE IF NOT EXISTS scheduled_messages (
+                        id BIGINT PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
+                        sender TEXT NOT NULL REFERENCES users(username) ON DELETE CASCADE,
+                        receiver TEXT NOT NULL REFERENCES users(username) ON DELETE CASCADE,
+                        content TEXT NOT NULL,
+                        responding_to_msg TEXT,
+                        responding_to_user TEXT,
+                        encrypted BOOLEAN NOT NULL DEFAULT false,
+                        deliver_at TIMESTAMPTZ NOT NULL,
+                        created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+                        );",
+                &[],
+            ),
+        )
+        .await?;
+        timed(
+            "create_scheduled_messages_deliver_at_index",
+            client.execute(
+                "CREATE INDEX IF NOT EXISTS scheduled_messages_deliver_at ON scheduled_messages (deliver_at);",
+                &[],
+            ),
+        )
+        .await?;
+        timed(
+            "alter_messages_scheduled_id",
+            client.execute(
+                "ALTER TABLE messages ADD COLUMN IF NOT EXISTS scheduled_id BIGINT;",
+                &[],
+            ),
+        )
+        .await?;
+        timed(
+            "alter_users_username_key",
+            client.execute(
+                "ALTER TABLE users ADD COLUMN IF NOT EXISTS username_key TEXT;",
+                &[],
+            ),
+        )
+        .await?;
+        timed(
+            "create_users_username_key_index",
+            client.execute(
+                "CREATE UNIQUE INDEX IF NOT EXISTS users_username_key ON users (username_key);",
+                &[],
+            ),
+        )
+        .await?;
+        DataBase::backfill_username_keys(client).await?;
+        timed(
+            "create_login_failures",
+            client.execute(
+                r"CREATE TABLE IF NOT EXISTS login_failures (
+                        scope TEXT NOT NULL,
+                        key TEXT NOT NULL,
+                        failures INT NOT NULL,
+                        last_failure TIMESTAMPTZ NOT NULL,
+                        locked_until TIMESTAMPTZ,
+                        PRIMARY KEY (scope, key)
+                        );",
+                &[],
+            ),
+        )
+        .await?;
+        timed(
+            "alter_users_bot_token_hash",
+            client.execute(
+                "ALTER TABLE users ADD COLUMN IF NOT EXISTS bot_token_hash TEXT;",
+                &[],
+            ),
+        )
+        .await?;
+        timed(
+            "create_webhook_deliveries",
+            client.execute(
+                r"CREATE TABLE IF NOT EXISTS webhook_deliveries (
+                        id BIGINT PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
+                        url TEXT NOT NULL,
+                        event TEXT NOT NULL,
+                        payload TEXT NOT NULL,
+                        status TEXT NOT NULL DEFAULT 'pending',
+                        attempts INT NOT NULL DEFAULT 0,
+                        next_attempt TIMESTAMPTZ NOT NULL DEFAULT now(),
+                        last_status INT,
+                        last_error TEXT,
+                        created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+                        delivered_at TIMESTAMPTZ
+                        );",
+                &[],
+            ),
+        )
+        .await?;
+        timed(
+            "create_webhook_deliveries_next_attempt_index",
+            client.execute(
+                r"CREATE INDEX IF NOT EXISTS webhook_deliveries_next_attempt
+                        ON webhook_deliveries (next_attempt) WHERE status = 'pending';",
+                &[],
+            ),
+        )
+        .await?;
+        timed(
+            "create_auth_log",
+            client.execute(
+                r"CREATE TABLE IF NOT EXISTS auth_log (
+                        id BIGINT PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
+                        username TEXT NOT NULL,
+                        ip TEXT NOT NULL,
+                        outcome TEXT NOT NULL,
+                        at TIMESTAMPTZ NOT NULL DEFAULT now()
+                        );",
+                &[],
+            ),
+        )
+        .await?;
+        timed(
+            "alter_messages_edited_at",
+            client.execute(
+                "ALTER TABLE messages ADD COLUMN IF NOT EXISTS edited_at TIMESTAMPTZ;",
+                &[],
+            ),
+        )
+        .await?;
+        timed(
+            "alter_messages_client_id",
+            client.execute(
+                "ALTER TABLE messages ADD COLUMN IF NOT EXISTS client_id TEXT;",
+                &[],
+            ),
+        )
+        .await?;
+        timed(
+            "create_messages_client_id_index",
+            client.execute(
+                r"CREATE UNIQUE INDEX IF NOT EXISTS messages_sender_client_id
+                        ON messages (sender, client_id) WHERE client_id IS NOT NULL;",
+                &[],
+            ),
+        )
+        .await?;
+        timed(
+            "create_command_runs",
+            client.execute(
+                r"CREATE TABLE IF NOT EXISTS command_runs (
+                        sender TEXT NOT NULL REFERENCES users(username) ON DELETE CASCADE,
+                        client_id TEXT NOT NULL,
+                        succes BOOLEAN,
+                        code TEXT,
+                        output TEXT,
+                        at TIMESTAMPTZ NOT NULL DEFAULT now(),
+                        PRIMARY KEY (sender, client_id)
+                        );",
+                &[],
+            ),
+        )
+        .await?;
+        timed(
+            "create_conversation_reads",
+            client.execute(
+                r"CREATE TABLE IF NOT EXISTS conversation_reads (
+                        username TEXT NOT NULL REFERENCES users(username) ON DELETE CASCADE,
+                        peer TEXT NOT NULL REFERENCES users(username) ON DELETE CASCADE,
+                        last_read INT NOT NULL,
+                        PRIMARY KEY (username, peer)
+                        );",
+                &[],
+            ),
+        )
+        .await?;
+        // Every change a reconnecting client may have missed (a message
+        // stored or edited, deleted, or read) takes the next value of
+        // `sync_seq`, so a single number tells what a client has seen.
+        timed(
+            "create_sync_seq",
+            client.execute("CREATE SEQUENCE IF NOT EXISTS sync_seq;", &[]),
+        )
+        .await?;
+        timed(
+            "alter_messages_seq",
+            client.execute(
+                "ALTER TABLE messages ADD COLUMN IF NOT EXISTS seq BIGINT NOT NULL DEFAULT nextval('sync_seq');",
+                &[],
+            ),
+        )
+        .await?;
+        timed(
+            "create_messages_seq_index",
+            client.execute(
+                "CREATE INDEX IF NOT EXISTS messages_seq ON messages (seq);",
+                &[],
+            ),
+        )
+        .await?;
+        timed(
+            "alter_conversation_reads_seq",
+            client.execute(
+                "ALTER TABLE conversation_reads ADD COLUMN IF NOT EXISTS seq BIGINT NOT NULL DEFAULT nextval('sync_seq');",
+                &[],
+            ),
+        )
+        .await?;
+        timed(
+            "create_message_tombstones",
+            client.execute(
+                r"CREATE TABLE IF NOT EXISTS message_tombstones (
+                        seq BIGINT PRIMARY KEY DEFAULT nextval('sync_seq'),
+                        id_message INT NOT NULL,
+                        sender TEXT NOT NULL,
+                        receiver TEXT NOT NULL,
+                        deleted_at TIMESTAMPTZ NOT NULL DEFAULT now()
+                        );",
+                &[],
+            ),
+        )
+        .await?;
+        timed(
+            "create_sync_horizon",
+            client.execute(
+                r"CREATE TABLE IF NOT EXISTS sync_horizon (
+                        id BOOLEAN PRIMARY KEY DEFAULT true CHECK (id),
+                        seq BIGINT NOT NULL
+                        );",
+                &[],
+            ),
+        )
+        .await?;
+        // A transaction that takes a `sync_seq` value holds this lock until
+        // it ends, and `sync_cursor` takes it exclusively before reading the
+        // sequence. A cursor can then never pass a value whose row is not
+        // committed yet and would be skipped once it is.
+        timed(
+            "create_sync_stamp_function",
+            client.execute(
+                r"CREATE OR REPLACE FUNCTION sync_stamp() RETURNS trigger
+                    LANGUAGE plpgsql AS $$
+                    BEGIN
+                        PERFORM pg_advisory_xact_lock_shared('sync_seq'::regclass::oid::BIGINT);
+                        NEW.seq := nextval('sync_seq');
+                        RETURN NEW;
+                    END $$;",
+                &[],
+            ),
+        )
+        .await?;
+        timed(
+            "create_sync_cursor_function",
+            client.execute(
+                r"CREATE OR REPLACE FUNCTION sync_cursor() RETURNS BIGINT
+                    LANGUAGE plpgsql AS $$
+                    BEGIN
+                        PERFORM pg_advisory_xact_lock('sync_seq'::regclass::oid::BIGINT);
+                        RETURN (SELECT CASE WHEN is_called THEN last_value ELSE 0 END FROM sync_seq);
+                    END $$;",
+                &[],
+            ),
+        )
+        .await?;
+        timed(
+            "create_messages_sync_function",
+            client.execute(
+                r"CREATE OR REPLACE FUNCTION messages_sync() RETURNS trigger
+                    LANGUAGE plpgsql AS $$
+                    BEGIN
+                        INSERT INTO message_tombstones (id_message, sender, receiver)
+                            VALUES (OLD.id_message, OLD.sender, OLD.receiver);
+                        RETURN OLD;
+                    END $$;",
+                &[],
+            ),
+        )
+        .await?;
+        for (name, statement) in [
+            (
+                "create_messages_stored_trigger",
+                r"CREATE OR REPLACE TRIGGER messages_stored
+                    BEFORE INSERT ON messages
+                    FOR EACH ROW EXECUTE FUNCTION sync_stamp();",
+            ),
+            (
+                "create_messages_edited_trigger",
+                r"CREATE OR REPLACE TRIGGER messages_edited
+                    BEFORE UPDATE OF content, edited_at ON messages
+                    FOR EACH ROW EXECUTE FUNCTION sync_stamp();",
+            ),
+            (
+                "create_messages_deleted_trigger",
+                r"CREATE OR REPLACE TRIGGER messages_deleted
+                    AFTER DELETE ON messages
+                    FOR EACH ROW EXECUTE FUNCTION messages_sync();",
+            ),
+            (
+                "create_message_tombstones_trigger",
+                r"CREATE OR REPLACE TRIGGER message_tombstones_stored
+                    BEFORE INSERT ON message_tombstones
+                    FOR EACH ROW EXECUTE FUNCTION sync_stamp();",
+            ),
+            (
+                "create_conversation_reads_trigger",
+                r"CREATE OR REPLACE TRIGGER conversation_reads_moved
+                    BEFORE INSERT OR UPDATE ON conversation_reads
+                    FOR EACH ROW EXECUTE FUNCTION sync_stamp();",
+            ),
+        ] {
+            timed(name, client.execute(statement, &[])).await?;
+        }
+        timed(
+            "create_presence",
+            client.execute(
+                r"CREATE TABLE IF NOT EXISTS presence (
+                        instance TEXT NOT NULL,
+                        username TEXT NOT NULL,
+                        seen_at TIMESTAMPTZ NOT NULL,
+                        PRIMARY KEY (instance, username)
+                        );",
+                &[],
+            ),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Drops the connection to the database. Queries issued afterwards fail.
+    pub fn close(&self) {
+        self.connection.abort();
+        info!("Database connection closed");
+    }
+    /// Fills in `username_key` for accounts created before it existed. Names
+    /// that fold to the same key as an earlier account keep a NULL key and
+    /// can still log in, but block nobody from signing up.
+    async fn backfill_username_keys(client: &Client) -> Result<(), Error> {
+        let rows = timed(
+            "backfill_username_keys.select",
+            client.query(
+                "SELECT username FROM users WHERE username_key IS NULL ORDER BY username;",
+                &[],
+            ),
+        )
+        .await?;
+        for row in rows {
+            let username: String = row.get(0);
+            let key = validation::username_key(&username);
+            let result = timed(
+                "backfill_username_keys.update",
+                client.execute(
+                    "UPDATE users SET username_key = $2 WHERE username = $1;",
+                    &[&username, &key],
+                ),
             )
-            .await?;
-        Ok(Arc::new(Self {
-            client: Arc::new(client),
-        }))
+            .await;
+            match result {
+                Ok(_) => {}
+                Err(err) if err.code() == Some(&SqlState::UNIQUE_VIOLATION) => {
+                    warn!(%username, "Username collides with another account after folding, leaving it without a key");
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
     }
     pub async fn signin(&self, user_info: SigninReq) -> Result<Response, Error> {
-        let exists: bool = self
-            .client
-            .query_one(
-                "SELECT EXISTS(SELECT 1 FROM users WHERE username = $1);",
-                &[&user_info.username],
-            )
-            .await?
-            .get(0);
+        let taken = Response {
+            succes: false,
+            message: "Username taken!".to_string(),
+            code: Some(ErrorCode::UsernameTaken),
+            message_id: None,
+        };
+        let key = validation::username_key(&user_info.username);
+        let exists: bool = timed(
+            "signin.exists",
+            self.client.query_one(
+                "SELECT EXISTS(SELECT 1 FROM users WHERE username = $1 OR username_key = $2);",
+                &[&user_info.username, &key],
+            ),
+        )
+        .await?
+        .get(0);
         if exists {
-            let resp = Response {
-                succes: false,
-                message: "Username taken!".to_string(),
-            };
-            return Ok(resp);
+            return Ok(taken);
+        }
+        let inserted = timed(
+            "signin.insert",
+            self.client.execute(
+                "INSERT INTO users (username, password, username_key) VALUES ($1, $2, $3);",
+                &[&user_info.username, &user_info.password, &key],
+            ),
+        )
+        .await;
+        match inserted {
+            Ok(_) => {}
+            // Lost a race with another sign in for the same name.
+            Err(err) if err.code() == Some(&SqlState::UNIQUE_VIOLATION) => return Ok(taken),
+            Err(err) => return Err(err),
         }
-        println!("Hmm...");
-        self.client
-            .execute(
-                "INSERT INTO users (username, password) VALUES ($1, $2);",
-                &[&user_info.username, &user_info.password],
-            )
-            .await?;
         let resp = Response {
             succes: true,
             message: "Signed in with succes!".to_string(),
+            code: None,
+            message_id: None,
         };
         Ok(resp)
     }
+    /// Stored spelling of the account `username` names, compared the way
+    /// sign-up compares names, so `Alice` finds `alice`.
+    pub async fn account_name(&self, username: &str) -> Result<Option<String>, Error> {
+        let key = validation::username_key(username);
+        Ok(timed(
+            "login.account_name",
+            self.client
+                .query_opt("SELECT username FROM users WHERE username_key = $1;", &[&key]),
+        )
+        .await?
+        .map(|row| row.get(0)))
+    }
     pub async fn login(&self, user_info: LoginReq) -> Result<Response, Error> {
-        let exists: bool = self
-            .client
-            .query_one(
-                "SELECT EXISTS(SELECT 1 FROM users WHERE username = $1 AND password = $2);",
+        let disabled: Option<bool> = timed(
+            "login.check",
+            self.client.query_opt(
+                "SELECT disabled FROM users WHERE username = $1 AND password = $2;",
                 &[&user_info.username, &user_info.password],
-            )
-            .await?
-            .get(0);
-        if !exists {
-            let resp = Response {
-                succes: false,
-                message: "Invalid username and/or password.".to_string(),
-            };
-            return Ok(resp);
+            ),
+        )
+        .await?
+        .map(|row| row.get(0));
+        Ok(DataBase::login_response(
+            disabled,
+            "Invalid username and/or password.",
+        ))
+    }
+    /// Login for a client whose certificate already proved it is `username`.
+    pub async fn login_with_certificate(&self, username: &str) -> Result<Response, Error> {
+        let disabled: Option<bool> = timed(
+            "login.certificate",
+            self.client.query_opt(
+                "SELECT disabled FROM users WHERE username = $1;",
+                &[&username],
+            ),
+        )
+        .await?
+        .map(|row| row.get(0));
+        Ok(DataBase::login_response(
+            disabled,
+            "There is no account for this certificate.",
+        ))
+    }
+    fn login_response(disabled: Option<bool>, unknown: &str) -> Response {
+        match disabled {
+            None => {
+                let resp = Response {
+                    succes: false,
+                    message: unknown.to_string(),
+                    code: Some(ErrorCode::InvalidCredentials),
+                    message_id: None,
+                };
+                return resp;
+            }
+            Some(true) => {
+                let resp = Response {
+                    succes: false,
+                    message: "This account is disabled.".to_string(),
+                    code: Some(ErrorCode::Disabled),
+                    message_id: None,
+                };
+                return resp;
+            }
+            Some(false) => {}
         }
-        let resp = Response {
+        Response {
             succes: true,
             message: "Logged in with succes!".to_string(),
-        };
-        Ok(resp)
+            code: None,
+            message_id: None,
+        }
     }
+    /// Stores a message. `client_id` is the id the client sent it under; a
+    /// second message from the same sender with the same id is not stored
+    /// again, the first one's result is returned with the flag set instead.
     pub async fn send_message(
         &self,
         sender: &str,
         receiver: &str,
         message: &str,
-    ) -> Result<Response, Error> {
-        let exists: bool = self
-            .client
-            .query_one(
+        encrypted: bool,
+        client_id: Option<&str>,
+    ) -> Result<(Response, bool), Error> {
+        let exists: bool = timed(
+            "send_message.sender_exists",
+            self.client.query_one(
                 "SELECT EXISTS(SELECT 1 FROM users WHERE username = $1);",
                 &[&sender],
-            )
-            .await?
-            .get(0);
+            ),
+        )
+        .await?
+        .get(0);
         if !exists {
             let resp = Response {
                 succes: false,
                 message: "The sender is not in the database".to_string(),
+                code: None,
+                message_id: None,
             };
-            return Ok(resp);
+            return Ok((resp, false));
         }
-        let exists: bool = self
-            .client
-            .query_one(
+        let exists: bool = timed(
+            "send_message.receiver_exists",
+            self.client.query_one(
                 "SELECT EXISTS(SELECT 1 FROM users WHERE username = $1);",
                 &[&receiver],
-            )
-            .await?
-            .get(0);
+            ),
+        )
+        .await?
+        .get(0);
         if !exists {
             let resp = Response {
                 succes: false,
                 message: "The receiver is not in the database".to_string(),
+                code: None,
+                message_id: None,
             };
-            return Ok(resp);
+            return Ok((resp, false));
         }
 
-        self.client
-            .execute(
-                "INSERT INTO messages (content, sender, receiver) VALUES ($1, $2, $3);",
-                &[&message, &sender, &receiver],
-            )
-            .await?;
-        let resp = Response {
-            succes: true,
-            message: "Message saved".to_string(),
-        };
-        Ok(resp)
+        let row = timed(
+            "send_message.insert",
+            self.client.query_opt(
+                &format!(
+                    "INSERT INTO messages (content, sender, receiver, encrypted, expires_at, client_id) VALUES ($1, $2, $3, $4, {EXPIRES_AT}, $5)
+                        ON CONFLICT (sender, client_id) WHERE client_id IS NOT NULL DO NOTHING RETURNING id_message;"
+                ),
+                &[&message, &sender, &receiver, &encrypted, &client_id],
+            ),
+        )
+        .await?;
+        self.saved(sender, client_id, row).await
     }
     pub async fn send_message_with_resp(
         &self,
         sender: &str,
         receiver: &str,
         message: &str,
-        resp_msg: &str,
-        resp_usr: &str,
-    ) -> Result<Response, Error> {
-        let exists: bool = self
-            .client
-            .query_one(
+        (resp_msg, resp_usr): (&str, &str),
+        encrypted: bool,
+        client_id: Option<&str>,
+    ) -> Result<(Response, bool), Error> {
+        let exists: bool = timed(
+            "send_message_with_resp.sender_exists",
+            self.client.query_one(
                 "SELECT EXISTS(SELECT 1 FROM users WHERE username = $1);",
                 &[&sender],
-            )
-            .await?
-            .get(0);
+            ),
+        )
+        .await?
+        .get(0);
         if !exists {
             let resp = Response {
                 succes: false,
                 message: "The sender is not in the database".to_string(),
+                code: None,
+                message_id: None,
             };
-            return Ok(resp);
+            return Ok((resp, false));
         }
-        let exists: bool = self
-            .client
-            .query_one(
+        let exists: bool = timed(
+            "send_message_with_resp.receiver_exists",
+            self.client.query_one(
                 "SELECT EXISTS(SELECT 1 FROM users WHERE username = $1);",
                 &[&receiver],
-            )
-            .await?
-            .get(0);
+            ),
+        )
+        .await?
+        .get(0);
         if !exists {
             let resp = Response {
                 succes: false,
                 message: "The receiver is not in the database".to_string(),
+                code: None,
+                message_id: None,
             };
-            return Ok(resp);
+            return Ok((resp, false));
         }
 
-        self.client
-            .execute(
-                "INSERT INTO messages (content, sender, receiver, responding_to_msg, responding_to_user) VALUES ($1, $2, $3, $4, $5);",
-                &[&message, &sender, &receiver, &resp_msg, &resp_usr],
-            )
+        let row = timed("send_message_with_resp.insert", self.client
+            .query_opt(
+                &format!(
+                    "INSERT INTO messages (content, sender, receiver, responding_to_msg, responding_to_user, encrypted, expires_at, client_id) VALUES ($1, $2, $3, $4, $5, $6, {EXPIRES_AT}, $7)
+                        ON CONFLICT (sender, client_id) WHERE client_id IS NOT NULL DO NOTHING RETURNING id_message;"
+                ),
+                &[&message, &sender, &receiver, &resp_msg, &resp_usr, &encrypted, &client_id],
+            ))
             .await?;
+        self.saved(sender, client_id, row).await
+    }
+    /// Result of an insert into `messages`. No row means the sender already
+    /// stored a message under `client_id`, whose id is looked up instead.
+    async fn saved(
+        &self,
+        sender: &str,
+        client_id: Option<&str>,
+        inserted: Option<Row>,
+    ) -> Result<(Response, bool), Error> {
+        let (row, duplicate) = match inserted {
+            Some(row) => (Some(row), false),
+            None => {
+                let row = timed(
+                    "send_message.original",
+                    self.client.query_opt(
+                        "SELECT id_message FROM messages WHERE sender = $1 AND client_id = $2;",
+                        &[&sender, &client_id],
+                    ),
+                )
+                .await?;
+                (row, true)
+            }
+        };
         let resp = Response {
             succes: true,
-            message: "Message saved".to_string(),
+            message: match duplicate {
+                true => "Message already saved".to_string(),
+                false => "Message saved".to_string(),
+            },
+            code: None,
+            // The original may have expired since.
+            message_id: row.map(|row| i64::from(row.get::<_, i32>(0))),
         };
-        Ok(resp)
+        Ok((resp, duplicate))
+    }
+    /// Claims running the command `sender` sent as `client_id`. Returns the
+    /// `(succes, code, output)` of an earlier run instead, all NULL while it
+    /// is still running.
+    pub async fn claim_command(&self, sender: &str, client_id: &str) -> Result<Option<Row>, Error> {
+        let claimed = timed(
+            "claim_command",
+            self.client.execute(
+                r"INSERT INTO command_runs (sender, client_id) VALUES ($1, $2)
+                    ON CONFLICT (sender, client_id) DO NOTHING;",
+                &[&sender, &client_id],
+            ),
+        )
+        .await?;
+        if claimed == 1 {
+            return Ok(None);
+        }
+        timed(
+            "claim_command.earlier",
+            self.client.query_opt(
+                "SELECT succes, code, output FROM command_runs WHERE sender = $1 AND client_id = $2;",
+                &[&sender, &client_id],
+            ),
+        )
+        .await
+    }
+    /// Stores the result of a command claimed with `claim_command`.
+    pub async fn finish_command(
+        &self,
+        sender: &str,
+        client_id: &str,
+        succes: bool,
+        code: Option<&str>,
+        output: &str,
+    ) -> Result<(), Error> {
+        timed(
+            "finish_command",
+            self.client.execute(
+                "UPDATE command_runs SET succes = $3, code = $4, output = $5 WHERE sender = $1 AND client_id = $2;",
+                &[&sender, &client_id, &succes, &code, &output],
+            ),
+        )
+        .await?;
+        Ok(())
     }
+    /// Drops a claim, so a retry runs the command again.
+    pub async fn forget_command(&self, sender: &str, client_id: &str) -> Result<(), Error> {
+        timed(
+            "forget_command",
+            self.client.execute(
+                "DELETE FROM command_runs WHERE sender = $1 AND client_id = $2;",
+                &[&sender, &client_id],
+            ),
+        )
+        .await?;
+        Ok(())
+    }
+    /// Forgets command results older than `age`.
+    pub async fn prune_command_runs(&self, age: Duration) -> Result<u64, Error> {
+        timed(
+            "prune_command_runs",
+            self.client.execute(
+                "DELETE FROM command_runs WHERE at < now() - make_interval(secs => $1);",
+                &[&age.as_secs_f64()],
+            ),
+        )
+        .await
+    }
+    /// Up to 50 messages between the two users from `offset`, oldest first,
+    /// as `(id, sender, receiver, content, encrypted, date in UTC,
+    /// responding_to_msg, responding_to_user, edited_at)`.
     pub async fn get_messages(
         &self,
         user1: &str,
         user2: &str,
         offset: i64,
     ) -> Result<Option<Vec<Row>>, Error> {
-        let exists: bool = self
-            .client
-            .query_one(
+        let exists: bool = timed(
+            "get_messages.user1_exists",
+            self.client.query_one(
                 "SELECT EXISTS(SELECT 1 FROM users WHERE username = $1);",
                 &[&user1],
-            )
-            .await?
-            .get(0);
+            ),
+        )
+        .await?
+        .get(0);
         if !exists {
             return Ok(None);
         }
-        let exists: bool = self
-            .client
-            .query_one(
+        let exists: bool = timed(
+            "get_messages.user2_exists",
+            self.client.query_one(
                 "SELECT EXISTS(SELECT 1 FROM users WHERE username = $1);",
                 &[&user2],
-            )
-            .await?
-            .get(0);
+            ),
+        )
+        .await?
+        .get(0);
         if !exists {
             return Ok(None);
         }
-        let row = self.client.query(r"SELECT content, sender, responding_to_msg, responding_to_user FROM 
+        let row = timed("get_messages.select", self.client.query(r"SELECT id_message::BIGINT, sender, receiver, content, encrypted,
+                            date AT TIME ZONE current_setting('TimeZone'),
+                            responding_to_msg, responding_to_user, edited_at FROM
                             messages m JOIN users u1 ON m.sender = u1.username JOIN users u2 ON m.receiver = u2.username 
-                            WHERE (u1.username = $1 AND u2.username = $2) OR (u1.username = $2 AND u2.username = $1)
-                            ORDER BY date ASC LIMIT 50 OFFSET $3;", &[&user1, &user2, &offset]).await?;
+                            WHERE ((u1.username = $1 AND u2.username = $2) OR (u1.username = $2 AND u2.username = $1))
+                            AND (m.expires_at IS NULL OR m.expires_at > now())
+                            ORDER BY date ASC LIMIT 50 OFFSET $3;", &[&user1, &user2, &offset])).await?;
         Ok(Some(row))
     }
 
+    /// `(sender, receiver, content, resp_msg, resp_user, encrypted)` of one
+    /// message.
+    pub async fn message_by_id(&self, id: i64) -> Result<Option<Row>, Error> {
+        let Ok(id) = i32::try_from(id) else {
+            return Ok(None);
+        };
+        timed(
+            "message_by_id",
+            self.client.query_opt(
+                r"SELECT sender, receiver, content, responding_to_msg, responding_to_user, encrypted
+                    FROM messages WHERE id_message = $1;",
+                &[&id],
+            ),
+        )
+        .await
+    }
+
+    /// Disappearing-messages timer of the conversation between `user` and
+    /// `with`, in seconds. `None` when messages are kept.
+    pub async fn conversation_expiry(&self, user: &str, with: &str) -> Result<Option<i64>, Error> {
+        let row = timed(
+            "conversation_expiry",
+            self.client.query_opt(
+                r"SELECT expiry_secs FROM conversation_settings
+                    WHERE user_a = LEAST($1, $2) AND user_b = GREATEST($1, $2);",
+                &[&user, &with],
+            ),
+        )
+        .await?;
+        Ok(row.and_then(|r| r.get(0)))
+    }
+
+    /// Sets the timer for messages sent from now on. Returns false if `with`
+    /// does not exist.
+    pub async fn set_conversation_expiry(
+        &self,
+        user: &str,
+        with: &str,
+        expiry_secs: Option<i64>,
+    ) -> Result<bool, Error> {
+        let updated = timed(
+            "set_conversation_expiry",
+            self.client.execute(
+                r"INSERT INTO conversation_settings (user_a, user_b, expiry_secs, updated_by)
+                    SELECT LEAST($1, $2), GREATEST($1, $2), $3, $1
+                    WHERE EXISTS(SELECT 1 FROM users WHERE username = $2)
+                    ON CONFLICT (user_a, user_b) DO UPDATE
+                    SET expiry_secs = EXCLUDED.expiry_secs,
+                        updated_by = EXCLUDED.updated_by,
+                        updated_at = now();",
+                &[&user, &with, &expiry_secs],
+            ),
+        )
+        .await?;
+        Ok(updated == 1)
+    }
+
+    /// Deletes every expired message and returns them as
+    /// `(id_message, sender, receiver)`.
+    pub async fn delete_expired(&self) -> Result<Vec<Row>, Error> {
+        timed(
+            "delete_expired",
+            self.client.query(
+                "DELETE FROM messages WHERE expires_at <= now() RETURNING id_message, sender, receiver;",
+                &[],
+            ),
+        )
+        .await
+    }
+
+    /// Stores a message for later delivery and returns its id, or `None` if
+    /// the receiver does not exist.
+    pub async fn schedule_message(
+        &self,
+        sender: &str,
+        message: &ScheduledMessage,
+    ) -> Result<Option<i64>, Error> {
+        let row = timed(
+            "schedule_message",
+            self.client.query_opt(
+                r"INSERT INTO scheduled_messages
+                    (sender, receiver, content, responding_to_msg, responding_to_user, encrypted, deliver_at)
+                    SELECT $1, $2, $3, $4, $5, $6, to_timestamp($7::BIGINT)
+                    WHERE EXISTS(SELECT 1 FROM users WHERE username = $2)
+                    RETURNING id;",
+                &[
+                    &sender,
+                    &message.to,
+                    &message.message,
+                    &message.resp_msg,
+                    &message.resp_user,
+                    &message.encrypted,
+                    &message.deliver_at,
+                ],
+            ),
+        )
+        .await?;
+        Ok(row.map(|r| r.get(0)))
+    }
+
+    pub async fn count_scheduled(&self, sender: &str) -> Result<i64, Error> {
+        let row = timed(
+            "count_scheduled",
+            self.client.query_one(
+                "SELECT COUNT(*) FROM scheduled_messages WHERE sender = $1;",
+                &[&sender],
+            ),
+        )
+        .await?;
+        Ok(row.get(0))
+    }
+
+    /// Pending messages of `sender` as `(id, receiver, content,
+    /// responding_to_msg, responding_to_user, encrypted, deliver_at)`, with
+    /// `deliver_at` in Unix seconds.
+    pub async fn scheduled_messages(&self, sender: &str) -> Result<Vec<Row>, Error> {
+        timed(
+            "scheduled_messages",
+            self.client.query(
+                r"SELECT id, receiver, content, responding_to_msg, responding_to_user, encrypted,
+                        EXTRACT(EPOCH FROM deliver_at)::BIGINT
+                    FROM scheduled_messages WHERE sender = $1 ORDER BY deliver_at, id;",
+                &[&sender],
+            ),
+        )
+        .await
+    }
+
+    /// Returns false if `sender` has no pending message with this id.
+    pub async fn cancel_scheduled(&self, sender: &str, id: i64) -> Result<bool, Error> {
+        let deleted = timed(
+            "cancel_scheduled",
+            self.client.execute(
+                "DELETE FROM scheduled_messages WHERE id = $1 AND sender = $2;",
+                &[&id, &sender],
+            ),
+        )
+        .await?;
+        Ok(deleted == 1)
+    }
+
+    /// Moves up to `limit` due scheduled messages into `messages` in a
+    /// single statement, so each one is either still pending or stored even
+    /// if the server stops halfway. Messages of banned or muted senders stay
+    /// pending until the sanction ends. Returns `(scheduled_id, id_message,
+    /// sender, receiver, content, responding_to_msg, responding_to_user,
+    /// encrypted)`.
+    pub async fn deliver_due(&self, limit: i64) -> Result<Vec<Row>, Error> {
+        timed(
+            "deliver_due",
+            self.client.query(
+                r"WITH due AS (
+                        DELETE FROM scheduled_messages WHERE id IN (
+                            SELECT s.id FROM scheduled_messages s
+                            WHERE s.deliver_at <= now()
+                            AND NOT EXISTS(
+                                SELECT 1 FROM sanctions x
+                                WHERE x.username = s.sender AND x.lifted_at IS NULL
+                                AND (x.until IS NULL OR x.until > now())
+                            )
+                            ORDER BY s.deliver_at, s.id
+                            LIMIT $1
+                            FOR UPDATE SKIP LOCKED
+                        )
+                        RETURNING *
+                    )
+                    INSERT INTO messages (content, sender, receiver, responding_to_msg,
+                        responding_to_user, encrypted, scheduled_id, expires_at)
+                    SELECT content, sender, receiver, responding_to_msg, responding_to_user,
+                        encrypted, id,
+                        now() + (SELECT make_interval(secs => c.expiry_secs) FROM conversation_settings c
+                            WHERE c.user_a = LEAST(due.sender, due.receiver)
+                            AND c.user_b = GREATEST(due.sender, due.receiver))
+                    FROM due ORDER BY deliver_at, id
+                    RETURNING scheduled_id, id_message, sender, receiver, content,
+                        responding_to_msg, responding_to_user, encrypted;",
+                &[&limit],
+            ),
+        )
+        .await
+    }
+
+    /// Adds or replaces the public key of one of `user`'s devices.
+    pub async fn put_device_key(
+        &self,
+        user: &str,
+        device_id: &str,
+        public_key: &str,
+    ) -> Result<(), Error> {
+        timed(
+            "put_device_key",
+            self.client.execute(
+                r"INSERT INTO device_keys (username, device_id, public_key) VALUES ($1, $2, $3)
+                    ON CONFLICT (username, device_id)
+                    DO UPDATE SET public_key = EXCLUDED.public_key, updated_at = now();",
+                &[&user, &device_id, &public_key],
+            ),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Published `(device_id, public_key)` pairs of `user`, or `None` if the
+    /// user does not exist.
+    pub async fn device_keys(&self, user: &str) -> Result<Option<Vec<Row>>, Error> {
+        let exists: bool = timed(
+            "device_keys.user_exists",
+            self.client.query_one(
+                "SELECT EXISTS(SELECT 1 FROM users WHERE username = $1);",
+                &[&user],
+            ),
+        )
+        .await?
+        .get(0);
+        if !exists {
+            return Ok(None);
+        }
+        let rows = timed(
+            "device_keys.select",
+            self.client.query(
+                "SELECT device_id, public_key FROM device_keys WHERE username = $1 ORDER BY device_id;",
+                &[&user],
+            ),
+        )
+        .await?;
+        Ok(Some(rows))
+    }
+
+    /// Round trip to the database, used by the readiness check.
+    pub async fn ping(&self) -> Result<(), Error> {
+        timed("ping", self.client.simple_query("SELECT 1;")).await?;
+        Ok(())
+    }
+
+    /// The inbox of `user`, or only its conversation with `with`, as
+    /// `(peer, last sender, last content, encrypted, last date in Unix
+    /// seconds, unread, last message id)`. Newest activity first, then the
+    /// users never talked to, so new conversations can be started. The
+    /// conversation with oneself is listed once it has messages.
+    pub async fn conversations(&self, user: &str, with: Option<&str>) -> Result<Vec<Row>, Error> {
+        timed(
+            "conversations",
+            self.client.query(
+                r"WITH live AS (
+                        SELECT CASE WHEN sender = $1 THEN receiver ELSE sender END AS peer,
+                            id_message, sender, receiver, content, encrypted, date
+                        FROM messages
+                        WHERE (sender = $1 OR receiver = $1)
+                            AND (expires_at IS NULL OR expires_at > now())
+                    ), filtered AS (
+                        SELECT * FROM live WHERE $2::TEXT IS NULL OR peer = $2
+                    ), latest AS (
+                        SELECT DISTINCT ON (peer) * FROM filtered
+                        ORDER BY peer, date DESC, id_message DESC
+                    ), unread AS (
+                        SELECT f.peer, COUNT(*) AS unread FROM filtered f
+                        LEFT JOIN conversation_reads r ON r.username = $1 AND r.peer = f.peer
+                        WHERE f.receiver = $1 AND f.sender != $1
+                            AND f.id_message > COALESCE(r.last_read, 0)
+                        GROUP BY f.peer
+                    )
+                    SELECT u.username, l.sender, l.content, COALESCE(l.encrypted, false),
+                        EXTRACT(EPOCH FROM l.date AT TIME ZONE current_setting('TimeZone'))::BIGINT,
+                        COALESCE(n.unread, 0), l.id_message::BIGINT
+                    FROM users u
+                    LEFT JOIN latest l ON l.peer = u.username
+                    LEFT JOIN unread n ON n.peer = u.username
+                    WHERE ($2::TEXT IS NULL OR u.username = $2)
+                        AND (u.username != $1 OR l.peer IS NOT NULL)
+                    ORDER BY l.date DESC NULLS LAST, l.id_message DESC, u.username ASC;",
+                &[&user, &with],
+            ),
+        )
+        .await
+    }
+
+    /// Marks the messages `user` received from `peer` up to `message_id` as
+    /// read. Never moves the mark back.
+    pub async fn mark_read(&self, user: &str, peer: &str, message_id: i64) -> Result<(), Error> {
+        let Ok(message_id) = i32::try_from(message_id) else {
+            return Ok(());
+        };
+        timed(
+            "mark_read",
+            self.client.execute(
+                r"INSERT INTO conversation_reads (username, peer, last_read)
+                    SELECT $1, $2, $3 WHERE EXISTS (SELECT 1 FROM users WHERE username = $2)
+                    ON CONFLICT (username, peer)
+                    DO UPDATE SET last_read = EXCLUDED.last_read
+                    WHERE conversation_reads.last_read < EXCLUDED.last_read;",
+                &[&user, &peer, &message_id],
+            ),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// The last value of `sync_seq` whose change is committed, with none
+    /// below it still in flight, and the oldest value a client can still
+    /// sync from: deletions before it are forgotten.
+    pub async fn sync_state(&self) -> Result<(i64, i64), Error> {
+        let row = timed(
+            "sync_state",
+            self.client.query_one(
+                "SELECT sync_cursor(), COALESCE((SELECT seq FROM sync_horizon), 0);",
+                &[],
+            ),
+        )
+        .await?;
+        Ok((row.get(0), row.get(1)))
+    }
+
+    /// Up to `limit` messages of `user` stored or edited after `since` and
+    /// up to `until`, in that order, with the columns of `get_messages`
+    /// followed by `seq`.
+    pub async fn messages_since(
+        &self,
+        user: &str,
+        since: i64,
+        until: i64,
+        limit: i64,
+    ) -> Result<Vec<Row>, Error> {
+        timed(
+            "messages_since",
+            self.client.query(
+                r"SELECT id_message::BIGINT, sender, receiver, content, encrypted,
+                        date AT TIME ZONE current_setting('TimeZone'),
+                        responding_to_msg, responding_to_user, edited_at, seq
+                    FROM messages
+                    WHERE (sender = $1 OR receiver = $1) AND seq > $2 AND seq <= $3
+                        AND (expires_at IS NULL OR expires_at > now())
+                    ORDER BY seq LIMIT $4;",
+                &[&user, &since, &until, &limit],
+            ),
+        )
+        .await
+    }
+
+    /// `(id_message, seq)` of the messages of `user` deleted after `since`
+    /// and up to `until`.
+    pub async fn deleted_since(
+        &self,
+        user: &str,
+        since: i64,
+        until: i64,
+    ) -> Result<Vec<Row>, Error> {
+        timed(
+            "deleted_since",
+            self.client.query(
+                r"SELECT id_message::BIGINT, seq FROM message_tombstones
+                    WHERE (sender = $1 OR receiver = $1) AND seq > $2 AND seq <= $3
+                    ORDER BY seq;",
+                &[&user, &since, &until],
+            ),
+        )
+        .await
+    }
+
+    /// Read marks moved after `since` and up to `until`, by `user` on any
+    /// device or by the people `user` writes to, as `(reader, peer,
+    /// last_read, seq)`.
+    pub async fn reads_since(&self, user: &str, since: i64, until: i64) -> Result<Vec<Row>, Error> {
+        timed(
+            "reads_since",
+            self.client.query(
+                r"SELECT username, peer, last_read::BIGINT, seq FROM conversation_reads
+                    WHERE (username = $1 OR peer = $1) AND seq > $2 AND seq <= $3
+                    ORDER BY seq;",
+                &[&user, &since, &until],
+            ),
+        )
+        .await
+    }
+
+    /// Forgets deletions older than `age` and moves the sync horizon past
+    /// them, so clients that were away longer start over.
+    pub async fn prune_tombstones(&self, age: Duration) -> Result<i64, Error> {
+        let row = timed(
+            "prune_tombstones",
+            self.client.query_one(
+                r"WITH pruned AS (
+                        DELETE FROM message_tombstones
+                        WHERE deleted_at < now() - make_interval(secs => $1)
+                        RETURNING seq
+                    ), horizon AS (
+                        INSERT INTO sync_horizon (seq)
+                        SELECT MAX(seq) FROM pruned HAVING COUNT(*) > 0
+                        ON CONFLICT (id) DO UPDATE SET seq = GREATEST(sync_horizon.seq, EXCLUDED.seq)
+                    )
+                    SELECT COUNT(*) FROM pruned;",
+                &[&age.as_secs_f64()],
+            ),
+        )
+        .await?;
+        Ok(row.get(0))
+    }
+
+    /// Marks `username` as connected to `instance`.
+    pub async fn set_online(&self, instance: &str, username: &str) -> Result<(), Error> {
+        timed(
+            "set_online",
+            self.client.execute(
+                r"INSERT INTO presence (instance, username, seen_at) VALUES ($1, $2, now())
+                    ON CONFLICT (instance, username) DO UPDATE SET seen_at = now();",
+                &[&instance, &username],
+            ),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Marks `username` as no longer connected to `instance`.
+    pub async fn set_offline(&self, instance: &str, username: &str) -> Result<(), Error> {
+        timed(
+            "set_offline",
+            self.client.execute(
+                "DELETE FROM presence WHERE instance = $1 AND username = $2;",
+                &[&instance, &username],
+            ),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Replaces the presence rows of `instance` with `usernames`, all seen
+    /// now.
+    pub async fn refresh_presence(
+        &self,
+        instance: &str,
+        usernames: &[String],
+    ) -> Result<(), Error> {
+        timed(
+            "refresh_presence",
+            self.client.execute(
+                r"WITH gone AS (
+                        DELETE FROM presence WHERE instance = $1 AND NOT username = ANY($2)
+                    )
+                    INSERT INTO presence (instance, username, seen_at)
+                        SELECT $1, username, now() FROM unnest($2::TEXT[]) AS username
+                    ON CONFLICT (instance, username) DO UPDATE SET seen_at = now();",
+                &[&instance, &usernames],
+            ),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Users connected to any instance that checked in within `ttl`.
+    pub async fn online_users(&self, ttl: Duration) -> Result<Vec<String>, Error> {
+        let rows = timed(
+            "online_users",
+            self.client.query(
+                r"SELECT DISTINCT username FROM presence
+                    WHERE seen_at > now() - make_interval(secs => $1)
+                    ORDER BY username;",
+                &[&ttl.as_secs_f64()],
+            ),
+        )
+        .await?;
+        Ok(rows.iter().map(|row| row.get(0)).collect())
+    }
+
     pub async fn get_user_list(&self, user: &str) -> Result<Option<Vec<Row>>, Error> {
-        let row = self
-            .client
-            .query(
+        let row = timed(
+            "get_user_list.select",
+            self.client.query(
                 r"SELECT username FROM users WHERE username != $1 ORDER BY username ASC;",
                 &[&user],
+            ),
+        )
+        .await?;
+        Ok(Some(row))
+    }
+
+    /// Every account as `(username, disabled, messages sent, messages received)`.
+    pub async fn list_users(&self) -> Result<Vec<Row>, Error> {
+        timed(
+            "admin.list_users",
+            self.client.query(
+                r"SELECT u.username, u.disabled,
+                        (SELECT COUNT(*) FROM messages m WHERE m.sender = u.username),
+                        (SELECT COUNT(*) FROM messages m WHERE m.receiver = u.username),
+                        u.bot_token_hash IS NOT NULL
+                    FROM users u ORDER BY u.username ASC;",
+                &[],
+            ),
+        )
+        .await
+    }
+
+    /// Returns false if the user does not exist.
+    pub async fn set_password(&self, user: &str, password: &str) -> Result<bool, Error> {
+        let updated = timed(
+            "admin.set_password",
+            self.client.execute(
+                "UPDATE users SET password = $2 WHERE username = $1;",
+                &[&user, &password],
+            ),
+        )
+        .await?;
+        Ok(updated == 1)
+    }
+
+    /// Returns false if the user does not exist.
+    pub async fn set_disabled(&self, user: &str, disabled: bool) -> Result<bool, Error> {
+        let updated = timed(
+            "admin.set_disabled",
+            self.client.execute(
+                "UPDATE users SET disabled = $2 WHERE username = $1;",
+                &[&user, &disabled],
+            ),
+        )
+        .await?;
+        Ok(updated == 1)
+    }
+
+    /// Deletes every message the user sent or received and returns how many
+    /// rows were removed.
+    pub async fn purge_messages(&self, user: &str) -> Result<u64, Error> {
+        timed(
+            "admin.purge_messages",
+            self.client.execute(
+                "DELETE FROM messages WHERE sender = $1 OR receiver = $1;",
+                &[&user],
+            ),
+        )
+        .await
+    }
+
+    /// Per-user activity as `(username, sent, received, peers, first message,
+    /// last message)`. Dates are text, `None` when the user has no messages.
+    pub async fn user_stats(&self, user: Option<&str>) -> Result<Vec<Row>, Error> {
+        timed(
+            "admin.user_stats",
+            self.client.query(
+                r"SELECT u.username,
+                        COUNT(*) FILTER (WHERE m.sender = u.username),
+                        COUNT(*) FILTER (WHERE m.receiver = u.username),
+                        COUNT(DISTINCT CASE WHEN m.sender = u.username THEN m.receiver ELSE m.sender END),
+                        to_char(MIN(m.date), 'YYYY-MM-DD HH24:MI:SS'),
+                        to_char(MAX(m.date), 'YYYY-MM-DD HH24:MI:SS')
+                    FROM users u
+                    LEFT JOIN messages m ON m.sender = u.username OR m.receiver = u.username
+                    WHERE $1::TEXT IS NULL OR u.username = $1
+                    GROUP BY u.username ORDER BY u.username ASC;",
+                &[&user],
+            ),
+        )
+        .await
+    }
+
+    /// Every live message `user` sent or received, grouped by peer and
+    /// oldest first, as `(id, sender, receiver, content, sent_at, resp_msg,
+    /// resp_user, encrypted, expires_at)`. Times are UTC text.
+    pub async fn export_messages(&self, user: &str) -> Result<Vec<Row>, Error> {
+        timed(
+            "archive.export_messages",
+            self.client.query(
+                r#"SELECT m.id_message, m.sender, m.receiver, m.content,
+                        to_char((m.date AT TIME ZONE current_setting('TimeZone')) AT TIME ZONE 'UTC',
+                            'YYYY-MM-DD"T"HH24:MI:SS.US"Z"'),
+                        m.responding_to_msg, m.responding_to_user, m.encrypted,
+                        to_char(m.expires_at AT TIME ZONE 'UTC', 'YYYY-MM-DD"T"HH24:MI:SS.US"Z"')
+                    FROM messages m
+                    WHERE (m.sender = $1 OR m.receiver = $1)
+                    AND (m.expires_at IS NULL OR m.expires_at > now())
+                    ORDER BY CASE WHEN m.sender = $1 THEN m.receiver ELSE m.sender END, m.id_message;"#,
+                &[&user],
+            ),
+        )
+        .await
+    }
+
+    /// Moves the message id sequence past `max_id` so that ids taken over
+    /// from an archive are never handed out again.
+    pub async fn reserve_message_ids(&self, max_id: i32) -> Result<(), Error> {
+        timed(
+            "archive.reserve_message_ids",
+            self.client.execute(
+                r"SELECT setval(pg_get_serial_sequence('messages', 'id_message'), GREATEST(
+                        (SELECT COALESCE(MAX(id_message), 0) FROM messages),
+                        COALESCE(pg_sequence_last_value(pg_get_serial_sequence('messages', 'id_message')::regclass), 0),
+                        $1::INT,
+                        1));",
+                &[&max_id],
+            ),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Inserts archived messages given as a JSON array. Ids are kept when
+    /// they are free and renumbered otherwise; messages already present
+    /// (same sender, receiver, time and content), messages with unknown
+    /// users and expired ones are skipped. Returns `(total, importable,
+    /// kept, renumbered)`.
+    pub async fn import_messages(&self, messages: &str) -> Result<Row, Error> {
+        timed(
+            "archive.import_messages",
+            self.client.query_one(
+                r"WITH incoming AS (
+                        SELECT * FROM jsonb_to_recordset($1::TEXT::jsonb) AS x(
+                            id INT, sender TEXT, receiver TEXT, content TEXT, sent_at TIMESTAMPTZ,
+                            resp_msg TEXT, resp_user TEXT, encrypted BOOLEAN, expires_at TIMESTAMPTZ)
+                    ),
+                    known AS (
+                        SELECT i.* FROM incoming i
+                        WHERE EXISTS (SELECT 1 FROM users WHERE username = i.sender)
+                        AND EXISTS (SELECT 1 FROM users WHERE username = i.receiver)
+                        AND (i.expires_at IS NULL OR i.expires_at > now())
+                    ),
+                    fresh AS (
+                        SELECT k.* FROM known k
+                        WHERE NOT EXISTS (SELECT 1 FROM messages m
+                            WHERE m.sender = k.sender AND m.receiver = k.receiver
+                            AND m.date = k.sent_at AND m.content = k.content)
+                    ),
+                    kept AS (
+                        INSERT INTO messages (id_message, sender, receiver, content, date,
+                            responding_to_msg, responding_to_user, encrypted, expires_at)
+                        OVERRIDING SYSTEM VALUE
+                        SELECT id, sender, receiver, content, sent_at, resp_msg, resp_user, encrypted, expires_at
+                        FROM fresh f WHERE NOT EXISTS (SELECT 1 FROM messages m WHERE m.id_message = f.id)
+                        RETURNING 1
+                    ),
+                    renumbered AS (
+                        INSERT INTO messages (sender, receiver, content, date,
+                            responding_to_msg, responding_to_user, encrypted, expires_at)
+                        SELECT sender, receiver, content, sent_at, resp_msg, resp_user, encrypted, expires_at
+                        FROM fresh f WHERE EXISTS (SELECT 1 FROM messages m WHERE m.id_message = f.id)
+                        RETURNING 1
+                    )
+                    SELECT (SELECT COUNT(*) FROM incoming), (SELECT COUNT(*) FROM known),
+                        (SELECT COUNT(*) FROM kept), (SELECT COUNT(*) FROM renumbered);",
+                &[&messages],
+            ),
+        )
+        .await
+    }
+
+    pub async fn get_role(&self, user: &str) -> Result<Option<Role>, Error> {
+        let row = timed(
+            "get_role",
+            self.client
+                .query_opt("SELECT role FROM users WHERE username = $1;", &[&user]),
+        )
+        .await?;
+        Ok(row.and_then(|r| Role::parse(r.get(0))))
+    }
+
+    /// Returns false if the user does not exist.
+    pub async fn set_role(
+        &self,
+        user: &str,
+        role: Role,
+        audit: AuditEntry<'_>,
+    ) -> Result<bool, Error> {
+        let row = timed(
+            "set_role",
+            self.client.query_one(
+                r"WITH updated AS (
+                        UPDATE users SET role = $2 WHERE username = $1 RETURNING username
+                    ), audited AS (
+                        INSERT INTO audit_log (actor, action, target, detail)
+                        SELECT $3, $4, username, $5 FROM updated
+                    )
+                    SELECT COUNT(*) FROM updated;",
+                &[
+                    &user,
+                    &role.as_str(),
+                    &audit.actor,
+                    &audit.action,
+                    &audit.detail,
+                ],
+            ),
+        )
+        .await?;
+        Ok(row.get::<_, i64>(0) == 1)
+    }
+
+    /// The longest-running active sanction of the given kind, if any.
+    pub async fn active_sanction(
+        &self,
+        user: &str,
+        kind: SanctionKind,
+    ) -> Result<Option<Sanction>, Error> {
+        let row = timed(
+            "active_sanction",
+            self.client.query_opt(
+                r"SELECT to_char(until AT TIME ZONE 'UTC', 'YYYY-MM-DD HH24:MI') || ' UTC', reason
+                    FROM sanctions
+                    WHERE username = $1 AND kind = $2 AND lifted_at IS NULL
+                        AND (until IS NULL OR until > now())
+                    ORDER BY until DESC NULLS FIRST LIMIT 1;",
+                &[&user, &kind.as_str()],
+            ),
+        )
+        .await?;
+        Ok(row.map(|r| Sanction {
+            kind,
+            until: r.get(0),
+            reason: r.get(1),
+        }))
+    }
+
+    /// `duration_secs` of `None` makes the sanction permanent. The audit
+    /// actor issued it.
+    pub async fn add_sanction(
+        &self,
+        user: &str,
+        kind: SanctionKind,
+        duration_secs: Option<i64>,
+        reason: Option<&str>,
+        audit: AuditEntry<'_>,
+    ) -> Result<(), Error> {
+        timed(
+            "add_sanction",
+            self.client.execute(
+                r"WITH added AS (
+                        INSERT INTO sanctions (username, kind, until, reason, issued_by)
+                        VALUES ($1, $2, now() + make_interval(secs => $3::BIGINT), $4, $5)
+                        RETURNING username
+                    )
+                    INSERT INTO audit_log (actor, action, target, detail)
+                    SELECT $5, $6, username, $7 FROM added;",
+                &[
+                    &user,
+                    &kind.as_str(),
+                    &duration_secs,
+                    &reason,
+                    &audit.actor,
+                    &audit.action,
+                    &audit.detail,
+                ],
+            ),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Lifts every active sanction of the given kind and returns how many
+    /// there were. Nothing is audited when there were none.
+    pub async fn lift_sanction(
+        &self,
+        user: &str,
+        kind: SanctionKind,
+        audit: AuditEntry<'_>,
+    ) -> Result<u64, Error> {
+        let row = timed(
+            "lift_sanction",
+            self.client.query_one(
+                r"WITH lifted AS (
+                        UPDATE sanctions SET lifted_at = now()
+                        WHERE username = $1 AND kind = $2 AND lifted_at IS NULL
+                            AND (until IS NULL OR until > now())
+                        RETURNING username
+                    ), audited AS (
+                        INSERT INTO audit_log (actor, action, target, detail)
+                        SELECT $3, $4, $1, $5 WHERE EXISTS (SELECT 1 FROM lifted)
+                    )
+                    SELECT COUNT(*) FROM lifted;",
+                &[
+                    &user,
+                    &kind.as_str(),
+                    &audit.actor,
+                    &audit.action,
+                    &audit.detail,
+                ],
+            ),
+        )
+        .await?;
+        Ok(row.get::<_, i64>(0) as u64)
+    }
+
+    pub async fn audit(
+        &self,
+        actor: &str,
+        action: &str,
+        target: &str,
+        detail: &str,
+    ) -> Result<(), Error> {
+        timed(
+            "audit",
+            self.client.execute(
+                "INSERT INTO audit_log (actor, action, target, detail) VALUES ($1, $2, $3, $4);",
+                &[&actor, &action, &target, &detail],
+            ),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Most recent audit entries as `(at, actor, action, target, detail)`.
+    pub async fn audit_entries(&self, limit: i64) -> Result<Vec<Row>, Error> {
+        timed(
+            "audit_entries",
+            self.client.query(
+                r"SELECT to_char(at AT TIME ZONE 'UTC', 'YYYY-MM-DD HH24:MI:SS'), actor, action, target, detail
+                    FROM audit_log ORDER BY id DESC LIMIT $1;",
+                &[&limit],
+            ),
+        )
+        .await
+    }
+
+    /// Counts a login attempt as failed against `username` and `ip` before
+    /// the password is checked, and locks out whichever reaches its
+    /// threshold (0 never locks). An empty `username` counts against the
+    /// address only.
+    ///
+    /// Attempts made while locked out or before the backoff earned by
+    /// earlier failures has passed are not counted; the seconds to wait are
+    /// returned instead. The check and the count are one statement per
+    /// scope, so parallel attempts cannot slip past either.
+    pub async fn count_login_attempt(
+        &self,
+        username: &str,
+        ip: &str,
+        (user_threshold, ip_threshold): (u32, u32),
+        lockout: Duration,
+        (backoff_base, backoff_max): (Duration, Duration),
+    ) -> Result<Option<i64>, Error> {
+        let scopes = [("user", username, user_threshold), ("ip", ip, ip_threshold)];
+        for (n, (scope, key, threshold)) in scopes.into_iter().enumerate() {
+            if key.is_empty() {
+                continue;
+            }
+            let counted = timed(
+                "count_login_attempt",
+                self.client.query_opt(
+                    r"INSERT INTO login_failures AS f (scope, key, failures, last_failure, locked_until)
+                        VALUES ($1, $2, 1, now(), CASE WHEN $4 = 1 THEN now() + make_interval(secs => $3) END)
+                        ON CONFLICT (scope, key) DO UPDATE SET
+                            failures = CASE WHEN f.last_failure > now() - make_interval(secs => $3)
+                                THEN f.failures + 1 ELSE 1 END,
+                            last_failure = now(),
+                            locked_until = CASE WHEN $4 > 0 AND $4 <= CASE WHEN f.last_failure > now() - make_interval(secs => $3)
+                                THEN f.failures + 1 ELSE 1 END
+                                THEN now() + make_interval(secs => $3) END
+                        WHERE (f.locked_until IS NULL OR f.locked_until <= now())
+                            AND (f.failures <= 0
+                                OR f.last_failure <= now() - make_interval(secs => $3)
+                                OR f.last_failure + make_interval(secs => LEAST($5 * power(2, f.failures - 1), $6)) <= now())
+                        RETURNING failures;",
+                    &[
+                        &scope,
+                        &key,
+                        &lockout.as_secs_f64(),
+                        &(threshold as i32),
+                        &backoff_base.as_secs_f64(),
+                        &backoff_max.as_secs_f64(),
+                    ],
+                ),
+            )
+            .await?
+            .is_some();
+            if counted {
+                continue;
+            }
+            // Give back what the scopes before this one counted.
+            for (scope, key, threshold) in scopes.into_iter().take(n) {
+                self.refund_login_failure(scope, key, threshold).await?;
+            }
+            let row = timed(
+                "count_login_attempt.retry_after",
+                self.client.query_opt(
+                    r"SELECT CEIL(EXTRACT(EPOCH FROM GREATEST(locked_until,
+                            CASE WHEN failures > 0 AND last_failure > now() - make_interval(secs => $3)
+                                THEN last_failure + make_interval(secs => LEAST($4 * power(2, failures - 1), $5)) END)
+                            - now()))::BIGINT
+                        FROM login_failures WHERE scope = $1 AND key = $2;",
+                    &[
+                        &scope,
+                        &key,
+                        &lockout.as_secs_f64(),
+                        &backoff_base.as_secs_f64(),
+                        &backoff_max.as_secs_f64(),
+                    ],
+                ),
             )
             .await?;
-        Ok(Some(row))
+            let secs: Option<i64> = row.and_then(|r| r.get(0));
+            return Ok(Some(secs.unwrap_or(0).max(1)));
+        }
+        Ok(None)
+    }
+
+    /// Takes back an attempt `count_login_attempt` counted that turned out
+    /// not to be a failed login.
+    pub async fn refund_login_attempt(
+        &self,
+        username: &str,
+        ip: &str,
+        (user_threshold, ip_threshold): (u32, u32),
+    ) -> Result<(), Error> {
+        for (scope, key, threshold) in
+            [("user", username, user_threshold), ("ip", ip, ip_threshold)]
+        {
+            if !key.is_empty() {
+                self.refund_login_failure(scope, key, threshold).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Lowers one failure count, lifting the lockout when it was that
+    /// attempt that reached `threshold`.
+    async fn refund_login_failure(
+        &self,
+        scope: &str,
+        key: &str,
+        threshold: u32,
+    ) -> Result<(), Error> {
+        timed(
+            "refund_login_failure",
+            self.client.execute(
+                r"UPDATE login_failures SET failures = GREATEST(failures - 1, 0),
+                        locked_until = CASE WHEN failures - 1 < $3 THEN NULL ELSE locked_until END
+                    WHERE scope = $1 AND key = $2;",
+                &[&scope, &key, &(threshold as i32)],
+            ),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Forgets the failed logins of `username` after a successful one. Those
+    /// of the address stay, a valid account does not vouch for it.
+    pub async fn clear_login_failures(&self, username: &str) -> Result<(), Error> {
+        timed(
+            "clear_login_failures",
+            self.client.execute(
+                "DELETE FROM login_failures WHERE scope = 'user' AND key = $1;",
+                &[&username],
+            ),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Lifts the lockout and forgets the failures of a username and/or an
+    /// address. Returns how many of the two were tracked.
+    pub async fn unlock_login(
+        &self,
+        username: Option<&str>,
+        ip: Option<&str>,
+    ) -> Result<u64, Error> {
+        timed(
+            "unlock_login",
+            self.client.execute(
+                "DELETE FROM login_failures WHERE (scope = 'user' AND key = $1) OR (scope = 'ip' AND key = $2);",
+                &[&username, &ip],
+            ),
+        )
+        .await
+    }
+
+    pub async fn auth_log(&self, username: &str, ip: &str, outcome: &str) -> Result<(), Error> {
+        timed(
+            "auth_log",
+            self.client.execute(
+                "INSERT INTO auth_log (username, ip, outcome) VALUES ($1, $2, $3);",
+                &[&username, &ip, &outcome],
+            ),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Most recent login attempts as `(at, username, ip, outcome)`,
+    /// optionally only those for one username or from one address.
+    pub async fn auth_log_entries(
+        &self,
+        username: Option<&str>,
+        ip: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<Row>, Error> {
+        timed(
+            "auth_log_entries",
+            self.client.query(
+                r"SELECT to_char(at AT TIME ZONE 'UTC', 'YYYY-MM-DD HH24:MI:SS'), username, ip, outcome
+                    FROM auth_log
+                    WHERE ($1::TEXT IS NULL OR username = $1) AND ($2::TEXT IS NULL OR ip = $2)
+                    ORDER BY id DESC LIMIT $3;",
+                &[&username, &ip, &limit],
+            ),
+        )
+        .await
+    }
+
+    /// Queues one delivery of `payload` per URL.
+    pub async fn enqueue_webhooks(
+        &self,
+        urls: &[String],
+        event: &str,
+        payload: &str,
+    ) -> Result<(), Error> {
+        timed(
+            "enqueue_webhooks",
+            self.client.execute(
+                "INSERT INTO webhook_deliveries (url, event, payload) SELECT unnest($1::TEXT[]), $2, $3;",
+                &[&urls, &event, &payload],
+            ),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Takes up to `limit` due deliveries as `(id, url, event, payload,
+    /// attempts)`. They are not due again for `lease`, so another instance
+    /// does not send them at the same time.
+    pub async fn claim_webhooks(&self, limit: i64, lease: Duration) -> Result<Vec<Row>, Error> {
+        timed(
+            "claim_webhooks",
+            self.client.query(
+                r"UPDATE webhook_deliveries SET next_attempt = now() + make_interval(secs => $2)
+                    WHERE id IN (
+                        SELECT id FROM webhook_deliveries
+                        WHERE status = 'pending' AND next_attempt <= now()
+                        ORDER BY next_attempt LIMIT $1
+                        FOR UPDATE SKIP LOCKED)
+                    RETURNING id, url, event, payload, attempts;",
+                &[&limit, &lease.as_secs_f64()],
+            ),
+        )
+        .await
+    }
+
+    pub async fn webhook_delivered(&self, id: i64, status: i32) -> Result<(), Error> {
+        timed(
+            "webhook_delivered",
+            self.client.execute(
+                r"UPDATE webhook_deliveries
+                    SET status = 'delivered', attempts = attempts + 1, last_status = $2,
+                        last_error = NULL, delivered_at = now()
+                    WHERE id = $1;",
+                &[&id, &status],
+            ),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Records a failed attempt. The delivery is tried again after
+    /// `retry_in`, or given up on when it is `None`.
+    pub async fn webhook_failed(
+        &self,
+        id: i64,
+        status: Option<i32>,
+        error: &str,
+        retry_in: Option<Duration>,
+    ) -> Result<(), Error> {
+        timed(
+            "webhook_failed",
+            self.client.execute(
+                r"UPDATE webhook_deliveries
+                    SET status = CASE WHEN $4::FLOAT8 IS NULL THEN 'failed' ELSE 'pending' END,
+                        attempts = attempts + 1, last_status = $2, last_error = $3,
+                        next_attempt = now() + make_interval(secs => COALESCE($4, 0))
+                    WHERE id = $1;",
+                &[&id, &status, &error, &retry_in.map(|d| d.as_secs_f64())],
+            ),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Most recent deliveries as `(id, created_at, url, event, status,
+    /// attempts, last_status, last_error)`, optionally only those with
+    /// `status`.
+    pub async fn webhook_deliveries(
+        &self,
+        status: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<Row>, Error> {
+        timed(
+            "webhook_deliveries",
+            self.client.query(
+                r"SELECT id, to_char(created_at AT TIME ZONE 'UTC', 'YYYY-MM-DD HH24:MI:SS'), url, event,
+                        status, attempts, last_status, last_error
+                    FROM webhook_deliveries
+                    WHERE $1::TEXT IS NULL OR status = $1
+                    ORDER BY id DESC LIMIT $2;",
+                &[&status, &limit],
+            ),
+        )
+        .await
+    }
+
+    /// Queues a failed delivery again, with a fresh set of attempts.
+    /// Returns false when there is no failed delivery `id`.
+    pub async fn retry_webhook(&self, id: i64) -> Result<bool, Error> {
+        let updated = timed(
+            "retry_webhook",
+            self.client.execute(
+                r"UPDATE webhook_deliveries SET status = 'pending', attempts = 0, next_attempt = now()
+                    WHERE id = $1 AND status = 'failed';",
+                &[&id],
+            ),
+        )
+        .await?;
+        Ok(updated > 0)
+    }
+
+    /// Deletes finished deliveries created more than `age` ago.
+    pub async fn prune_webhooks(&self, age: Duration) -> Result<u64, Error> {
+        timed(
+            "prune_webhooks",
+            self.client.execute(
+                r"DELETE FROM webhook_deliveries
+                    WHERE status <> 'pending' AND created_at < now() - make_interval(secs => $1);",
+                &[&age.as_secs_f64()],
+            ),
+        )
+        .await
+    }
+
+    /// Creates a bot account, driven with the API token whose hash is
+    /// `token_hash`. Its password is random, bots cannot use `/login`.
+    pub async fn create_bot(&self, username: &str, token_hash: &str) -> Result<Response, Error> {
+        let password = format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+        let resp = self
+            .signin(SigninReq {
+                username: username.to_string(),
+                password,
+            })
+            .await?;
+        if resp.succes {
+            timed(
+                "create_bot",
+                self.client.execute(
+                    "UPDATE users SET bot_token_hash = $2 WHERE username = $1;",
+                    &[&username, &token_hash],
+                ),
+            )
+            .await?;
+        }
+        Ok(resp)
+    }
+
+    /// Replaces the API token of a bot. Returns false if `username` is not a
+    /// bot account.
+    pub async fn set_bot_token(&self, username: &str, token_hash: &str) -> Result<bool, Error> {
+        let updated = timed(
+            "set_bot_token",
+            self.client.execute(
+                "UPDATE users SET bot_token_hash = $2 WHERE username = $1 AND bot_token_hash IS NOT NULL;",
+                &[&username, &token_hash],
+            ),
+        )
+        .await?;
+        Ok(updated > 0)
+    }
+
+    /// The bot holding the API token with this hash, and whether it is
+    /// disabled.
+    pub async fn bot_by_token(&self, token_hash: &str) -> Result<Option<(String, bool)>, Error> {
+        let row = timed(
+            "bot_by_token",
+            self.client.query_opt(
+                "SELECT username, disabled FROM users WHERE bot_token_hash = $1;",
+                &[&token_hash],
+            ),
+        )
+        .await?;
+        Ok(row.map(|row| (row.get(0), row.get(1))))
+    }
+
+    pub async fn is_bot(&self, username: &str) -> Result<bool, Error> {
+        let row = timed(
+            "is_bot",
+            self.client.query_opt(
+                "SELECT bot_token_hash IS NOT NULL FROM users WHERE username = $1;",
+                &[&username],
+            ),
+        )
+        .await?;
+        Ok(row.is_some_and(|row| row.get(0)))
     }
 }
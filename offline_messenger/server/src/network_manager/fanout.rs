@@ -0,0 +1,242 @@
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::{sync::Arc, time::Duration};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio_postgres::{AsyncMessage, Client, NoTls};
+use tracing::{error, info, warn};
+
+use crate::network_manager::{handlers::InternalMessage, metrics::METRICS};
+
+/// Postgres channel the instances talk on.
+const CHANNEL: &str = "messenger_fanout";
+/// NOTIFY payloads must stay below 8000 bytes.
+const MAX_PAYLOAD: usize = 7900;
+/// How often a lost bus connection is retried.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// The session a message was sent from. It already shows the message, so
+/// delivery skips it.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Origin {
+    pub instance: String,
+    pub session: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum FanoutEvent {
+    /// A new chat message for the sessions of both participants.
+    Message {
+        origin: Option<Origin>,
+        from: String,
+        to: String,
+        message: InternalMessage,
+    },
+    /// A chat message too large for a NOTIFY payload. Receivers load it
+    /// from `messages`.
+    StoredMessage {
+        origin: Option<Origin>,
+        message_id: i64,
+    },
+    /// An event for every session of one user.
+    User {
+        user: String,
+        message: InternalMessage,
+    },
+    /// Close every session of a user.
+    Kick { user: String },
+}
+
+/// Carries live events between server instances.
+///
+/// Every published event comes back to every instance, the publishing one
+/// included, through the receiver handed out when the bus is created. Each
+/// instance then delivers it to its own sessions.
+///
+/// All queues on the way are bounded. When one is full the newest event is
+/// dropped and counted; the messages themselves are already stored, so
+/// clients pick them up with their next `Sync`.
+pub trait FanoutBus: Send + Sync {
+    fn publish(&self, event: FanoutEvent);
+}
+
+/// Bus for a single instance: events go straight back to this process.
+pub struct LocalBus {
+    tx: mpsc::Sender<FanoutEvent>,
+}
+
+impl LocalBus {
+    pub fn new(capacity: usize) -> (Arc<Self>, mpsc::Receiver<FanoutEvent>) {
+        let (tx, rx) = mpsc::channel(capacity);
+        (Arc::new(Self { tx }), rx)
+    }
+}
+
+impl FanoutBus for LocalBus {
+    fn publish(&self, event: FanoutEvent) {
+        offer(&self.tx, event, "the delivery task");
+    }
+}
+
+/// Queues `item` without waiting, dropping it when `tx` is full. Returns
+/// false once the receiving task is gone.
+fn offer<T>(tx: &mpsc::Sender<T>, item: T, receiver: &str) -> bool {
+    match tx.try_send(item) {
+        Ok(()) => true,
+        Err(TrySendError::Full(_)) => {
+            METRICS.fanout_dropped.inc();
+            warn!("Dropping a fan-out event, {receiver} is behind");
+            true
+        }
+        Err(TrySendError::Closed(_)) => {
+            warn!("Dropping a fan-out event, {receiver} is gone");
+            false
+        }
+    }
+}
+
+/// Bus shared by every instance connected to the same database, over
+/// Postgres LISTEN/NOTIFY on a dedicated connection.
+///
+/// Events published while the connection is down are lost. The messages
+/// themselves are already stored, so clients see them on their next load.
+pub struct PostgresBus {
+    payloads: mpsc::Sender<String>,
+}
+
+impl PostgresBus {
+    pub async fn connect(
+        database_url: &str,
+        capacity: usize,
+    ) -> Result<(Arc<Self>, mpsc::Receiver<FanoutEvent>), tokio_postgres::Error> {
+        let (payloads, payloads_rx) = mpsc::channel(capacity);
+        let (events, events_rx) = mpsc::channel(capacity);
+        let client = listen(database_url, events.clone()).await?;
+        info!(channel = CHANNEL, "Fan-out bus connected");
+        tokio::spawn(publish_loop(
+            database_url.to_string(),
+            client,
+            payloads_rx,
+            events,
+        ));
+        Ok((Arc::new(Self { payloads }), events_rx))
+    }
+}
+
+impl FanoutBus for PostgresBus {
+    fn publish(&self, event: FanoutEvent) {
+        let mut payload = match serde_json::to_string(&event) {
+            Ok(p) => p,
+            Err(err) => {
+                error!("Error while encoding a fan-out event: {err}");
+                return;
+            }
+        };
+        if payload.len() > MAX_PAYLOAD {
+            let FanoutEvent::Message {
+                origin,
+                message: InternalMessage::Notification { message_id, .. },
+                ..
+            } = event
+            else {
+                warn!(
+                    bytes = payload.len(),
+                    "Dropping a fan-out event too large for NOTIFY"
+                );
+                return;
+            };
+            payload =
+                match serde_json::to_string(&FanoutEvent::StoredMessage { origin, message_id }) {
+                    Ok(p) => p,
+                    Err(err) => {
+                        error!("Error while encoding a fan-out event: {err}");
+                        return;
+                    }
+                };
+        }
+        offer(&self.payloads, payload, "the bus task");
+    }
+}
+
+/// Opens a connection subscribed to `CHANNEL` and forwards its
+/// notifications to `events` until the connection ends.
+async fn listen(
+    database_url: &str,
+    events: mpsc::Sender<FanoutEvent>,
+) -> Result<Client, tokio_postgres::Error> {
+    let (client, mut connection) = tokio_postgres::connect(database_url, NoTls).await?;
+    tokio::spawn(async move {
+        let mut messages = futures_util::stream::poll_fn(move |cx| connection.poll_message(cx));
+        while let Some(message) = messages.next().await {
+            match message {
+                Ok(AsyncMessage::Notification(n)) => match serde_json::from_str(n.payload()) {
+                    Ok(event) => {
+                        if !offer(&events, event, "the delivery task") {
+                            return;
+                        }
+                    }
+                    Err(err) => warn!("Ignoring an invalid fan-out event: {err}"),
+                },
+                Ok(_) => {}
+                Err(err) => {
+                    error!("Fan-out bus connection lost: {err}");
+                    return;
+                }
+            }
+        }
+    });
+    client.batch_execute(&format!("LISTEN {CHANNEL};")).await?;
+    Ok(client)
+}
+
+/// Sends queued payloads with `pg_notify`, reconnecting when the
+/// connection has dropped.
+async fn publish_loop(
+    database_url: String,
+    client: Client,
+    mut payloads: mpsc::Receiver<String>,
+    events: mpsc::Sender<FanoutEvent>,
+) {
+    let mut client = Some(client);
+    let mut health = tokio::time::interval(RECONNECT_DELAY);
+    loop {
+        tokio::select! {
+            payload = payloads.recv() => {
+                let Some(payload) = payload else {
+                    return;
+                };
+                if client.as_ref().is_none_or(Client::is_closed) {
+                    client = reconnect(&database_url, &events).await;
+                }
+                let Some(c) = &client else {
+                    warn!("Dropping a fan-out event, the bus is disconnected");
+                    continue;
+                };
+                if let Err(err) = c
+                    .execute("SELECT pg_notify($1, $2);", &[&CHANNEL, &payload])
+                    .await
+                {
+                    error!("Error while publishing a fan-out event: {err}");
+                }
+            }
+            _ = health.tick() => {
+                if client.as_ref().is_none_or(Client::is_closed) {
+                    client = reconnect(&database_url, &events).await;
+                }
+            }
+        }
+    }
+}
+
+async fn reconnect(database_url: &str, events: &mpsc::Sender<FanoutEvent>) -> Option<Client> {
+    match listen(database_url, events.clone()).await {
+        Ok(client) => {
+            info!("Fan-out bus reconnected");
+            Some(client)
+        }
+        Err(err) => {
+            warn!("Error while reconnecting the fan-out bus: {err}");
+            None
+        }
+    }
+}
@@ -1,20 +1,91 @@
 use axum::{
-    Json,
+    Extension, Json,
     extract::{
-        State,
+        ConnectInfo, Path, State,
         ws::{Message, WebSocket, WebSocketUpgrade},
     },
-    http::StatusCode,
+    http::{StatusCode, header},
     response::IntoResponse,
 };
-use futures_util::{sink::SinkExt, stream::StreamExt};
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use chrono::{DateTime, Utc};
+use futures_util::{
+    sink::SinkExt,
+    stream::{SplitSink, StreamExt},
+};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
 use tokio::sync::mpsc;
+use tokio_postgres::Row;
+use tracing::{Instrument, Span, debug, error, field, info, info_span, trace, warn};
+
+use crate::network_manager::{
+    archive::{self, ArchiveError},
+    commands::Dispatch,
+    fanout::{FanoutEvent, Origin},
+    lockout::{self, AuthOutcome},
+    logging::session_tag,
+    markdown,
+    metrics::METRICS,
+    moderation::{ModAction, Moderation, Role, SanctionKind},
+    queue::SessionHandle,
+    server::AppState,
+    tls::ClientIdentity,
+    validation,
+    webhooks::{self, WebhookEvent},
+};
+
+/// A stored message, as sent in history responses.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ChatMessage {
+    pub id: i64,
+    pub from: String,
+    pub to: String,
+    pub content: String,
+    /// The content (and quote) is an end-to-end encrypted envelope.
+    pub encrypted: bool,
+    /// When the server stored the message.
+    pub sent_at: DateTime<Utc>,
+    pub reply_to: Option<Reply>,
+    /// Last edit, `None` for messages never edited.
+    pub edited_at: Option<DateTime<Utc>>,
+}
+
+/// The message a reply quotes, and who wrote it.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Reply {
+    pub user: String,
+    pub message: String,
+}
 
-use crate::network_manager::server::AppState;
+impl ChatMessage {
+    /// From a row of `get_messages`.
+    fn from_row(row: &Row) -> Self {
+        let reply_to = match (row.get(6), row.get(7)) {
+            (Some(message), Some(user)) => Some(Reply { user, message }),
+            _ => None,
+        };
+        ChatMessage {
+            id: row.get(0),
+            from: row.get(1),
+            to: row.get(2),
+            content: row.get(3),
+            encrypted: row.get(4),
+            sent_at: row.get(5),
+            reply_to,
+            edited_at: row.get(8),
+        }
+    }
+}
 
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "type")]
 pub enum InternalMessage {
     Notification {
         sender: String,
@@ -22,28 +93,224 @@ pub enum InternalMessage {
         content: String,
         resp_msg: Option<String>,
         resp_user: Option<String>,
+        encrypted: bool,
+        message_id: i64,
     },
     Chat {
-        messages: Vec<(String, String, Option<String>, Option<String>)>,
+        messages: Vec<ChatMessage>,
     },
     Response {
         id: String,
         succes: bool,
         message: String,
+        code: Option<ErrorCode>,
+        message_id: Option<i64>,
+    },
+    ExpirySetting {
+        with: String,
+        expiry_secs: Option<i64>,
+        changed_by: Option<String>,
+    },
+    MessagesExpired {
+        ids: Vec<i64>,
+    },
+    ScheduledList {
+        items: Vec<ScheduledItem>,
+    },
+    ScheduledDelivered {
+        scheduled_id: i64,
+        message_id: i64,
     },
     Users {
         users_list: Vec<String>,
     },
+    Conversations {
+        conversations: Vec<Conversation>,
+    },
+    ConversationUpdate {
+        conversation: Conversation,
+    },
+    Synced {
+        messages: Vec<ChatMessage>,
+        deleted: Vec<i64>,
+        reads: Vec<ReadMark>,
+        cursor: i64,
+        more: bool,
+        reset: bool,
+    },
+    ServerShutdown {
+        reconnect_after_ms: u64,
+    },
+    CommandOutput {
+        id: String,
+        text: String,
+    },
+}
+
+impl InternalMessage {
+    /// What the message is, for the logs.
+    fn what(&self) -> &'static str {
+        match self {
+            InternalMessage::Notification { .. } => "a message",
+            InternalMessage::Response { .. } => "a response",
+            InternalMessage::Chat { .. } => "the history",
+            InternalMessage::Users { .. } => "the user list",
+            InternalMessage::ExpirySetting { .. } => "the expiry setting",
+            InternalMessage::ScheduledList { .. } => "the scheduled messages",
+            InternalMessage::ScheduledDelivered { .. } => "a scheduled delivery",
+            InternalMessage::MessagesExpired { .. } => "expired messages",
+            InternalMessage::ServerShutdown { .. } => "the shutdown notice",
+            InternalMessage::CommandOutput { .. } => "command output",
+            InternalMessage::Conversations { .. } => "the inbox",
+            InternalMessage::ConversationUpdate { .. } => "an inbox update",
+            InternalMessage::Synced { .. } => "a sync page",
+        }
+    }
+}
+
+impl From<InternalMessage> for WsMessageBack {
+    fn from(message: InternalMessage) -> Self {
+        match message {
+            InternalMessage::Notification {
+                sender,
+                reciever,
+                content,
+                resp_msg,
+                resp_user,
+                encrypted,
+                message_id,
+            } => WsMessageBack::Message {
+                from: sender,
+                to: reciever,
+                message: content,
+                resp_msg,
+                resp_user,
+                encrypted,
+                message_id,
+            },
+            InternalMessage::Response {
+                id,
+                succes,
+                message,
+                code,
+                message_id,
+            } => WsMessageBack::Response {
+                id,
+                succes,
+                message,
+                code,
+                message_id,
+            },
+            InternalMessage::Chat { messages } => WsMessageBack::Chat { messages },
+            InternalMessage::Users { users_list } => WsMessageBack::UserList { list: users_list },
+            InternalMessage::ExpirySetting {
+                with,
+                expiry_secs,
+                changed_by,
+            } => WsMessageBack::ExpirySetting {
+                with,
+                expiry_secs,
+                changed_by,
+            },
+            InternalMessage::ScheduledList { items } => WsMessageBack::ScheduledList { items },
+            InternalMessage::ScheduledDelivered {
+                scheduled_id,
+                message_id,
+            } => WsMessageBack::ScheduledDelivered {
+                scheduled_id,
+                message_id,
+            },
+            InternalMessage::MessagesExpired { ids } => WsMessageBack::MessagesExpired { ids },
+            InternalMessage::ServerShutdown { reconnect_after_ms } => {
+                WsMessageBack::ServerShutdown { reconnect_after_ms }
+            }
+            InternalMessage::CommandOutput { id, text } => {
+                WsMessageBack::CommandOutput { id, text }
+            }
+            InternalMessage::Conversations { conversations } => {
+                WsMessageBack::Conversations { conversations }
+            }
+            InternalMessage::ConversationUpdate { conversation } => {
+                WsMessageBack::ConversationUpdate { conversation }
+            }
+            InternalMessage::Synced {
+                messages,
+                deleted,
+                reads,
+                cursor,
+                more,
+                reset,
+            } => WsMessageBack::Synced {
+                messages,
+                deleted,
+                reads,
+                cursor,
+                more,
+                reset,
+            },
+        }
+    }
+}
+
+/// A message waiting in `scheduled_messages`, as shown to its sender.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct ScheduledItem {
+    pub scheduled_id: i64,
+    pub to: String,
+    pub message: String,
+    pub resp_msg: Option<String>,
+    pub resp_user: Option<String>,
+    pub encrypted: bool,
+    /// Unix seconds.
+    pub deliver_at: i64,
+}
+
+/// One entry of a user's inbox.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct Conversation {
+    pub with: String,
+    /// Sender of the newest message, `None` if there is none yet.
+    pub last_from: Option<String>,
+    /// The newest message, shortened unless it is encrypted.
+    pub last_message: Option<String>,
+    pub encrypted: bool,
+    /// Unix seconds.
+    pub last_at: Option<i64>,
+    pub last_message_id: Option<i64>,
+    /// Messages from `with` not yet marked read.
+    pub unread: i64,
 }
 
-#[derive(Deserialize, Clone)]
+/// Where `user` has read the conversation with `with` up to.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct ReadMark {
+    pub user: String,
+    pub with: String,
+    pub last_read: i64,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct ScheduledMessage {
+    pub to: String,
+    pub message: String,
+    pub resp_msg: Option<String>,
+    pub resp_user: Option<String>,
+    #[serde(default)]
+    pub encrypted: bool,
+    /// Unix seconds.
+    pub deliver_at: i64,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct SigninReq {
     pub username: String,
     pub password: String,
 }
-#[derive(Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct LoginReq {
     pub username: String,
+    /// May be empty when the connection has a client certificate.
+    #[serde(default)]
     pub password: String,
 }
 #[derive(Serialize, Deserialize)]
@@ -51,9 +318,10 @@ pub struct SessionInfo {
     pub username: String,
     pub token: String,
 }
+/// Requests a client sends over `/ws` after the session info frame.
 #[derive(Deserialize, Serialize, Clone)]
 #[serde(tag = "type")]
-enum WsMessage {
+pub enum WsMessage {
     SendMessage {
         id: String,
         token: String,
@@ -62,6 +330,8 @@ enum WsMessage {
         message: String,
         resp_msg: Option<String>,
         resp_user: Option<String>,
+        #[serde(default)]
+        encrypted: bool,
     },
     GetMessage {
         from: String,
@@ -70,56 +340,288 @@ enum WsMessage {
     GetUserList {
         user: String,
     },
+    GetConversations,
+    /// Marks the messages received from `with` up to `message_id` as read.
+    MarkRead {
+        with: String,
+        message_id: i64,
+    },
+    /// Asks for what changed after the sync cursor `since`, across all
+    /// conversations. Without one, only the current cursor is sent.
+    Sync {
+        since: Option<i64>,
+    },
+    SetExpiry {
+        id: String,
+        with: String,
+        expiry_secs: Option<i64>,
+    },
+    ScheduleMessage {
+        id: String,
+        #[serde(flatten)]
+        message: ScheduledMessage,
+    },
+    ListScheduled,
+    CancelScheduled {
+        id: String,
+        scheduled_id: i64,
+    },
+    BanUser {
+        id: String,
+        user: String,
+        duration_secs: Option<i64>,
+        reason: Option<String>,
+    },
+    UnbanUser {
+        id: String,
+        user: String,
+    },
+    MuteUser {
+        id: String,
+        user: String,
+        duration_secs: Option<i64>,
+        reason: Option<String>,
+    },
+    UnmuteUser {
+        id: String,
+        user: String,
+    },
+    SetRole {
+        id: String,
+        user: String,
+        role: Role,
+    },
+    /// Ends the session now instead of keeping it for a reconnect.
+    Logout,
 }
 
+/// Frames the server sends over `/ws`.
 #[derive(Deserialize, Serialize, Clone)]
 #[serde(tag = "type")]
-enum WsMessageBack {
+pub enum WsMessageBack {
     Message {
         from: String,
         to: String,
         message: String,
         resp_msg: Option<String>,
         resp_user: Option<String>,
+        encrypted: bool,
+        message_id: i64,
     },
     Response {
         id: String,
         succes: bool,
         message: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        code: Option<ErrorCode>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        message_id: Option<i64>,
+    },
+    ExpirySetting {
+        with: String,
+        expiry_secs: Option<i64>,
+        changed_by: Option<String>,
+    },
+    MessagesExpired {
+        ids: Vec<i64>,
+    },
+    ScheduledList {
+        items: Vec<ScheduledItem>,
+    },
+    ScheduledDelivered {
+        scheduled_id: i64,
+        message_id: i64,
     },
     Chat {
-        messages: Vec<(String, String, Option<String>, Option<String>)>,
+        messages: Vec<ChatMessage>,
     },
     UserList {
         list: Vec<String>,
     },
+    /// The inbox, newest activity first.
+    Conversations {
+        conversations: Vec<Conversation>,
+    },
+    /// One inbox entry changed: a message came in or went out, or it was
+    /// read on another device.
+    ConversationUpdate {
+        conversation: Conversation,
+    },
+    /// Changes after the requested cursor, oldest first: messages stored or
+    /// edited, ids of deleted messages and moved read marks. `cursor` is
+    /// the one to send next time. With `more`, there is another page to
+    /// ask for right away. With `reset`, the cursor was too old and the
+    /// client has to reload everything.
+    Synced {
+        messages: Vec<ChatMessage>,
+        deleted: Vec<i64>,
+        reads: Vec<ReadMark>,
+        cursor: i64,
+        more: bool,
+        reset: bool,
+    },
+    ServerShutdown {
+        reconnect_after_ms: u64,
+    },
+    /// What a slash command sent as message `id` printed. Only the sender
+    /// sees it.
+    CommandOutput {
+        id: String,
+        text: String,
+    },
+}
+
+/// Machine-readable reason attached to failed responses.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    Banned,
+    Muted,
+    Forbidden,
+    NotFound,
+    InvalidRequest,
+    InvalidUsername,
+    UsernameTaken,
+    InvalidMessage,
+    MessageTooLarge,
+    InvalidCredentials,
+    Disabled,
+    TooManyAttempts,
+    UnknownCommand,
+    Internal,
+}
+
+#[derive(Deserialize)]
+pub struct PublishKeyReq {
+    pub username: String,
+    pub token: String,
+    pub device_id: String,
+    pub public_key: String,
+}
+
+#[derive(Deserialize)]
+pub struct ExportReq {
+    pub username: String,
+    pub token: String,
+}
+
+#[derive(Deserialize)]
+pub struct ImportReq {
+    pub username: String,
+    pub token: String,
+    /// Base64 of the tar archive produced by an export.
+    pub archive: String,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Response {
     pub succes: bool,
     pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<ErrorCode>,
+    /// Id of the stored message, for requests that create one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<i64>,
+}
+
+/// Limits for scheduled messages.
+const MAX_SCHEDULE_AHEAD_SECS: i64 = 365 * 86_400;
+const MAX_PENDING_SCHEDULED: i64 = 100;
+
+/// Characters of the newest message shown in the inbox.
+const PREVIEW_CHARS: usize = 100;
+
+/// Most messages sent in one `Synced` page.
+const SYNC_PAGE: i64 = 500;
+
+/// Bounds for the disappearing-messages timer.
+const MIN_EXPIRY_SECS: i64 = 5;
+const MAX_EXPIRY_SECS: i64 = 30 * 86_400;
+
+/// Longest `SendMessage` id kept for recognising retries. Clients use UUIDs.
+const MAX_CLIENT_ID_LEN: usize = 64;
+
+/// User named by the connection's client certificate, if it sent one.
+fn certificate_user(identity: &Option<Extension<ClientIdentity>>) -> Option<&str> {
+    identity
+        .as_ref()
+        .and_then(|Extension(identity)| identity.user.as_deref())
+}
+
+/// Why `username` may not be used on a connection whose client certificate
+/// names someone else.
+fn certificate_mismatch(
+    identity: &Option<Extension<ClientIdentity>>,
+    username: &str,
+) -> Option<String> {
+    match certificate_user(identity) {
+        Some(user) if user != username => {
+            Some(format!("The client certificate belongs to {user}."))
+        }
+        _ => None,
+    }
 }
 
 pub struct Handlers {}
 impl Handlers {
     pub async fn signin(
         State(app_state): State<Arc<AppState>>,
-        Json(payload): Json<SigninReq>,
+        identity: Option<Extension<ClientIdentity>>,
+        Json(mut payload): Json<SigninReq>,
     ) -> impl IntoResponse {
-        println!("Sign in attempt");
+        Span::current().record("user", payload.username.as_str());
+        info!("Sign in attempt");
+        payload.username = match validation::username(&payload.username, &app_state.config) {
+            Ok(username) => username,
+            Err(err) => {
+                info!("Sign in rejected: {err}");
+                return (
+                    StatusCode::BAD_REQUEST,
+                    Json(Response {
+                        succes: false,
+                        message: err.to_string(),
+                        code: Some(err.code()),
+                        message_id: None,
+                    }),
+                );
+            }
+        };
+        if let Some(message) = certificate_mismatch(&identity, &payload.username) {
+            warn!("Sign in rejected: {message}");
+            return (
+                StatusCode::FORBIDDEN,
+                Json(Response {
+                    succes: false,
+                    message,
+                    code: Some(ErrorCode::Forbidden),
+                    message_id: None,
+                }),
+            );
+        }
         match app_state.database.signin(payload.clone()).await {
             Ok(r) => match r.succes {
-                true => (StatusCode::CREATED, Json(r)),
+                true => {
+                    webhooks::enqueue(
+                        &app_state.database,
+                        &app_state.config,
+                        WebhookEvent::UserSignedUp {
+                            username: payload.username,
+                        },
+                    )
+                    .await;
+                    (StatusCode::CREATED, Json(r))
+                }
                 false => (StatusCode::CONFLICT, Json(r)),
             },
             Err(err) => {
-                println!("Error during sign in: {err}");
+                error!("Error during sign in: {err}");
                 (
                     StatusCode::INTERNAL_SERVER_ERROR,
                     Json(Response {
                         succes: false,
                         message: "Internal server error".to_string(),
+                        code: Some(ErrorCode::Internal),
+                        message_id: None,
                     }),
                 )
             }
@@ -128,52 +630,449 @@ impl Handlers {
 
     pub async fn login(
         State(app_state): State<Arc<AppState>>,
-        Json(payload): Json<LoginReq>,
-    ) -> impl IntoResponse {
-        println!("Login attempt as {}", payload.username.clone());
-        match app_state.database.login(payload.clone()).await {
+        identity: Option<Extension<ClientIdentity>>,
+        ConnectInfo(peer): ConnectInfo<SocketAddr>,
+        Json(mut payload): Json<LoginReq>,
+    ) -> axum::response::Response {
+        Span::current().record("user", payload.username.as_str());
+        info!("Login attempt");
+        payload.username = validation::login_username(&payload.username);
+        let ip = peer.ip().to_string();
+        let database = &app_state.database;
+        let config = &app_state.config;
+        // Everything below, lockouts included, uses the stored spelling.
+        match database.account_name(&payload.username).await {
+            Ok(Some(username)) => payload.username = username,
+            Ok(None) => {}
+            Err(err) => {
+                error!("Error while looking up the account: {err}");
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(json!({
+                        "succes": false,
+                        "token": "".to_string(),
+                        "message": "Internal server error".to_string(),
+                        "code": ErrorCode::Internal,
+                    })),
+                )
+                    .into_response();
+            }
+        }
+        if let Some(message) = certificate_mismatch(&identity, &payload.username) {
+            METRICS.login(false);
+            warn!("Login rejected: {message}");
+            lockout::record(database, &payload.username, &ip, AuthOutcome::Forbidden).await;
+            return (
+                StatusCode::FORBIDDEN,
+                Json(json!({
+                    "succes": false,
+                    "token": "".to_string(),
+                    "message": message,
+                    "code": ErrorCode::Forbidden,
+                })),
+            )
+                .into_response();
+        }
+        // A client certificate signed by the configured CA stands in for the
+        // password. It cannot be guessed, so it is not subject to lockouts.
+        let with_certificate = certificate_user(&identity).is_some() && payload.password.is_empty();
+        if !with_certificate {
+            match lockout::check(database, config, &payload.username, &ip).await {
+                Ok(None) => {}
+                Ok(Some(retry_after)) => {
+                    METRICS.login(false);
+                    warn!(%ip, retry_after, "Login refused, locked out");
+                    lockout::record(database, &payload.username, &ip, AuthOutcome::LockedOut).await;
+                    return (
+                        StatusCode::TOO_MANY_REQUESTS,
+                        [(header::RETRY_AFTER, retry_after.to_string())],
+                        Json(json!({
+                            "succes": false,
+                            "token": "".to_string(),
+                            "message": format!(
+                                "Too many failed logins, try again in {retry_after} seconds."
+                            ),
+                            "code": ErrorCode::TooManyAttempts,
+                            "retry_after": retry_after,
+                        })),
+                    )
+                        .into_response();
+                }
+                Err(err) => {
+                    error!("Error while checking failed logins: {err}");
+                    return (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        Json(json!({
+                            "succes": false,
+                            "token": "".to_string(),
+                            "message": "Internal server error".to_string(),
+                            "code": ErrorCode::Internal,
+                        })),
+                    )
+                        .into_response();
+                }
+            }
+        }
+        let checked = if with_certificate {
+            app_state
+                .database
+                .login_with_certificate(&payload.username)
+                .await
+        } else {
+            app_state.database.login(payload.clone()).await
+        };
+        let result = match checked {
+            Ok(r) if r.succes => {
+                match app_state
+                    .database
+                    .active_sanction(&payload.username, SanctionKind::Ban)
+                    .await
+                {
+                    Ok(Some(ban)) => Ok(Response {
+                        succes: false,
+                        message: ban.describe(),
+                        code: Some(ban.code()),
+                        message_id: None,
+                    }),
+                    Ok(None) => Ok(r),
+                    Err(err) => Err(err),
+                }
+            }
+            other => other,
+        };
+        let outcome = result.as_ref().ok().map(|r| match r.succes {
+            true => AuthOutcome::Success,
+            false => AuthOutcome::from_code(r.code),
+        });
+        if let Some(outcome) = outcome {
+            lockout::record(database, &payload.username, &ip, outcome).await;
+        }
+        if !with_certificate && outcome != Some(AuthOutcome::InvalidCredentials) {
+            lockout::refund(database, config, &payload.username, &ip).await;
+        }
+        match result {
             Ok(r) => match r.succes {
                 true => {
+                    METRICS.login(true);
                     let token = app_state.session_manager.new_session(&payload.username);
+                    info!(session = session_tag(&token), "Login succeeded");
                     (
                         StatusCode::OK,
                         Json(json!({
                             "succes": r.succes,
                             "token": token,
+                            "username": payload.username,
                             "message": r.message,
                         })),
                     )
+                        .into_response()
+                }
+                false => {
+                    METRICS.login(false);
+                    info!("Login rejected");
+                    let status = match r.code {
+                        Some(ErrorCode::Banned) => StatusCode::FORBIDDEN,
+                        _ => StatusCode::UNAUTHORIZED,
+                    };
+                    (
+                        status,
+                        Json(json!({
+                            "succes": r.succes,
+                            "token": "".to_string(),
+                            "message": r.message,
+                            "code": r.code,
+                        })),
+                    )
+                        .into_response()
                 }
-                false => (
-                    StatusCode::UNAUTHORIZED,
-                    Json(json!({
-                        "succes": r.succes,
-                        "token": "".to_string(),
-                        "message": r.message,
-                    })),
-                ),
             },
             Err(err) => {
-                println!("Error during sign in: {err}");
+                error!("Error during login: {err}");
                 (
                     StatusCode::INTERNAL_SERVER_ERROR,
                     Json(json!({
                         "succes": false,
                         "token": "".to_string(),
                         "message": "Internal server error".to_string(),
+                        "code": ErrorCode::Internal,
+                    })),
+                )
+                    .into_response()
+            }
+        }
+    }
+
+    /// Publishes the X25519 identity key of one of the caller's devices.
+    pub async fn publish_key(
+        State(app_state): State<Arc<AppState>>,
+        Json(payload): Json<PublishKeyReq>,
+    ) -> impl IntoResponse {
+        Span::current().record("user", payload.username.as_str());
+        let reject = |status: StatusCode, code: ErrorCode, message: &str| {
+            (
+                status,
+                Json(Response {
+                    succes: false,
+                    message: message.to_string(),
+                    code: Some(code),
+                    message_id: None,
+                }),
+            )
+        };
+        if app_state.session_manager.user_for(&payload.token).as_ref() != Some(&payload.username) {
+            return reject(
+                StatusCode::UNAUTHORIZED,
+                ErrorCode::Forbidden,
+                "Invalid session token",
+            );
+        }
+        let key_ok = BASE64
+            .decode(&payload.public_key)
+            .is_ok_and(|key| key.len() == 32);
+        let device_ok = !payload.device_id.is_empty()
+            && payload.device_id.len() <= 64
+            && payload
+                .device_id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-');
+        if !key_ok || !device_ok {
+            return reject(
+                StatusCode::BAD_REQUEST,
+                ErrorCode::InvalidRequest,
+                "Invalid device id or public key",
+            );
+        }
+        match app_state
+            .database
+            .put_device_key(&payload.username, &payload.device_id, &payload.public_key)
+            .await
+        {
+            Ok(()) => {
+                info!(device = %payload.device_id, "Device key published");
+                (
+                    StatusCode::OK,
+                    Json(Response {
+                        succes: true,
+                        message: "Key published".to_string(),
+                        code: None,
+                        message_id: None,
+                    }),
+                )
+            }
+            Err(err) => {
+                error!("Error while working with the database: {err}");
+                reject(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    ErrorCode::Internal,
+                    "Internal server error",
+                )
+            }
+        }
+    }
+
+    /// Key directory lookup: every published device key of `user`.
+    pub async fn device_keys(
+        State(app_state): State<Arc<AppState>>,
+        Path(user): Path<String>,
+    ) -> impl IntoResponse {
+        match app_state.database.device_keys(&user).await {
+            Ok(Some(rows)) => {
+                let keys: Vec<_> = rows
+                    .iter()
+                    .map(|row| {
+                        json!({
+                            "device_id": row.get::<_, String>(0),
+                            "public_key": row.get::<_, String>(1),
+                        })
+                    })
+                    .collect();
+                (StatusCode::OK, Json(json!({ "user": user, "keys": keys })))
+            }
+            Ok(None) => (
+                StatusCode::NOT_FOUND,
+                Json(json!({
+                    "succes": false,
+                    "message": format!("No user named {user}"),
+                    "code": ErrorCode::NotFound,
+                })),
+            ),
+            Err(err) => {
+                error!("Error while working with the database: {err}");
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(json!({
+                        "succes": false,
+                        "message": "Internal server error",
+                        "code": ErrorCode::Internal,
                     })),
                 )
             }
         }
     }
 
+    /// Downloads the caller's whole history as a tar archive.
+    pub async fn export_history(
+        State(app_state): State<Arc<AppState>>,
+        Json(payload): Json<ExportReq>,
+    ) -> axum::response::Response {
+        Span::current().record("user", payload.username.as_str());
+        if app_state.session_manager.user_for(&payload.token).as_ref() != Some(&payload.username) {
+            return (
+                StatusCode::UNAUTHORIZED,
+                Json(Response {
+                    succes: false,
+                    message: "Invalid session token".to_string(),
+                    code: Some(ErrorCode::Forbidden),
+                    message_id: None,
+                }),
+            )
+                .into_response();
+        }
+        match archive::export(&app_state.database, &payload.username).await {
+            Ok(data) => {
+                let file: String = payload
+                    .username
+                    .chars()
+                    .filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_')
+                    .collect();
+                (
+                    StatusCode::OK,
+                    [
+                        (header::CONTENT_TYPE, "application/x-tar".to_string()),
+                        (
+                            header::CONTENT_DISPOSITION,
+                            format!("attachment; filename=\"{file}-history.tar\""),
+                        ),
+                    ],
+                    data,
+                )
+                    .into_response()
+            }
+            Err(err) => {
+                error!("Error while exporting the history: {err}");
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(Response {
+                        succes: false,
+                        message: "Internal server error".to_string(),
+                        code: Some(ErrorCode::Internal),
+                        message_id: None,
+                    }),
+                )
+                    .into_response()
+            }
+        }
+    }
+
+    /// Loads an exported archive, for moving users between servers.
+    ///
+    /// Admins only: an archive can hold messages in anyone's name.
+    pub async fn import_history(
+        State(app_state): State<Arc<AppState>>,
+        Json(payload): Json<ImportReq>,
+    ) -> axum::response::Response {
+        Span::current().record("user", payload.username.as_str());
+        let reject = |status: StatusCode, code: ErrorCode, message: &str| {
+            (
+                status,
+                Json(Response {
+                    succes: false,
+                    message: message.to_string(),
+                    code: Some(code),
+                    message_id: None,
+                }),
+            )
+                .into_response()
+        };
+        if app_state.session_manager.user_for(&payload.token).as_ref() != Some(&payload.username) {
+            return reject(
+                StatusCode::UNAUTHORIZED,
+                ErrorCode::Forbidden,
+                "Invalid session token",
+            );
+        }
+        match app_state.database.get_role(&payload.username).await {
+            Ok(Some(Role::Admin)) => {}
+            Ok(_) => {
+                return reject(
+                    StatusCode::FORBIDDEN,
+                    ErrorCode::Forbidden,
+                    "Only admins can import history",
+                );
+            }
+            Err(err) => {
+                error!("Error while working with the database: {err}");
+                return reject(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    ErrorCode::Internal,
+                    "Internal server error",
+                );
+            }
+        }
+        let Ok(data) = BASE64.decode(&payload.archive) else {
+            return reject(
+                StatusCode::BAD_REQUEST,
+                ErrorCode::InvalidRequest,
+                "The archive is not valid base64",
+            );
+        };
+        match archive::import(&app_state.database, &data).await {
+            Ok(report) => {
+                if let Err(err) = app_state
+                    .database
+                    .audit(&payload.username, "import", &report.user, &report.detail())
+                    .await
+                {
+                    warn!("Error while writing the audit log: {err}");
+                }
+                (
+                    StatusCode::OK,
+                    Json(json!({
+                        "succes": true,
+                        "message": "History imported",
+                        "report": report,
+                    })),
+                )
+                    .into_response()
+            }
+            Err(ArchiveError::Invalid(reason)) => reject(
+                StatusCode::BAD_REQUEST,
+                ErrorCode::InvalidRequest,
+                &format!("Invalid archive: {reason}"),
+            ),
+            Err(err) => {
+                error!("Error while importing the history: {err}");
+                reject(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    ErrorCode::Internal,
+                    "Internal server error",
+                )
+            }
+        }
+    }
+
     pub async fn ws_handler(
         ws: WebSocketUpgrade,
+        ConnectInfo(peer): ConnectInfo<SocketAddr>,
+        identity: Option<Extension<ClientIdentity>>,
         State(app_state): State<Arc<AppState>>,
     ) -> impl IntoResponse {
-        ws.on_upgrade(move |socket| Handlers::handle_socket(socket, app_state.clone()))
+        let certificate_user = certificate_user(&identity).map(str::to_string);
+        let span = info_span!(
+            "ws_session",
+            peer = %peer,
+            user = field::Empty,
+            session = field::Empty,
+        );
+        ws.on_upgrade(move |socket| {
+            Handlers::handle_socket(socket, app_state.clone(), certificate_user).instrument(span)
+        })
     }
-    async fn handle_socket(socket: WebSocket, app_state: Arc<AppState>) {
+    async fn handle_socket(
+        socket: WebSocket,
+        app_state: Arc<AppState>,
+        certificate_user: Option<String>,
+    ) {
         let (mut sender, mut receiver) = socket.split();
         let session_info;
         if let Some(Ok(Message::Text(raw_json))) = receiver.next().await {
@@ -181,389 +1080,369 @@ impl Handlers {
             session_info = match message {
                 Ok(m) => m,
                 Err(err) => {
-                    println!("Error at the start message: {err}");
+                    warn!("Error at the start message: {err}");
                     return;
                 }
             };
         } else {
-            println!("Error at the start message: idk");
+            warn!("Connection closed before the start message");
             return;
         }
-        let (tx, mut rx) = mpsc::unbounded_channel::<InternalMessage>();
+        if app_state
+            .session_manager
+            .user_for(&session_info.token)
+            .as_ref()
+            != Some(&session_info.username)
+        {
+            warn!(user = %session_info.username, "Rejected WebSocket with an invalid session token");
+            return;
+        }
+        if certificate_user
+            .as_ref()
+            .is_some_and(|user| *user != session_info.username)
+        {
+            warn!(user = %session_info.username, "Rejected WebSocket with another user's client certificate");
+            return;
+        }
+        match app_state
+            .database
+            .active_sanction(&session_info.username, SanctionKind::Ban)
+            .await
+        {
+            Ok(None) => {}
+            Ok(Some(_)) => {
+                warn!(user = %session_info.username, "Rejected WebSocket of a banned user");
+                app_state.session_manager.close_session(&session_info.token);
+                return;
+            }
+            Err(err) => {
+                error!("Error while working with the database: {err}");
+                return;
+            }
+        }
+        let (session, mut rx) = SessionHandle::new(
+            app_state.config.queue_capacity,
+            app_state.config.session_spill_capacity(),
+        );
+        let session_id = session.id;
 
-        let tx_clone = tx.clone();
+        let tx_clone = session.tx.clone();
+        let kick = session.kick.clone();
         {
             let mut map = match app_state.map.lock() {
                 Ok(m) => m,
                 Err(err) => {
-                    println!("Error while locking the map in app_state: {err}");
+                    error!("Error while locking the map in app_state: {err}");
                     return;
                 }
             };
             let sessions = map
                 .entry(session_info.username.clone())
                 .or_insert(HashMap::new());
-            sessions.insert(session_info.token.clone(), tx);
-        }
-        println!("User {} is now connected.", session_info.username.clone());
-
-        let send_task = tokio::spawn(async move {
-            while let Some(msg) = rx.recv().await {
-                match msg {
-                    InternalMessage::Notification {
-                        sender: s,
-                        reciever: r,
-                        content: c,
-                        resp_msg: r_m,
-                        resp_user: r_u,
-                    } => {
-                        let r = WsMessageBack::Message {
-                            from: s,
-                            to: r,
-                            message: c,
-                            resp_msg: r_m,
-                            resp_user: r_u,
-                        };
-                        if let Ok(message) = serde_json::to_string(&r) {
-                            match sender.send(Message::Text(message.into())).await {
-                                Ok(_) => {}
-                                Err(err) => {
-                                    println!("Error while sending message to client: {err}");
-                                    break;
-                                }
-                            }
-                        }
-                    }
-                    InternalMessage::Response {
-                        id: idx,
-                        succes: s,
-                        message: m,
-                    } => {
-                        let r = WsMessageBack::Response {
-                            id: idx,
-                            succes: s,
-                            message: m,
-                        };
-                        if let Ok(message) = serde_json::to_string(&r) {
-                            match sender.send(Message::Text(message.into())).await {
-                                Ok(_) => {}
-                                Err(err) => {
-                                    println!("Error while sending message to client: {err}");
-                                    break;
-                                }
-                            }
-                        }
-                    }
-                    InternalMessage::Chat {
-                        messages: chat_messages,
-                    } => {
-                        let r = WsMessageBack::Chat {
-                            messages: chat_messages,
-                        };
-                        if let Ok(chat) = serde_json::to_string(&r) {
-                            match sender.send(Message::Text(chat.into())).await {
-                                Ok(_) => {}
-                                Err(err) => {
-                                    println!("Error while sending message to client: {err}");
-                                    break;
-                                }
-                            }
-                        }
-                    }
-                    InternalMessage::Users { users_list } => {
-                        let r = WsMessageBack::UserList { list: users_list };
-                        if let Ok(epstein) = serde_json::to_string(&r) {
-                            match sender.send(Message::Text(epstein.into())).await {
-                                Ok(_) => {}
-                                Err(err) => {
-                                    println!("Error while sending message to client: {err}");
-                                    break;
-                                }
-                            }
-                        }
+            sessions.insert(session_info.token.clone(), session);
+        }
+        app_state.session_manager.attach(&session_info.token);
+        if let Err(err) = app_state
+            .database
+            .set_online(&app_state.instance_id, &session_info.username)
+            .await
+        {
+            error!("Error while updating presence: {err}");
+        }
+        app_state.open_sessions.send_modify(|n| *n += 1);
+        Span::current().record("user", session_info.username.as_str());
+        Span::current().record("session", session_tag(&session_info.token));
+        info!("User is now connected");
+        webhooks::enqueue(
+            &app_state.database,
+            &app_state.config,
+            WebhookEvent::UserConnected {
+                username: session_info.username.clone(),
+            },
+        )
+        .await;
+
+        let mut send_task = tokio::spawn(
+            async move {
+                while let Some(msg) = rx.recv().await {
+                    if !Handlers::send_frame(&mut sender, msg).await {
+                        break;
                     }
                 }
+                let _ = sender.send(Message::Close(None)).await;
             }
-        });
+            .instrument(Span::current()),
+        );
 
-        while let Some(Ok(msg)) = receiver.next().await {
+        let mut shutdown = app_state.shutdown.subscribe();
+        let mut drain = true;
+        loop {
+            let msg = tokio::select! {
+                msg = receiver.next() => msg,
+                _ = kick.notified() => {
+                    warn!("Closing the session: kicked by the server");
+                    drain = false;
+                    break;
+                }
+                _ = shutdown.wait_for(|s| *s) => {
+                    info!("Closing the session: server is shutting down");
+                    break;
+                }
+            };
+            let Some(Ok(msg)) = msg else {
+                break;
+            };
             if let Message::Text(raw_json) = msg {
                 let message: Result<WsMessage, _> = serde_json::from_str(&raw_json);
                 match message {
                     Ok(WsMessage::SendMessage {
                         id,
-                        token,
+                        token: _,
                         from,
                         to,
                         message,
                         resp_msg,
                         resp_user,
+                        encrypted,
                     }) => {
-                        println!("Sending message from {} to {}", from.clone(), to.clone());
-                        if let (Some(r_m), Some(r_u)) = (resp_msg, resp_user) {
-                            match app_state
+                        debug!(%from, %to, encrypted, "Sending message");
+                        if app_state.config.log_message_content && !encrypted {
+                            trace!(content = %message, "Message content");
+                        }
+                        let checked =
+                            validation::message(&message, app_state.config.max_message_bytes);
+                        let (message, rejection) = match checked {
+                            _ if from != session_info.username => (
+                                message,
+                                Some((
+                                    ErrorCode::Forbidden,
+                                    "You can only send messages as yourself".to_string(),
+                                )),
+                            ),
+                            _ if id.len() > MAX_CLIENT_ID_LEN => (
+                                message,
+                                Some((
+                                    ErrorCode::InvalidRequest,
+                                    format!("Message ids are limited to {MAX_CLIENT_ID_LEN} bytes"),
+                                )),
+                            ),
+                            Err(err) => (message, Some((err.code(), err.to_string()))),
+                            Ok(cleaned) => {
+                                (cleaned, Handlers::mute_rejection(&app_state, &from).await)
+                            }
+                        };
+                        if let Some((code, message)) = rejection {
+                            METRICS.messages_failed.inc();
+                            let response = InternalMessage::Response {
+                                id,
+                                succes: false,
+                                message,
+                                code: Some(code),
+                                message_id: None,
+                            };
+                            if !Handlers::queue(&tx_clone, response).await {
+                                break;
+                            }
+                            continue;
+                        }
+                        // A retry reuses the id, so it identifies the message.
+                        let client_id = Some(id.as_str()).filter(|id| !id.is_empty());
+                        let dispatch = app_state
+                            .commands
+                            .dispatch(&app_state, &from, &to, message, encrypted, client_id)
+                            .await;
+                        let message = match dispatch {
+                            Dispatch::Send(message) if encrypted => message,
+                            Dispatch::Send(message) => markdown::sanitize(&message),
+                            Dispatch::Ran(result) => {
+                                let (response, output) = match result {
+                                    Ok(text) => (
+                                        InternalMessage::Response {
+                                            id: id.clone(),
+                                            succes: true,
+                                            message: "Command run".to_string(),
+                                            code: None,
+                                            message_id: None,
+                                        },
+                                        Some(InternalMessage::CommandOutput { id, text }),
+                                    ),
+                                    Err((code, message)) => (
+                                        InternalMessage::Response {
+                                            id,
+                                            succes: false,
+                                            message,
+                                            code: Some(code),
+                                            message_id: None,
+                                        },
+                                        None,
+                                    ),
+                                };
+                                if !Handlers::queue(&tx_clone, response).await {
+                                    break;
+                                }
+                                if let Some(output) = output
+                                    && !Handlers::queue(&tx_clone, output).await
+                                {
+                                    break;
+                                }
+                                continue;
+                            }
+                        };
+                        let (resp_msg, resp_user) = match (resp_msg, resp_user) {
+                            (Some(r_m), Some(r_u)) => (Some(r_m), Some(r_u)),
+                            _ => (None, None),
+                        };
+                        let result = if let (Some(r_m), Some(r_u)) = (&resp_msg, &resp_user) {
+                            app_state
                                 .database
-                                .send_message_with_resp(&from, &to, &message, &r_m, &r_u)
+                                .send_message_with_resp(
+                                    &from,
+                                    &to,
+                                    &message,
+                                    (r_m, r_u),
+                                    encrypted,
+                                    client_id,
+                                )
                                 .await
-                            {
-                                Ok(r) => match r.succes {
-                                    true => {
-                                        {
-                                            let map = match app_state.map.lock() {
-                                                Ok(m) => m,
-                                                Err(err) => {
-                                                    println!(
-                                                        "Error while locking the map in app_state: {err}"
-                                                    );
-                                                    match tx_clone.send(InternalMessage::Response {
-                                                        id,
-                                                        succes: false,
-                                                        message: "Internal server error"
-                                                            .to_string(),
-                                                    }) {
-                                                        Ok(_) => {}
-                                                        Err(err) => {
-                                                            println!(
-                                                                "Error while sending the notification to the receiver: {err}"
-                                                            );
-                                                        }
-                                                    }
-                                                    break;
-                                                }
-                                            };
-                                            if let Some(s) = map.get(&to) {
-                                                for tx in s.values() {
-                                                    match tx.send(InternalMessage::Notification {
-                                                        sender: from.clone(),
-                                                        reciever: to.clone(),
-                                                        content: message.clone(),
-                                                        resp_msg: Some(r_m.clone()),
-                                                        resp_user: Some(r_u.clone()),
-                                                    }) {
-                                                        Ok(_) => {}
-                                                        Err(err) => println!(
-                                                            "Error while sending the notification to the receiver: {err}"
-                                                        ),
-                                                    };
-                                                }
-                                            }
-                                            if let Some(s) = map.get(&from) {
-                                                for e in s {
-                                                    if *e.0 != token {
-                                                        let tx = e.1;
-                                                        match tx.send(
-                                                            InternalMessage::Notification {
-                                                                sender: from.clone(),
-                                                                reciever: to.clone(),
-                                                                content: message.clone(),
-                                                                resp_msg: Some(r_m.clone()),
-                                                                resp_user: Some(r_u.clone()),
-                                                            },
-                                                        ) {
-                                                            Ok(_) => {}
-                                                            Err(err) => println!(
-                                                                "Error while sending the notification to the receiver: {err}"
-                                                            ),
-                                                        };
-                                                    }
-                                                }
-                                            }
-                                        }
-
-                                        match tx_clone.send(InternalMessage::Response {
-                                            id,
-                                            succes: r.succes,
-                                            message: r.message,
-                                        }) {
-                                            Ok(_) => {}
-                                            Err(err) => {
-                                                println!(
-                                                    "Error while sending error to client: {err}"
-                                                );
-                                                break;
-                                            }
-                                        }
-                                    }
-                                    false => {
-                                        match tx_clone.send(InternalMessage::Response {
-                                            id,
-                                            succes: r.succes,
-                                            message: r.message,
-                                        }) {
-                                            Ok(_) => {}
-                                            Err(err) => {
-                                                println!(
-                                                    "Error while sending error to client: {err}"
-                                                );
-                                                break;
-                                            }
-                                        }
-                                    }
-                                },
-                                Err(err) => {
-                                    println!("Error while working with the database: {err}");
-
-                                    match tx_clone.send(InternalMessage::Response {
+                        } else {
+                            app_state
+                                .database
+                                .send_message(&from, &to, &message, encrypted, client_id)
+                                .await
+                        };
+                        match result {
+                            Ok((r, true)) => {
+                                // Stored and fanned out by an earlier attempt.
+                                METRICS.messages_deduplicated.inc();
+                                debug!(%id, "Repeated message id, not storing it again");
+                                let response = InternalMessage::Response {
+                                    id,
+                                    succes: r.succes,
+                                    message: r.message,
+                                    code: r.code,
+                                    message_id: r.message_id,
+                                };
+                                if !Handlers::queue(&tx_clone, response).await {
+                                    break;
+                                }
+                            }
+                            Ok((r, false)) => {
+                                if r.succes {
+                                    METRICS.messages_sent.inc();
+                                } else {
+                                    METRICS.messages_failed.inc();
+                                }
+                                if r.succes {
+                                    let event = WebhookEvent::MessageSent {
+                                        message_id: r.message_id.unwrap_or_default(),
+                                        from: from.clone(),
+                                        to: to.clone(),
+                                        encrypted,
+                                        content: (!encrypted).then(|| message.clone()),
+                                    };
+                                    app_state.publish(FanoutEvent::Message {
+                                        origin: Some(Origin {
+                                            instance: app_state.instance_id.clone(),
+                                            session: session_id,
+                                        }),
+                                        from: from.clone(),
+                                        to: to.clone(),
+                                        message: InternalMessage::Notification {
+                                            sender: from,
+                                            reciever: to,
+                                            content: message,
+                                            resp_msg,
+                                            resp_user,
+                                            encrypted,
+                                            message_id: r.message_id.unwrap_or_default(),
+                                        },
+                                    });
+                                    webhooks::enqueue(
+                                        &app_state.database,
+                                        &app_state.config,
+                                        event,
+                                    )
+                                    .await;
+                                }
+                                if !Handlers::queue(
+                                    &tx_clone,
+                                    InternalMessage::Response {
                                         id,
-                                        succes: false,
-                                        message: "Internal server error".to_string(),
-                                    }) {
-                                        Ok(_) => {}
-                                        Err(err) => {
-                                            println!("Error while sending error to client: {err}");
-                                            break;
-                                        }
-                                    }
+                                        succes: r.succes,
+                                        message: r.message,
+                                        code: r.code,
+                                        message_id: r.message_id,
+                                    },
+                                )
+                                .await
+                                {
+                                    break;
                                 }
                             }
-                        } else {
-                            match app_state.database.send_message(&from, &to, &message).await {
-                                Ok(r) => match r.succes {
-                                    true => {
-                                        {
-                                            let map = match app_state.map.lock() {
-                                                Ok(m) => m,
-                                                Err(err) => {
-                                                    println!(
-                                                        "Error while locking the map in app_state: {err}"
-                                                    );
-                                                    match tx_clone.send(InternalMessage::Response {
-                                                        id,
-                                                        succes: false,
-                                                        message: "Internal server error"
-                                                            .to_string(),
-                                                    }) {
-                                                        Ok(_) => {}
-                                                        Err(err) => {
-                                                            println!(
-                                                                "Error while sending the notification to the receiver: {err}"
-                                                            );
-                                                        }
-                                                    }
-                                                    break;
-                                                }
-                                            };
-                                            if let Some(s) = map.get(&to) {
-                                                for tx in s.values() {
-                                                    match tx.send(InternalMessage::Notification {
-                                                        sender: from.clone(),
-                                                        reciever: to.clone(),
-                                                        content: message.clone(),
-                                                        resp_msg: None,
-                                                        resp_user: None,
-                                                    }) {
-                                                        Ok(_) => {}
-                                                        Err(err) => println!(
-                                                            "Error while sending the notification to the receiver: {err}"
-                                                        ),
-                                                    };
-                                                }
-                                            }
-                                            if let Some(s) = map.get(&from) {
-                                                for e in s {
-                                                    if *e.0 != token {
-                                                        let tx = e.1;
-                                                        match tx.send(
-                                                            InternalMessage::Notification {
-                                                                sender: from.clone(),
-                                                                reciever: to.clone(),
-                                                                content: message.clone(),
-                                                                resp_msg: None,
-                                                                resp_user: None,
-                                                            },
-                                                        ) {
-                                                            Ok(_) => {}
-                                                            Err(err) => println!(
-                                                                "Error while sending the notification to the receiver: {err}"
-                                                            ),
-                                                        };
-                                                    }
-                                                }
-                                            }
-                                        }
-
-                                        match tx_clone.send(InternalMessage::Response {
-                                            id,
-                                            succes: r.succes,
-                                            message: r.message,
-                                        }) {
-                                            Ok(_) => {}
-                                            Err(err) => {
-                                                println!(
-                                                    "Error while sending error to client: {err}"
-                                                );
-                                                break;
-                                            }
-                                        }
-                                    }
-                                    false => {
-                                        match tx_clone.send(InternalMessage::Response {
-                                            id,
-                                            succes: r.succes,
-                                            message: r.message,
-                                        }) {
-                                            Ok(_) => {}
-                                            Err(err) => {
-                                                println!(
-                                                    "Error while sending error to client: {err}"
-                                                );
-                                                break;
-                                            }
-                                        }
-                                    }
-                                },
-                                Err(err) => {
-                                    println!("Error while working with the database: {err}");
+                            Err(err) => {
+                                error!("Error while working with the database: {err}");
+                                METRICS.messages_failed.inc();
 
-                                    match tx_clone.send(InternalMessage::Response {
+                                if !Handlers::queue(
+                                    &tx_clone,
+                                    InternalMessage::Response {
                                         id,
                                         succes: false,
                                         message: "Internal server error".to_string(),
-                                    }) {
-                                        Ok(_) => {}
-                                        Err(err) => {
-                                            println!("Error while sending error to client: {err}");
-                                            break;
-                                        }
-                                    }
+                                        code: Some(ErrorCode::Internal),
+                                        message_id: None,
+                                    },
+                                )
+                                .await
+                                {
+                                    break;
                                 }
                             }
                         }
                     }
                     Ok(WsMessage::GetMessage { from, idx }) => {
+                        if idx == 0 {
+                            match app_state
+                                .database
+                                .conversation_expiry(&session_info.username, &from)
+                                .await
+                            {
+                                Ok(expiry_secs) => {
+                                    let setting = InternalMessage::ExpirySetting {
+                                        with: from.clone(),
+                                        expiry_secs,
+                                        changed_by: None,
+                                    };
+                                    if !Handlers::queue(&tx_clone, setting).await {
+                                        break;
+                                    }
+                                }
+                                Err(err) => {
+                                    error!("Error while getting the expiry setting: {err}");
+                                }
+                            }
+                        }
                         match app_state
                             .database
                             .get_messages(&session_info.username, &from, idx)
                             .await
                         {
                             Ok(Some(v)) => {
-                                let mut chat_messages: Vec<(
-                                    String,
-                                    String,
-                                    Option<String>,
-                                    Option<String>,
-                                )> = Vec::new();
-                                for row in v {
-                                    chat_messages.push((
-                                        row.get(1),
-                                        row.get(0),
-                                        row.get(2),
-                                        row.get(3),
-                                    ));
-                                }
-                                match tx_clone.send(InternalMessage::Chat {
-                                    messages: chat_messages,
-                                }) {
-                                    Ok(_) => {}
-                                    Err(err) => {
-                                        println!("Error while sending error to client: {err}");
-                                        break;
-                                    }
+                                let chat_messages = v.iter().map(ChatMessage::from_row).collect();
+                                if !Handlers::queue(
+                                    &tx_clone,
+                                    InternalMessage::Chat {
+                                        messages: chat_messages,
+                                    },
+                                )
+                                .await
+                                {
+                                    break;
                                 }
                             }
                             Ok(None) => {}
                             Err(err) => {
-                                println!("Error while getting the messages: {err}");
+                                error!("Error while getting the messages: {err}");
                             }
                         }
                     }
@@ -574,43 +1453,696 @@ impl Handlers {
                                 for row in v {
                                     users.push(row.get(0));
                                 }
-                                match tx_clone.send(InternalMessage::Users { users_list: users }) {
-                                    Ok(_) => {}
-                                    Err(err) => {
-                                        println!("Error while sending error to client: {err}");
-                                        break;
-                                    }
+                                if !Handlers::queue(
+                                    &tx_clone,
+                                    InternalMessage::Users { users_list: users },
+                                )
+                                .await
+                                {
+                                    break;
                                 }
                             }
                             Ok(None) => {}
                             Err(err) => {
-                                println!("Error while getting users: {err}");
+                                error!("Error while getting users: {err}");
+                            }
+                        }
+                    }
+                    Ok(WsMessage::GetConversations) => {
+                        if !Handlers::send_conversations(
+                            &app_state,
+                            &tx_clone,
+                            &session_info.username,
+                        )
+                        .await
+                        {
+                            break;
+                        }
+                    }
+                    Ok(WsMessage::MarkRead { with, message_id }) => {
+                        Handlers::mark_read(&app_state, &session_info.username, &with, message_id)
+                            .await;
+                    }
+                    Ok(WsMessage::Logout) => {
+                        app_state.session_manager.close_session(&session_info.token);
+                        break;
+                    }
+                    Ok(WsMessage::Sync { since }) => {
+                        if !Handlers::sync(&app_state, &tx_clone, &session_info.username, since)
+                            .await
+                        {
+                            break;
+                        }
+                    }
+                    Ok(WsMessage::SetExpiry {
+                        id,
+                        with,
+                        expiry_secs,
+                    }) => {
+                        let response = Handlers::set_expiry(
+                            &app_state,
+                            &session_info.username,
+                            &with,
+                            expiry_secs,
+                        )
+                        .await;
+                        let response = InternalMessage::Response {
+                            id,
+                            succes: response.succes,
+                            message: response.message,
+                            code: response.code,
+                            message_id: None,
+                        };
+                        if !Handlers::queue(&tx_clone, response).await {
+                            break;
+                        }
+                    }
+                    Ok(WsMessage::ScheduleMessage { id, message }) => {
+                        let result =
+                            Handlers::schedule(&app_state, &session_info.username, message).await;
+                        let response = match result {
+                            Ok(message) => InternalMessage::Response {
+                                id,
+                                succes: true,
+                                message,
+                                code: None,
+                                message_id: None,
+                            },
+                            Err((code, message)) => InternalMessage::Response {
+                                id,
+                                succes: false,
+                                message,
+                                code: Some(code),
+                                message_id: None,
+                            },
+                        };
+                        if !Handlers::queue(&tx_clone, response).await {
+                            break;
+                        }
+                        if !Handlers::send_scheduled_list(
+                            &app_state,
+                            &tx_clone,
+                            &session_info.username,
+                        )
+                        .await
+                        {
+                            break;
+                        }
+                    }
+                    Ok(WsMessage::ListScheduled) => {
+                        if !Handlers::send_scheduled_list(
+                            &app_state,
+                            &tx_clone,
+                            &session_info.username,
+                        )
+                        .await
+                        {
+                            break;
+                        }
+                    }
+                    Ok(WsMessage::CancelScheduled { id, scheduled_id }) => {
+                        let response = match app_state
+                            .database
+                            .cancel_scheduled(&session_info.username, scheduled_id)
+                            .await
+                        {
+                            Ok(true) => {
+                                info!(scheduled_id, "Scheduled message cancelled");
+                                InternalMessage::Response {
+                                    id,
+                                    succes: true,
+                                    message: "Scheduled message cancelled".to_string(),
+                                    code: None,
+                                    message_id: None,
+                                }
+                            }
+                            Ok(false) => InternalMessage::Response {
+                                id,
+                                succes: false,
+                                message: "No such scheduled message".to_string(),
+                                code: Some(ErrorCode::NotFound),
+                                message_id: None,
+                            },
+                            Err(err) => {
+                                error!("Error while working with the database: {err}");
+                                InternalMessage::Response {
+                                    id,
+                                    succes: false,
+                                    message: "Internal server error".to_string(),
+                                    code: Some(ErrorCode::Internal),
+                                    message_id: None,
+                                }
                             }
+                        };
+                        if !Handlers::queue(&tx_clone, response).await {
+                            break;
+                        }
+                        if !Handlers::send_scheduled_list(
+                            &app_state,
+                            &tx_clone,
+                            &session_info.username,
+                        )
+                        .await
+                        {
+                            break;
+                        }
+                    }
+                    Ok(WsMessage::BanUser {
+                        id,
+                        user,
+                        duration_secs,
+                        reason,
+                    }) => {
+                        let action = ModAction::Sanction {
+                            kind: SanctionKind::Ban,
+                            user,
+                            duration_secs,
+                            reason,
+                        };
+                        if !Handlers::moderate(&app_state, &tx_clone, &session_info, id, action)
+                            .await
+                        {
+                            break;
+                        }
+                    }
+                    Ok(WsMessage::UnbanUser { id, user }) => {
+                        let action = ModAction::Lift {
+                            kind: SanctionKind::Ban,
+                            user,
+                        };
+                        if !Handlers::moderate(&app_state, &tx_clone, &session_info, id, action)
+                            .await
+                        {
+                            break;
+                        }
+                    }
+                    Ok(WsMessage::MuteUser {
+                        id,
+                        user,
+                        duration_secs,
+                        reason,
+                    }) => {
+                        let action = ModAction::Sanction {
+                            kind: SanctionKind::Mute,
+                            user,
+                            duration_secs,
+                            reason,
+                        };
+                        if !Handlers::moderate(&app_state, &tx_clone, &session_info, id, action)
+                            .await
+                        {
+                            break;
+                        }
+                    }
+                    Ok(WsMessage::UnmuteUser { id, user }) => {
+                        let action = ModAction::Lift {
+                            kind: SanctionKind::Mute,
+                            user,
+                        };
+                        if !Handlers::moderate(&app_state, &tx_clone, &session_info, id, action)
+                            .await
+                        {
+                            break;
+                        }
+                    }
+                    Ok(WsMessage::SetRole { id, user, role }) => {
+                        let action = ModAction::SetRole { user, role };
+                        if !Handlers::moderate(&app_state, &tx_clone, &session_info, id, action)
+                            .await
+                        {
+                            break;
                         }
                     }
                     Err(err) => {
-                        println!("Error: {err}");
+                        warn!("Invalid WebSocket message: {err}");
                     }
                 }
             }
         }
 
-        send_task.abort();
-        {
-            let mut map = match app_state.map.lock() {
-                Ok(m) => m,
-                Err(err) => {
-                    println!("Error while locking the map in app_state: {err}");
-                    return;
+        // The slow-consumer policy may have removed the session already,
+        // and a reconnect with the same token may have replaced it.
+        let last_session = match app_state.map.lock() {
+            Ok(mut map) => {
+                if let Some(sessions) = map.get_mut(&session_info.username) {
+                    if sessions
+                        .get(&session_info.token)
+                        .is_some_and(|session| session.id == session_id)
+                    {
+                        sessions.remove(&session_info.token);
+                    }
+                    if sessions.is_empty() {
+                        map.remove(&session_info.username);
+                    }
                 }
+                !map.contains_key(&session_info.username)
+            }
+            Err(err) => {
+                error!("Error while locking the map in app_state: {err}");
+                false
+            }
+        };
+        if last_session
+            && let Err(err) = app_state
+                .database
+                .set_offline(&app_state.instance_id, &session_info.username)
+                .await
+        {
+            error!("Error while updating presence: {err}");
+        }
+        // With every sender gone the send task writes out what is still
+        // queued, closes the socket and exits on its own.
+        drop(tx_clone);
+        if drain {
+            let flush = tokio::time::timeout(app_state.config.shutdown_deadline, &mut send_task);
+            if flush.await.is_err() {
+                warn!("Outbound queue was not flushed in time");
+                send_task.abort();
+            }
+        } else {
+            send_task.abort();
+        }
+        app_state
+            .session_manager
+            .detach(&session_info.token, app_state.config.session_grace);
+        app_state.open_sessions.send_modify(|n| *n -= 1);
+    }
+
+    /// Error to send back when `user` is muted and may not post messages.
+    async fn mute_rejection(app_state: &AppState, user: &str) -> Option<(ErrorCode, String)> {
+        match app_state
+            .database
+            .active_sanction(user, SanctionKind::Mute)
+            .await
+        {
+            Ok(Some(mute)) => Some((mute.code(), mute.describe())),
+            Ok(None) => None,
+            Err(err) => {
+                error!("Error while working with the database: {err}");
+                Some((ErrorCode::Internal, "Internal server error".to_string()))
+            }
+        }
+    }
+
+    /// Stores a message for delivery at `deliver_at` (Unix seconds).
+    pub async fn schedule(
+        app_state: &AppState,
+        user: &str,
+        mut message: ScheduledMessage,
+    ) -> Result<String, (ErrorCode, String)> {
+        if let Some(rejection) = Handlers::mute_rejection(app_state, user).await {
+            return Err(rejection);
+        }
+        message.message = validation::message(&message.message, app_state.config.max_message_bytes)
+            .map_err(|err| (err.code(), err.to_string()))?;
+        if !message.encrypted {
+            message.message = markdown::sanitize(&message.message);
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or_default();
+        if message.deliver_at <= now {
+            return Err((
+                ErrorCode::InvalidRequest,
+                "The delivery time is in the past".to_string(),
+            ));
+        }
+        if message.deliver_at - now > MAX_SCHEDULE_AHEAD_SECS {
+            return Err((
+                ErrorCode::InvalidRequest,
+                format!(
+                    "Messages can be scheduled at most {} days ahead",
+                    MAX_SCHEDULE_AHEAD_SECS / 86_400
+                ),
+            ));
+        }
+        let internal = |err: tokio_postgres::Error| {
+            error!("Error while working with the database: {err}");
+            (ErrorCode::Internal, "Internal server error".to_string())
+        };
+        let pending = app_state
+            .database
+            .count_scheduled(user)
+            .await
+            .map_err(internal)?;
+        if pending >= MAX_PENDING_SCHEDULED {
+            return Err((
+                ErrorCode::InvalidRequest,
+                format!("You already have {MAX_PENDING_SCHEDULED} scheduled messages"),
+            ));
+        }
+        if message.resp_msg.is_none() || message.resp_user.is_none() {
+            message.resp_msg = None;
+            message.resp_user = None;
+        }
+        let scheduled_id = app_state
+            .database
+            .schedule_message(user, &message)
+            .await
+            .map_err(internal)?;
+        let Some(scheduled_id) = scheduled_id else {
+            return Err((
+                ErrorCode::NotFound,
+                "The receiver is not in the database".to_string(),
+            ));
+        };
+        info!(to = %message.to, scheduled_id, deliver_at = message.deliver_at, "Message scheduled");
+        Ok("Message scheduled".to_string())
+    }
+
+    /// Sends the session's pending scheduled messages. Returns false if the
+    /// session's queue is gone.
+    async fn send_scheduled_list(
+        app_state: &AppState,
+        tx: &mpsc::Sender<InternalMessage>,
+        user: &str,
+    ) -> bool {
+        let rows = match app_state.database.scheduled_messages(user).await {
+            Ok(rows) => rows,
+            Err(err) => {
+                error!("Error while getting the scheduled messages: {err}");
+                return true;
+            }
+        };
+        let items = rows
+            .iter()
+            .map(|row| ScheduledItem {
+                scheduled_id: row.get(0),
+                to: row.get(1),
+                message: row.get(2),
+                resp_msg: row.get(3),
+                resp_user: row.get(4),
+                encrypted: row.get(5),
+                deliver_at: row.get(6),
+            })
+            .collect();
+        Handlers::queue(tx, InternalMessage::ScheduledList { items }).await
+    }
+
+    fn conversation(row: &Row) -> Conversation {
+        let encrypted: bool = row.get(3);
+        let last_message: Option<String> = row.get(2);
+        Conversation {
+            with: row.get(0),
+            last_from: row.get(1),
+            // An encrypted preview is only readable whole.
+            last_message: match encrypted {
+                true => last_message,
+                false => last_message.map(|m| m.chars().take(PREVIEW_CHARS).collect()),
+            },
+            encrypted,
+            last_at: row.get(4),
+            unread: row.get(5),
+            last_message_id: row.get(6),
+        }
+    }
+
+    /// Writes `message` to the socket. Returns false once the socket is
+    /// gone.
+    async fn send_frame(
+        sender: &mut SplitSink<WebSocket, Message>,
+        message: InternalMessage,
+    ) -> bool {
+        let what = message.what();
+        let frame = match serde_json::to_string(&WsMessageBack::from(message)) {
+            Ok(frame) => frame,
+            Err(err) => {
+                error!("Error while serializing {what}: {err}");
+                return true;
+            }
+        };
+        match sender.send(Message::Text(frame.into())).await {
+            Ok(_) => true,
+            Err(err) => {
+                warn!("Error while sending {what} to the client: {err}");
+                false
+            }
+        }
+    }
+
+    /// Queues `message` for the session's socket. Returns false if the
+    /// session's queue is gone.
+    async fn queue(tx: &mpsc::Sender<InternalMessage>, message: InternalMessage) -> bool {
+        let what = message.what();
+        match tx.send(message).await {
+            Ok(_) => true,
+            Err(err) => {
+                warn!("Error while queueing {what} for the client: {err}");
+                false
+            }
+        }
+    }
+
+    /// Sends the session the inbox of `user`. Returns false if the
+    /// session's queue is gone.
+    async fn send_conversations(
+        app_state: &AppState,
+        tx: &mpsc::Sender<InternalMessage>,
+        user: &str,
+    ) -> bool {
+        let rows = match app_state.database.conversations(user, None).await {
+            Ok(rows) => rows,
+            Err(err) => {
+                error!("Error while getting the conversations: {err}");
+                return true;
+            }
+        };
+        let conversations = rows.iter().map(Handlers::conversation).collect();
+        Handlers::queue(tx, InternalMessage::Conversations { conversations }).await
+    }
+
+    /// The inbox entry of `user` for the conversation with `with`, as sent
+    /// to their sessions when it changes.
+    pub async fn conversation_update(
+        app_state: &AppState,
+        user: &str,
+        with: &str,
+    ) -> Option<InternalMessage> {
+        match app_state.database.conversations(user, Some(with)).await {
+            Ok(rows) => rows.first().map(|row| InternalMessage::ConversationUpdate {
+                conversation: Handlers::conversation(row),
+            }),
+            Err(err) => {
+                error!("Error while getting the conversation: {err}");
+                None
+            }
+        }
+    }
+
+    /// Sends the session one page of the changes `user` missed after
+    /// `since`. The session is already registered, so anything stored while
+    /// the page is read reaches it live. Returns false if the session's
+    /// queue is gone.
+    async fn sync(
+        app_state: &AppState,
+        tx: &mpsc::Sender<InternalMessage>,
+        user: &str,
+        since: Option<i64>,
+    ) -> bool {
+        let synced = match Handlers::changes_since(app_state, user, since).await {
+            Ok(synced) => synced,
+            Err(err) => {
+                error!("Error while syncing: {err}");
+                return true;
+            }
+        };
+        Handlers::queue(tx, synced).await
+    }
+
+    async fn changes_since(
+        app_state: &AppState,
+        user: &str,
+        since: Option<i64>,
+    ) -> Result<InternalMessage, tokio_postgres::Error> {
+        let database = &app_state.database;
+        let (current, horizon) = database.sync_state().await?;
+        let since = match since {
+            Some(since) if since >= horizon && since <= current => since,
+            since => {
+                return Ok(InternalMessage::Synced {
+                    messages: Vec::new(),
+                    deleted: Vec::new(),
+                    reads: Vec::new(),
+                    cursor: current,
+                    more: false,
+                    reset: since.is_some(),
+                });
+            }
+        };
+        let mut rows = database
+            .messages_since(user, since, current, SYNC_PAGE + 1)
+            .await?;
+        let more = rows.len() > SYNC_PAGE as usize;
+        rows.truncate(SYNC_PAGE as usize);
+        // A full page ends at its last message, so the deletions and reads
+        // after it come with the next one.
+        let until = match rows.last() {
+            Some(row) if more => row.get(9),
+            _ => current,
+        };
+        let deleted = database.deleted_since(user, since, until).await?;
+        let reads = database.reads_since(user, since, until).await?;
+        Ok(InternalMessage::Synced {
+            messages: rows.iter().map(ChatMessage::from_row).collect(),
+            deleted: deleted.iter().map(|row| row.get(0)).collect(),
+            reads: reads
+                .iter()
+                .map(|row| ReadMark {
+                    user: row.get(0),
+                    with: row.get(1),
+                    last_read: row.get(2),
+                })
+                .collect(),
+            cursor: until,
+            more,
+            reset: false,
+        })
+    }
+
+    /// Moves the read mark and updates the unread count on every session
+    /// of `user`.
+    async fn mark_read(app_state: &AppState, user: &str, with: &str, message_id: i64) {
+        if let Err(err) = app_state.database.mark_read(user, with, message_id).await {
+            error!("Error while marking messages read: {err}");
+            return;
+        }
+        if let Some(message) = Handlers::conversation_update(app_state, user, with).await {
+            app_state.publish(FanoutEvent::User {
+                user: user.to_string(),
+                message,
+            });
+        }
+    }
+
+    /// Changes the disappearing-message timer of the conversation between
+    /// `user` and `with` and tells every live session of both users.
+    async fn set_expiry(
+        app_state: &AppState,
+        user: &str,
+        with: &str,
+        expiry_secs: Option<i64>,
+    ) -> Response {
+        let fail = |code: ErrorCode, message: String| Response {
+            succes: false,
+            message,
+            code: Some(code),
+            message_id: None,
+        };
+        let expiry_secs = expiry_secs.filter(|s| *s != 0);
+        if let Some(secs) = expiry_secs
+            && !(MIN_EXPIRY_SECS..=MAX_EXPIRY_SECS).contains(&secs)
+        {
+            return fail(
+                ErrorCode::InvalidRequest,
+                format!(
+                    "The timer must be between {MIN_EXPIRY_SECS} seconds and {} days",
+                    MAX_EXPIRY_SECS / 86_400
+                ),
+            );
+        }
+        match app_state
+            .database
+            .set_conversation_expiry(user, with, expiry_secs)
+            .await
+        {
+            Ok(true) => {}
+            Ok(false) => return fail(ErrorCode::NotFound, format!("No user named {with}")),
+            Err(err) => {
+                error!("Error while working with the database: {err}");
+                return fail(ErrorCode::Internal, "Internal server error".to_string());
+            }
+        }
+        info!(%with, ?expiry_secs, "Disappearing messages timer changed");
+        for (owner, other) in [(user, with), (with, user)] {
+            app_state.publish(FanoutEvent::User {
+                user: owner.to_string(),
+                message: InternalMessage::ExpirySetting {
+                    with: other.to_string(),
+                    expiry_secs,
+                    changed_by: Some(user.to_string()),
+                },
+            });
+            if user == with {
+                break;
+            }
+        }
+        Response {
+            succes: true,
+            message: match expiry_secs {
+                Some(secs) => format!("Messages now disappear after {secs} seconds"),
+                None => "Disappearing messages are off".to_string(),
+            },
+            code: None,
+            message_id: None,
+        }
+    }
+
+    /// Runs a moderation command for the session's user and sends the
+    /// outcome back. Returns false if the session's queue is gone.
+    async fn moderate(
+        app_state: &AppState,
+        tx: &mpsc::Sender<InternalMessage>,
+        session_info: &SessionInfo,
+        id: String,
+        action: ModAction,
+    ) -> bool {
+        let response = match Moderation::apply(app_state, &session_info.username, action).await {
+            Ok(message) => InternalMessage::Response {
+                id,
+                succes: true,
+                message,
+                code: None,
+                message_id: None,
+            },
+            Err((code, message)) => InternalMessage::Response {
+                id,
+                succes: false,
+                message,
+                code: Some(code),
+                message_id: None,
+            },
+        };
+        Handlers::queue(tx, response).await
+    }
+
+    /// Pushes a new message to every live session of the receiver and the
+    /// sender on this instance, except the session it was sent from.
+    /// Sessions whose outbound queue is full are handled according to the
+    /// configured `SlowConsumerPolicy`.
+    /// Returns false if the session map could not be locked.
+    pub fn fan_out(
+        app_state: &AppState,
+        skip: Option<u64>,
+        from: &str,
+        to: &str,
+        notification: &InternalMessage,
+    ) -> bool {
+        let mut map = match app_state.map.lock() {
+            Ok(m) => m,
+            Err(err) => {
+                error!("Error while locking the map in app_state: {err}");
+                return false;
+            }
+        };
+        let mut slow: Vec<(String, String)> = Vec::new();
+        for user in [to, from] {
+            let Some(sessions) = map.get(user) else {
+                continue;
             };
-            if let Some(sessions) = map.get_mut(&session_info.username) {
-                sessions.remove(&session_info.token);
-                if sessions.is_empty() {
-                    map.remove(&session_info.username);
+            for (session_token, session) in sessions {
+                if Some(session.id) == skip {
+                    continue;
+                }
+                if !app_state.offer(user, session, notification.clone()) {
+                    slow.push((user.to_string(), session_token.clone()));
                 }
             }
+            if to == from {
+                break;
+            }
         }
-        app_state.session_manager.close_session(&session_info.token);
+        app_state.disconnect_slow(&mut map, slow);
+        true
     }
 }
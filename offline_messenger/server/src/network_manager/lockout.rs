@@ -0,0 +1,96 @@
+use tokio_postgres::Error;
+use tracing::error;
+
+use crate::network_manager::{
+    config::ServerConfig, database_manager::DataBase, handlers::ErrorCode,
+};
+
+/// What became of a login attempt, as written to the auth log.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AuthOutcome {
+    Success,
+    InvalidCredentials,
+    Disabled,
+    Banned,
+    /// Refused without checking the password because of a lockout or
+    /// backoff.
+    LockedOut,
+    /// The client certificate names another user.
+    Forbidden,
+}
+
+impl AuthOutcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuthOutcome::Success => "success",
+            AuthOutcome::InvalidCredentials => "invalid_credentials",
+            AuthOutcome::Disabled => "disabled",
+            AuthOutcome::Banned => "banned",
+            AuthOutcome::LockedOut => "locked_out",
+            AuthOutcome::Forbidden => "forbidden",
+        }
+    }
+
+    /// Outcome of a login the database refused with `code`.
+    pub fn from_code(code: Option<ErrorCode>) -> Self {
+        match code {
+            Some(ErrorCode::Disabled) => AuthOutcome::Disabled,
+            Some(ErrorCode::Banned) => AuthOutcome::Banned,
+            _ => AuthOutcome::InvalidCredentials,
+        }
+    }
+}
+
+/// Counts the attempt as a failed login for `username` and from `ip` before
+/// the password is checked, so parallel attempts all see it. Returns the
+/// seconds to wait instead when either is locked out or still in the backoff
+/// earned by earlier failures; the attempt is then not counted. Attempts
+/// that pass and then do not fail on their credentials go to `refund`.
+pub async fn check(
+    database: &DataBase,
+    config: &ServerConfig,
+    username: &str,
+    ip: &str,
+) -> Result<Option<i64>, Error> {
+    database
+        .count_login_attempt(
+            username,
+            ip,
+            thresholds(config),
+            config.login_lockout,
+            (config.login_backoff_base, config.login_backoff_max),
+        )
+        .await
+}
+
+/// Writes the attempt to the auth log, and forgets the failed logins of
+/// `username` after a successful one. The answer to the login does not
+/// depend on it, so errors are only logged.
+pub async fn record(database: &DataBase, username: &str, ip: &str, outcome: AuthOutcome) {
+    if let Err(err) = database.auth_log(username, ip, outcome.as_str()).await {
+        error!("Error while writing the auth log: {err}");
+    }
+    if outcome == AuthOutcome::Success
+        && let Err(err) = database.clear_login_failures(username).await
+    {
+        error!("Error while tracking failed logins: {err}");
+    }
+}
+
+/// Gives back the failure `check` counted for an attempt that did not fail
+/// on its credentials.
+pub async fn refund(database: &DataBase, config: &ServerConfig, username: &str, ip: &str) {
+    if let Err(err) = database
+        .refund_login_attempt(username, ip, thresholds(config))
+        .await
+    {
+        error!("Error while tracking failed logins: {err}");
+    }
+}
+
+fn thresholds(config: &ServerConfig) -> (u32, u32) {
+    (
+        config.login_lockout_threshold,
+        config.login_ip_lockout_threshold,
+    )
+}
@@ -0,0 +1,64 @@
+use axum::{body::Body, extract::ConnectInfo, http::Request};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::{env, net::SocketAddr, sync::LazyLock};
+use tracing::{Span, field};
+use tracing_subscriber::{EnvFilter, fmt};
+use uuid::Uuid;
+
+const DEFAULT_FILTER: &str = "server=info,tower_http=info";
+
+/// Installs the global `tracing` subscriber.
+///
+/// The level filter is read from `RUST_LOG` (falling back to
+/// `DEFAULT_FILTER`) and `MESSENGER_LOG_FORMAT=json` switches the output to
+/// one JSON object per line.
+pub fn init() {
+    let filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(DEFAULT_FILTER));
+    let json = matches!(env::var("MESSENGER_LOG_FORMAT"), Ok(v) if v.eq_ignore_ascii_case("json"));
+    let builder = fmt().with_env_filter(filter).with_target(false);
+    let result = if json {
+        builder
+            .json()
+            .flatten_event(true)
+            .with_span_list(false)
+            .try_init()
+    } else {
+        builder.try_init()
+    };
+    if let Err(err) = result {
+        eprintln!("Error while installing the tracing subscriber: {err}");
+    }
+}
+
+/// Span wrapped around every HTTP request. `user` is filled in by the
+/// handlers once the request body has been parsed.
+pub fn http_span(request: &Request<Body>) -> Span {
+    let peer = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.to_string());
+    tracing::info_span!(
+        "http",
+        method = %request.method(),
+        path = %request.uri().path(),
+        peer = peer.as_deref().unwrap_or("unknown"),
+        user = field::Empty,
+    )
+}
+
+/// Key of the session tags, new on every start like the sessions.
+static TAG_KEY: LazyLock<[u8; 16]> = LazyLock::new(|| *Uuid::new_v4().as_bytes());
+
+/// Short tag for a session token, safe to put in logs: a truncated keyed
+/// hash, so it tells nothing about the token itself.
+pub fn session_tag(token: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(TAG_KEY.as_slice()).expect("HMAC takes any key length");
+    mac.update(token.as_bytes());
+    mac.finalize().into_bytes()[..4]
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
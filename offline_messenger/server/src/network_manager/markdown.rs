@@ -0,0 +1,104 @@
+//! The Markdown subset messages may use:
+//!
+//! - `**bold**` and `*italics*` (or `__bold__` and `_italics_`)
+//! - `` `inline code` `` and code blocks, fenced with ```` ``` ```` or indented
+//! - `[links](https://example.com)` and `<https://example.com>` to http,
+//!   https and mailto addresses
+//! - bulleted (`-`, `*`, `+`) and numbered (`1.`) lists, which may nest
+//!
+//! Line breaks are kept as typed. Anything else (headings, quotes, HTML,
+//! images, rules, links to other schemes) is escaped before the message is
+//! stored, so it shows as the text that was typed. So is markup that does
+//! not parse, such as an unclosed `**`, without any change.
+
+use pulldown_cmark::{Event, LinkType, Options, Parser, Tag};
+use std::ops::Range;
+
+const LINK_SCHEMES: [&str; 3] = ["http://", "https://", "mailto:"];
+
+fn supported(tag: &Tag) -> bool {
+    match tag {
+        Tag::Paragraph
+        | Tag::Strong
+        | Tag::Emphasis
+        | Tag::CodeBlock(_)
+        | Tag::List(_)
+        | Tag::Item => true,
+        Tag::Link {
+            link_type: LinkType::Email,
+            ..
+        } => true,
+        Tag::Link { dest_url, .. } => {
+            let url = dest_url.to_ascii_lowercase();
+            LINK_SCHEMES.iter().any(|scheme| url.starts_with(scheme))
+        }
+        _ => false,
+    }
+}
+
+/// Byte ranges of `text` holding markup outside the subset, outermost
+/// first.
+fn unsupported(text: &str) -> Vec<Range<usize>> {
+    let mut ranges = Vec::new();
+    // How deep we are inside an unsupported element already in `ranges`.
+    let mut depth = 0;
+    for (event, range) in Parser::new_ext(text, Options::empty()).into_offset_iter() {
+        match event {
+            Event::Start(_) if depth > 0 => depth += 1,
+            Event::End(_) if depth > 0 => depth -= 1,
+            _ if depth > 0 => {}
+            Event::Start(tag) if !supported(&tag) => {
+                depth = 1;
+                ranges.push(range);
+            }
+            Event::Html(_) | Event::InlineHtml(_) | Event::Rule => ranges.push(range),
+            _ => {}
+        }
+    }
+    ranges
+}
+
+/// `text` with the markup outside the subset backslash-escaped. Everything
+/// else is left as it is, so most messages come back unchanged.
+pub fn sanitize(text: &str) -> String {
+    let mut sanitized = text.to_string();
+    // Escaping a line can change what the next one means (`===` under it
+    // makes a heading), so repeat until nothing is left to escape.
+    loop {
+        let ranges = unsupported(&sanitized);
+        if ranges.is_empty() {
+            return sanitized;
+        }
+        let escaped = escape(&sanitized, &ranges);
+        if escaped == sanitized {
+            return sanitized;
+        }
+        sanitized = escaped;
+    }
+}
+
+fn escape(text: &str, ranges: &[Range<usize>]) -> String {
+    let mut escaped = String::with_capacity(text.len() + text.len() / 8);
+    let mut done = 0;
+    for range in ranges {
+        let start = range.start.max(done);
+        let end = range.end.max(start);
+        escaped.push_str(&text[done..start]);
+        let mut chars = text[start..end].chars().peekable();
+        while let Some(c) = chars.next() {
+            // CommonMark shows any escaped ASCII punctuation as itself.
+            if c == '\\' && chars.peek().is_some_and(|c| c.is_ascii_punctuation()) {
+                escaped.push(c);
+                escaped.extend(chars.next());
+                continue;
+            }
+            if c.is_ascii_punctuation() {
+                escaped.push('\\');
+            }
+            escaped.push(c);
+        }
+        done = end;
+    }
+    escaped.push_str(&text[done..]);
+    escaped
+}
@@ -0,0 +1,192 @@
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+use std::sync::{LazyLock, atomic::Ordering};
+use tracing::error;
+
+use crate::network_manager::server::AppState;
+
+/// Process-wide Prometheus registry. Counters are bumped where the events
+/// happen; gauges derived from `AppState` are refreshed on every scrape.
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub struct Metrics {
+    registry: Registry,
+    pub messages_sent: IntCounter,
+    pub messages_failed: IntCounter,
+    pub messages_deduplicated: IntCounter,
+    pub logins: IntCounterVec,
+    webhook_deliveries: IntCounterVec,
+    pub db_query_seconds: HistogramVec,
+    connected_sessions: IntGauge,
+    active_users: IntGauge,
+    queue_depth_max: IntGauge,
+    queue_depth_total: IntGauge,
+    queue_depth_high_watermark: IntGauge,
+    pub queue_dropped: IntCounter,
+    pub queue_spilled: IntCounter,
+    pub queue_disconnected: IntCounter,
+    pub fanout_dropped: IntCounter,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("messenger".to_string()), None)
+            .expect("valid registry prefix");
+        let messages_sent =
+            IntCounter::new("messages_sent_total", "Messages stored and fanned out")
+                .expect("valid metric");
+        let messages_failed = IntCounter::new(
+            "messages_failed_total",
+            "SendMessage requests that were rejected",
+        )
+        .expect("valid metric");
+        let messages_deduplicated = IntCounter::new(
+            "messages_deduplicated_total",
+            "SendMessage retries answered with the result of the first attempt",
+        )
+        .expect("valid metric");
+        let logins = IntCounterVec::new(
+            Opts::new("logins_total", "Login attempts by outcome"),
+            &["outcome"],
+        )
+        .expect("valid metric");
+        let webhook_deliveries = IntCounterVec::new(
+            Opts::new(
+                "webhook_deliveries_total",
+                "Webhook delivery attempts by outcome",
+            ),
+            &["outcome"],
+        )
+        .expect("valid metric");
+        let db_query_seconds = HistogramVec::new(
+            HistogramOpts::new("db_query_duration_seconds", "Database query latency").buckets(
+                vec![
+                    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0,
+                ],
+            ),
+            &["query"],
+        )
+        .expect("valid metric");
+        let connected_sessions =
+            IntGauge::new("connected_sessions", "Open WebSocket sessions").expect("valid metric");
+        let active_users = IntGauge::new("active_users", "Users with at least one open session")
+            .expect("valid metric");
+        let queue_depth_max = IntGauge::new(
+            "ws_queue_depth_max",
+            "Deepest outbound queue across all sessions",
+        )
+        .expect("valid metric");
+        let queue_depth_total = IntGauge::new(
+            "ws_queue_depth_total",
+            "Messages waiting in all outbound queues",
+        )
+        .expect("valid metric");
+        let queue_depth_high_watermark = IntGauge::new(
+            "ws_queue_depth_high_watermark",
+            "Deepest outbound queue seen since start",
+        )
+        .expect("valid metric");
+        let queue_dropped = IntCounter::new(
+            "ws_queue_dropped_total",
+            "Live notifications not queued because the queue was full",
+        )
+        .expect("valid metric");
+        let queue_spilled = IntCounter::new(
+            "ws_queue_spilled_total",
+            "Live notifications parked in a session's overflow store",
+        )
+        .expect("valid metric");
+        let queue_disconnected = IntCounter::new(
+            "ws_queue_disconnected_total",
+            "Sessions closed by the slow-consumer policy",
+        )
+        .expect("valid metric");
+        let fanout_dropped = IntCounter::new(
+            "fanout_dropped_total",
+            "Fan-out bus events dropped because a bus queue was full",
+        )
+        .expect("valid metric");
+
+        let metrics = Self {
+            registry,
+            messages_sent,
+            messages_failed,
+            messages_deduplicated,
+            logins,
+            webhook_deliveries,
+            db_query_seconds,
+            connected_sessions,
+            active_users,
+            queue_depth_max,
+            queue_depth_total,
+            queue_depth_high_watermark,
+            queue_dropped,
+            queue_spilled,
+            queue_disconnected,
+            fanout_dropped,
+        };
+        let collectors: Vec<Box<dyn prometheus::core::Collector>> = vec![
+            Box::new(metrics.messages_sent.clone()),
+            Box::new(metrics.messages_failed.clone()),
+            Box::new(metrics.messages_deduplicated.clone()),
+            Box::new(metrics.logins.clone()),
+            Box::new(metrics.webhook_deliveries.clone()),
+            Box::new(metrics.db_query_seconds.clone()),
+            Box::new(metrics.connected_sessions.clone()),
+            Box::new(metrics.active_users.clone()),
+            Box::new(metrics.queue_depth_max.clone()),
+            Box::new(metrics.queue_depth_total.clone()),
+            Box::new(metrics.queue_depth_high_watermark.clone()),
+            Box::new(metrics.queue_dropped.clone()),
+            Box::new(metrics.queue_spilled.clone()),
+            Box::new(metrics.queue_disconnected.clone()),
+            Box::new(metrics.fanout_dropped.clone()),
+        ];
+        for collector in collectors {
+            if let Err(err) = metrics.registry.register(collector) {
+                error!("Error while registering a metric: {err}");
+            }
+        }
+        metrics
+    }
+
+    pub fn login(&self, succes: bool) {
+        let outcome = if succes { "success" } else { "failure" };
+        self.logins.with_label_values(&[outcome]).inc();
+    }
+
+    /// Counts a webhook attempt: `delivered`, `retry` or `failed`.
+    pub fn webhook(&self, outcome: &str) {
+        self.webhook_deliveries.with_label_values(&[outcome]).inc();
+    }
+
+    /// Refreshes the gauges derived from `app_state` and encodes every
+    /// metric in the Prometheus text format.
+    pub fn render(&self, app_state: &AppState) -> String {
+        let depths = app_state.queue_depths();
+        let active_users = match app_state.map.lock() {
+            Ok(map) => map.len(),
+            Err(err) => {
+                error!("Error while locking the map in app_state: {err}");
+                0
+            }
+        };
+        self.connected_sessions.set(depths.len() as i64);
+        self.active_users.set(active_users as i64);
+        self.queue_depth_max
+            .set(depths.iter().map(|d| d.2).max().unwrap_or(0) as i64);
+        self.queue_depth_total
+            .set(depths.iter().map(|d| d.2).sum::<usize>() as i64);
+        let queue_metrics = &app_state.queue_metrics;
+        self.queue_depth_high_watermark
+            .set(queue_metrics.max_depth.load(Ordering::Relaxed) as i64);
+
+        let mut buffer = Vec::new();
+        if let Err(err) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            error!("Error while encoding metrics: {err}");
+        }
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+}
@@ -1,4 +1,19 @@
+pub mod admin;
+pub mod archive;
+pub mod bots;
+pub mod commands;
+pub mod config;
 pub mod database_manager;
+pub mod fanout;
 pub mod handlers;
+pub mod lockout;
+pub mod logging;
+pub mod markdown;
+pub mod metrics;
+pub mod moderation;
+pub mod queue;
 pub mod server;
 pub mod session_manager;
+pub mod tls;
+pub mod validation;
+pub mod webhooks;
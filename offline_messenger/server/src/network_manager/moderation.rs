@@ -0,0 +1,237 @@
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, warn};
+
+use crate::network_manager::{
+    database_manager::AuditEntry, fanout::FanoutEvent, handlers::ErrorCode, server::AppState,
+};
+
+/// Longest timed sanction, ten years. Leave the duration out for a
+/// permanent one.
+pub const MAX_SANCTION_SECS: i64 = 10 * 365 * 86_400;
+
+/// Account role stored in `users.role`. Ordered by privilege.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    User,
+    Moderator,
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::User => "user",
+            Role::Moderator => "moderator",
+            Role::Admin => "admin",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "user" => Some(Role::User),
+            "moderator" => Some(Role::Moderator),
+            "admin" => Some(Role::Admin),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SanctionKind {
+    Ban,
+    Mute,
+}
+
+impl SanctionKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SanctionKind::Ban => "ban",
+            SanctionKind::Mute => "mute",
+        }
+    }
+}
+
+/// An active ban or mute. `until` is `None` for permanent sanctions.
+pub struct Sanction {
+    pub kind: SanctionKind,
+    pub until: Option<String>,
+    pub reason: Option<String>,
+}
+
+impl Sanction {
+    /// Message shown to the sanctioned user.
+    pub fn describe(&self) -> String {
+        let what = match self.kind {
+            SanctionKind::Ban => "You are banned",
+            SanctionKind::Mute => "You are muted",
+        };
+        let mut text = match &self.until {
+            Some(until) => format!("{what} until {until}"),
+            None => what.to_string(),
+        };
+        if let Some(reason) = &self.reason {
+            text.push_str(&format!(": {reason}"));
+        }
+        text
+    }
+
+    pub fn code(&self) -> ErrorCode {
+        match self.kind {
+            SanctionKind::Ban => ErrorCode::Banned,
+            SanctionKind::Mute => ErrorCode::Muted,
+        }
+    }
+}
+
+pub enum ModAction {
+    Sanction {
+        kind: SanctionKind,
+        user: String,
+        duration_secs: Option<i64>,
+        reason: Option<String>,
+    },
+    Lift {
+        kind: SanctionKind,
+        user: String,
+    },
+    SetRole {
+        user: String,
+        role: Role,
+    },
+}
+
+impl ModAction {
+    fn target(&self) -> &str {
+        match self {
+            ModAction::Sanction { user, .. }
+            | ModAction::Lift { user, .. }
+            | ModAction::SetRole { user, .. } => user,
+        }
+    }
+}
+
+pub struct Moderation {}
+impl Moderation {
+    /// Checks that `actor` may perform `action`, applies it and records it
+    /// in the audit table with the same statement. Returns the message for the actor, or the error
+    /// code and message when the action was refused.
+    pub async fn apply(
+        app_state: &AppState,
+        actor: &str,
+        action: ModAction,
+    ) -> Result<String, (ErrorCode, String)> {
+        let database = &app_state.database;
+        let internal = |err: tokio_postgres::Error| {
+            error!("Error while working with the database: {err}");
+            (ErrorCode::Internal, "Internal server error".to_string())
+        };
+        let actor_role = database
+            .get_role(actor)
+            .await
+            .map_err(internal)?
+            .unwrap_or(Role::User);
+        let target = action.target().to_string();
+        let Some(target_role) = database.get_role(&target).await.map_err(internal)? else {
+            return Err((ErrorCode::NotFound, format!("No user named {target}")));
+        };
+
+        let allowed = match &action {
+            ModAction::SetRole { .. } => actor_role == Role::Admin && target != actor,
+            _ => actor_role >= Role::Moderator && target_role < actor_role,
+        };
+        if !allowed {
+            warn!(%actor, %target, "Moderation action refused");
+            return Err((
+                ErrorCode::Forbidden,
+                "You are not allowed to do that".to_string(),
+            ));
+        }
+
+        let (audit_action, detail, reply) = match action {
+            ModAction::Sanction {
+                kind,
+                user,
+                duration_secs,
+                reason,
+            } => {
+                if duration_secs.is_some_and(|d| !(1..=MAX_SANCTION_SECS).contains(&d)) {
+                    return Err((
+                        ErrorCode::InvalidRequest,
+                        format!(
+                            "The duration must be between 1 second and {} days, leave it out for a permanent sanction",
+                            MAX_SANCTION_SECS / 86_400
+                        ),
+                    ));
+                }
+                let length = match duration_secs {
+                    Some(d) => format!("{d}s"),
+                    None => "permanent".to_string(),
+                };
+                let detail = match &reason {
+                    Some(r) => format!("duration={length} reason={r}"),
+                    None => format!("duration={length}"),
+                };
+                let audit = AuditEntry {
+                    actor,
+                    action: kind.as_str(),
+                    detail: &detail,
+                };
+                database
+                    .add_sanction(&user, kind, duration_secs, reason.as_deref(), audit)
+                    .await
+                    .map_err(internal)?;
+                if kind == SanctionKind::Ban {
+                    app_state.publish(FanoutEvent::Kick { user: user.clone() });
+                }
+                (
+                    kind.as_str().to_string(),
+                    detail,
+                    format!("{user}: {} ({length})", kind.as_str()),
+                )
+            }
+            ModAction::Lift { kind, user } => {
+                let audit_action = format!("un{}", kind.as_str());
+                let audit = AuditEntry {
+                    actor,
+                    action: &audit_action,
+                    detail: "",
+                };
+                let lifted = database
+                    .lift_sanction(&user, kind, audit)
+                    .await
+                    .map_err(internal)?;
+                if lifted == 0 {
+                    return Err((
+                        ErrorCode::NotFound,
+                        format!("{user} has no active {}", kind.as_str()),
+                    ));
+                }
+                (
+                    audit_action,
+                    String::new(),
+                    format!("{user}: {} lifted", kind.as_str()),
+                )
+            }
+            ModAction::SetRole { user, role } => {
+                let detail = format!("role={}", role.as_str());
+                let audit = AuditEntry {
+                    actor,
+                    action: "set_role",
+                    detail: &detail,
+                };
+                database
+                    .set_role(&user, role, audit)
+                    .await
+                    .map_err(internal)?;
+                (
+                    "set_role".to_string(),
+                    detail,
+                    format!("{user} is now {}", role.as_str()),
+                )
+            }
+        };
+        info!(%actor, %target, action = %audit_action, %detail, "Moderation action");
+        Ok(reply)
+    }
+}
@@ -0,0 +1,147 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+};
+use tokio::sync::{
+    Notify,
+    mpsc::{self, error::TryRecvError, error::TrySendError},
+};
+
+use crate::network_manager::{handlers::InternalMessage, metrics::METRICS};
+
+static NEXT_SESSION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Handle to one connected WebSocket session, stored in `AppState.map`.
+pub struct SessionHandle {
+    /// Unique within this process, unlike the token safe to share.
+    pub id: u64,
+    pub tx: mpsc::Sender<InternalMessage>,
+    /// Woken when the server wants the session's socket closed.
+    pub kick: Arc<Notify>,
+    /// Notifications that did not fit in `tx`, see `offer`.
+    overflow: Arc<Mutex<VecDeque<InternalMessage>>>,
+    spill_capacity: usize,
+}
+
+/// What became of a notification passed to `SessionHandle::offer`.
+#[derive(Debug, PartialEq)]
+pub enum Offer {
+    Queued,
+    /// Parked in the overflow store until the socket catches up.
+    Spilled,
+    /// Neither the queue nor the overflow store had room.
+    Full,
+    /// The session's send task is gone.
+    Closed,
+}
+
+impl SessionHandle {
+    /// `spill_capacity` is how many notifications may wait in the overflow
+    /// store once the queue is full; 0 disables it.
+    pub fn new(capacity: usize, spill_capacity: usize) -> (Self, SessionReceiver) {
+        let (tx, rx) = mpsc::channel::<InternalMessage>(capacity);
+        let overflow = Arc::new(Mutex::new(VecDeque::new()));
+        (
+            Self {
+                id: NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed),
+                tx,
+                kick: Arc::new(Notify::new()),
+                overflow: overflow.clone(),
+                spill_capacity,
+            },
+            SessionReceiver { rx, overflow },
+        )
+    }
+
+    /// Queues a notification without waiting. Once anything has spilled,
+    /// later notifications spill too so the client sees them in order.
+    pub fn offer(&self, message: InternalMessage) -> Offer {
+        let Ok(mut overflow) = self.overflow.lock() else {
+            return Offer::Closed;
+        };
+        if overflow.is_empty() {
+            match self.tx.try_send(message) {
+                Ok(()) => return Offer::Queued,
+                Err(TrySendError::Closed(_)) => return Offer::Closed,
+                Err(TrySendError::Full(message)) if self.spill_capacity > 0 => {
+                    overflow.push_back(message);
+                    return Offer::Spilled;
+                }
+                Err(TrySendError::Full(_)) => return Offer::Full,
+            }
+        }
+        if self.tx.is_closed() {
+            Offer::Closed
+        } else if overflow.len() < self.spill_capacity {
+            overflow.push_back(message);
+            Offer::Spilled
+        } else {
+            Offer::Full
+        }
+    }
+
+    /// Number of messages waiting to be written to the socket.
+    pub fn depth(&self) -> usize {
+        self.tx.max_capacity() - self.tx.capacity()
+    }
+}
+
+/// Read side of a session's queue, drained by its send task.
+pub struct SessionReceiver {
+    rx: mpsc::Receiver<InternalMessage>,
+    overflow: Arc<Mutex<VecDeque<InternalMessage>>>,
+}
+
+impl SessionReceiver {
+    /// Next message for the socket. The overflow store is drained only once
+    /// the queue is empty, since everything in it was offered later.
+    pub async fn recv(&mut self) -> Option<InternalMessage> {
+        match self.rx.try_recv() {
+            Ok(message) => return Some(message),
+            Err(TryRecvError::Disconnected) => return None,
+            Err(TryRecvError::Empty) => {}
+        }
+        let spilled = self.overflow.lock().ok()?.pop_front();
+        match spilled {
+            Some(message) => Some(message),
+            None => self.rx.recv().await,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct QueueMetrics {
+    pub dropped: AtomicU64,
+    pub disconnected: AtomicU64,
+    pub max_depth: AtomicUsize,
+}
+
+impl QueueMetrics {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    pub fn observe_depth(&self, depth: usize) {
+        self.max_depth.fetch_max(depth, Ordering::Relaxed);
+    }
+
+    /// A live notification did not fit in a session's queue.
+    pub fn record_dropped(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+        METRICS.queue_dropped.inc();
+    }
+
+    /// A live notification was parked in a session's overflow store.
+    pub fn record_spilled(&self) {
+        METRICS.queue_spilled.inc();
+    }
+
+    /// A session was closed by the slow-consumer policy.
+    pub fn record_disconnected(&self) {
+        self.disconnected.fetch_add(1, Ordering::Relaxed);
+        METRICS.queue_disconnected.inc();
+    }
+}
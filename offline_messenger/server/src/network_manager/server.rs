@@ -1,74 +1,762 @@
 use crate::network_manager::{
+    admin::AdminHandlers,
+    archive,
+    bots::BotHandlers,
+    commands::Commands,
+    config::{FanoutBackend, ServerConfig},
     database_manager::DataBase,
+    fanout::{FanoutBus, FanoutEvent, LocalBus, Origin, PostgresBus},
     handlers::{Handlers, InternalMessage},
+    logging,
+    metrics::METRICS,
+    queue::{Offer, QueueMetrics, SessionHandle},
     session_manager::SessionManager,
+    tls,
+    webhooks::{self, WebhookEvent},
 };
 use axum::{
     Router,
-    routing::{any, get},
+    extract::DefaultBodyLimit,
+    routing::{any, get, post, put},
 };
-use axum_server::tls_rustls::RustlsConfig;
+use axum_server::{Handle, tls_rustls::RustlsConfig};
+use futures_util::future::join_all;
 use std::{
     collections::HashMap,
     error::Error,
+    io,
     net::SocketAddr,
-    path::PathBuf,
-    sync::{Arc, Mutex},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
 };
-use tokio::sync::mpsc;
+#[cfg(unix)]
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::{sync::watch, task::JoinHandle};
+use tower_http::trace::{DefaultOnResponse, TraceLayer};
+use tracing::{Level, debug, error, info, warn};
+use uuid::Uuid;
+
+type UserSessions = HashMap<String, SessionHandle>;
+
+/// How often each instance re-announces the users connected to it.
+const PRESENCE_INTERVAL: Duration = Duration::from_secs(15);
+/// Most bus events delivered together, see `AppState::deliver`.
+const FANOUT_BATCH: usize = 256;
+/// Most scheduled messages delivered per scheduler tick.
+const SCHEDULER_BATCH: i64 = 500;
+/// How long deletions are remembered for clients catching up with `Sync`.
+const SYNC_RETENTION: Duration = Duration::from_secs(30 * 86_400);
+/// How long a retried command is answered with its first result.
+const COMMAND_RETENTION: Duration = Duration::from_secs(86_400);
 
-type UserTx = mpsc::UnboundedSender<InternalMessage>;
-type UserSessions = HashMap<String, UserTx>;
 pub struct AppState {
     pub session_manager: Arc<SessionManager>,
     pub database: Arc<DataBase>,
     pub map: Arc<Mutex<HashMap<String, UserSessions>>>,
+    pub config: Arc<ServerConfig>,
+    pub queue_metrics: Arc<QueueMetrics>,
+    /// Set once the TLS certificate and key have been loaded.
+    pub tls_loaded: AtomicBool,
+    /// Flipped to true when the server starts shutting down.
+    pub shutdown: watch::Sender<bool>,
+    /// Number of `handle_socket` tasks still running.
+    pub open_sessions: watch::Sender<usize>,
+    /// Reaches sessions on every instance, see `FanoutBus`.
+    pub bus: Arc<dyn FanoutBus>,
+    /// Tells this process apart from other instances on the bus.
+    pub instance_id: String,
+    /// Slash commands answered by the server.
+    pub commands: Commands,
+}
+
+impl AppState {
+    /// Current outbound queue depth of every connected session, as
+    /// `(username, token, depth)`.
+    pub fn queue_depths(&self) -> Vec<(String, String, usize)> {
+        let map = match self.map.lock() {
+            Ok(m) => m,
+            Err(err) => {
+                error!("Error while locking the map in app_state: {err}");
+                return Vec::new();
+            }
+        };
+        let mut depths = Vec::new();
+        for (user, sessions) in map.iter() {
+            for (token, session) in sessions {
+                depths.push((user.clone(), token.clone(), session.depth()));
+            }
+        }
+        depths
+    }
+
+    /// Removes every live session of `user` from the map, closes their
+    /// sockets and revokes their tokens. Returns how many sessions were
+    /// closed.
+    pub fn kick_user(&self, user: &str) -> usize {
+        self.session_manager.close_user(user);
+        let sessions = match self.map.lock() {
+            Ok(mut map) => map.remove(user),
+            Err(err) => {
+                error!("Error while locking the map in app_state: {err}");
+                return 0;
+            }
+        };
+        let Some(sessions) = sessions else {
+            return 0;
+        };
+        for session in sessions.values() {
+            session.kick.notify_one();
+        }
+        info!(%user, sessions = sessions.len(), "Kicked user");
+        sessions.len()
+    }
+
+    /// Queues a `ServerShutdown` event for every connected client, then tells
+    /// the sessions to flush their queues and close.
+    fn begin_shutdown(&self) {
+        match self.map.lock() {
+            Ok(map) => {
+                for sessions in map.values() {
+                    for session in sessions.values() {
+                        let notice = InternalMessage::ServerShutdown {
+                            reconnect_after_ms: self.config.reconnect_after_ms,
+                        };
+                        if let Err(err) = session.tx.try_send(notice) {
+                            warn!("Error while queueing the shutdown notice: {err}");
+                        }
+                    }
+                }
+            }
+            Err(err) => error!("Error while locking the map in app_state: {err}"),
+        }
+        self.shutdown.send_replace(true);
+    }
+
+    /// Hands a live event to the fan-out bus, which brings it to the sessions
+    /// on every instance.
+    pub fn publish(&self, event: FanoutEvent) {
+        self.bus.publish(event);
+    }
+
+    /// Delivers a batch of events from the bus to the sessions of this
+    /// instance. Stored messages are loaded concurrently, events are fanned
+    /// out in order, and each conversation's inbox entry is refreshed once.
+    async fn deliver(&self, events: Vec<FanoutEvent>) {
+        let skip = |origin: Option<Origin>| {
+            origin
+                .filter(|o| o.instance == self.instance_id)
+                .map(|o| o.session)
+        };
+        let events = join_all(events.into_iter().map(|e| self.load_stored(e))).await;
+        let mut conversations: Vec<(String, String)> = Vec::new();
+        for event in events.into_iter().flatten() {
+            match event {
+                FanoutEvent::Message {
+                    origin,
+                    from,
+                    to,
+                    message,
+                } => {
+                    Handlers::fan_out(self, skip(origin), &from, &to, &message);
+                    let pair = match from <= to {
+                        true => (from, to),
+                        false => (to, from),
+                    };
+                    if !conversations.contains(&pair) {
+                        conversations.push(pair);
+                    }
+                }
+                // Turned into `Message` by `load_stored`.
+                FanoutEvent::StoredMessage { .. } => {}
+                FanoutEvent::User { user, message } => self.notify_user(&user, &message),
+                FanoutEvent::Kick { user } => {
+                    self.kick_user(&user);
+                }
+            }
+        }
+        join_all(
+            conversations
+                .iter()
+                .map(|(a, b)| self.update_conversations(a, b)),
+        )
+        .await;
+    }
+
+    /// Replaces a `StoredMessage` with the `Message` it stands for. Returns
+    /// `None` when the message is gone or could not be loaded.
+    async fn load_stored(&self, event: FanoutEvent) -> Option<FanoutEvent> {
+        let FanoutEvent::StoredMessage { origin, message_id } = event else {
+            return Some(event);
+        };
+        let row = match self.database.message_by_id(message_id).await {
+            Ok(row) => row?,
+            Err(err) => {
+                error!("Error while loading message {message_id}: {err}");
+                return None;
+            }
+        };
+        let from: String = row.get(0);
+        let to: String = row.get(1);
+        let message = InternalMessage::Notification {
+            sender: from.clone(),
+            reciever: to.clone(),
+            content: row.get(2),
+            resp_msg: row.get(3),
+            resp_user: row.get(4),
+            encrypted: row.get(5),
+            message_id,
+        };
+        Some(FanoutEvent::Message {
+            origin,
+            from,
+            to,
+            message,
+        })
+    }
+
+    /// Sends the new inbox entry of a conversation that just got a message
+    /// to the participants' sessions on this instance, the sending one
+    /// included.
+    async fn update_conversations(&self, from: &str, to: &str) {
+        let participants = match from == to {
+            true => vec![(from, to)],
+            false => vec![(from, to), (to, from)],
+        };
+        for (user, with) in participants {
+            let connected = match self.map.lock() {
+                Ok(map) => map.contains_key(user),
+                Err(err) => {
+                    error!("Error while locking the map in app_state: {err}");
+                    false
+                }
+            };
+            if !connected {
+                continue;
+            }
+            if let Some(message) = Handlers::conversation_update(self, user, with).await {
+                self.notify_user(user, &message);
+            }
+        }
+    }
+
+    /// Queues `message` for every live session of `user` on this instance,
+    /// applying the slow-consumer policy to sessions that cannot take it.
+    fn notify_user(&self, user: &str, message: &InternalMessage) {
+        let mut map = match self.map.lock() {
+            Ok(m) => m,
+            Err(err) => {
+                error!("Error while locking the map in app_state: {err}");
+                return;
+            }
+        };
+        let Some(sessions) = map.get(user) else {
+            return;
+        };
+        let slow: Vec<(String, String)> = sessions
+            .iter()
+            .filter(|(_, session)| !self.offer(user, session, message.clone()))
+            .map(|(token, _)| (user.to_string(), token.clone()))
+            .collect();
+        self.disconnect_slow(&mut map, slow);
+    }
+
+    /// Offers a live notification to one session. Returns false when the
+    /// session has no room left and has to be disconnected.
+    pub(crate) fn offer(
+        &self,
+        user: &str,
+        session: &SessionHandle,
+        message: InternalMessage,
+    ) -> bool {
+        match session.offer(message) {
+            Offer::Queued => {
+                self.queue_metrics.observe_depth(session.depth());
+                true
+            }
+            Offer::Spilled => {
+                self.queue_metrics.record_spilled();
+                true
+            }
+            Offer::Full => {
+                self.queue_metrics.record_dropped();
+                false
+            }
+            Offer::Closed => {
+                warn!(%user, "Error while queueing a notification: the session is closed");
+                true
+            }
+        }
+    }
+
+    /// Removes the given `(user, token)` sessions from `map` and closes their
+    /// sockets. They catch up with `Sync` when they reconnect.
+    pub(crate) fn disconnect_slow(
+        &self,
+        map: &mut HashMap<String, UserSessions>,
+        slow: Vec<(String, String)>,
+    ) {
+        for (user, session_token) in slow {
+            if let Some(sessions) = map.get_mut(&user) {
+                if let Some(session) = sessions.remove(&session_token) {
+                    warn!(%user, session = logging::session_tag(&session_token), "Disconnecting slow consumer");
+                    self.queue_metrics.record_disconnected();
+                    session.kick.notify_one();
+                }
+                if sessions.is_empty() {
+                    map.remove(&user);
+                }
+            }
+        }
+    }
+
+    /// Users connected to any instance, sorted. Instances that stopped
+    /// checking in for a few rounds of `PRESENCE_INTERVAL` are left out.
+    pub async fn online_users(&self) -> Result<Vec<String>, tokio_postgres::Error> {
+        self.database.online_users(PRESENCE_INTERVAL * 3).await
+    }
+
+    /// Re-announces the users connected to this instance, keeping them in
+    /// `online_users` and correcting rows a racing connect or disconnect
+    /// left behind.
+    async fn refresh_presence(&self) {
+        let users: Vec<String> = match self.map.lock() {
+            Ok(map) => map.keys().cloned().collect(),
+            Err(err) => {
+                error!("Error while locking the map in app_state: {err}");
+                return;
+            }
+        };
+        if let Err(err) = self
+            .database
+            .refresh_presence(&self.instance_id, &users)
+            .await
+        {
+            error!("Error while refreshing presence: {err}");
+        }
+    }
+
+    /// Deletes expired messages and tells the live sessions of both
+    /// participants which ones are gone.
+    async fn sweep_expired(&self) {
+        let rows = match self.database.delete_expired().await {
+            Ok(rows) => rows,
+            Err(err) => {
+                error!("Error while deleting expired messages: {err}");
+                return;
+            }
+        };
+        if rows.is_empty() {
+            return;
+        }
+        let mut expired: HashMap<String, Vec<i64>> = HashMap::new();
+        for row in &rows {
+            let id = i64::from(row.get::<_, i32>(0));
+            let sender: String = row.get(1);
+            let receiver: String = row.get(2);
+            if sender != receiver {
+                expired.entry(receiver).or_default().push(id);
+            }
+            expired.entry(sender).or_default().push(id);
+        }
+        for (user, ids) in expired {
+            self.publish(FanoutEvent::User {
+                user,
+                message: InternalMessage::MessagesExpired { ids },
+            });
+        }
+        info!(count = rows.len(), "Deleted expired messages");
+    }
+
+    /// Forgets deletions older than `SYNC_RETENTION` and command results
+    /// older than `COMMAND_RETENTION`.
+    async fn prune_tombstones(&self) {
+        match self.database.prune_tombstones(SYNC_RETENTION).await {
+            Ok(0) => {}
+            Ok(count) => info!(count, "Pruned message tombstones"),
+            Err(err) => error!("Error while pruning message tombstones: {err}"),
+        }
+        match self.database.prune_command_runs(COMMAND_RETENTION).await {
+            Ok(0) => {}
+            Ok(count) => info!(count, "Pruned command results"),
+            Err(err) => error!("Error while pruning command results: {err}"),
+        }
+    }
+
+    /// Stores and fans out scheduled messages that are due, the same way a
+    /// `SendMessage` would, and tells the senders which ones went out.
+    async fn deliver_scheduled(&self) {
+        let rows = match self.database.deliver_due(SCHEDULER_BATCH).await {
+            Ok(rows) => rows,
+            Err(err) => {
+                error!("Error while delivering scheduled messages: {err}");
+                return;
+            }
+        };
+        for row in &rows {
+            let scheduled_id: i64 = row.get(0);
+            let message_id = i64::from(row.get::<_, i32>(1));
+            let from: String = row.get(2);
+            let to: String = row.get(3);
+            let content: String = row.get(4);
+            let resp_msg: Option<String> = row.get(5);
+            let resp_user: Option<String> = row.get(6);
+            let encrypted: bool = row.get(7);
+            METRICS.messages_sent.inc();
+            debug!(%from, %to, scheduled_id, message_id, "Delivered scheduled message");
+            let event = WebhookEvent::MessageSent {
+                message_id,
+                from: from.clone(),
+                to: to.clone(),
+                encrypted,
+                content: (!encrypted).then(|| content.clone()),
+            };
+            self.publish(FanoutEvent::Message {
+                origin: None,
+                from: from.clone(),
+                to: to.clone(),
+                message: InternalMessage::Notification {
+                    sender: from.clone(),
+                    reciever: to,
+                    content,
+                    resp_msg,
+                    resp_user,
+                    encrypted,
+                    message_id,
+                },
+            });
+            self.publish(FanoutEvent::User {
+                user: from,
+                message: InternalMessage::ScheduledDelivered {
+                    scheduled_id,
+                    message_id,
+                },
+            });
+            webhooks::enqueue(&self.database, &self.config, event).await;
+        }
+        if !rows.is_empty() {
+            info!(count = rows.len(), "Delivered scheduled messages");
+        }
+    }
+
+    fn report_queues(&self) {
+        let depths = self.queue_depths();
+        let deepest = depths.iter().max_by_key(|d| d.2);
+        let dropped = self.queue_metrics.dropped.load(Ordering::Relaxed);
+        let disconnected = self.queue_metrics.disconnected.load(Ordering::Relaxed);
+        let max_depth = self.queue_metrics.max_depth.load(Ordering::Relaxed);
+        if let Some((user, _, depth)) = deepest {
+            info!(
+                sessions = depths.len(),
+                deepest = depth,
+                deepest_user = %user,
+                capacity = self.config.queue_capacity,
+                high_watermark = max_depth,
+                dropped,
+                disconnected,
+                "Outbound queue report"
+            );
+        }
+    }
 }
 
 pub struct Server {
     session_manager: Arc<SessionManager>,
+    config: Arc<ServerConfig>,
+}
+
+/// A server started with `Server::spawn`, serving in the background.
+pub struct RunningServer {
+    /// Address the HTTPS listener is bound to.
+    pub addr: SocketAddr,
+    /// Address the admin listener is bound to, when it is enabled.
+    pub admin_addr: Option<SocketAddr>,
+    pub app_state: Arc<AppState>,
+    handle: Handle<SocketAddr>,
+    serve_task: JoinHandle<io::Result<()>>,
+    /// Scheduler, sweeper and the other background loops.
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl RunningServer {
+    /// Drains the sessions the same way a SIGTERM does, then stops the
+    /// listener and the background tasks.
+    pub async fn shutdown(mut self) {
+        let deadline = self.app_state.config.shutdown_deadline;
+        let started = Instant::now();
+        info!(
+            deadline_secs = deadline.as_secs(),
+            "Shutdown signal received, draining sessions"
+        );
+        self.app_state.begin_shutdown();
+        self.handle.graceful_shutdown(Some(deadline));
+        let mut open_sessions = self.app_state.open_sessions.subscribe();
+        if tokio::time::timeout(deadline, open_sessions.wait_for(|n| *n == 0))
+            .await
+            .is_err()
+        {
+            warn!("Some sessions did not close before the shutdown deadline");
+        }
+        let remaining = deadline.saturating_sub(started.elapsed());
+        if tokio::time::timeout(remaining, &mut self.serve_task)
+            .await
+            .is_err()
+        {
+            self.serve_task.abort();
+        }
+        for task in &self.tasks {
+            task.abort();
+        }
+        self.app_state.database.close();
+        info!("Server stopped");
+    }
 }
 
 impl Server {
     pub fn new() -> Self {
+        Self::with_config(ServerConfig::from_env())
+    }
+
+    pub fn with_config(config: ServerConfig) -> Self {
         Self {
             session_manager: Arc::new(SessionManager::new()),
+            config: Arc::new(config),
         }
     }
+
+    /// Runs until Ctrl-C or SIGTERM, then shuts down gracefully.
     pub async fn start(&self) -> Result<(), Box<dyn Error>> {
-        let database = DataBase::new().await?;
+        let mut running = self.spawn().await?;
+        tokio::select! {
+            result = &mut running.serve_task => {
+                result??;
+                return Ok(());
+            }
+            _ = Server::shutdown_signal() => {}
+        }
+        running.shutdown().await;
+        Ok(())
+    }
+
+    /// Connects to the database, starts the background tasks and binds the
+    /// listeners. Returns once the HTTPS listener accepts connections.
+    pub async fn spawn(&self) -> Result<RunningServer, Box<dyn Error>> {
+        // reqwest links ring next to axum-server's aws-lc-rs, so rustls can
+        // no longer pick a crypto provider on its own.
+        let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+        let database = DataBase::connect(&self.config.database_url).await?;
+        database.migrate().await?;
+        let (bus, mut events): (Arc<dyn FanoutBus>, _) = match self.config.fanout {
+            FanoutBackend::Local => {
+                let (bus, events) = LocalBus::new(self.config.fanout_capacity);
+                (bus, events)
+            }
+            FanoutBackend::Postgres => {
+                let (bus, events) =
+                    PostgresBus::connect(&self.config.database_url, self.config.fanout_capacity)
+                        .await?;
+                (bus, events)
+            }
+        };
         let app_state = Arc::new(AppState {
             session_manager: self.session_manager.clone(),
             database: database.clone(),
             map: Arc::new(Mutex::new(HashMap::new())),
+            config: self.config.clone(),
+            queue_metrics: QueueMetrics::new(),
+            tls_loaded: AtomicBool::new(false),
+            shutdown: watch::channel(false).0,
+            open_sessions: watch::channel(0).0,
+            bus,
+            instance_id: Uuid::new_v4().to_string(),
+            commands: Commands::builtin(),
         });
+        let mut tasks = Vec::new();
+
+        let fanout_state = app_state.clone();
+        tasks.push(tokio::spawn(async move {
+            let mut batch = Vec::with_capacity(FANOUT_BATCH);
+            while events.recv_many(&mut batch, FANOUT_BATCH).await > 0 {
+                fanout_state.deliver(std::mem::take(&mut batch)).await;
+            }
+        }));
+
+        let report_state = app_state.clone();
+        tasks.push(tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(60));
+            loop {
+                interval.tick().await;
+                report_state.report_queues();
+            }
+        }));
+
+        let presence_state = app_state.clone();
+        tasks.push(tokio::spawn(async move {
+            let mut interval = tokio::time::interval(PRESENCE_INTERVAL);
+            loop {
+                interval.tick().await;
+                presence_state.refresh_presence().await;
+            }
+        }));
+
+        let scheduler_state = app_state.clone();
+        let scheduler_interval = self.config.scheduler_interval;
+        tasks.push(tokio::spawn(async move {
+            let mut interval = tokio::time::interval(scheduler_interval);
+            loop {
+                interval.tick().await;
+                scheduler_state.deliver_scheduled().await;
+            }
+        }));
+
+        let sweep_state = app_state.clone();
+        let sweep_interval = self.config.expiry_sweep_interval;
+        tasks.push(tokio::spawn(async move {
+            let mut interval = tokio::time::interval(sweep_interval);
+            loop {
+                interval.tick().await;
+                sweep_state.sweep_expired().await;
+                sweep_state.prune_tombstones().await;
+            }
+        }));
+
+        if self.config.webhooks_enabled() {
+            info!(
+                urls = self.config.webhook_urls.len(),
+                events = ?self.config.webhook_events,
+                "Webhooks enabled"
+            );
+            tasks.push(tokio::spawn(webhooks::run(
+                database.clone(),
+                self.config.clone(),
+            )));
+        }
+
+        let mut admin_addr = None;
+        if let Some(addr) = self.config.admin_addr {
+            let listener = tokio::net::TcpListener::bind(addr).await?;
+            let addr = listener.local_addr()?;
+            admin_addr = Some(addr);
+            let admin_app = AdminHandlers::router(app_state.clone());
+            info!(admin_addr = %addr, "Admin listener started");
+            tasks.push(tokio::spawn(async move {
+                if let Err(err) = axum::serve(listener, admin_app).await {
+                    error!("Error on the admin listener: {err}");
+                }
+            }));
+        }
+
+        let (cert, key) = (&self.config.tls_cert, &self.config.tls_key);
+        if tls::ensure_certificate(cert, key, &self.config.tls_sans)? {
+            warn!(
+                cert = %cert.display(),
+                sans = ?self.config.tls_sans,
+                "No TLS certificate found, generated a self-signed one"
+            );
+        }
+        let settings = tls::TlsSettings::from_config(&self.config);
+        let config = RustlsConfig::from_config(settings.server_config()?);
+        app_state.tls_loaded.store(true, Ordering::Relaxed);
+        match tls::fingerprint(cert) {
+            Ok(fingerprint) => info!(%fingerprint, "TLS certificate loaded"),
+            Err(err) => warn!("TLS certificate loaded, fingerprint unavailable: {err}"),
+        }
+        if let Some(ca) = &self.config.client_ca {
+            info!(ca = %ca.display(), mode = ?self.config.client_auth, "Client certificates enabled");
+        }
+        tasks.push(tokio::spawn(tls::watch(
+            config.clone(),
+            settings,
+            self.config.tls_watch_interval,
+        )));
 
+        let handle = Handle::new();
+        let serve_task = tokio::spawn(
+            axum_server::bind(self.config.listen_addr)
+                .acceptor(tls::ClientCertAcceptor::new(config))
+                .handle(handle.clone())
+                .serve(
+                    Server::router(app_state.clone())
+                        .into_make_service_with_connect_info::<SocketAddr>(),
+                ),
+        );
+        let Some(addr) = handle.listening().await else {
+            serve_task.await??;
+            return Err("the HTTPS listener stopped while starting".into());
+        };
+        info!(%addr, fanout = ?self.config.fanout, "Listening");
+        Ok(RunningServer {
+            addr,
+            admin_addr,
+            app_state,
+            handle,
+            serve_task,
+            tasks,
+        })
+    }
+
+    /// Every public HTTPS and WebSocket route.
+    pub fn router(app_state: Arc<AppState>) -> Router {
         let start_routes: Router = Router::new()
             .route("/login", get(Handlers::login))
             .route("/signin", get(Handlers::signin))
+            .route("/bot/login", post(BotHandlers::login))
             .with_state(app_state.clone());
         let messenger_routes: Router = Router::new()
             .route("/ws", any(Handlers::ws_handler))
-            .with_state(app_state.clone());
-        let app = Router::new().merge(start_routes).merge(messenger_routes);
-        let config = match RustlsConfig::from_pem_file(
-            PathBuf::from(env!("CARGO_MANIFEST_DIR"))
-                .join("certs")
-                .join("server.crt"),
-            PathBuf::from(env!("CARGO_MANIFEST_DIR"))
-                .join("certs")
-                .join("server.key"),
-        )
-        .await
-        {
-            Ok(config) => config,
-            Err(err) => panic!("Error, certs {err}"),
+            .route("/keys", put(Handlers::publish_key))
+            .route("/keys/{user}", get(Handlers::device_keys))
+            .route("/export", get(Handlers::export_history))
+            .route(
+                "/import",
+                post(Handlers::import_history).layer(DefaultBodyLimit::max(
+                    archive::MAX_ARCHIVE_BYTES * 4 / 3 + 1024,
+                )),
+            )
+            .with_state(app_state);
+        Router::new()
+            .merge(start_routes)
+            .merge(messenger_routes)
+            .layer(
+                TraceLayer::new_for_http()
+                    .make_span_with(logging::http_span)
+                    .on_response(DefaultOnResponse::new().level(Level::INFO)),
+            )
+    }
+
+    /// Resolves on Ctrl-C or, on Unix, SIGTERM.
+    async fn shutdown_signal() {
+        let ctrl_c = async {
+            if let Err(err) = tokio::signal::ctrl_c().await {
+                error!("Error while listening for Ctrl-C: {err}");
+                std::future::pending::<()>().await;
+            }
+        };
+        #[cfg(unix)]
+        let terminate = async {
+            match signal(SignalKind::terminate()) {
+                Ok(mut sigterm) => {
+                    sigterm.recv().await;
+                }
+                Err(err) => {
+                    error!("Error while listening for SIGTERM: {err}");
+                    std::future::pending::<()>().await;
+                }
+            }
         };
-        let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
+        #[cfg(not(unix))]
+        let terminate = std::future::pending::<()>();
+        tokio::select! {
+            _ = ctrl_c => {}
+            _ = terminate => {}
+        }
+    }
+}
 
-        axum_server::bind_rustls(addr, config)
-            .serve(app.into_make_service_with_connect_info::<SocketAddr>())
-            .await?;
-        Ok(())
+impl Default for Server {
+    fn default() -> Self {
+        Self::new()
     }
 }
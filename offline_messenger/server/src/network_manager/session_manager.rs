@@ -1,11 +1,21 @@
 use std::{
     collections::HashMap,
-    sync::{Arc, Mutex},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::{Duration, Instant},
 };
+use tracing::info;
 use uuid::Uuid;
 
+struct Session {
+    user: String,
+    /// Open WebSockets using the token.
+    sockets: usize,
+    /// When the last of them closed.
+    idle_since: Option<Instant>,
+}
+
 pub struct SessionManager {
-    sessions: Arc<Mutex<HashMap<String, String>>>,
+    sessions: Arc<Mutex<HashMap<String, Session>>>,
 }
 
 impl SessionManager {
@@ -17,18 +27,91 @@ impl SessionManager {
 
     pub fn new_session(&self, user: &str) -> String {
         let token = &Uuid::new_v4().to_string().replace("-", "")[..16];
-        let mut sessions = self.sessions.lock().unwrap();
-        sessions.insert(token.to_string(), user.to_string());
+        let mut sessions = self.sessions();
+        sessions.insert(
+            token.to_string(),
+            Session {
+                user: user.to_string(),
+                sockets: 0,
+                idle_since: None,
+            },
+        );
         token.to_string()
     }
+    /// The user a session token was issued to.
+    pub fn user_for(&self, token: &str) -> Option<String> {
+        Some(self.sessions().get(token)?.user.clone())
+    }
+
+    /// The session table. Every change to it is a single insert, update or
+    /// removal, so a panic while it was locked cannot leave it half updated
+    /// and a poisoned lock is used as is.
+    fn sessions(&self) -> MutexGuard<'_, HashMap<String, Session>> {
+        self.sessions.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
     pub fn close_session(&self, token: &str) -> bool {
-        let mut sessions = self.sessions.lock().unwrap();
+        let mut sessions = self.sessions();
         match sessions.remove(token) {
-            Some(user) => {
-                println!("{} logged out", user.clone());
+            Some(session) => {
+                info!(user = %session.user, "Logged out");
                 true
             }
             None => false,
         }
     }
+
+    /// Ends every session of `user`. Returns how many there were.
+    pub fn close_user(&self, user: &str) -> usize {
+        let mut sessions = self.sessions();
+        let before = sessions.len();
+        sessions.retain(|_, session| session.user != user);
+        before - sessions.len()
+    }
+
+    /// A WebSocket started using `token`.
+    pub fn attach(&self, token: &str) {
+        if let Some(session) = self.sessions().get_mut(token) {
+            session.sockets += 1;
+            session.idle_since = None;
+        }
+    }
+
+    /// A WebSocket using `token` closed. Once it was the last one, the
+    /// session ends unless a new one attaches within `grace`.
+    pub fn detach(self: &Arc<Self>, token: &str, grace: Duration) {
+        {
+            let mut sessions = self.sessions();
+            let Some(session) = sessions.get_mut(token) else {
+                return;
+            };
+            session.sockets = session.sockets.saturating_sub(1);
+            if session.sockets > 0 {
+                return;
+            }
+            session.idle_since = Some(Instant::now());
+        }
+        let manager = self.clone();
+        let token = token.to_string();
+        tokio::spawn(async move {
+            tokio::time::sleep(grace).await;
+            manager.expire_idle(&token, grace);
+        });
+    }
+
+    fn expire_idle(&self, token: &str, grace: Duration) {
+        let mut sessions = self.sessions();
+        let idle = sessions.get(token).is_some_and(|session| {
+            session.sockets == 0 && session.idle_since.is_some_and(|at| at.elapsed() >= grace)
+        });
+        if idle && let Some(session) = sessions.remove(token) {
+            info!(user = %session.user, "Session expired");
+        }
+    }
+}
+
+impl Default for SessionManager {
+    fn default() -> Self {
+        Self::new()
+    }
 }
@@ -0,0 +1,297 @@
+use axum::{Extension, middleware::AddExtension};
+use axum_server::{
+    accept::Accept,
+    tls_rustls::{RustlsAcceptor, RustlsConfig},
+};
+use futures_util::future::BoxFuture;
+use rcgen::{CertificateParams, DnType, KeyPair};
+use rustls::{
+    RootCertStore, ServerConfig as RustlsServerConfig,
+    pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+    server::WebPkiClientVerifier,
+};
+use sha2::{Digest, Sha256};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+#[cfg(unix)]
+use tokio::signal::unix::{SignalKind, signal};
+use tokio_rustls::server::TlsStream;
+use tower::Layer;
+use tracing::{debug, error, info, warn};
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use crate::network_manager::config::{ClientAuth, ServerConfig};
+
+/// Files the HTTPS listener is configured from.
+#[derive(Clone)]
+pub struct TlsSettings {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    pub client_ca: Option<PathBuf>,
+    pub client_auth: ClientAuth,
+}
+
+impl TlsSettings {
+    pub fn from_config(config: &ServerConfig) -> Self {
+        Self {
+            cert: config.tls_cert.clone(),
+            key: config.tls_key.clone(),
+            client_ca: config.client_ca.clone(),
+            client_auth: config.client_auth,
+        }
+    }
+
+    fn paths(&self) -> Vec<&Path> {
+        let mut paths = vec![self.cert.as_path(), self.key.as_path()];
+        paths.extend(self.client_ca.as_deref());
+        paths
+    }
+
+    /// Reads the files into a rustls configuration.
+    pub fn server_config(&self) -> io::Result<Arc<RustlsServerConfig>> {
+        let invalid = |err| io::Error::new(io::ErrorKind::InvalidData, err);
+        let certs = CertificateDer::pem_file_iter(&self.cert)
+            .map_err(invalid)?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(invalid)?;
+        let key = PrivateKeyDer::from_pem_file(&self.key).map_err(invalid)?;
+        let builder = RustlsServerConfig::builder();
+        let builder = match &self.client_ca {
+            Some(ca) => {
+                let mut roots = RootCertStore::empty();
+                for cert in CertificateDer::pem_file_iter(ca).map_err(invalid)? {
+                    roots
+                        .add(cert.map_err(invalid)?)
+                        .map_err(io::Error::other)?;
+                }
+                let verifier = WebPkiClientVerifier::builder(Arc::new(roots));
+                let verifier = match self.client_auth {
+                    ClientAuth::Optional => verifier.allow_unauthenticated(),
+                    ClientAuth::Required => verifier,
+                };
+                builder.with_client_cert_verifier(verifier.build().map_err(io::Error::other)?)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let mut config = builder
+            .with_single_cert(certs, key)
+            .map_err(io::Error::other)?;
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Ok(Arc::new(config))
+    }
+}
+
+/// Writes a self-signed certificate valid for `sans` to `cert` and `key`
+/// when neither file exists. Returns whether one was generated.
+///
+/// Only one of the two files existing is an error rather than a reason to
+/// overwrite it.
+pub fn ensure_certificate(cert: &Path, key: &Path, sans: &[String]) -> io::Result<bool> {
+    match (cert.exists(), key.exists()) {
+        (true, true) => return Ok(false),
+        (false, false) => {}
+        (true, false) => return Err(missing_pair(key, cert)),
+        (false, true) => return Err(missing_pair(cert, key)),
+    }
+    let mut params = CertificateParams::new(sans.to_vec()).map_err(io::Error::other)?;
+    params
+        .distinguished_name
+        .push(DnType::CommonName, "messenger server");
+    let key_pair = KeyPair::generate().map_err(io::Error::other)?;
+    let certificate = params.self_signed(&key_pair).map_err(io::Error::other)?;
+
+    for path in [cert, key] {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+    }
+    write_private(key, key_pair.serialize_pem().as_bytes())?;
+    fs::write(cert, certificate.pem())?;
+    Ok(true)
+}
+
+fn missing_pair(missing: &Path, present: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!(
+            "{} is missing but {} exists, refusing to generate a new pair",
+            missing.display(),
+            present.display()
+        ),
+    )
+}
+
+/// Creates `path` readable by the owner only.
+fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::{io::Write, os::unix::fs::OpenOptionsExt};
+        fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)?
+            .write_all(contents)
+    }
+    #[cfg(not(unix))]
+    fs::write(path, contents)
+}
+
+/// SHA-256 of the first certificate in `cert`, as colon separated hex.
+/// This is what clients pin.
+pub fn fingerprint(cert: &Path) -> io::Result<String> {
+    let pem = fs::read(cert)?;
+    let der = CertificateDer::pem_slice_iter(&pem)
+        .next()
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("no certificate in {}", cert.display()),
+            )
+        })?
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    Ok(Sha256::digest(&der)
+        .iter()
+        .map(|b| format!("{b:02X}"))
+        .collect::<Vec<_>>()
+        .join(":"))
+}
+
+/// Swaps the certificate (and client CA) used for new connections.
+/// Connections already established keep the ones they were opened with. On
+/// error the previous configuration stays in use.
+pub fn reload(config: &RustlsConfig, settings: &TlsSettings) {
+    match settings.server_config() {
+        Ok(server_config) => {
+            config.reload_from_config(server_config);
+            match fingerprint(&settings.cert) {
+                Ok(fingerprint) => info!(%fingerprint, "TLS certificate reloaded"),
+                Err(err) => warn!("TLS certificate reloaded, fingerprint unavailable: {err}"),
+            }
+        }
+        Err(err) => error!("Error while reloading the TLS certificate, keeping the old one: {err}"),
+    }
+}
+
+/// Reloads the TLS files on SIGHUP and, when `poll` is set, whenever the
+/// modification time of one of them changes.
+pub async fn watch(config: RustlsConfig, settings: TlsSettings, poll: Option<Duration>) {
+    #[cfg(unix)]
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(s) => Some(s),
+        Err(err) => {
+            error!("Error while listening for SIGHUP: {err}");
+            None
+        }
+    };
+    // The ticker only matters when polling is on.
+    let mut ticker = tokio::time::interval(poll.unwrap_or(Duration::from_secs(3600)));
+    ticker.tick().await;
+    let mut last = modified(&settings);
+    loop {
+        #[cfg(unix)]
+        let hangup_received = async {
+            match hangup.as_mut() {
+                Some(s) => s.recv().await,
+                None => std::future::pending().await,
+            }
+        };
+        #[cfg(not(unix))]
+        let hangup_received = std::future::pending::<Option<()>>();
+        tokio::select! {
+            _ = hangup_received => {
+                info!("SIGHUP received, reloading the TLS certificate");
+                last = modified(&settings);
+                reload(&config, &settings);
+            }
+            _ = ticker.tick(), if poll.is_some() => {
+                let current = modified(&settings);
+                if current != last {
+                    last = current;
+                    info!("TLS certificate files changed, reloading");
+                    reload(&config, &settings);
+                }
+            }
+        }
+    }
+}
+
+fn modified(settings: &TlsSettings) -> Vec<Option<SystemTime>> {
+    settings
+        .paths()
+        .into_iter()
+        .map(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+/// Who the client certificate of a connection belongs to. Added to every
+/// request on the connection by `ClientCertAcceptor`.
+#[derive(Clone, Debug, Default)]
+pub struct ClientIdentity {
+    /// Common name of the certificate subject, `None` when the client did
+    /// not present a certificate.
+    pub user: Option<String>,
+}
+
+impl ClientIdentity {
+    fn from_certificates(certs: Option<&[CertificateDer<'_>]>) -> Self {
+        let user = certs.and_then(|certs| certs.first()).and_then(|der| {
+            match X509Certificate::from_der(der) {
+                Ok((_, cert)) => cert
+                    .subject()
+                    .iter_common_name()
+                    .next()
+                    .and_then(|cn| cn.as_str().ok())
+                    .map(str::to_string),
+                Err(err) => {
+                    warn!("Error while reading a client certificate: {err}");
+                    None
+                }
+            }
+        });
+        Self { user }
+    }
+}
+
+/// TLS acceptor that also records the client certificate. Verification
+/// against the CA already happened during the handshake.
+#[derive(Clone)]
+pub struct ClientCertAcceptor {
+    inner: RustlsAcceptor,
+}
+
+impl ClientCertAcceptor {
+    pub fn new(config: RustlsConfig) -> Self {
+        Self {
+            inner: RustlsAcceptor::new(config),
+        }
+    }
+}
+
+impl<I, S> Accept<I, S> for ClientCertAcceptor
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: Send + 'static,
+{
+    type Stream = TlsStream<I>;
+    type Service = AddExtension<S, ClientIdentity>;
+    type Future = BoxFuture<'static, io::Result<(Self::Stream, Self::Service)>>;
+
+    fn accept(&self, stream: I, service: S) -> Self::Future {
+        let acceptor = self.inner.clone();
+        Box::pin(async move {
+            let (stream, service) = acceptor.accept(stream, service).await?;
+            let identity =
+                ClientIdentity::from_certificates(stream.get_ref().1.peer_certificates());
+            if let Some(user) = &identity.user {
+                debug!(%user, "Client certificate accepted");
+            }
+            Ok((stream, Extension(identity).layer(service)))
+        })
+    }
+}
@@ -0,0 +1,127 @@
+use std::fmt;
+use unicode_normalization::UnicodeNormalization;
+
+use crate::network_manager::{config::ServerConfig, handlers::ErrorCode};
+
+/// Why a username or message body was refused.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValidationError {
+    UsernameLength {
+        min: usize,
+        max: usize,
+    },
+    /// A character other than letters, digits, `_`, `-` and `.`.
+    UsernameCharacter(char),
+    ReservedUsername,
+    EmptyMessage,
+    MessageTooLarge {
+        max_bytes: usize,
+    },
+}
+
+impl ValidationError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            ValidationError::UsernameLength { .. }
+            | ValidationError::UsernameCharacter(_)
+            | ValidationError::ReservedUsername => ErrorCode::InvalidUsername,
+            ValidationError::EmptyMessage => ErrorCode::InvalidMessage,
+            ValidationError::MessageTooLarge { .. } => ErrorCode::MessageTooLarge,
+        }
+    }
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidationError::UsernameLength { min, max } => {
+                write!(f, "Usernames must be {min} to {max} characters long.")
+            }
+            ValidationError::UsernameCharacter(c) if c.is_control() || c.is_whitespace() => write!(
+                f,
+                "Usernames may only contain letters, digits, '_', '-' and '.', not spaces or control characters."
+            ),
+            ValidationError::UsernameCharacter(c) => write!(
+                f,
+                "Usernames may only contain letters, digits, '_', '-' and '.', not '{c}'."
+            ),
+            ValidationError::ReservedUsername => write!(f, "This username is reserved."),
+            ValidationError::EmptyMessage => write!(f, "The message is empty."),
+            ValidationError::MessageTooLarge { max_bytes } => {
+                write!(f, "Messages are limited to {max_bytes} bytes.")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+/// Checks a username chosen at sign in and returns the form to store: NFKC
+/// normalized, case kept.
+pub fn username(raw: &str, config: &ServerConfig) -> Result<String, ValidationError> {
+    // Bounded, so a huge name costs no more than a long one.
+    let name: String = raw.nfkc().take(config.username_max_chars + 1).collect();
+    let chars = name.chars().count();
+    if chars < config.username_min_chars || chars > config.username_max_chars {
+        return Err(ValidationError::UsernameLength {
+            min: config.username_min_chars,
+            max: config.username_max_chars,
+        });
+    }
+    if let Some(c) = name
+        .chars()
+        .find(|c| !(c.is_alphanumeric() || matches!(c, '_' | '-' | '.')))
+    {
+        return Err(ValidationError::UsernameCharacter(c));
+    }
+    let key = username_key(&name);
+    if config
+        .reserved_usernames
+        .iter()
+        .any(|reserved| username_key(reserved) == key)
+    {
+        return Err(ValidationError::ReservedUsername);
+    }
+    Ok(name)
+}
+
+/// Form two usernames share when they only differ in case or in
+/// compatibility characters, such as `Alice`, `ALICE` and `ａｌｉｃｅ`. Kept
+/// unique in `users.username_key`.
+///
+/// This is NFKC, lowercase, then NFKC again, with the two foldings that
+/// lowercasing misses for common scripts (`ß` and final sigma) added.
+pub fn username_key(name: &str) -> String {
+    let lower: String = name.nfkc().collect::<String>().to_lowercase();
+    let mut folded = String::with_capacity(lower.len());
+    for c in lower.chars() {
+        match c {
+            'ß' => folded.push_str("ss"),
+            'ς' => folded.push('σ'),
+            _ => folded.push(c),
+        }
+    }
+    folded.nfkc().collect()
+}
+
+/// Normalizes the username given at login. The account is then looked up
+/// by its [`username_key`], so logins ignore case like sign-up does.
+pub fn login_username(raw: &str) -> String {
+    raw.nfkc().collect()
+}
+
+/// Strips control characters other than newlines and tabs from a message
+/// body and checks its size.
+pub fn message(raw: &str, max_bytes: usize) -> Result<String, ValidationError> {
+    let cleaned: String = raw
+        .chars()
+        .filter(|c| !c.is_control() || matches!(c, '\n' | '\t'))
+        .collect();
+    if cleaned.trim().is_empty() {
+        return Err(ValidationError::EmptyMessage);
+    }
+    if cleaned.len() > max_bytes {
+        return Err(ValidationError::MessageTooLarge { max_bytes });
+    }
+    Ok(cleaned)
+}
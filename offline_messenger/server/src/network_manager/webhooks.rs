@@ -0,0 +1,227 @@
+use futures_util::future::join_all;
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use serde_json::json;
+use sha2::Sha256;
+use std::{
+    error::Error,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio_postgres::Row;
+use tracing::{debug, error, info, warn};
+
+use crate::network_manager::{config::ServerConfig, database_manager::DataBase, metrics::METRICS};
+
+/// Most deliveries sent per tick.
+const BATCH: i64 = 50;
+/// Longest wait between two attempts of a delivery.
+const MAX_BACKOFF: Duration = Duration::from_secs(3600);
+/// How long finished deliveries stay in the log.
+const RETENTION: Duration = Duration::from_secs(7 * 86_400);
+
+/// Something that happened, as POSTed to the webhooks:
+/// `{"event": "message.sent", "data": {...}, "at": <unix seconds>}`.
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "event", content = "data")]
+pub enum WebhookEvent {
+    #[serde(rename = "message.sent")]
+    MessageSent {
+        message_id: i64,
+        from: String,
+        to: String,
+        encrypted: bool,
+        /// `None` for end-to-end encrypted messages.
+        content: Option<String>,
+    },
+    #[serde(rename = "user.signed_up")]
+    UserSignedUp { username: String },
+    #[serde(rename = "user.connected")]
+    UserConnected { username: String },
+}
+
+impl WebhookEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            WebhookEvent::MessageSent { .. } => "message.sent",
+            WebhookEvent::UserSignedUp { .. } => "user.signed_up",
+            WebhookEvent::UserConnected { .. } => "user.connected",
+        }
+    }
+}
+
+/// Value of the `X-Messenger-Signature` header: the hex HMAC-SHA256 of
+/// `"{timestamp}.{body}"`, keyed with the webhook secret, prefixed with
+/// `sha256=`. Receivers should also reject old timestamps.
+pub fn signature(secret: &str, timestamp: u64, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(format!("{timestamp}.{body}").as_bytes());
+    let digest = mac.finalize().into_bytes();
+    let hex: String = digest.iter().map(|b| format!("{b:02x}")).collect();
+    format!("sha256={hex}")
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Queues `event` for every configured webhook, unless webhooks are off or
+/// the event is filtered out. Errors are logged, the action that caused
+/// the event goes ahead regardless.
+pub async fn enqueue(database: &DataBase, config: &ServerConfig, event: WebhookEvent) {
+    if !config.webhooks_enabled()
+        || !(config.webhook_events.is_empty()
+            || config.webhook_events.iter().any(|e| e == event.name()))
+    {
+        return;
+    }
+    let mut payload = match serde_json::to_value(&event) {
+        Ok(payload) => payload,
+        Err(err) => {
+            error!("Error while serializing a webhook event: {err}");
+            return;
+        }
+    };
+    payload["at"] = json!(unix_now());
+    if let Err(err) = database
+        .enqueue_webhooks(&config.webhook_urls, event.name(), &payload.to_string())
+        .await
+    {
+        error!(
+            event = event.name(),
+            "Error while queueing a webhook: {err}"
+        );
+    }
+}
+
+/// Delay before attempt `attempts + 1` of a delivery.
+fn backoff(attempts: u32, config: &ServerConfig) -> Duration {
+    let factor = 1u32
+        .checked_shl(attempts.saturating_sub(1))
+        .unwrap_or(u32::MAX);
+    config
+        .webhook_backoff_base
+        .saturating_mul(factor)
+        .min(MAX_BACKOFF)
+}
+
+/// Sends due deliveries every `webhook_interval` until the task is
+/// aborted.
+pub async fn run(database: Arc<DataBase>, config: Arc<ServerConfig>) {
+    let http = match reqwest::Client::builder()
+        .timeout(config.webhook_timeout)
+        .build()
+    {
+        Ok(http) => http,
+        Err(err) => {
+            error!("Error while creating the webhook client, webhooks are off: {err}");
+            return;
+        }
+    };
+    // A claimed delivery is retried by anyone once this runs out, should
+    // this instance die while sending it.
+    let lease = config.webhook_timeout * 2 + Duration::from_secs(30);
+    let mut pruned = Instant::now();
+    let mut interval = tokio::time::interval(config.webhook_interval);
+    loop {
+        interval.tick().await;
+        let rows = match database.claim_webhooks(BATCH, lease).await {
+            Ok(rows) => rows,
+            Err(err) => {
+                error!("Error while loading webhook deliveries: {err}");
+                continue;
+            }
+        };
+        join_all(
+            rows.iter()
+                .map(|row| deliver(&database, &config, &http, row)),
+        )
+        .await;
+        if pruned.elapsed() > Duration::from_secs(3600) {
+            pruned = Instant::now();
+            match database.prune_webhooks(RETENTION).await {
+                Ok(0) => {}
+                Ok(count) => info!(count, "Pruned the webhook delivery log"),
+                Err(err) => error!("Error while pruning the webhook delivery log: {err}"),
+            }
+        }
+    }
+}
+
+/// Makes one attempt at a delivery claimed by `claim_webhooks`.
+async fn deliver(database: &DataBase, config: &ServerConfig, http: &reqwest::Client, row: &Row) {
+    let id: i64 = row.get(0);
+    let url: String = row.get(1);
+    let event: String = row.get(2);
+    let payload: String = row.get(3);
+    let attempts = row.get::<_, i32>(4) as u32;
+    let timestamp = unix_now();
+    let mut request = http
+        .post(&url)
+        .header("content-type", "application/json")
+        .header("x-messenger-event", &event)
+        .header("x-messenger-delivery", id.to_string())
+        .header("x-messenger-timestamp", timestamp.to_string());
+    // A signature made with an empty key would look authentic to anyone.
+    if let Some(secret) = &config.webhook_secret {
+        request = request.header(
+            "x-messenger-signature",
+            signature(secret, timestamp, &payload),
+        );
+    }
+    let result = request.body(payload).send().await;
+    let (status, failure) = match result {
+        Ok(resp) => {
+            let status = resp.status();
+            let failure = (!status.is_success()).then(|| format!("HTTP {status}"));
+            (Some(i32::from(status.as_u16())), failure)
+        }
+        Err(err) => (None, Some(error_chain(&err))),
+    };
+    let recorded = match failure {
+        None => {
+            METRICS.webhook("delivered");
+            debug!(id, %url, %event, "Webhook delivered");
+            database
+                .webhook_delivered(id, status.unwrap_or_default())
+                .await
+        }
+        Some(failure) => {
+            let attempts = attempts + 1;
+            let retry_in =
+                (attempts < config.webhook_max_attempts).then(|| backoff(attempts, config));
+            match retry_in {
+                Some(delay) => {
+                    METRICS.webhook("retry");
+                    warn!(id, %url, %event, attempts, retry_in_ms = delay.as_millis() as u64, "Webhook failed: {failure}");
+                }
+                None => {
+                    METRICS.webhook("failed");
+                    error!(id, %url, %event, attempts, "Webhook failed, giving up: {failure}");
+                }
+            }
+            database
+                .webhook_failed(id, status, &failure, retry_in)
+                .await
+        }
+    };
+    if let Err(err) = recorded {
+        error!(id, "Error while recording a webhook delivery: {err}");
+    }
+}
+
+/// The error and its sources, which is where reqwest says what went wrong.
+fn error_chain(err: &dyn Error) -> String {
+    let mut text = err.to_string();
+    let mut source = err.source();
+    while let Some(err) = source {
+        text.push_str(": ");
+        text.push_str(&err.to_string());
+        source = err.source();
+    }
+    text
+}
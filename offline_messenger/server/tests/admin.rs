@@ -0,0 +1,96 @@
+mod common;
+
+use common::TestServer;
+use serde_json::{Value, json};
+use std::net::SocketAddr;
+
+async fn admin_server() -> TestServer {
+    TestServer::start_with(|config| {
+        config.admin_addr = Some(SocketAddr::from(([127, 0, 0, 1], 0)));
+    })
+    .await
+}
+
+#[tokio::test]
+async fn readiness_follows_the_database() {
+    let server = admin_server().await;
+
+    let (status, body) = server.admin("/healthz").await;
+    assert_eq!(status, 200, "{body}");
+    let (status, body) = server.admin("/readyz").await;
+    assert_eq!(status, 200, "{body}");
+    let ready: Value = serde_json::from_str(&body).expect("a JSON body");
+    assert_eq!(ready["status"], "ready");
+    assert_eq!(ready["checks"]["database"]["ok"], true);
+    assert_eq!(ready["checks"]["tls"]["ok"], true);
+    assert_eq!(ready["checks"]["session_store"]["ok"], true);
+
+    server.database().close();
+    let (status, body) = server.admin("/readyz").await;
+    assert_eq!(status, 503, "{body}");
+    let ready: Value = serde_json::from_str(&body).expect("a JSON body");
+    assert_eq!(ready["status"], "not_ready");
+    assert_eq!(ready["checks"]["database"]["ok"], false);
+    // Liveness does not depend on anything else.
+    let (status, _) = server.admin("/healthz").await;
+    assert_eq!(status, 200);
+
+    server.stop().await;
+}
+
+/// Value of the sample `series` (name and labels) in a Prometheus text
+/// body. Labelled series only appear once they were counted.
+fn sample(metrics: &str, series: &str) -> Option<f64> {
+    metrics
+        .lines()
+        .find_map(|line| line.strip_prefix(series)?.strip_prefix(' '))
+        .map(|value| value.parse().expect("a number"))
+}
+
+#[tokio::test]
+async fn metrics_count_sessions_messages_and_logins() {
+    let server = admin_server().await;
+    // The counters are shared by every server in this process.
+    let (_, before) = server.admin("/metrics").await;
+    let alice_token = server.register("alice").await;
+    server.register("bob").await;
+    let (status, _) = server.login("bob", "wrong").await;
+    assert_eq!(status, 401);
+
+    let mut alice = server.connect("alice", &alice_token).await;
+    alice
+        .send(json!({
+            "type": "SendMessage",
+            "id": "1",
+            "token": alice_token,
+            "from": "alice",
+            "to": "bob",
+            "message": "hi",
+            "resp_msg": null,
+            "resp_user": null,
+        }))
+        .await;
+    let response = alice.response("1").await;
+    assert_eq!(response["succes"], true, "{response}");
+
+    let (status, after) = server.admin("/metrics").await;
+    assert_eq!(status, 200);
+    assert_eq!(sample(&after, "messenger_connected_sessions"), Some(1.0));
+    assert_eq!(sample(&after, "messenger_active_users"), Some(1.0));
+    let grew = |series: &str| {
+        let now = sample(&after, series).unwrap_or_else(|| panic!("no {series} in\n{after}"));
+        now - sample(&before, series).unwrap_or(0.0)
+    };
+    assert!(grew("messenger_messages_sent_total") >= 1.0, "{after}");
+    assert!(
+        grew("messenger_logins_total{outcome=\"success\"}") >= 2.0,
+        "{after}"
+    );
+    assert!(
+        grew("messenger_logins_total{outcome=\"failure\"}") >= 1.0,
+        "{after}"
+    );
+
+    alice.close().await;
+    server.stop().await;
+}
@@ -0,0 +1,249 @@
+mod common;
+
+use common::{TestServer, WsClient};
+use serde_json::{Value, json};
+use server::network_manager::archive::{self, ArchiveError};
+
+async fn send(client: &mut WsClient, id: &str, from: &str, to: &str, text: &str, reply: Value) {
+    client
+        .send(json!({
+            "type": "SendMessage",
+            "id": id,
+            "token": "",
+            "from": from,
+            "to": to,
+            "message": text,
+            "resp_msg": reply.get("content"),
+            "resp_user": reply.get("user"),
+        }))
+        .await;
+    let response = client.response(id).await;
+    assert_eq!(response["succes"], true, "{response}");
+}
+
+/// A server where alice greets bob, bob answers quoting her and alice
+/// writes to dave.
+async fn history() -> TestServer {
+    let server = TestServer::start().await;
+    let alice_token = server.register("alice").await;
+    let bob_token = server.register("bob").await;
+    server.register("dave").await;
+    let mut alice = server.connect("alice", &alice_token).await;
+    let mut bob = server.connect("bob", &bob_token).await;
+    send(&mut alice, "1", "alice", "bob", "hi", Value::Null).await;
+    let quote = json!({ "user": "alice", "content": "hi" });
+    send(&mut bob, "2", "bob", "alice", "hello", quote).await;
+    send(&mut alice, "3", "alice", "dave", "psst", Value::Null).await;
+    alice.close().await;
+    bob.close().await;
+    server
+}
+
+/// `(id, sender, receiver, content, responding_to_user, responding_to_msg)`
+/// of every message, by id.
+async fn messages(
+    server: &TestServer,
+) -> Vec<(i32, String, String, String, Option<String>, Option<String>)> {
+    server
+        .sql()
+        .await
+        .query(
+            r"SELECT id_message, sender, receiver, content, responding_to_user, responding_to_msg
+                FROM messages ORDER BY id_message;",
+            &[],
+        )
+        .await
+        .expect("read the messages")
+        .iter()
+        .map(|r| (r.get(0), r.get(1), r.get(2), r.get(3), r.get(4), r.get(5)))
+        .collect()
+}
+
+#[tokio::test]
+async fn an_export_imports_with_its_ids() {
+    let source = history().await;
+    let data = archive::export(source.database(), "alice")
+        .await
+        .expect("export alice");
+
+    let target = TestServer::start().await;
+    for user in ["alice", "bob", "dave"] {
+        target.register(user).await;
+    }
+    let report = archive::import(target.database(), &data)
+        .await
+        .expect("import alice");
+    assert_eq!(report.user, "alice");
+    assert_eq!(
+        (
+            report.imported,
+            report.renumbered,
+            report.duplicates,
+            report.skipped
+        ),
+        (3, 0, 0, 0)
+    );
+    assert_eq!(messages(&target).await, messages(&source).await);
+
+    let again = archive::import(target.database(), &data)
+        .await
+        .expect("import alice again");
+    assert_eq!(
+        (
+            again.imported,
+            again.renumbered,
+            again.duplicates,
+            again.skipped
+        ),
+        (0, 0, 3, 0)
+    );
+
+    source.stop().await;
+    target.stop().await;
+}
+
+#[tokio::test]
+async fn taken_ids_are_renumbered_and_replies_kept() {
+    let source = history().await;
+    let data = archive::export(source.database(), "alice")
+        .await
+        .expect("export alice");
+
+    // Message 1 is taken and dave has no account here.
+    let target = TestServer::start().await;
+    target.register("alice").await;
+    let bob_token = target.register("bob").await;
+    target.register("carol").await;
+    let mut bob = target.connect("bob", &bob_token).await;
+    send(&mut bob, "t", "bob", "carol", "taken", Value::Null).await;
+    bob.close().await;
+
+    let report = archive::import(target.database(), &data)
+        .await
+        .expect("import alice");
+    assert_eq!(
+        (
+            report.imported,
+            report.renumbered,
+            report.duplicates,
+            report.skipped
+        ),
+        (2, 1, 0, 1)
+    );
+    let imported = messages(&target).await;
+    let contents: Vec<(i32, &str)> = imported
+        .iter()
+        .map(|(id, _, _, content, _, _)| (*id, content.as_str()))
+        .collect();
+    assert_eq!(contents[..2], [(1, "taken"), (2, "hello")]);
+    assert_eq!(contents[2].1, "hi");
+    assert!(contents[2].0 > 3, "{contents:?}");
+    // The reply still quotes the renumbered message.
+    assert_eq!(
+        (imported[1].4.as_deref(), imported[1].5.as_deref()),
+        (Some("alice"), Some("hi"))
+    );
+
+    source.stop().await;
+    target.stop().await;
+}
+
+fn tar_of(files: &[(&str, String)]) -> Vec<u8> {
+    let mut builder = tar::Builder::new(Vec::new());
+    for (path, data) in files {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, path, data.as_bytes())
+            .expect("add a file");
+    }
+    builder.into_inner().expect("finish the archive")
+}
+
+fn manifest(format: &str) -> String {
+    json!({
+        "format": format,
+        "version": archive::VERSION,
+        "user": "alice",
+        "exported_at": 0,
+        "conversations": [{ "peer": "bob", "file": "conversations/bob.jsonl", "messages": 2 }],
+    })
+    .to_string()
+}
+
+fn line(id: i32, from: &str, to: &str) -> String {
+    json!({
+        "id": id,
+        "from": from,
+        "to": to,
+        "content": "hi",
+        "sent_at": "2024-01-01T00:00:00Z",
+        "reply": null,
+        "encrypted": false,
+        "expires_at": null,
+    })
+    .to_string()
+}
+
+#[test]
+fn malformed_archives_are_rejected() {
+    let valid = format!("{}\n{}\n", line(1, "alice", "bob"), line(2, "bob", "alice"));
+    let (manifest_read, messages) = archive::read(&tar_of(&[
+        ("manifest.json", manifest(archive::FORMAT)),
+        ("conversations/bob.jsonl", valid.clone()),
+    ]))
+    .expect("a valid archive");
+    assert_eq!(manifest_read.user, "alice");
+    assert_eq!(messages.len(), 2);
+
+    let cases = [
+        ("not a tar", b"not a tar".to_vec()),
+        (
+            "no manifest",
+            tar_of(&[("conversations/bob.jsonl", valid.clone())]),
+        ),
+        (
+            "another format",
+            tar_of(&[
+                ("manifest.json", manifest("something-else")),
+                ("conversations/bob.jsonl", valid.clone()),
+            ]),
+        ),
+        (
+            "missing conversation",
+            tar_of(&[("manifest.json", manifest(archive::FORMAT))]),
+        ),
+        (
+            "another conversation",
+            tar_of(&[
+                ("manifest.json", manifest(archive::FORMAT)),
+                ("conversations/bob.jsonl", line(1, "alice", "carol")),
+            ]),
+        ),
+        (
+            "repeated id",
+            tar_of(&[
+                ("manifest.json", manifest(archive::FORMAT)),
+                (
+                    "conversations/bob.jsonl",
+                    format!("{}\n{}\n", line(1, "alice", "bob"), line(1, "bob", "alice")),
+                ),
+            ]),
+        ),
+        (
+            "broken line",
+            tar_of(&[
+                ("manifest.json", manifest(archive::FORMAT)),
+                ("conversations/bob.jsonl", "{\"id\": 1".to_string()),
+            ]),
+        ),
+    ];
+    for (case, data) in cases {
+        assert!(
+            matches!(archive::read(&data), Err(ArchiveError::Invalid(_))),
+            "{case} was accepted"
+        );
+    }
+}
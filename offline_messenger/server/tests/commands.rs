@@ -0,0 +1,179 @@
+mod common;
+
+use common::{TestServer, WsClient};
+use serde_json::{Value, json};
+use server::network_manager::bots;
+use std::time::Duration;
+
+async fn send(client: &mut WsClient, token: &str, id: &str, to: &str, message: &str) -> Value {
+    client
+        .send(json!({
+            "type": "SendMessage",
+            "id": id,
+            "token": token,
+            "from": "alice",
+            "to": to,
+            "message": message,
+            "resp_msg": null,
+            "resp_user": null,
+        }))
+        .await;
+    client.response(id).await
+}
+
+#[tokio::test]
+async fn builtin_commands_answer_the_sender() {
+    let server = TestServer::start_with(|config| {
+        config.scheduler_interval = Duration::from_millis(100);
+    })
+    .await;
+    let token = server.register("alice").await;
+    server.register("bob").await;
+    let mut alice = server.connect("alice", &token).await;
+
+    let response = send(&mut alice, &token, "c1", "bob", "/help").await;
+    assert_eq!(response["succes"], true, "{response}");
+    let output = alice.recv_type("CommandOutput").await;
+    assert_eq!(output["id"], "c1");
+    assert!(
+        output["text"].as_str().unwrap().contains("/who"),
+        "{output}"
+    );
+
+    // carol is connected to another instance.
+    server
+        .database()
+        .set_online("other-instance", "carol")
+        .await
+        .expect("announce carol");
+    send(&mut alice, &token, "c2", "bob", "/who").await;
+    let output = alice.recv_type("CommandOutput").await;
+    assert_eq!(output["text"], "Online: alice, carol");
+
+    let response = send(&mut alice, &token, "c3", "bob", "/nope").await;
+    assert_eq!(response["succes"], false);
+    assert_eq!(response["code"], "unknown_command");
+
+    let response = send(&mut alice, &token, "c4", "alice", "/remind 1s stretch").await;
+    assert_eq!(response["succes"], true, "{response}");
+    let reminder = alice.recv_type("Message").await;
+    assert_eq!(reminder["from"], "alice");
+    assert_eq!(reminder["message"], "Reminder: stretch");
+
+    alice.close().await;
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn a_retried_command_is_not_run_again() {
+    let server = TestServer::start_with(|config| {
+        config.scheduler_interval = Duration::from_millis(100);
+    })
+    .await;
+    let token = server.register("alice").await;
+    let mut alice = server.connect("alice", &token).await;
+    send(&mut alice, &token, "r1", "alice", "/remind 1s stretch").await;
+    let first = alice.recv_type("CommandOutput").await;
+    alice.close().await;
+
+    // The client lost the answer and sends the command again.
+    let mut alice = server.connect("alice", &token).await;
+    let response = send(&mut alice, &token, "r1", "alice", "/remind 1s stretch").await;
+    assert_eq!(response["succes"], true, "{response}");
+    assert_eq!(alice.recv_type("CommandOutput").await, first);
+
+    for _ in 0..2 {
+        let response = send(&mut alice, &token, "r2", "alice", "/remind soon").await;
+        assert_eq!(response["code"], "invalid_request", "{response}");
+    }
+
+    let reminder = alice.recv_type("Message").await;
+    assert_eq!(reminder["message"], "Reminder: stretch");
+    send(&mut alice, &token, "r3", "alice", "/remind 1s next").await;
+    let reminder = alice.recv_type("Message").await;
+    assert_eq!(reminder["message"], "Reminder: next");
+
+    alice.close().await;
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn double_slash_sends_a_literal_slash() {
+    let server = TestServer::start().await;
+    let token = server.register("alice").await;
+    let bob_token = server.register("bob").await;
+    let mut alice = server.connect("alice", &token).await;
+    let mut bob = server.connect("bob", &bob_token).await;
+
+    let response = send(&mut alice, &token, "m1", "bob", "//shrug").await;
+    assert_eq!(response["succes"], true, "{response}");
+    let delivered = bob.recv_type("Message").await;
+    assert_eq!(delivered["message"], "/shrug");
+
+    alice.close().await;
+    bob.close().await;
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn bots_log_in_with_a_token_and_receive_commands() {
+    let server = TestServer::start().await;
+    let token = server.register("alice").await;
+    let api_token = bots::new_token();
+    let resp = server
+        .database()
+        .create_bot("helper", &bots::token_hash(&api_token))
+        .await
+        .expect("create the bot");
+    assert!(resp.succes, "{}", resp.message);
+
+    let refused = server
+        .http()
+        .post(server.url("/bot/login"))
+        .bearer_auth("bot_wrong")
+        .send()
+        .await
+        .expect("bot login");
+    assert_eq!(refused.status(), 401);
+
+    let body: Value = server
+        .http()
+        .post(server.url("/bot/login"))
+        .bearer_auth(&api_token)
+        .send()
+        .await
+        .expect("bot login")
+        .json()
+        .await
+        .expect("a JSON body");
+    assert_eq!(body["succes"], true, "{body}");
+    assert_eq!(body["username"], "helper");
+    let bot_token = body["token"].as_str().unwrap().to_string();
+
+    let mut alice = server.connect("alice", &token).await;
+    let mut bot = server.connect("helper", &bot_token).await;
+    let response = send(&mut alice, &token, "b1", "helper", "/help").await;
+    assert_eq!(response["succes"], true, "{response}");
+    let delivered = bot.recv_type("Message").await;
+    assert_eq!(delivered["from"], "alice");
+    assert_eq!(delivered["message"], "/help");
+
+    bot.send(json!({
+        "type": "SendMessage",
+        "id": "r1",
+        "token": bot_token,
+        "from": "helper",
+        "to": "alice",
+        "message": "I can echo things.",
+        "resp_msg": null,
+        "resp_user": null,
+    }))
+    .await;
+    assert_eq!(bot.response("r1").await["succes"], true);
+    let reply = alice.recv_type("Message").await;
+    assert_eq!(reply["from"], "helper");
+
+    alice.close().await;
+    bot.close().await;
+    server.stop().await;
+}
@@ -0,0 +1,335 @@
+//! Boots the server in-process for the integration tests.
+//!
+//! Every `TestServer` listens on a random port with the self-signed
+//! certificate the server generates on first start, and keeps its tables
+//! in a schema of its own, so the tests can run in parallel against one
+//! Postgres. The database is taken from `MESSENGER_TEST_DATABASE_URL`
+//! (key=value form), falling back to the server's default.
+
+#![allow(dead_code)]
+
+use futures_util::{SinkExt, StreamExt};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject};
+use serde_json::{Value, json};
+use server::network_manager::{
+    config::{FanoutBackend, ServerConfig},
+    database_manager::DataBase,
+    server::{RunningServer, Server},
+};
+use std::{
+    env,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::net::TcpStream;
+use tokio_postgres::NoTls;
+use tokio_tungstenite::{
+    Connector, MaybeTlsStream, WebSocketStream, connect_async_tls_with_config, tungstenite::Message,
+};
+use uuid::Uuid;
+
+/// How long a test waits for a frame before giving up.
+const RECV_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct TestServer {
+    pub addr: SocketAddr,
+    running: RunningServer,
+    http: reqwest::Client,
+    tls: Arc<rustls::ClientConfig>,
+    trusted: Vec<u8>,
+    /// Client certificate and key presented by later connections.
+    identity: Option<(Vec<u8>, Vec<u8>)>,
+    database_url: String,
+    schema: String,
+    dir: PathBuf,
+}
+
+impl TestServer {
+    pub async fn start() -> Self {
+        TestServer::start_with(|_| {}).await
+    }
+
+    /// Like `start`, with `configure` applied to the config last.
+    pub async fn start_with(configure: impl FnOnce(&mut ServerConfig)) -> Self {
+        let database_url = env::var("MESSENGER_TEST_DATABASE_URL")
+            .unwrap_or_else(|_| ServerConfig::default().database_url);
+        let schema = format!("test_{}", Uuid::new_v4().simple());
+        let (client, connection) = tokio_postgres::connect(&database_url, NoTls)
+            .await
+            .unwrap_or_else(|err| {
+                panic!("the integration tests need Postgres at {database_url:?}: {err}")
+            });
+        tokio::spawn(connection);
+        client
+            .batch_execute(&format!("CREATE SCHEMA {schema};"))
+            .await
+            .expect("create the test schema");
+
+        let dir = env::temp_dir().join(&schema);
+        let mut config = base_config(&database_url, &schema, &dir);
+        configure(&mut config);
+        let running = Server::with_config(config)
+            .spawn()
+            .await
+            .expect("start the server");
+
+        let trusted =
+            std::fs::read(dir.join("server.crt")).expect("read the generated certificate");
+        let (http, tls) = clients(&trusted, None);
+        Self {
+            addr: running.addr,
+            running,
+            http,
+            tls,
+            trusted,
+            identity: None,
+            database_url,
+            schema,
+            dir,
+        }
+    }
+
+    /// Directory holding the server's `server.crt` and `server.key`.
+    pub fn cert_dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Makes later requests and connections trust only `pem`.
+    pub fn trust(&mut self, pem: &[u8]) {
+        self.trusted = pem.to_vec();
+        (self.http, self.tls) = clients(&self.trusted, self.identity.as_ref());
+    }
+
+    /// Makes later requests and connections present this client
+    /// certificate, or none.
+    pub fn present(&mut self, identity: Option<(Vec<u8>, Vec<u8>)>) {
+        self.identity = identity;
+        (self.http, self.tls) = clients(&self.trusted, self.identity.as_ref());
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("https://localhost:{}{path}", self.addr.port())
+    }
+
+    pub fn http(&self) -> &reqwest::Client {
+        &self.http
+    }
+
+    /// GETs `path` from the admin listener, which the test has to enable
+    /// through `admin_addr`. Returns the status and the body.
+    pub async fn admin(&self, path: &str) -> (u16, String) {
+        let addr = self
+            .running
+            .admin_addr
+            .expect("the admin listener is enabled");
+        let response = reqwest::get(format!("http://{addr}{path}"))
+            .await
+            .expect("reach the admin listener");
+        let status = response.status().as_u16();
+        (status, response.text().await.expect("read the response"))
+    }
+
+    /// The server's own database connection.
+    pub fn database(&self) -> &DataBase {
+        &self.running.app_state.database
+    }
+
+    /// A connection of its own to the test schema, for what the server's
+    /// cannot do, like holding a transaction open.
+    pub async fn sql(&self) -> tokio_postgres::Client {
+        let url = format!(
+            "{} options='-c search_path={}'",
+            self.database_url, self.schema
+        );
+        let (client, connection) = tokio_postgres::connect(&url, NoTls)
+            .await
+            .expect("connect to the test schema");
+        tokio::spawn(connection);
+        client
+    }
+
+    /// Creates an account. Returns the status and the response body.
+    pub async fn signin(&self, username: &str, password: &str) -> (u16, Value) {
+        self.auth("/signin", username, password).await
+    }
+
+    pub async fn login(&self, username: &str, password: &str) -> (u16, Value) {
+        self.auth("/login", username, password).await
+    }
+
+    async fn auth(&self, path: &str, username: &str, password: &str) -> (u16, Value) {
+        let response = self
+            .http
+            .get(self.url(path))
+            .json(&json!({ "username": username, "password": password }))
+            .send()
+            .await
+            .expect("send the request");
+        let status = response.status().as_u16();
+        (status, response.json().await.expect("read the response"))
+    }
+
+    /// Signs `username` up with password `pw`, logs in and returns the token.
+    pub async fn register(&self, username: &str) -> String {
+        let (status, body) = self.signin(username, "pw").await;
+        assert_eq!(status, 201, "signin of {username}: {body}");
+        let (status, body) = self.login(username, "pw").await;
+        assert_eq!(status, 200, "login of {username}: {body}");
+        body["token"].as_str().expect("a token").to_string()
+    }
+
+    /// Opens `/ws` and sends the session info frame.
+    pub async fn connect(&self, username: &str, token: &str) -> WsClient {
+        let (mut stream, _) = connect_async_tls_with_config(
+            format!("wss://localhost:{}/ws", self.addr.port()),
+            None,
+            false,
+            Some(Connector::Rustls(self.tls.clone())),
+        )
+        .await
+        .expect("open the WebSocket");
+        stream
+            .send(Message::text(
+                json!({ "username": username, "token": token }).to_string(),
+            ))
+            .await
+            .expect("send the session info");
+        WsClient { stream }
+    }
+
+    /// Shuts the server down and starts it again on the same schema and
+    /// certificate, with `configure` applied to the config last. Sessions
+    /// do not survive, log in again.
+    pub async fn restart(self, configure: impl FnOnce(&mut ServerConfig)) -> Self {
+        self.running.shutdown().await;
+        let mut config = base_config(&self.database_url, &self.schema, &self.dir);
+        configure(&mut config);
+        let running = Server::with_config(config)
+            .spawn()
+            .await
+            .expect("restart the server");
+        Self {
+            addr: running.addr,
+            running,
+            ..self
+        }
+    }
+
+    /// Shuts the server down and drops its schema.
+    pub async fn stop(self) {
+        self.running.shutdown().await;
+        if let Ok((client, connection)) = tokio_postgres::connect(&self.database_url, NoTls).await {
+            tokio::spawn(connection);
+            let _ = client
+                .batch_execute(&format!("DROP SCHEMA {} CASCADE;", self.schema))
+                .await;
+        }
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn base_config(database_url: &str, schema: &str, dir: &Path) -> ServerConfig {
+    ServerConfig {
+        database_url: format!("{database_url} options='-c search_path={schema}'"),
+        listen_addr: SocketAddr::from(([127, 0, 0, 1], 0)),
+        tls_cert: dir.join("server.crt"),
+        tls_key: dir.join("server.key"),
+        fanout: FanoutBackend::Local,
+        admin_addr: None,
+        shutdown_deadline: Duration::from_secs(2),
+        tls_watch_interval: Some(Duration::from_millis(100)),
+        // Tests that log in again right after a failure would be refused.
+        login_backoff_base: Duration::ZERO,
+        ..ServerConfig::default()
+    }
+}
+
+fn clients(
+    pem: &[u8],
+    identity: Option<&(Vec<u8>, Vec<u8>)>,
+) -> (reqwest::Client, Arc<rustls::ClientConfig>) {
+    let mut http = reqwest::Client::builder()
+        .add_root_certificate(reqwest::Certificate::from_pem(pem).expect("parse the certificate"));
+    if let Some((cert, key)) = identity {
+        http = http.identity(
+            reqwest::Identity::from_pem(&[cert.as_slice(), key.as_slice()].concat())
+                .expect("parse the client identity"),
+        );
+    }
+    let http = http.build().expect("build the HTTP client");
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in CertificateDer::pem_slice_iter(pem) {
+        roots
+            .add(cert.expect("parse the certificate"))
+            .expect("trust the certificate");
+    }
+    let tls = rustls::ClientConfig::builder().with_root_certificates(roots);
+    let tls = match identity {
+        Some((cert, key)) => tls
+            .with_client_auth_cert(
+                CertificateDer::pem_slice_iter(cert)
+                    .collect::<Result<_, _>>()
+                    .expect("parse the client certificate"),
+                PrivateKeyDer::from_pem_slice(key).expect("parse the client key"),
+            )
+            .expect("use the client certificate"),
+        None => tls.with_no_client_auth(),
+    };
+    (http, Arc::new(tls))
+}
+
+pub struct WsClient {
+    stream: WebSocketStream<MaybeTlsStream<TcpStream>>,
+}
+
+impl WsClient {
+    pub async fn send(&mut self, message: Value) {
+        self.stream
+            .send(Message::text(message.to_string()))
+            .await
+            .expect("send a frame");
+    }
+
+    /// Next JSON frame, `None` once the server has closed the socket.
+    pub async fn recv(&mut self) -> Option<Value> {
+        loop {
+            let frame = tokio::time::timeout(RECV_TIMEOUT, self.stream.next())
+                .await
+                .expect("timed out waiting for a frame");
+            match frame {
+                Some(Ok(Message::Text(text))) => {
+                    return Some(serde_json::from_str(&text).expect("a JSON frame"));
+                }
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return None,
+                Some(Ok(_)) => {}
+            }
+        }
+    }
+
+    /// Skips frames until one of the given `type` arrives.
+    pub async fn recv_type(&mut self, ty: &str) -> Value {
+        loop {
+            match self.recv().await {
+                Some(frame) if frame["type"] == ty => return frame,
+                Some(_) => {}
+                None => panic!("socket closed while waiting for {ty}"),
+            }
+        }
+    }
+
+    /// Skips frames until the `Response` to request `id` arrives.
+    pub async fn response(&mut self, id: &str) -> Value {
+        loop {
+            let frame = self.recv_type("Response").await;
+            if frame["id"] == id {
+                return frame;
+            }
+        }
+    }
+
+    pub async fn close(mut self) {
+        let _ = self.stream.close(None).await;
+    }
+}
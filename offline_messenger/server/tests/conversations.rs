@@ -0,0 +1,81 @@
+mod common;
+
+use common::{TestServer, WsClient};
+use serde_json::{Value, json};
+
+async fn send(client: &mut WsClient, from: &str, token: &str, id: &str, message: &str) -> i64 {
+    client
+        .send(json!({
+            "type": "SendMessage",
+            "id": id,
+            "token": token,
+            "from": from,
+            "to": "alice",
+            "message": message,
+            "resp_msg": null,
+            "resp_user": null,
+        }))
+        .await;
+    let response = client.response(id).await;
+    assert_eq!(response["succes"], true, "{response}");
+    response["message_id"].as_i64().expect("a message id")
+}
+
+async fn conversations(client: &mut WsClient) -> Vec<Value> {
+    client.send(json!({ "type": "GetConversations" })).await;
+    let frame = client.recv_type("Conversations").await;
+    frame["conversations"].as_array().expect("a list").clone()
+}
+
+#[tokio::test]
+async fn inbox_is_ordered_by_activity_with_unread_counts() {
+    let server = TestServer::start().await;
+    let token = server.register("alice").await;
+    let bob_token = server.register("bob").await;
+    let carol_token = server.register("carol").await;
+    server.register("dave").await;
+    let mut alice = server.connect("alice", &token).await;
+    let mut bob = server.connect("bob", &bob_token).await;
+    let mut carol = server.connect("carol", &carol_token).await;
+
+    send(&mut bob, "bob", &bob_token, "b1", "first").await;
+    let last_from_bob = send(&mut bob, "bob", &bob_token, "b2", "second").await;
+    send(&mut carol, "carol", &carol_token, "c1", "hi alice").await;
+
+    // Each message updates the entry live.
+    let mut updates = Vec::new();
+    for _ in 0..3 {
+        updates.push(alice.recv_type("ConversationUpdate").await["conversation"].clone());
+    }
+    assert_eq!(updates[1]["with"], "bob");
+    assert_eq!(updates[1]["unread"], 2);
+    assert_eq!(updates[2]["with"], "carol");
+    assert_eq!(updates[2]["last_message"], "hi alice");
+
+    let inbox = conversations(&mut alice).await;
+    let order: Vec<&str> = inbox.iter().map(|c| c["with"].as_str().unwrap()).collect();
+    assert_eq!(order, ["carol", "bob", "dave"]);
+    assert_eq!(inbox[1]["last_from"], "bob");
+    assert_eq!(inbox[1]["last_message"], "second");
+    assert_eq!(inbox[1]["last_message_id"], last_from_bob);
+    assert!(inbox[1]["last_at"].as_i64().is_some());
+    assert_eq!(inbox[2]["last_message"], json!(null));
+    assert_eq!(inbox[2]["unread"], 0);
+
+    alice
+        .send(json!({ "type": "MarkRead", "with": "bob", "message_id": last_from_bob }))
+        .await;
+    let update = alice.recv_type("ConversationUpdate").await;
+    assert_eq!(update["conversation"]["with"], "bob");
+    assert_eq!(update["conversation"]["unread"], 0);
+
+    // Bob's own copy counts nothing as unread.
+    let inbox = conversations(&mut bob).await;
+    assert_eq!(inbox[0]["with"], "alice");
+    assert_eq!(inbox[0]["unread"], 0);
+
+    alice.close().await;
+    bob.close().await;
+    carol.close().await;
+    server.stop().await;
+}
@@ -0,0 +1,39 @@
+mod common;
+
+use common::TestServer;
+use serde_json::json;
+
+#[tokio::test]
+async fn a_burst_is_delivered_in_order() {
+    let server = TestServer::start().await;
+    let token = server.register("alice").await;
+    let bob_token = server.register("bob").await;
+    let mut alice = server.connect("alice", &token).await;
+    let mut bob = server.connect("bob", &bob_token).await;
+
+    let sent: Vec<String> = (0..100).map(|n| format!("m{n}")).collect();
+    for id in &sent {
+        alice
+            .send(json!({
+                "type": "SendMessage",
+                "id": id,
+                "token": token,
+                "from": "alice",
+                "to": "bob",
+                "message": id,
+                "resp_msg": null,
+                "resp_user": null,
+            }))
+            .await;
+    }
+    let mut received = Vec::new();
+    while received.len() < sent.len() {
+        let frame = bob.recv_type("Message").await;
+        received.push(frame["message"].as_str().expect("a message").to_string());
+    }
+    assert_eq!(received, sent);
+
+    alice.close().await;
+    bob.close().await;
+    server.stop().await;
+}
@@ -0,0 +1,291 @@
+mod common;
+
+use common::TestServer;
+use serde_json::json;
+use server::network_manager::logging::session_tag;
+
+#[tokio::test]
+async fn signin_and_login() {
+    let server = TestServer::start().await;
+
+    let (status, body) = server.signin("alice", "pw").await;
+    assert_eq!(status, 201, "{body}");
+    assert_eq!(body["succes"], true);
+
+    let (status, body) = server.signin("alice", "other").await;
+    assert_eq!(status, 409, "{body}");
+    assert_eq!(body["succes"], false);
+
+    let (status, body) = server.login("alice", "wrong").await;
+    assert_eq!(status, 401, "{body}");
+    assert_eq!(body["token"], "");
+
+    let (status, body) = server.login("alice", "pw").await;
+    assert_eq!(status, 200, "{body}");
+    assert!(!body["token"].as_str().unwrap_or_default().is_empty());
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn message_is_delivered_and_stored() {
+    let server = TestServer::start().await;
+    let alice_token = server.register("alice").await;
+    let bob_token = server.register("bob").await;
+    let mut alice = server.connect("alice", &alice_token).await;
+    let mut bob = server.connect("bob", &bob_token).await;
+
+    alice
+        .send(json!({
+            "type": "SendMessage",
+            "id": "m1",
+            "token": alice_token,
+            "from": "alice",
+            "to": "bob",
+            "message": "hello bob",
+            "resp_msg": null,
+            "resp_user": null,
+        }))
+        .await;
+    let response = alice.response("m1").await;
+    assert_eq!(response["succes"], true, "{response}");
+    let message_id = response["message_id"].as_i64().expect("a message id");
+
+    let delivered = bob.recv_type("Message").await;
+    assert_eq!(delivered["from"], "alice");
+    assert_eq!(delivered["message"], "hello bob");
+    assert_eq!(delivered["message_id"], message_id);
+
+    bob.send(json!({ "type": "GetMessage", "from": "alice", "idx": 0 }))
+        .await;
+    let setting = bob.recv_type("ExpirySetting").await;
+    assert_eq!(setting["expiry_secs"], json!(null));
+    let chat = bob.recv_type("Chat").await;
+    let history = chat["messages"].as_array().expect("a list");
+    assert_eq!(history.len(), 1, "{chat}");
+    assert_eq!(history[0]["id"], message_id);
+    assert_eq!(history[0]["from"], "alice");
+    assert_eq!(history[0]["to"], "bob");
+    assert_eq!(history[0]["content"], "hello bob");
+    assert_eq!(history[0]["encrypted"], false);
+    assert_eq!(history[0]["reply_to"], json!(null));
+    assert_eq!(history[0]["edited_at"], json!(null));
+    let sent_at = history[0]["sent_at"].as_str().expect("a timestamp");
+    let sent_at = chrono::DateTime::parse_from_rfc3339(sent_at).expect("an RFC 3339 timestamp");
+    assert!((chrono::Utc::now() - sent_at.to_utc()).num_seconds().abs() < 60);
+
+    alice.close().await;
+    bob.close().await;
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn invalid_token_closes_the_socket() {
+    let server = TestServer::start().await;
+    server.register("alice").await;
+
+    let mut ws = server.connect("alice", "not-a-token").await;
+    assert_eq!(ws.recv().await, None);
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn cannot_send_as_someone_else() {
+    let server = TestServer::start().await;
+    let alice_token = server.register("alice").await;
+    server.register("bob").await;
+    let mut alice = server.connect("alice", &alice_token).await;
+
+    alice
+        .send(json!({
+            "type": "SendMessage",
+            "id": "m1",
+            "token": alice_token,
+            "from": "bob",
+            "to": "alice",
+            "message": "forged",
+            "resp_msg": null,
+            "resp_user": null,
+        }))
+        .await;
+    let response = alice.response("m1").await;
+    assert_eq!(response["succes"], false);
+    assert_eq!(response["code"], "forbidden");
+
+    alice.close().await;
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn expiry_timer_is_validated_and_shared() {
+    let server = TestServer::start().await;
+    let alice_token = server.register("alice").await;
+    let bob_token = server.register("bob").await;
+    let mut alice = server.connect("alice", &alice_token).await;
+    let mut bob = server.connect("bob", &bob_token).await;
+
+    alice
+        .send(json!({ "type": "SetExpiry", "id": "e1", "with": "bob", "expiry_secs": 1 }))
+        .await;
+    let response = alice.response("e1").await;
+    assert_eq!(response["succes"], false);
+    assert_eq!(response["code"], "invalid_request");
+
+    alice
+        .send(json!({ "type": "SetExpiry", "id": "e2", "with": "bob", "expiry_secs": 60 }))
+        .await;
+    let response = alice.response("e2").await;
+    assert_eq!(response["succes"], true, "{response}");
+    let setting = bob.recv_type("ExpirySetting").await;
+    assert_eq!(setting["with"], "alice");
+    assert_eq!(setting["expiry_secs"], 60);
+    assert_eq!(setting["changed_by"], "alice");
+
+    alice.close().await;
+    bob.close().await;
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn usernames_and_messages_are_validated() {
+    let server = TestServer::start_with(|config| config.max_message_bytes = 8).await;
+
+    let (status, body) = server.signin("root", "pw").await;
+    assert_eq!(status, 400);
+    assert_eq!(body["code"], "invalid_username");
+    let (status, body) = server.signin("a b", "pw").await;
+    assert_eq!(status, 400);
+    assert_eq!(body["code"], "invalid_username");
+
+    let token = server.register("Alice").await;
+    let (status, body) = server.signin("ＡＬＩＣＥ", "pw").await;
+    assert_eq!(status, 409);
+    assert_eq!(body["code"], "username_taken");
+    server.register("bob").await;
+
+    let mut alice = server.connect("Alice", &token).await;
+    for (id, message, code) in [
+        ("m1", "123456789", "message_too_large"),
+        ("m2", "\u{7}", "invalid_message"),
+    ] {
+        alice
+            .send(json!({
+                "type": "SendMessage",
+                "id": id,
+                "token": token,
+                "from": "Alice",
+                "to": "bob",
+                "message": message,
+                "resp_msg": null,
+                "resp_user": null,
+            }))
+            .await;
+        let response = alice.response(id).await;
+        assert_eq!(response["succes"], false);
+        assert_eq!(response["code"], code);
+    }
+
+    alice.close().await;
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn repeated_message_id_is_stored_once() {
+    let server = TestServer::start().await;
+    let alice_token = server.register("alice").await;
+    let bob_token = server.register("bob").await;
+    let mut alice = server.connect("alice", &alice_token).await;
+    let mut bob = server.connect("bob", &bob_token).await;
+
+    let send = json!({
+        "type": "SendMessage",
+        "id": "7d3c1a52-0b8e-4c59-9a51-3f1f0e6c2b11",
+        "token": alice_token,
+        "from": "alice",
+        "to": "bob",
+        "message": "only once",
+        "resp_msg": null,
+        "resp_user": null,
+    });
+    alice.send(send.clone()).await;
+    let first = alice.response("7d3c1a52-0b8e-4c59-9a51-3f1f0e6c2b11").await;
+    assert_eq!(first["succes"], true, "{first}");
+    alice.send(send).await;
+    let retry = alice.response("7d3c1a52-0b8e-4c59-9a51-3f1f0e6c2b11").await;
+    assert_eq!(retry["succes"], true, "{retry}");
+    assert_eq!(retry["message_id"], first["message_id"]);
+
+    let delivered = bob.recv_type("Message").await;
+    assert_eq!(delivered["message"], "only once");
+    alice
+        .send(json!({
+            "type": "SendMessage",
+            "id": "m2",
+            "token": alice_token,
+            "from": "alice",
+            "to": "bob",
+            "message": "next",
+            "resp_msg": null,
+            "resp_user": null,
+        }))
+        .await;
+    // The retry was not fanned out a second time.
+    let delivered = bob.recv_type("Message").await;
+    assert_eq!(delivered["message"], "next");
+
+    bob.send(json!({ "type": "GetMessage", "from": "alice", "idx": 0 }))
+        .await;
+    let chat = bob.recv_type("Chat").await;
+    assert_eq!(chat["messages"].as_array().map(Vec::len), Some(2), "{chat}");
+
+    alice.close().await;
+    bob.close().await;
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn repeated_command_id_runs_the_command_once() {
+    let server = TestServer::start().await;
+    let token = server.register("alice").await;
+    let mut alice = server.connect("alice", &token).await;
+
+    let remind = json!({
+        "type": "SendMessage",
+        "id": "r1",
+        "token": token,
+        "from": "alice",
+        "to": "alice",
+        "message": "/remind 10m standup",
+        "resp_msg": null,
+        "resp_user": null,
+    });
+    let mut outputs = Vec::new();
+    for _ in 0..2 {
+        alice.send(remind.clone()).await;
+        let response = alice.response("r1").await;
+        assert_eq!(response["succes"], true, "{response}");
+        outputs.push(alice.recv_type("CommandOutput").await["text"].clone());
+    }
+    assert_eq!(outputs[0], outputs[1]);
+
+    let row = server
+        .sql()
+        .await
+        .query_one("SELECT COUNT(*) FROM scheduled_messages;", &[])
+        .await
+        .expect("count scheduled messages");
+    assert_eq!(row.get::<_, i64>(0), 1);
+
+    alice.close().await;
+    server.stop().await;
+}
+
+#[test]
+fn session_tags_reveal_nothing_of_the_token() {
+    let token = "3f2a9c4e-1b7d-4e8a-9c0f-6d5b4a3e2f1c";
+    let tag = session_tag(token);
+    assert_eq!(tag, session_tag(token));
+    assert_ne!(tag, session_tag("3f2a9c4e-0000-0000-0000-000000000000"));
+    assert!(!token.starts_with(&tag), "{tag}");
+}
@@ -0,0 +1,69 @@
+mod common;
+
+use common::TestServer;
+use serde_json::Value;
+use std::process::Output;
+use tokio::process::Command;
+
+/// Runs `messenger-loadtest` with two users for a second against `server`.
+async fn two_users(server: &TestServer, extra: &[&str]) -> Output {
+    let output = Command::new(env!("CARGO_BIN_EXE_messenger-loadtest"))
+        .arg("--url")
+        .arg(server.url(""))
+        .arg("--ca-cert")
+        .arg(server.cert_dir().join("server.crt"))
+        .args([
+            "--users",
+            "2",
+            "--rate",
+            "5",
+            "--duration",
+            "1",
+            "--drain",
+            "2",
+        ])
+        .args(extra)
+        .output()
+        .await
+        .expect("run messenger-loadtest");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{stderr}");
+    output
+}
+
+#[tokio::test]
+async fn a_two_user_run_reports_every_message() {
+    let server = TestServer::start().await;
+
+    let output = two_users(&server, &["--json"]).await;
+    let report: Value = serde_json::from_slice(&output.stdout).expect("a JSON report");
+    assert_eq!(report["users"], 2, "{report}");
+    assert_eq!(report["connect_errors"], 0, "{report}");
+    let sent = report["sent"].as_u64().expect("a sent count");
+    assert!(sent > 0, "{report}");
+    assert_eq!(report["acked"], sent, "{report}");
+    assert_eq!(report["delivered"], sent, "{report}");
+    assert_eq!(report["lost"], 0, "{report}");
+    assert_eq!(report["disconnects"], 0, "{report}");
+    for latency in ["ack_latency", "delivery_latency"] {
+        let p = &report[latency];
+        let max = p["max_ms"].as_f64().expect("a maximum");
+        assert!(p["p50_ms"].as_f64().expect("a median") <= max, "{report}");
+    }
+
+    let output = two_users(&server, &[]).await;
+    let text = String::from_utf8_lossy(&output.stdout);
+    let field = |label: &str| {
+        text.lines()
+            .find_map(|line| line.strip_prefix(label))
+            .map(str::trim)
+            .unwrap_or_else(|| panic!("no {label} in\n{text}"))
+            .to_string()
+    };
+    assert!(field("Users").starts_with("2 (0 failed"), "{text}");
+    assert_eq!(field("Not delivered"), "0", "{text}");
+    assert_eq!(field("Disconnects"), "0", "{text}");
+    assert_eq!(field("Delivery").split_whitespace().count(), 4, "{text}");
+
+    server.stop().await;
+}
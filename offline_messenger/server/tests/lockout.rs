@@ -0,0 +1,126 @@
+mod common;
+
+use common::TestServer;
+use futures_util::future::join_all;
+use std::time::Duration;
+
+#[tokio::test]
+async fn retrying_within_the_backoff_is_refused() {
+    let server = TestServer::start_with(|config| {
+        config.login_backoff_base = Duration::from_secs(3);
+    })
+    .await;
+    server.register("alice").await;
+
+    let (status, _) = server.login("alice", "wrong").await;
+    assert_eq!(status, 401);
+    let (status, body) = server.login("alice", "pw").await;
+    assert_eq!(status, 429, "{body}");
+    assert!(
+        body["retry_after"]
+            .as_i64()
+            .is_some_and(|secs| (1..=3).contains(&secs)),
+        "{body}"
+    );
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn parallel_attempts_cannot_pass_the_threshold() {
+    let server = TestServer::start_with(|config| {
+        config.login_lockout_threshold = 3;
+    })
+    .await;
+    server.register("alice").await;
+
+    let statuses = join_all((0..20).map(|_| server.login("alice", "wrong"))).await;
+    let checked = statuses.iter().filter(|(status, _)| *status == 401).count();
+    assert_eq!(checked, 3, "{statuses:?}");
+    assert!(
+        statuses
+            .iter()
+            .all(|(status, _)| [401, 429].contains(status))
+    );
+    let (status, body) = server.login("alice", "pw").await;
+    assert_eq!(status, 429, "{body}");
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn repeated_failures_lock_the_account_until_unlocked() {
+    let server = TestServer::start_with(|config| {
+        config.login_lockout_threshold = 3;
+    })
+    .await;
+    server.register("alice").await;
+
+    for _ in 0..3 {
+        let (status, body) = server.login("alice", "wrong").await;
+        assert_eq!(status, 401);
+        assert_eq!(body["code"], "invalid_credentials");
+    }
+    // Locked out, even with the right password.
+    let (status, body) = server.login("alice", "pw").await;
+    assert_eq!(status, 429, "{body}");
+    assert_eq!(body["code"], "too_many_attempts");
+    assert!(body["retry_after"].as_i64().is_some_and(|secs| secs > 0));
+
+    let cleared = server
+        .database()
+        .unlock_login(Some("alice"), None)
+        .await
+        .expect("unlock");
+    assert_eq!(cleared, 1);
+    let (status, body) = server.login("alice", "pw").await;
+    assert_eq!(status, 200, "{body}");
+
+    let outcomes: Vec<String> = server
+        .database()
+        .auth_log_entries(Some("alice"), None, 10)
+        .await
+        .expect("read the auth log")
+        .iter()
+        .map(|row| row.get(3))
+        .collect();
+    assert_eq!(
+        outcomes,
+        [
+            "success",
+            "locked_out",
+            "invalid_credentials",
+            "invalid_credentials",
+            "invalid_credentials",
+            "success",
+        ]
+    );
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn failures_from_one_address_lock_every_username() {
+    let server = TestServer::start_with(|config| {
+        config.login_ip_lockout_threshold = 4;
+    })
+    .await;
+    server.register("alice").await;
+
+    for user in ["bob", "carol", "dave", "erin"] {
+        let (status, _) = server.login(user, "guess").await;
+        assert_eq!(status, 401);
+    }
+    let (status, body) = server.login("alice", "pw").await;
+    assert_eq!(status, 429, "{body}");
+
+    server
+        .database()
+        .unlock_login(None, Some("127.0.0.1"))
+        .await
+        .expect("unlock");
+    let (status, _) = server.login("alice", "pw").await;
+    assert_eq!(status, 200);
+
+    server.stop().await;
+}
@@ -0,0 +1,70 @@
+mod common;
+
+use common::TestServer;
+use serde_json::json;
+use server::network_manager::markdown::sanitize;
+
+#[test]
+fn the_subset_is_stored_as_typed() {
+    for text in [
+        "hello bob",
+        "**bold**, *italics* and `code`",
+        "see [the docs](https://example.com/a_b) or <mailto:bob@example.com>",
+        "- one\n- two\n  1. nested\n\n```rust\nfn main() {}\n```",
+        "**unclosed and 2 < 3",
+        "line one\nline two",
+    ] {
+        assert_eq!(sanitize(text), text);
+    }
+}
+
+#[test]
+fn other_markup_is_escaped_to_plain_text() {
+    assert_eq!(sanitize("# Title"), "\\# Title");
+    assert_eq!(sanitize("> quoted"), "\\> quoted");
+    assert_eq!(sanitize("<b>hi</b>"), "\\<b\\>hi\\<\\/b\\>");
+    assert_eq!(sanitize("![cat](cat.png)"), "\\!\\[cat\\]\\(cat\\.png\\)");
+    assert_eq!(
+        sanitize("*[click](javascript:alert(1))*"),
+        "*\\[click\\]\\(javascript\\:alert\\(1\\)\\)*"
+    );
+    assert_eq!(sanitize("- ok\n\n---"), "- ok\n\n\\-\\-\\-");
+    for text in ["# Title\n> quote\n<div>x</div>", "![a](b) *c*", "***\n==="] {
+        let once = sanitize(text);
+        assert_eq!(sanitize(&once), once, "{text:?}");
+    }
+}
+
+#[tokio::test]
+async fn plain_messages_are_sanitized_before_they_are_stored() {
+    let server = TestServer::start().await;
+    let token = server.register("alice").await;
+    let bob_token = server.register("bob").await;
+    let mut alice = server.connect("alice", &token).await;
+    let mut bob = server.connect("bob", &bob_token).await;
+    for (id, message, encrypted) in [("m1", "<b>hi</b> **there**", false), ("m2", "<b>", true)] {
+        alice
+            .send(json!({
+                "type": "SendMessage",
+                "id": id,
+                "token": token,
+                "from": "alice",
+                "to": "bob",
+                "message": message,
+                "resp_msg": null,
+                "resp_user": null,
+                "encrypted": encrypted,
+            }))
+            .await;
+        assert_eq!(alice.response(id).await["succes"], true);
+    }
+    let delivered = bob.recv_type("Message").await;
+    assert_eq!(delivered["message"], "\\<b\\>hi\\<\\/b\\> **there**");
+    // Encrypted bodies are opaque to the server.
+    let delivered = bob.recv_type("Message").await;
+    assert_eq!(delivered["message"], "<b>");
+
+    alice.close().await;
+    bob.close().await;
+    server.stop().await;
+}
@@ -0,0 +1,249 @@
+mod common;
+
+use common::{TestServer, WsClient};
+use serde_json::{Value, json};
+use server::network_manager::{database_manager::AuditEntry, moderation::Role};
+
+/// Registers `username` and gives them `role`.
+async fn staff(server: &TestServer, username: &str, role: Role) -> String {
+    let token = server.register(username).await;
+    let audit = AuditEntry {
+        actor: "test",
+        action: "set_role",
+        detail: "",
+    };
+    server
+        .database()
+        .set_role(username, role, audit)
+        .await
+        .expect("set the role");
+    token
+}
+
+async fn request(client: &mut WsClient, frame: Value) -> Value {
+    let id = frame["id"].as_str().expect("an id").to_string();
+    client.send(frame).await;
+    client.response(&id).await
+}
+
+/// `(actor, action, target, detail)` of the audit log, oldest first.
+async fn audit_log(server: &TestServer) -> Vec<(String, String, String, String)> {
+    let rows = server
+        .database()
+        .audit_entries(100)
+        .await
+        .expect("read the audit log");
+    rows.iter()
+        .rev()
+        .map(|r| (r.get(1), r.get(2), r.get(3), r.get(4)))
+        .filter(|(actor, ..): &(String, String, String, String)| actor != "test")
+        .collect()
+}
+
+fn entry(
+    actor: &str,
+    action: &str,
+    target: &str,
+    detail: &str,
+) -> (String, String, String, String) {
+    (
+        actor.to_string(),
+        action.to_string(),
+        target.to_string(),
+        detail.to_string(),
+    )
+}
+
+#[tokio::test]
+async fn a_ban_ends_the_session_and_refuses_logins() {
+    let server = TestServer::start().await;
+    let mod_token = staff(&server, "mod", Role::Moderator).await;
+    let bob_token = server.register("bob").await;
+    let mut moderator = server.connect("mod", &mod_token).await;
+    let mut bob = server.connect("bob", &bob_token).await;
+    bob.send(json!({ "type": "Sync", "since": null })).await;
+    bob.recv_type("Synced").await;
+
+    let response = request(
+        &mut moderator,
+        json!({
+            "type": "BanUser",
+            "id": "b1",
+            "user": "bob",
+            "duration_secs": 3600,
+            "reason": "spam",
+        }),
+    )
+    .await;
+    assert_eq!(response["succes"], true, "{response}");
+    while bob.recv().await.is_some() {}
+
+    let (status, body) = server.login("bob", "pw").await;
+    assert_eq!(status, 403, "{body}");
+    assert_eq!(body["code"], "banned");
+    // The token of the kicked session cannot be used again either.
+    let mut bob = server.connect("bob", &bob_token).await;
+    bob.send(json!({ "type": "Sync", "since": null })).await;
+    assert_eq!(bob.recv().await, None);
+
+    let response = request(
+        &mut moderator,
+        json!({ "type": "UnbanUser", "id": "b2", "user": "bob" }),
+    )
+    .await;
+    assert_eq!(response["succes"], true, "{response}");
+    let (status, body) = server.login("bob", "pw").await;
+    assert_eq!(status, 200, "{body}");
+
+    assert_eq!(
+        audit_log(&server).await,
+        [
+            entry("mod", "ban", "bob", "duration=3600s reason=spam"),
+            entry("mod", "unban", "bob", ""),
+        ]
+    );
+
+    moderator.close().await;
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn a_muted_user_cannot_send() {
+    let server = TestServer::start().await;
+    let mod_token = staff(&server, "mod", Role::Moderator).await;
+    let bob_token = server.register("bob").await;
+    let mut moderator = server.connect("mod", &mod_token).await;
+    let mut bob = server.connect("bob", &bob_token).await;
+
+    let response = request(
+        &mut moderator,
+        json!({
+            "type": "MuteUser",
+            "id": "m1",
+            "user": "bob",
+            "duration_secs": null,
+            "reason": null,
+        }),
+    )
+    .await;
+    assert_eq!(response["succes"], true, "{response}");
+
+    let send = |id: &str| {
+        json!({
+            "type": "SendMessage",
+            "id": id,
+            "token": bob_token,
+            "from": "bob",
+            "to": "mod",
+            "message": "hello",
+            "resp_msg": null,
+            "resp_user": null,
+        })
+    };
+    let response = request(&mut bob, send("s1")).await;
+    assert_eq!(response["succes"], false, "{response}");
+    assert_eq!(response["code"], "muted");
+
+    let response = request(
+        &mut moderator,
+        json!({ "type": "UnmuteUser", "id": "m2", "user": "bob" }),
+    )
+    .await;
+    assert_eq!(response["succes"], true, "{response}");
+    let response = request(&mut bob, send("s2")).await;
+    assert_eq!(response["succes"], true, "{response}");
+
+    assert_eq!(
+        audit_log(&server).await,
+        [
+            entry("mod", "mute", "bob", "duration=permanent"),
+            entry("mod", "unmute", "bob", ""),
+        ]
+    );
+
+    moderator.close().await;
+    bob.close().await;
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn only_staff_may_moderate() {
+    let server = TestServer::start().await;
+    let dana_token = staff(&server, "dana", Role::Admin).await;
+    let mod_token = staff(&server, "mod", Role::Moderator).await;
+    let alice_token = server.register("alice").await;
+    server.register("bob").await;
+    let mut dana = server.connect("dana", &dana_token).await;
+    let mut moderator = server.connect("mod", &mod_token).await;
+    let mut alice = server.connect("alice", &alice_token).await;
+
+    let ban = |id: &str, user: &str| {
+        json!({
+            "type": "BanUser",
+            "id": id,
+            "user": user,
+            "duration_secs": null,
+            "reason": null,
+        })
+    };
+    let response = request(&mut alice, ban("a1", "bob")).await;
+    assert_eq!(response["code"], "forbidden", "{response}");
+    // Moderators cannot act on their peers or set roles.
+    let response = request(&mut moderator, ban("m1", "dana")).await;
+    assert_eq!(response["code"], "forbidden", "{response}");
+    let response = request(
+        &mut moderator,
+        json!({ "type": "SetRole", "id": "m2", "user": "alice", "role": "moderator" }),
+    )
+    .await;
+    assert_eq!(response["code"], "forbidden", "{response}");
+
+    let response = request(
+        &mut dana,
+        json!({ "type": "SetRole", "id": "r1", "user": "alice", "role": "moderator" }),
+    )
+    .await;
+    assert_eq!(response["succes"], true, "{response}");
+    let response = request(&mut alice, ban("a2", "bob")).await;
+    assert_eq!(response["succes"], true, "{response}");
+
+    assert_eq!(
+        audit_log(&server).await,
+        [
+            entry("dana", "set_role", "alice", "role=moderator"),
+            entry("alice", "ban", "bob", "duration=permanent"),
+        ]
+    );
+
+    dana.close().await;
+    moderator.close().await;
+    alice.close().await;
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn sanction_durations_are_bounded() {
+    let server = TestServer::start().await;
+    let mod_token = staff(&server, "mod", Role::Moderator).await;
+    server.register("bob").await;
+    let mut moderator = server.connect("mod", &mod_token).await;
+
+    for (id, duration) in [("d1", 0), ("d2", -5), ("d3", i64::MAX)] {
+        let response = request(
+            &mut moderator,
+            json!({
+                "type": "MuteUser",
+                "id": id,
+                "user": "bob",
+                "duration_secs": duration,
+                "reason": null,
+            }),
+        )
+        .await;
+        assert_eq!(response["code"], "invalid_request", "{response}");
+    }
+    assert_eq!(audit_log(&server).await, []);
+
+    moderator.close().await;
+    server.stop().await;
+}
@@ -0,0 +1,132 @@
+mod common;
+
+use common::TestServer;
+use rcgen::{
+    BasicConstraints, CertificateParams, CertifiedIssuer, DnType, ExtendedKeyUsagePurpose, IsCa,
+    KeyPair,
+};
+use server::network_manager::config::ClientAuth;
+use std::{fs, path::PathBuf};
+use uuid::Uuid;
+
+/// A throwaway CA, written to a temporary file for `client_ca`.
+struct TestCa {
+    issuer: CertifiedIssuer<'static, KeyPair>,
+    path: PathBuf,
+}
+
+impl TestCa {
+    fn new() -> Self {
+        let mut params = CertificateParams::new(Vec::<String>::new()).expect("CA params");
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "messenger test CA");
+        let issuer = CertifiedIssuer::self_signed(params, KeyPair::generate().expect("CA key"))
+            .expect("CA certificate");
+        let path = std::env::temp_dir().join(format!("client-ca-{}.crt", Uuid::new_v4().simple()));
+        fs::write(&path, issuer.pem()).expect("write the CA");
+        Self { issuer, path }
+    }
+
+    /// Certificate and key, as PEM, for a client named `user`.
+    fn client(&self, user: &str) -> (Vec<u8>, Vec<u8>) {
+        let mut params = CertificateParams::new(Vec::<String>::new()).expect("client params");
+        params.distinguished_name.push(DnType::CommonName, user);
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+        let key = KeyPair::generate().expect("client key");
+        let cert = params
+            .signed_by(&key, &self.issuer)
+            .expect("client certificate");
+        (cert.pem().into_bytes(), key.serialize_pem().into_bytes())
+    }
+}
+
+impl Drop for TestCa {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[tokio::test]
+async fn certificate_logs_in_without_a_password() {
+    let ca = TestCa::new();
+    let mut server = TestServer::start_with(|config| {
+        config.client_ca = Some(ca.path.clone());
+        config.client_auth = ClientAuth::Optional;
+    })
+    .await;
+    let (status, _) = server.signin("alice", "pw").await;
+    assert_eq!(status, 201);
+
+    // Without a certificate the password is still needed.
+    let (status, _) = server.login("alice", "").await;
+    assert_eq!(status, 401);
+
+    server.present(Some(ca.client("alice")));
+    let (status, body) = server.login("alice", "").await;
+    assert_eq!(status, 200, "{body}");
+    let token = body["token"].as_str().expect("a token").to_string();
+
+    let mut ws = server.connect("alice", &token).await;
+    ws.send(serde_json::json!({ "type": "ListScheduled" }))
+        .await;
+    ws.recv_type("ScheduledList").await;
+    ws.close().await;
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn certificate_cannot_be_used_for_another_user() {
+    let ca = TestCa::new();
+    let mut server = TestServer::start_with(|config| {
+        config.client_ca = Some(ca.path.clone());
+    })
+    .await;
+    let bob_token = server.register("bob").await;
+    server.register("alice").await;
+
+    server.present(Some(ca.client("alice")));
+    let (status, body) = server.login("bob", "pw").await;
+    assert_eq!(status, 403, "{body}");
+    assert_eq!(body["code"], "forbidden");
+    let (status, _) = server.signin("mallory", "pw").await;
+    assert_eq!(status, 403);
+
+    // Bob's token, obtained with his password, is no good on alice's
+    // certificate either.
+    let mut ws = server.connect("bob", &bob_token).await;
+    assert_eq!(ws.recv().await, None);
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn required_mode_rejects_clients_without_a_certificate() {
+    let ca = TestCa::new();
+    let other_ca = TestCa::new();
+    let mut server = TestServer::start_with(|config| {
+        config.client_ca = Some(ca.path.clone());
+        config.client_auth = ClientAuth::Required;
+    })
+    .await;
+
+    let no_cert = server.http().get(server.url("/login")).send().await;
+    assert!(
+        no_cert.is_err(),
+        "handshake without a certificate succeeded"
+    );
+
+    server.present(Some(other_ca.client("alice")));
+    let wrong_ca = server.http().get(server.url("/login")).send().await;
+    assert!(wrong_ca.is_err(), "certificate from another CA accepted");
+
+    server.present(Some(ca.client("alice")));
+    let (status, _) = server.signin("alice", "pw").await;
+    assert_eq!(status, 201);
+    let (status, body) = server.login("alice", "").await;
+    assert_eq!(status, 200, "{body}");
+
+    server.stop().await;
+}
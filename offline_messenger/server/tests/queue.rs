@@ -0,0 +1,49 @@
+use server::network_manager::{
+    handlers::InternalMessage,
+    queue::{Offer, SessionHandle},
+};
+
+fn notice(n: u64) -> InternalMessage {
+    InternalMessage::ServerShutdown {
+        reconnect_after_ms: n,
+    }
+}
+
+fn number(message: InternalMessage) -> u64 {
+    match message {
+        InternalMessage::ServerShutdown { reconnect_after_ms } => reconnect_after_ms,
+        _ => panic!("unexpected message"),
+    }
+}
+
+#[tokio::test]
+async fn spilled_notifications_arrive_in_order() {
+    let (session, mut rx) = SessionHandle::new(2, 3);
+    let offers: Vec<Offer> = (1..=4).map(|n| session.offer(notice(n))).collect();
+    assert_eq!(
+        offers,
+        [Offer::Queued, Offer::Queued, Offer::Spilled, Offer::Spilled]
+    );
+
+    assert_eq!(number(rx.recv().await.unwrap()), 1);
+    // The queue has room again, but 3 and 4 are still waiting.
+    assert_eq!(session.offer(notice(5)), Offer::Spilled);
+    assert_eq!(session.offer(notice(6)), Offer::Full);
+    let mut rest = Vec::new();
+    for _ in 0..4 {
+        rest.push(number(rx.recv().await.unwrap()));
+    }
+    assert_eq!(rest, [2, 3, 4, 5]);
+
+    assert_eq!(session.offer(notice(7)), Offer::Queued);
+    assert_eq!(number(rx.recv().await.unwrap()), 7);
+}
+
+#[tokio::test]
+async fn without_a_spill_store_a_full_queue_is_full() {
+    let (session, rx) = SessionHandle::new(1, 0);
+    assert_eq!(session.offer(notice(1)), Offer::Queued);
+    assert_eq!(session.offer(notice(2)), Offer::Full);
+    drop(rx);
+    assert_eq!(session.offer(notice(3)), Offer::Closed);
+}
@@ -0,0 +1,193 @@
+mod common;
+
+use common::{TestServer, WsClient};
+use serde_json::{Value, json};
+use server::network_manager::{
+    config::ServerConfig, database_manager::AuditEntry, moderation::SanctionKind,
+};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+fn fast_scheduler(config: &mut ServerConfig) {
+    config.scheduler_interval = Duration::from_millis(100);
+}
+
+/// Unix seconds `secs` from now. Truncated to whole seconds, so 1 may
+/// already be the current second by the time the server checks it.
+fn in_secs(secs: i64) -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("a clock after 1970")
+        .as_secs() as i64
+        + secs
+}
+
+/// Schedules `message` from alice to bob. Returns its id.
+async fn schedule(alice: &mut WsClient, id: &str, message: &str, deliver_at: i64) -> i64 {
+    alice
+        .send(json!({
+            "type": "ScheduleMessage",
+            "id": id,
+            "to": "bob",
+            "message": message,
+            "resp_msg": null,
+            "resp_user": null,
+            "deliver_at": deliver_at,
+        }))
+        .await;
+    let response = alice.response(id).await;
+    assert_eq!(response["succes"], true, "{response}");
+    let list = alice.recv_type("ScheduledList").await;
+    let item = list["items"]
+        .as_array()
+        .expect("a list")
+        .iter()
+        .find(|item| item["message"] == message)
+        .unwrap_or_else(|| panic!("{message} is not in {list}"))
+        .clone();
+    item["scheduled_id"].as_i64().expect("an id")
+}
+
+async fn pending(alice: &mut WsClient) -> Vec<Value> {
+    alice.send(json!({ "type": "ListScheduled" })).await;
+    let list = alice.recv_type("ScheduledList").await;
+    list["items"].as_array().expect("a list").clone()
+}
+
+async fn count(server: &TestServer, table: &str) -> i64 {
+    server
+        .sql()
+        .await
+        .query_one(&format!("SELECT COUNT(*) FROM {table};"), &[])
+        .await
+        .expect("count the rows")
+        .get(0)
+}
+
+/// Sends a message bob receives right away, so the next one he gets shows
+/// whether anything else arrived before it.
+async fn marker(alice: &mut WsClient, token: &str, bob: &mut WsClient) {
+    alice
+        .send(json!({
+            "type": "SendMessage",
+            "id": "marker",
+            "token": token,
+            "from": "alice",
+            "to": "bob",
+            "message": "marker",
+            "resp_msg": null,
+            "resp_user": null,
+        }))
+        .await;
+    assert_eq!(bob.recv_type("Message").await["message"], "marker");
+}
+
+#[tokio::test]
+async fn a_due_message_is_delivered_once() {
+    let server = TestServer::start_with(fast_scheduler).await;
+    let token = server.register("alice").await;
+    let bob_token = server.register("bob").await;
+    let mut alice = server.connect("alice", &token).await;
+    let mut bob = server.connect("bob", &bob_token).await;
+
+    let scheduled_id = schedule(&mut alice, "s1", "later", in_secs(2)).await;
+    let delivered = bob.recv_type("Message").await;
+    assert_eq!(delivered["message"], "later");
+    let notice = alice.recv_type("ScheduledDelivered").await;
+    assert_eq!(notice["scheduled_id"], scheduled_id);
+    assert_eq!(notice["message_id"], delivered["message_id"]);
+
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    marker(&mut alice, &token, &mut bob).await;
+    assert_eq!(pending(&mut alice).await, Vec::<Value>::new());
+    assert_eq!(count(&server, "messages").await, 2);
+
+    alice.close().await;
+    bob.close().await;
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn a_cancelled_message_is_never_delivered() {
+    let server = TestServer::start_with(fast_scheduler).await;
+    let token = server.register("alice").await;
+    let bob_token = server.register("bob").await;
+    let mut alice = server.connect("alice", &token).await;
+    let mut bob = server.connect("bob", &bob_token).await;
+
+    let scheduled_id = schedule(&mut alice, "s1", "never", in_secs(2)).await;
+    for (id, code) in [("c1", Value::Null), ("c2", json!("not_found"))] {
+        alice
+            .send(json!({ "type": "CancelScheduled", "id": id, "scheduled_id": scheduled_id }))
+            .await;
+        assert_eq!(alice.response(id).await["code"], code);
+    }
+
+    tokio::time::sleep(Duration::from_millis(2500)).await;
+    marker(&mut alice, &token, &mut bob).await;
+    assert_eq!(count(&server, "messages").await, 1);
+
+    alice.close().await;
+    bob.close().await;
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn a_sanctioned_senders_messages_wait() {
+    let server = TestServer::start_with(fast_scheduler).await;
+    let token = server.register("alice").await;
+    let bob_token = server.register("bob").await;
+    let mut alice = server.connect("alice", &token).await;
+    let mut bob = server.connect("bob", &bob_token).await;
+
+    schedule(&mut alice, "s1", "held", in_secs(2)).await;
+    let audit = |action| AuditEntry {
+        actor: "test",
+        action,
+        detail: "",
+    };
+    server
+        .database()
+        .add_sanction("alice", SanctionKind::Mute, None, None, audit("mute"))
+        .await
+        .expect("mute alice");
+    tokio::time::sleep(Duration::from_millis(2500)).await;
+    assert_eq!(pending(&mut alice).await.len(), 1);
+
+    server
+        .database()
+        .lift_sanction("alice", SanctionKind::Mute, audit("unmute"))
+        .await
+        .expect("unmute alice");
+    assert_eq!(bob.recv_type("Message").await["message"], "held");
+
+    alice.close().await;
+    bob.close().await;
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn pending_messages_survive_a_restart() {
+    let server = TestServer::start_with(fast_scheduler).await;
+    let token = server.register("alice").await;
+    server.register("bob").await;
+    let mut alice = server.connect("alice", &token).await;
+    schedule(&mut alice, "s1", "after the restart", in_secs(3)).await;
+    alice.close().await;
+
+    let server = server.restart(fast_scheduler).await;
+    let (_, body) = server.login("alice", "pw").await;
+    let token = body["token"].as_str().expect("a token");
+    let mut alice = server.connect("alice", token).await;
+    assert_eq!(pending(&mut alice).await.len(), 1);
+    let (_, body) = server.login("bob", "pw").await;
+    let bob_token = body["token"].as_str().expect("a token");
+    let mut bob = server.connect("bob", bob_token).await;
+    let delivered = bob.recv_type("Message").await;
+    assert_eq!(delivered["message"], "after the restart");
+    alice.recv_type("ScheduledDelivered").await;
+    assert_eq!(count(&server, "scheduled_messages").await, 0);
+
+    alice.close().await;
+    bob.close().await;
+    server.stop().await;
+}
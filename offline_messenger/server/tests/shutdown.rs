@@ -0,0 +1,42 @@
+mod common;
+
+use common::TestServer;
+use serde_json::json;
+use std::time::{Duration, Instant};
+
+#[tokio::test]
+async fn shutdown_tells_clients_when_to_reconnect() {
+    let deadline = Duration::from_secs(1);
+    let server = TestServer::start_with(|config| {
+        config.shutdown_deadline = deadline;
+        config.reconnect_after_ms = 1234;
+    })
+    .await;
+    let alice_token = server.register("alice").await;
+    let bob_token = server.register("bob").await;
+    let mut alice = server.connect("alice", &alice_token).await;
+    let mut bob = server.connect("bob", &bob_token).await;
+    for client in [&mut alice, &mut bob] {
+        client.send(json!({ "type": "Sync", "since": null })).await;
+        client.recv_type("Synced").await;
+    }
+
+    let stopping = tokio::spawn(async move {
+        let started = Instant::now();
+        server.stop().await;
+        started.elapsed()
+    });
+    for client in [&mut alice, &mut bob] {
+        let notice = client.recv_type("ServerShutdown").await;
+        assert_eq!(notice["reconnect_after_ms"], 1234);
+    }
+    // Alice hangs up, bob waits for the server to close his socket.
+    alice.close().await;
+    assert_eq!(bob.recv().await, None);
+
+    let took = stopping.await.expect("the shutdown finishes");
+    assert!(
+        took < deadline + Duration::from_secs(1),
+        "shutdown took {took:?}"
+    );
+}
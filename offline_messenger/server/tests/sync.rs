@@ -0,0 +1,184 @@
+mod common;
+
+use common::{TestServer, WsClient};
+use serde_json::{Value, json};
+use std::time::Duration;
+
+async fn send(client: &mut WsClient, from: &str, token: &str, to: &str, id: &str) -> i64 {
+    client
+        .send(json!({
+            "type": "SendMessage",
+            "id": id,
+            "token": token,
+            "from": from,
+            "to": to,
+            "message": id,
+            "resp_msg": null,
+            "resp_user": null,
+        }))
+        .await;
+    let response = client.response(id).await;
+    assert_eq!(response["succes"], true, "{response}");
+    response["message_id"].as_i64().expect("a message id")
+}
+
+async fn sync(client: &mut WsClient, since: Value) -> Value {
+    client.send(json!({ "type": "Sync", "since": since })).await;
+    client.recv_type("Synced").await
+}
+
+#[tokio::test]
+async fn reconnecting_gets_only_what_changed() {
+    let server = TestServer::start().await;
+    let token = server.register("alice").await;
+    let bob_token = server.register("bob").await;
+    let carol_token = server.register("carol").await;
+
+    let mut alice = server.connect("alice", &token).await;
+    let to_bob = send(&mut alice, "alice", &token, "bob", "a1").await;
+    let first = sync(&mut alice, json!(null)).await;
+    assert_eq!(first["reset"], false);
+    assert_eq!(first["messages"], json!([]));
+    let cursor = first["cursor"].clone();
+    alice.close().await;
+
+    // While alice is away: two messages, a read receipt and a deletion.
+    let mut bob = server.connect("bob", &bob_token).await;
+    let mut carol = server.connect("carol", &carol_token).await;
+    send(&mut bob, "bob", &bob_token, "alice", "b1").await;
+    let last = send(&mut bob, "bob", &bob_token, "alice", "b2").await;
+    bob.send(json!({ "type": "MarkRead", "with": "alice", "message_id": to_bob }))
+        .await;
+    bob.recv_type("ConversationUpdate").await;
+    let gone = send(&mut carol, "carol", &carol_token, "alice", "c1").await;
+    server
+        .database()
+        .purge_messages("carol")
+        .await
+        .expect("purge carol's messages");
+
+    // The session outlives the socket, so alice comes back with her token.
+    let mut alice = server.connect("alice", &token).await;
+    let synced = sync(&mut alice, cursor.clone()).await;
+    assert_eq!(synced["reset"], false);
+    assert_eq!(synced["more"], false);
+    let messages = synced["messages"].as_array().expect("a list");
+    let contents: Vec<&str> = messages
+        .iter()
+        .map(|m| m["content"].as_str().unwrap())
+        .collect();
+    assert_eq!(contents, ["b1", "b2"]);
+    assert_eq!(messages[1]["id"], last);
+    assert_eq!(messages[1]["from"], "bob");
+    assert_eq!(synced["deleted"], json!([gone]));
+    assert_eq!(
+        synced["reads"],
+        json!([{ "user": "bob", "with": "alice", "last_read": to_bob }])
+    );
+    assert!(synced["cursor"].as_i64() > cursor.as_i64());
+
+    let again = sync(&mut alice, synced["cursor"].clone()).await;
+    assert_eq!(again["messages"], json!([]));
+    assert_eq!(again["deleted"], json!([]));
+    assert_eq!(again["reads"], json!([]));
+    assert_eq!(again["cursor"], synced["cursor"]);
+
+    alice.close().await;
+    bob.close().await;
+    carol.close().await;
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn unknown_cursors_start_over() {
+    let server = TestServer::start().await;
+    let token = server.register("alice").await;
+    let mut alice = server.connect("alice", &token).await;
+    send(&mut alice, "alice", &token, "alice", "note").await;
+
+    let current = sync(&mut alice, json!(null)).await["cursor"].clone();
+    for since in [json!(-1), json!(current.as_i64().unwrap() + 1000)] {
+        let synced = sync(&mut alice, since).await;
+        assert_eq!(synced["reset"], true, "{synced}");
+        assert_eq!(synced["messages"], json!([]));
+        assert_eq!(synced["cursor"], current);
+    }
+
+    alice.close().await;
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn a_change_committed_late_is_not_skipped() {
+    let server = TestServer::start().await;
+    let token = server.register("alice").await;
+    server.register("bob").await;
+    let mut alice = server.connect("alice", &token).await;
+    let cursor = sync(&mut alice, json!(null)).await["cursor"].clone();
+
+    // Another writer takes a seq first but commits after a later message.
+    let mut other = server.sql().await;
+    let late = other.transaction().await.expect("begin");
+    late.execute(
+        "INSERT INTO messages (sender, receiver, content) VALUES ('bob', 'alice', 'late');",
+        &[],
+    )
+    .await
+    .expect("store the late message");
+    send(&mut alice, "alice", &token, "bob", "early").await;
+
+    alice.send(json!({ "type": "Sync", "since": cursor })).await;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    late.commit().await.expect("commit");
+    let synced = alice.recv_type("Synced").await;
+    let mut contents: Vec<&str> = synced["messages"]
+        .as_array()
+        .expect("a list")
+        .iter()
+        .map(|m| m["content"].as_str().unwrap())
+        .collect();
+    contents.sort();
+    assert_eq!(contents, ["early", "late"], "{synced}");
+
+    let again = sync(&mut alice, synced["cursor"].clone()).await;
+    assert_eq!(again["messages"], json!([]));
+
+    alice.close().await;
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn a_session_expires_after_the_grace_period() {
+    let server = TestServer::start_with(|config| {
+        config.session_grace = Duration::from_millis(200);
+    })
+    .await;
+    let token = server.register("alice").await;
+    server.connect("alice", &token).await.close().await;
+
+    let mut alice = server.connect("alice", &token).await;
+    sync(&mut alice, json!(null)).await;
+    alice.close().await;
+
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    let mut alice = server.connect("alice", &token).await;
+    alice.send(json!({ "type": "Sync", "since": null })).await;
+    assert_eq!(alice.recv().await, None);
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn logging_out_ends_the_session() {
+    let server = TestServer::start().await;
+    let token = server.register("alice").await;
+    let mut alice = server.connect("alice", &token).await;
+    alice.send(json!({ "type": "Logout" })).await;
+    assert_eq!(alice.recv().await, None);
+
+    let mut alice = server.connect("alice", &token).await;
+    alice.send(json!({ "type": "Sync", "since": null })).await;
+    assert_eq!(alice.recv().await, None);
+
+    server.stop().await;
+}
@@ -0,0 +1,93 @@
+mod common;
+
+use common::TestServer;
+use serde_json::json;
+use server::network_manager::tls;
+use std::{fs, time::Duration};
+
+#[tokio::test]
+async fn certificate_is_generated_on_first_start() {
+    let server = TestServer::start().await;
+    let cert = server.cert_dir().join("server.crt");
+    let key = server.cert_dir().join("server.key");
+
+    let fingerprint = tls::fingerprint(&cert).expect("a fingerprint");
+    assert_eq!(fingerprint.len(), 32 * 3 - 1);
+    assert!(
+        !tls::ensure_certificate(&cert, &key, &["localhost".to_string()]).expect("check the pair")
+    );
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(&key)
+            .expect("key metadata")
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+    let (status, _) = server.signin("alice", "pw").await;
+    assert_eq!(status, 201);
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn half_a_pair_is_not_overwritten() {
+    let dir = std::env::temp_dir().join(format!("half-pair-{}", std::process::id()));
+    fs::create_dir_all(&dir).expect("create the directory");
+    let cert = dir.join("server.crt");
+    fs::write(&cert, "keep me").expect("write the certificate");
+
+    let result = tls::ensure_certificate(&cert, &dir.join("server.key"), &["localhost".into()]);
+    assert!(result.is_err());
+    assert_eq!(fs::read_to_string(&cert).expect("read back"), "keep me");
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn certificate_is_reloaded_without_dropping_connections() {
+    let mut server = TestServer::start().await;
+    let token = server.register("alice").await;
+    server.register("bob").await;
+    let mut alice = server.connect("alice", &token).await;
+
+    // Rotate the pair the way a deployment would: write the new files next
+    // to the old ones and rename them into place.
+    let staging = server.cert_dir().join("next");
+    let (new_cert, new_key) = (staging.join("server.crt"), staging.join("server.key"));
+    tls::ensure_certificate(&new_cert, &new_key, &["localhost".to_string()])
+        .expect("generate the next certificate");
+    let old_fingerprint = tls::fingerprint(&server.cert_dir().join("server.crt")).expect("old");
+    assert_ne!(tls::fingerprint(&new_cert).expect("new"), old_fingerprint);
+    fs::rename(&new_key, server.cert_dir().join("server.key")).expect("move the key");
+    fs::rename(&new_cert, server.cert_dir().join("server.crt")).expect("move the certificate");
+
+    server.trust(&fs::read(server.cert_dir().join("server.crt")).expect("read"));
+    let mut reloaded = false;
+    for _ in 0..50 {
+        if server.http().get(server.url("/login")).send().await.is_ok() {
+            reloaded = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert!(reloaded, "new connections never got the new certificate");
+
+    alice
+        .send(json!({
+            "type": "SendMessage",
+            "id": "m1",
+            "token": token,
+            "from": "alice",
+            "to": "bob",
+            "message": "still here",
+            "resp_msg": null,
+            "resp_user": null,
+        }))
+        .await;
+    let response = alice.response("m1").await;
+    assert_eq!(response["succes"], true, "{response}");
+
+    alice.close().await;
+    server.stop().await;
+}
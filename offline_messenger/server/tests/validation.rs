@@ -0,0 +1,79 @@
+mod common;
+
+use common::TestServer;
+use serde_json::json;
+use server::network_manager::{
+    config::ServerConfig,
+    validation::{ValidationError, message, username, username_key},
+};
+
+#[test]
+fn usernames_are_normalized_and_checked() {
+    let config = ServerConfig::default();
+    assert_eq!(username("ａｌｉｃｅ", &config), Ok("alice".to_string()));
+    assert_eq!(username("Zoë_1.x-y", &config), Ok("Zoë_1.x-y".to_string()));
+    assert!(matches!(
+        username("", &config),
+        Err(ValidationError::UsernameLength { .. })
+    ));
+    assert_eq!(
+        username("al ice", &config),
+        Err(ValidationError::UsernameCharacter(' '))
+    );
+    assert_eq!(
+        username("bob\u{0}", &config),
+        Err(ValidationError::UsernameCharacter('\u{0}'))
+    );
+    assert!(matches!(
+        username(&"a".repeat(10_000), &config),
+        Err(ValidationError::UsernameLength { .. })
+    ));
+    assert_eq!(
+        username("ADMIN", &config),
+        Err(ValidationError::ReservedUsername)
+    );
+}
+
+#[test]
+fn username_keys_fold_case_and_compatibility_forms() {
+    assert_eq!(username_key("Alice"), username_key("ＡＬＩＣＥ"));
+    assert_eq!(username_key("Straße"), username_key("STRASSE"));
+    assert_eq!(username_key("ΟΔΟΣ"), username_key("οδος"));
+    assert_ne!(username_key("alice"), username_key("alicia"));
+}
+
+#[test]
+fn messages_are_cleaned_and_limited() {
+    assert_eq!(
+        message("hi\u{7}\nthere\t!", 100),
+        Ok("hi\nthere\t!".to_string())
+    );
+    assert_eq!(message(" \u{1b} ", 100), Err(ValidationError::EmptyMessage));
+    assert_eq!(
+        message("héllo", 5),
+        Err(ValidationError::MessageTooLarge { max_bytes: 5 })
+    );
+}
+
+#[tokio::test]
+async fn logins_ignore_case_like_sign_up() {
+    let server = TestServer::start().await;
+    server.register("alice").await;
+    let (status, body) = server.signin("ALICE", "pw").await;
+    assert_eq!(status, 409, "{body}");
+
+    let (status, body) = server.login("Alice", "pw").await;
+    assert_eq!(status, 200, "{body}");
+    assert_eq!(body["username"], "alice");
+    // The session belongs to the stored name.
+    let token = body["token"].as_str().expect("a token");
+    let mut alice = server.connect("alice", token).await;
+    alice.send(json!({ "type": "Sync", "since": null })).await;
+    alice.recv_type("Synced").await;
+    alice.close().await;
+
+    let (status, body) = server.login("ＡＬＩＣＥ", "wrong").await;
+    assert_eq!(status, 401, "{body}");
+
+    server.stop().await;
+}
@@ -0,0 +1,229 @@
+mod common;
+
+use axum::{
+    Router,
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    routing::post,
+};
+use common::TestServer;
+use hmac::{Hmac, Mac};
+use serde_json::{Value, json};
+use server::network_manager::config::ServerConfig;
+use sha2::Sha256;
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// Plain HTTP endpoint standing in for a webhook receiver. Answers the
+/// first `failures` requests with a 500.
+#[derive(Clone)]
+struct Receiver {
+    requests: Arc<Mutex<Vec<(HeaderMap, String)>>>,
+    failures: Arc<Mutex<usize>>,
+    url: String,
+}
+
+impl Receiver {
+    async fn start(failures: usize) -> Self {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("bind the receiver");
+        let receiver = Receiver {
+            requests: Arc::default(),
+            failures: Arc::new(Mutex::new(failures)),
+            url: format!("http://{}/hook", listener.local_addr().expect("address")),
+        };
+        let app = Router::new()
+            .route("/hook", post(Receiver::handle))
+            .with_state(receiver.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        receiver
+    }
+
+    async fn handle(
+        State(receiver): State<Receiver>,
+        headers: HeaderMap,
+        body: String,
+    ) -> StatusCode {
+        receiver.requests.lock().unwrap().push((headers, body));
+        let mut failures = receiver.failures.lock().unwrap();
+        if *failures > 0 {
+            *failures -= 1;
+            StatusCode::INTERNAL_SERVER_ERROR
+        } else {
+            StatusCode::NO_CONTENT
+        }
+    }
+
+    fn requests(&self) -> Vec<(HeaderMap, String)> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+fn configure(config: &mut ServerConfig, receiver: &Receiver) {
+    config.webhook_urls = vec![receiver.url.clone()];
+    config.webhook_secret = Some("s3cret".to_string());
+    config.webhook_backoff_base = Duration::from_millis(20);
+    config.webhook_interval = Duration::from_millis(20);
+}
+
+/// Polls the delivery log until `done` holds for it.
+async fn wait_for(server: &TestServer, done: impl Fn(&[Value]) -> bool) -> Vec<Value> {
+    for _ in 0..250 {
+        let deliveries: Vec<Value> = server
+            .database()
+            .webhook_deliveries(None, 50)
+            .await
+            .expect("read the delivery log")
+            .iter()
+            .map(|row| {
+                json!({
+                    "event": row.get::<_, String>(3),
+                    "status": row.get::<_, String>(4),
+                    "attempts": row.get::<_, i32>(5),
+                })
+            })
+            .collect();
+        if done(&deliveries) {
+            return deliveries;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("the webhook deliveries never settled");
+}
+
+#[tokio::test]
+async fn events_are_signed_and_retried() {
+    let receiver = Receiver::start(1).await;
+    let server = TestServer::start_with(|config| configure(config, &receiver)).await;
+    let token = server.register("alice").await;
+    server.register("bob").await;
+    let mut alice = server.connect("alice", &token).await;
+    alice
+        .send(json!({
+            "type": "SendMessage",
+            "id": "m1",
+            "token": token,
+            "from": "alice",
+            "to": "bob",
+            "message": "hook me",
+            "resp_msg": null,
+            "resp_user": null,
+        }))
+        .await;
+    assert_eq!(alice.response("m1").await["succes"], true);
+
+    let deliveries = wait_for(&server, |d| {
+        d.len() == 4 && d.iter().all(|d| d["status"] == "delivered")
+    })
+    .await;
+    assert_eq!(
+        deliveries
+            .iter()
+            .map(|d| d["attempts"].as_i64().unwrap())
+            .sum::<i64>(),
+        5,
+        "one delivery needed a retry: {deliveries:?}"
+    );
+
+    let requests = receiver.requests();
+    assert_eq!(requests.len(), 5);
+    for (headers, body) in &requests {
+        let header = |name: &str| headers[name].to_str().expect("ASCII header").to_string();
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"s3cret").expect("key");
+        mac.update(format!("{}.{body}", header("x-messenger-timestamp")).as_bytes());
+        let expected: String = mac
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+        assert_eq!(
+            header("x-messenger-signature"),
+            format!("sha256={expected}")
+        );
+        let payload: Value = serde_json::from_str(body).expect("JSON payload");
+        assert_eq!(payload["event"], header("x-messenger-event"));
+    }
+    let sent: Value = requests
+        .iter()
+        .map(|(_, body)| serde_json::from_str::<Value>(body).unwrap())
+        .find(|p| p["event"] == "message.sent")
+        .expect("a message.sent event");
+    assert_eq!(sent["data"]["from"], "alice");
+    assert_eq!(sent["data"]["to"], "bob");
+    assert_eq!(sent["data"]["content"], "hook me");
+
+    alice.close().await;
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn failing_deliveries_are_given_up_and_can_be_retried() {
+    let receiver = Receiver::start(usize::MAX).await;
+    let server = TestServer::start_with(|config| {
+        configure(config, &receiver);
+        config.webhook_max_attempts = 2;
+        config.webhook_events = vec!["user.signed_up".to_string()];
+    })
+    .await;
+    let token = server.register("alice").await;
+    server.connect("alice", &token).await.close().await;
+
+    let deliveries = wait_for(&server, |d| d.len() == 1 && d[0]["status"] == "failed").await;
+    assert_eq!(deliveries[0]["event"], "user.signed_up");
+    assert_eq!(deliveries[0]["attempts"], 2);
+    assert_eq!(receiver.requests().len(), 2);
+
+    let id: i64 = server
+        .database()
+        .webhook_deliveries(Some("failed"), 1)
+        .await
+        .expect("read the delivery log")[0]
+        .get(0);
+    *receiver.failures.lock().unwrap() = 0;
+    assert!(server.database().retry_webhook(id).await.expect("retry"));
+    wait_for(&server, |d| d.len() == 1 && d[0]["status"] == "delivered").await;
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn nothing_is_sent_without_a_secret() {
+    let receiver = Receiver::start(0).await;
+    let server = TestServer::start_with(|config| {
+        configure(config, &receiver);
+        config.webhook_secret = None;
+    })
+    .await;
+    let token = server.register("alice").await;
+    server.register("bob").await;
+    let mut alice = server.connect("alice", &token).await;
+    alice
+        .send(json!({
+            "type": "SendMessage",
+            "id": "m1",
+            "token": token,
+            "from": "alice",
+            "to": "bob",
+            "message": "unsigned",
+            "resp_msg": null,
+            "resp_user": null,
+        }))
+        .await;
+    assert_eq!(alice.response("m1").await["succes"], true);
+
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let queued = server
+        .database()
+        .webhook_deliveries(None, 50)
+        .await
+        .expect("read the delivery log");
+    assert!(queued.is_empty());
+    assert!(receiver.requests().is_empty());
+
+    alice.close().await;
+    server.stop().await;
+}